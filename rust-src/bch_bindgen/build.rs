@@ -13,6 +13,14 @@ fn main() {
 
 	println!("cargo:rustc-link-lib=dylib=bcachefs");
 	println!("cargo:rustc-link-search={}", env!("LIBBCACHEFS_LIB"));
+	// DEP_BCACHEFS_LIB for the build scripts of crates using this one
+	println!("cargo:lib={}", env!("LIBBCACHEFS_LIB"));
+
+	// upstream revision the libbcachefs sources were last synced with
+	let revision_file = libbcachefs_inc_dir.join(".bcachefs_revision");
+	let revision = std::fs::read_to_string(&revision_file).unwrap_or_else(|_| "unknown".to_owned());
+	println!("cargo:rustc-env=LIBBCACHEFS_REVISION={}", revision.trim());
+	println!("cargo:rerun-if-changed={}", revision_file.display());
 
 	let _libbcachefs_dir = top_dir.join("libbcachefs").join("libbcachefs");
 	let bindings = bindgen::builder()
@@ -46,11 +54,17 @@ fn main() {
 		.blocklist_type("srcu_struct")
 		.allowlist_var("BCH_.*")
 		.allowlist_var("KEY_SPEC_.*")
+		.allowlist_var("bch2_opt_table")
+		.allowlist_var("bch2_metadata_versions")
 		.allowlist_type("bch_kdf_types")
+		.allowlist_type("bch_csum_type")
 		.allowlist_type("bch_sb_field_.*")
 		.allowlist_type("bch_encrypted_key")
 		.allowlist_type("nonce")
+		.allowlist_type("bch_option")
+		.allowlist_type("bcachefs_metadata_version")
 		.newtype_enum("bch_kdf_types")
+		.newtype_enum("opt_flags")
 		.opaque_type("gendisk")
 		.opaque_type("bkey")
 		// .opaque_type("bch_extent_ptr")
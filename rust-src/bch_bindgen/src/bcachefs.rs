@@ -28,6 +28,83 @@ impl bch_sb_field_crypt {
 	pub fn key(&self) -> &bch_encrypted_key {
 		&self.key
 	}
+	/// Length in bytes of the key that unlocks the filesystem, i.e. of the
+	/// `bch_key` in the encrypted key blob, not counting its magic. This is
+	/// fixed at 32 (a 256-bit ChaCha20 key): the KDF only derives the key
+	/// that decrypts it, and doesn't change its size.
+	pub fn key_len(&self) -> usize {
+		std::mem::size_of::<bch_key>()
+	}
+	/// The encryption setup, for describing it; anything that shows the
+	/// crypt field should go through this rather than read the field
+	pub fn info(&self) -> CryptInfo {
+		let kdf = match self.scrypt_flags() {
+			Some(s) => Kdf::Scrypt(Scrypt { n_log2: s.N() as u16, r_log2: s.R() as u16, p_log2: s.P() as u16 }),
+			None => Kdf::Unknown(bch_crypt_flags(self.flags).TYPE()),
+		};
+		CryptInfo { kdf, key_len: self.key_len() }
+	}
+}
+
+/// Parameters of the scrypt KDF. The superblock stores each of N, r and p as
+/// its base 2 logarithm; [`Scrypt::n`] and the others are the values scrypt
+/// is run with, `None` if they don't fit in a u64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scrypt {
+	pub n_log2: u16,
+	pub r_log2: u16,
+	pub p_log2: u16,
+}
+
+impl Scrypt {
+	pub fn n(&self) -> Option<u64> {
+		1u64.checked_shl(self.n_log2.into())
+	}
+	pub fn r(&self) -> Option<u64> {
+		1u64.checked_shl(self.r_log2.into())
+	}
+	pub fn p(&self) -> Option<u64> {
+		1u64.checked_shl(self.p_log2.into())
+	}
+}
+
+/// How the key that decrypts the filesystem key is derived from the passphrase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kdf {
+	Scrypt(Scrypt),
+	/// A type this libbcachefs doesn't know
+	Unknown(u64),
+}
+
+impl Kdf {
+	pub fn name(&self) -> &'static str {
+		match self {
+			Kdf::Scrypt(_) => "scrypt",
+			Kdf::Unknown(_) => "unknown",
+		}
+	}
+}
+
+/// What the crypt field says about the encryption setup. It holds no key
+/// material, encrypted or not, so neither Debug nor Display can show any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CryptInfo {
+	pub kdf: Kdf,
+	/// Length in bytes of the filesystem key, as [`bch_sb_field_crypt::key_len`]
+	pub key_len: usize,
+}
+
+impl std::fmt::Display for CryptInfo {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let value = |log2: u16| 1u64.checked_shl(log2.into()).map_or_else(|| format!("2^{}", log2), |v| v.to_string());
+		match self.kdf {
+			Kdf::Scrypt(s) => {
+				write!(f, "scrypt (N={}, r={}, p={})", value(s.n_log2), value(s.r_log2), value(s.p_log2))?
+			}
+			Kdf::Unknown(kdf_type) => write!(f, "unknown KDF type {}", kdf_type)?,
+		}
+		write!(f, ", {}-bit key", self.key_len * 8)
+	}
 }
 impl PartialEq for bch_sb {
 	fn eq(&self, other: &Self) -> bool {
@@ -46,20 +123,220 @@ impl std::fmt::Debug for bch_sb {
 			.field("uuid", &self.uuid())
 			.field("version", &(self.version, self.version_min))
 			.field("block_size", &self.block_size)
+			.field("btree_node_size", &self.btree_node_size())
 			.field("device_idx", &self.dev_idx)
 			.field("seq", &self.seq)
 			.field("csum", &(self.csum.lo, self.csum.hi))
 			.field("offset", &self.offset)
+			.field("layout", &self.layout())
+			.field("crypt", &self.crypt().map(bch_sb_field_crypt::info))
 		.finish_non_exhaustive()
     }
 }
 
 
+/// Where the copies of the superblock are on a member device, as the layout
+/// in its superblock lists them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+	pub layout_type: u8,
+	/// Largest size of a copy: base 2 of 512 byte sectors
+	pub sb_max_size_bits: u8,
+	/// Sectors the copies are at, the primary usually first
+	pub offsets: Vec<u64>,
+}
+
+/// A member device as recorded in the superblock
+#[derive(Debug, Clone)]
+pub struct MemberInfo {
+	pub dev_idx: u8,
+	pub uuid: uuid::Uuid,
+	pub nbuckets: u64,
+	pub first_bucket: u16,
+	/// Bucket size in 512 byte sectors
+	pub bucket_size: u16,
+	/// Time of the last mount, in seconds since the epoch
+	pub last_mount: u64,
+	/// `bch_member_state`: rw, ro, failed or spare
+	pub state: u64,
+	/// Index into the disk groups field, if the member belongs to a group
+	pub group: Option<u64>,
+	pub durability: u64,
+}
+
+impl From<(u8, &bch_member)> for MemberInfo {
+	fn from((dev_idx, m): (u8, &bch_member)) -> Self {
+		let flags = m.flags[0];
+		let group = (flags >> 20) & 0xff; // BCH_MEMBER_GROUP, biased by one
+		let durability = (flags >> 28) & 0x3; // BCH_MEMBER_DURABILITY, biased by one
+		MemberInfo {
+			dev_idx,
+			uuid: uuid::Uuid::from_bytes(m.uuid.b),
+			nbuckets: m.nbuckets,
+			first_bucket: m.first_bucket,
+			bucket_size: m.bucket_size,
+			last_mount: m.last_mount,
+			state: flags & 0xf,
+			group: group.checked_sub(1),
+			durability: durability.checked_sub(1).unwrap_or(1),
+		}
+	}
+}
+
+/// An entry of the replicas superblock field: some data of one type is kept
+/// on `devs`, and can be read as long as `nr_required` of them are present
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplicasEntry {
+	/// `bch_data_type`
+	pub data_type: u8,
+	pub nr_required: u8,
+	pub devs: Vec<u8>,
+}
+
+impl ReplicasEntry {
+	/// Whether this is cached data, which losing only costs a cache miss
+	pub fn is_cached(&self) -> bool {
+		self.data_type == 5 // BCH_DATA_cached
+	}
+
+	/// Entries of a replicas field's payload; replicas_v0 entries lack
+	/// `nr_required`, as one device was always enough. Zero padding ends it.
+	fn parse(mut bytes: &[u8], v0: bool) -> Vec<Self> {
+		let header = if v0 { 2 } else { 3 };
+		let mut entries = Vec::new();
+		while bytes.len() >= header && bytes[0] != 0 {
+			let end = header + bytes[1] as usize;
+			let devs = match bytes.get(header..end) {
+				Some(devs) => devs.to_vec(),
+				None => break,
+			};
+			let nr_required = if v0 { 1 } else { bytes[2] };
+			entries.push(ReplicasEntry { data_type: bytes[0], nr_required, devs });
+			bytes = &bytes[end..];
+		}
+		entries
+	}
+}
+
+/// An entry of the disk groups field: a label given to devices, e.g. "fast"
+/// of "ssd.fast", nested in its parent's
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskGroup {
+	pub label: String,
+	pub deleted: bool,
+	/// Index of the parent group, for nested labels
+	pub parent: Option<u64>,
+	/// `BCH_GROUP_DATA_ALLOWED`: data types allowed on the group, by bit
+	pub data_allowed: u64,
+}
+
+impl bch_sb_field_disk_groups {
+	/// The groups, by index
+	pub fn entries(&self) -> Vec<DiskGroup> {
+		use std::mem::size_of;
+
+		let bytes = (self.field.u64s as usize * 8).saturating_sub(size_of::<bch_sb_field_disk_groups>());
+		let nr = bytes / size_of::<bch_disk_group>();
+		unsafe { self.entries.as_slice(nr) }
+			.iter()
+			.map(|g| {
+				let label = g.label;
+				let len = label.iter().position(|&b| b == 0).unwrap_or(label.len());
+				let flags = g.flags[0];
+				DiskGroup {
+					label: String::from_utf8_lossy(&label[..len]).into_owned(),
+					deleted: flags & 1 != 0,
+					data_allowed: (flags >> 1) & 0x1f,
+					// BCH_GROUP_PARENT, biased by one
+					parent: ((flags >> 6) & 0x3ffff).checked_sub(1),
+				}
+			})
+			.collect()
+	}
+}
+
+/// Paths of disk groups, as in "ssd.fast"
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiskGroups(pub Vec<DiskGroup>);
+
+impl DiskGroups {
+	/// Indexes from group `index` up to the one at the top; `None` if one of
+	/// them is deleted or doesn't exist, or the parents go round in circles
+	fn ancestry(&self, index: u64) -> Option<Vec<u64>> {
+		let mut path = vec![index];
+		loop {
+			let group = self.0.get(*path.last()? as usize).filter(|g| !g.deleted)?;
+			match group.parent {
+				// at most 32 deep, as libbcachefs has it
+				Some(_) if path.len() == 32 => return None,
+				Some(parent) => path.push(parent),
+				None => return Some(path),
+			}
+		}
+	}
+
+	/// Labels from the top down to group `index`, joined by dots, as
+	/// bch2_disk_path_to_text() writes them; `None` if the group isn't valid
+	pub fn path(&self, index: u64) -> Option<String> {
+		let path = self.ancestry(index)?;
+		let labels: Vec<&str> = path.iter().rev().map(|&i| self.0[i as usize].label.as_str()).collect();
+		Some(labels.join("."))
+	}
+
+	/// Whether group `index` is `ancestor` or nested in it
+	pub fn is_within(&self, index: u64, ancestor: u64) -> bool {
+		self.ancestry(index).map_or(false, |path| path.contains(&ancestor))
+	}
+}
+
+/// What a target option points at: one device, or the devices of a disk
+/// group and the groups nested in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+	Device(u64),
+	Group(u64),
+}
+
+impl Target {
+	/// A target option's value: 0 for none, then devices from
+	/// TARGET_DEV_START, then groups from TARGET_GROUP_START
+	pub fn decode(v: u64) -> Option<Self> {
+		match v {
+			0 => None,
+			1..=256 => Some(Target::Device(v - 1)),
+			v => Some(Target::Group(v - 257)),
+		}
+	}
+}
+
+/// The part a device plays through the target options that cover it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+	Foreground,
+	Background,
+	Promote,
+	Metadata,
+}
+
+impl Role {
+	pub const ALL: [Role; 4] = [Role::Foreground, Role::Background, Role::Promote, Role::Metadata];
+
+	/// Name of the role, the option without its "_target"
+	pub fn name(self) -> &'static str {
+		match self {
+			Role::Foreground => "foreground",
+			Role::Background => "background",
+			Role::Promote => "promote",
+			Role::Metadata => "metadata",
+		}
+	}
+}
+
 impl bch_sb {
 	pub fn crypt(&self) -> Option<&bch_sb_field_crypt> {
 		unsafe {
 			let ptr = bch2_sb_field_get(self as *const _ as *mut _, bch_sb_field_type::BCH_SB_FIELD_crypt) as *const u8;
-			if ptr.is_null() {
+			if ptr.is_null() || ((*(ptr as *const bch_sb_field)).u64s as usize) * 8 < std::mem::size_of::<bch_sb_field_crypt>() {
 				None
 			} else {
 				let offset = offset_of!(bch_sb_field_crypt, field);
@@ -71,6 +348,253 @@ impl bch_sb {
 		uuid::Uuid::from_bytes(self.user_uuid.b)
 	}
 
+	/// The layout of the superblock copies on this member
+	pub fn layout(&self) -> Layout {
+		let offsets = self.layout.sb_offset;
+		let nr = (self.layout.nr_superblocks as usize).min(offsets.len());
+		Layout {
+			layout_type: self.layout.layout_type,
+			sb_max_size_bits: self.layout.sb_max_size_bits,
+			offsets: offsets[..nr].to_vec(),
+		}
+	}
+
+	/// Sectors the copies of the superblock are at, as its layout lists them
+	pub fn superblock_offsets(&self) -> Vec<u64> {
+		self.layout().offsets
+	}
+
+	/// The UUID the kernel identifies the filesystem by internally, e.g. in
+	/// its log lines, as opposed to the external `uuid` users pick it by
+	pub fn internal_uuid(&self) -> uuid::Uuid {
+		uuid::Uuid::from_bytes(self.uuid.b)
+	}
+
+	/// Filesystem label, `None` if it wasn't given one
+	pub fn label(&self) -> Option<String> {
+		let label = self.label;
+		let len = label.iter().position(|&b| b == 0).unwrap_or(label.len());
+		match len {
+			0 => None,
+			_ => Some(String::from_utf8_lossy(&label[..len]).into_owned()),
+		}
+	}
+
+	/// Contents of a superblock field, following its 8 byte header
+	fn field_payload(&self, ty: bch_sb_field_type) -> Option<&[u64]> {
+		unsafe {
+			let field = bch2_sb_field_get(self as *const _ as *mut _, ty);
+			if field.is_null() {
+				return None;
+			}
+			let u64s = ((*field).u64s as usize).saturating_sub(1);
+			Some(std::slice::from_raw_parts((field as *const u64).add(1), u64s))
+		}
+	}
+
+	/// Entries of the replicas field, which lists the sets of devices data is
+	/// kept on, or of replicas_v0 in older filesystems; `None` if there is
+	/// neither
+	pub fn replicas(&self) -> Option<Vec<ReplicasEntry>> {
+		use bch_sb_field_type::*;
+
+		let (payload, v0) = match self.field_payload(BCH_SB_FIELD_replicas) {
+			Some(payload) => (payload, false),
+			None => (self.field_payload(BCH_SB_FIELD_replicas_v0)?, true),
+		};
+		let bytes = unsafe { std::slice::from_raw_parts(payload.as_ptr() as *const u8, payload.len() * 8) };
+		Some(ReplicasEntry::parse(bytes, v0))
+	}
+
+	/// Size of the journal on this member device, in 512 byte sectors.
+	///
+	/// A larger journal allows more writes to be batched up before journal
+	/// reclaim has to flush btree updates, which helps write throughput, but
+	/// also means more has to be replayed when mounting after an unclean
+	/// shutdown.
+	pub fn journal_size_sectors(&self) -> u64 {
+		use bch_sb_field_type::*;
+
+		let buckets = match self.field_payload(BCH_SB_FIELD_journal_v2) {
+			// pairs of (first bucket, number of buckets)
+			Some(ranges) => ranges.chunks_exact(2).fold(0u64, |n, r| n.saturating_add(r[1])),
+			None => self.field_payload(BCH_SB_FIELD_journal).map_or(0, |b| b.len() as u64),
+		};
+		let bucket_size = self
+			.members()
+			.iter()
+			.find(|m| m.dev_idx == self.dev_idx)
+			.map_or(0, |m| m.bucket_size as u64);
+		buckets.saturating_mul(bucket_size)
+	}
+
+	/// Size of the journal on this member device, in bytes; see
+	/// [`journal_size_sectors`](Self::journal_size_sectors)
+	pub fn journal_size_bytes(&self) -> u64 {
+		self.journal_size_sectors().saturating_mul(512)
+	}
+
+	/// Block size in 512 byte sectors
+	pub fn block_size(&self) -> u16 {
+		self.block_size
+	}
+
+	/// Btree node size in 512 byte sectors (BCH_SB_BTREE_NODE_SIZE)
+	pub fn btree_node_size(&self) -> u32 {
+		let flags = self.flags;
+		((flags[0] >> 12) & 0xffff) as u32
+	}
+
+	/// Ways the sizes recorded in the superblock don't fit together, one
+	/// sentence each, empty if they do.
+	///
+	/// Members with different bucket sizes are fine, but not buckets that
+	/// can't hold a btree node or aren't made of whole blocks; and bucket
+	/// sizes more than 64 times apart are more likely a disk from another
+	/// filesystem than intentional.
+	pub fn geometry_problems(&self) -> Vec<String> {
+		// OPT_UINT ranges of block_size and btree_node_size in opts.h
+		const BLOCK_SIZE_MAX: u32 = (1 << 16) >> 9;
+		const BTREE_NODE_SIZE_MAX: u32 = (1 << 20) >> 9;
+
+		let block_size = self.block_size() as u32;
+		let btree_node_size = self.btree_node_size();
+		let mut problems = Vec::new();
+		if !block_size.is_power_of_two() || block_size > BLOCK_SIZE_MAX {
+			problems.push(format!("block size of {} sectors is not supported", block_size));
+		}
+		if !btree_node_size.is_power_of_two() || btree_node_size > BTREE_NODE_SIZE_MAX {
+			problems.push(format!("btree node size of {} sectors is not supported", btree_node_size));
+		} else if btree_node_size < block_size {
+			problems.push(format!(
+				"btree node size of {} sectors is smaller than the block size of {} sectors",
+				btree_node_size, block_size
+			));
+		}
+
+		let members = self.members();
+		for m in &members {
+			let bucket_size = m.bucket_size as u32;
+			if bucket_size < btree_node_size {
+				problems.push(format!(
+					"member {}: bucket size of {} sectors is smaller than the btree node size of {} sectors",
+					m.dev_idx, bucket_size, btree_node_size
+				));
+			}
+			if block_size != 0 && bucket_size % block_size != 0 {
+				problems.push(format!(
+					"member {}: bucket size of {} sectors is not a multiple of the block size of {} sectors",
+					m.dev_idx, bucket_size, block_size
+				));
+			}
+		}
+		let smallest = members.iter().min_by_key(|m| m.bucket_size);
+		let largest = members.iter().max_by_key(|m| m.bucket_size);
+		if let (Some(smallest), Some(largest)) = (smallest, largest) {
+			if largest.bucket_size as u32 > (smallest.bucket_size as u32).saturating_mul(64) {
+				problems.push(format!(
+					"member {} has buckets of {} sectors but member {} of {}, is one of them from another filesystem?",
+					largest.dev_idx, largest.bucket_size, smallest.dev_idx, smallest.bucket_size
+				));
+			}
+		}
+		problems
+	}
+
+	/// Whether the filesystem was shut down cleanly (BCH_SB_CLEAN); if not,
+	/// the journal has to be replayed on the next mount
+	pub fn is_clean(&self) -> bool {
+		let flags = self.flags;
+		flags[0] & (1 << 1) != 0
+	}
+
+	/// Whether the filesystem may be mounted read-write right now, possibly by
+	/// another host: BCH_SB_CLEAN is only set again on a clean unmount, so
+	/// this is stale after a crash.
+	pub fn possibly_in_use(&self) -> bool {
+		!self.is_clean()
+	}
+
+	/// Member devices of the filesystem, skipping unused slots.
+	///
+	/// This only knows the original `BCH_SB_FIELD_members` layout: the
+	/// libbcachefs we link against predates members_v2, and `bch2_read_super`
+	/// refuses superblocks new enough to carry it.
+	pub fn members(&self) -> Vec<MemberInfo> {
+		use std::mem::size_of;
+		unsafe {
+			let ptr = bch2_sb_field_get(self as *const _ as *mut _, bch_sb_field_type::BCH_SB_FIELD_members);
+			if ptr.is_null() {
+				return Vec::new();
+			}
+			let offset = offset_of!(bch_sb_field_members, field);
+			let field = &*((ptr as *const u8).sub(offset) as *const bch_sb_field_members);
+			let bytes = (field.field.u64s as usize * 8).saturating_sub(size_of::<bch_sb_field_members>());
+			let nr = (bytes / size_of::<bch_member>()).min(self.nr_devices as usize).min(BCH_SB_MEMBERS_MAX as usize);
+			field
+				.members
+				.as_slice(nr)
+				.iter()
+				.enumerate()
+				.filter(|(_, m)| m.uuid.b != [0; 16])
+				.map(|(i, m)| MemberInfo::from((i as u8, m)))
+				.collect()
+		}
+	}
+
+	pub fn disk_groups(&self) -> Option<&bch_sb_field_disk_groups> {
+		unsafe {
+			let ptr = bch2_sb_field_get(self as *const _ as *mut _, bch_sb_field_type::BCH_SB_FIELD_disk_groups);
+			if ptr.is_null() {
+				None
+			} else {
+				let offset = offset_of!(bch_sb_field_disk_groups, field);
+				Some(&*((ptr as *const u8).sub(offset) as *const _))
+			}
+		}
+	}
+
+	/// The disk groups, none if the field is missing
+	pub fn groups(&self) -> DiskGroups {
+		DiskGroups(self.disk_groups().map(|g| g.entries()).unwrap_or_default())
+	}
+
+	/// The target option for `role`: `BCH_SB_FOREGROUND_TARGET` and so on
+	pub fn target(&self, role: Role) -> Option<Target> {
+		let flags = self.flags;
+		let v = match role {
+			Role::Promote => flags[1] >> 28,
+			Role::Foreground => flags[1] >> 40,
+			Role::Background => flags[1] >> 52,
+			Role::Metadata => flags[3] >> 16,
+		};
+		Target::decode(v & 0xfff)
+	}
+
+	/// `target` as bch2_opt_target_to_text() writes it without a running
+	/// filesystem
+	pub fn target_to_string(&self, target: Option<Target>) -> String {
+		match target {
+			None => "none".to_owned(),
+			Some(Target::Device(i)) => match self.members().iter().find(|m| m.dev_idx as u64 == i) {
+				Some(m) => format!("Device {} ({})", m.uuid, i),
+				None => format!("Bad device {}", i),
+			},
+			Some(Target::Group(g)) => self.groups().path(g).unwrap_or_else(|| format!("invalid label {}", g)),
+		}
+	}
+
+	/// The roles of `member`: those whose target is the member itself, or a
+	/// group it is in
+	pub fn roles(&self, member: &MemberInfo) -> Vec<Role> {
+		let groups = self.groups();
+		let covers = |target| match target {
+			Target::Device(i) => member.dev_idx as u64 == i,
+			Target::Group(g) => member.group.map_or(false, |m| groups.is_within(m, g)),
+		};
+		Role::ALL.iter().copied().filter(|&role| self.target(role).map_or(false, covers)).collect()
+	}
+
 	/// Get the nonce used to encrypt the superblock
 	pub fn nonce(&self) -> nonce {
 		use byteorder::{LittleEndian, ReadBytesExt};
@@ -82,6 +606,55 @@ impl bch_sb {
 		}
 	}
 }
+impl bch_option {
+	pub fn name(&self) -> &str {
+		unsafe { std::ffi::CStr::from_ptr(self.attr.name) }.to_str().unwrap_or("")
+	}
+
+	/// Whether the option may be given at mount time
+	pub fn is_mount_opt(&self) -> bool {
+		self.flags.0 & opt_flags::OPT_MOUNT.0 != 0
+	}
+
+	/// Accepted values of a string option, empty for other option types
+	pub fn choices(&self) -> Vec<&str> {
+		let mut choices = Vec::new();
+		if self.type_ != opt_type::BCH_OPT_STR {
+			return choices;
+		}
+		unsafe {
+			let mut p = self.__bindgen_anon_1.__bindgen_anon_2.choices;
+			while !(*p).is_null() {
+				choices.push(std::ffi::CStr::from_ptr(*p).to_str().unwrap_or(""));
+				p = p.add(1);
+			}
+		}
+		choices
+	}
+
+	/// Parse and range check a value for this option the way the kernel will.
+	///
+	/// Options parsed by a callback (e.g. targets) need a running filesystem
+	/// and are accepted as is.
+	pub fn parse(&self, val: &str) -> Option<u64> {
+		let val = std::ffi::CString::new(val).ok()?;
+		let mut res = 0;
+		let ret = unsafe {
+			bch2_opt_parse(std::ptr::null_mut(), self, val.as_ptr(), &mut res, std::ptr::null_mut())
+		};
+		if ret == 0 {
+			Some(res)
+		} else {
+			None
+		}
+	}
+}
+
+/// A `bch_sb_handle` can be moved freely: `sb` is a buffer `bch2_sb_realloc`
+/// allocates with `krealloc`, and `bdev` and `bio` are separate allocations
+/// too, so nothing points back into the handle itself. Moving it around by
+/// value, e.g. into a `FileSystem` in a `HashMap`, leaves the superblock
+/// where it was.
 impl bch_sb_handle {
 	pub fn sb(&self) -> &bch_sb {
 		unsafe { &*self.sb }
@@ -90,6 +663,64 @@ impl bch_sb_handle {
 	pub fn bdev(&self) -> &block_device {
 		unsafe { &*self.bdev }
 	}
+
+	/// The device `bch2_read_super` opened, for ioctls on it without opening
+	/// it again by path; `None` once the handle was freed.
+	///
+	/// The descriptor is only borrowed: it belongs to the handle and is
+	/// closed by `bch2_free_super`, which dropping the [`SbHandle`] owning it
+	/// calls, so it must not be closed or turned into a `File`, nor used once
+	/// the handle is gone. Copies of a handle share it.
+	pub fn fd(&self) -> Option<std::os::unix::io::RawFd> {
+		if self.bdev.is_null() {
+			return None;
+		}
+		Some(self.bdev().bd_fd)
+	}
+}
+
+/// A `bch_sb_handle` that is freed with `bch2_free_super` when dropped,
+/// which closes the device `bch2_read_super` opened and frees the
+/// superblock buffer; or one lent a superblock held elsewhere, e.g. in an
+/// `SbBuf`, which is left alone.
+#[derive(Debug)]
+pub struct SbHandle {
+	handle: bch_sb_handle,
+	owned: bool,
+}
+
+impl SbHandle {
+	/// Take over `handle`, as `bch2_read_super` filled it in.
+	///
+	/// # Safety
+	///
+	/// Nothing else may free `handle`, and copies of it must not be used
+	/// after this is dropped.
+	pub unsafe fn from_raw(handle: bch_sb_handle) -> Self {
+		SbHandle { handle, owned: true }
+	}
+
+	/// `handle` without taking it over: its superblock, and its device if
+	/// it has one, belong to someone else and must outlive it
+	pub fn borrowed(handle: bch_sb_handle) -> Self {
+		SbHandle { handle, owned: false }
+	}
+}
+
+impl std::ops::Deref for SbHandle {
+	type Target = bch_sb_handle;
+
+	fn deref(&self) -> &bch_sb_handle {
+		&self.handle
+	}
+}
+
+impl Drop for SbHandle {
+	fn drop(&mut self) {
+		if self.owned {
+			unsafe { bch2_free_super(&mut self.handle) };
+		}
+	}
 }
 
 #[repr(C)]
@@ -15,20 +15,25 @@ pub enum ReadSuperErr {
 type RResult<T> = std::io::Result<std::io::Result<T>>;
 
 #[tracing_attributes::instrument(skip(opts))]
-pub fn read_super_opts(path: &std::path::Path, mut opts: bcachefs::bch_opts) -> RResult<bcachefs::bch_sb_handle> {
+pub fn read_super_opts(path: &std::path::Path, mut opts: bcachefs::bch_opts) -> RResult<bcachefs::SbHandle> {
 	// let devp = camino::Utf8Path::from_path(devp).unwrap();
 
 	use std::os::unix::ffi::OsStrExt;
+	let raw_path = path;
 	let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
 
 	let mut sb = std::mem::MaybeUninit::zeroed();
 
-	// use gag::{BufferRedirect};
-	// // Stop libbcachefs from spamming the output
-	// let gag = BufferRedirect::stderr().unwrap();
-	// tracing::trace!("entering libbcachefs");
-
-	let ret = unsafe { crate::bcachefs::bch2_read_super(path.as_ptr(), &mut opts, sb.as_mut_ptr()) };
+	// libbcachefs explains itself at -vv, as the rest of the tool does
+	if tracing::level_filters::LevelFilter::current() >= tracing::Level::DEBUG {
+		opts.verbose = 1;
+		opts.set_verbose_defined(1);
+	}
+	let (ret, output) =
+		capture_output(|| unsafe { crate::bcachefs::bch2_read_super(path.as_ptr(), &mut opts, sb.as_mut_ptr()) });
+	for line in output {
+		tracing::debug!(msg="libbcachefs says", device=%raw_path.display(), %line);
+	}
 	tracing::trace!(%ret);
 
 	match -ret {
@@ -36,23 +41,433 @@ pub fn read_super_opts(path: &std::path::Path, mut opts: bcachefs::bch_opts) ->
 			std::io::ErrorKind::PermissionDenied,
 			"Access Permission Denied",
 		)),
-		0 => Ok(Ok(unsafe { sb.assume_init() })),
-		22 => Ok(Err(std::io::Error::new(
-			std::io::ErrorKind::InvalidData,
-			"Not a BCacheFS SuperBlock",
-		))),
+		0 => Ok(Ok(unsafe { bcachefs::SbHandle::from_raw(sb.assume_init()) })),
+		// a look at the raw superblock may tell why, e.g. a foreign endian write
+		22 => Ok(Err(match read_super_raw(raw_path) {
+			Err(e) if e.kind() == std::io::ErrorKind::InvalidData => e,
+			_ => std::io::Error::new(std::io::ErrorKind::InvalidData, "Not a BCacheFS SuperBlock"),
+		})),
 		code => {
 			tracing::debug!(msg = "BCacheFS return error code", ?code);
-			Ok(Err(std::io::Error::new(
-				std::io::ErrorKind::Other,
-				"Failed to Read SuperBlock",
-			)))
+			// the kind tells a device that went away from one that can't be read
+			let kind = match code {
+				libc::ENOENT | libc::ENXIO | libc::ENODEV => std::io::ErrorKind::NotFound,
+				libc::EPERM => std::io::ErrorKind::PermissionDenied,
+				_ => std::io::ErrorKind::Other,
+			};
+			Ok(Err(std::io::Error::new(kind, "Failed to Read SuperBlock")))
 		}
 	}
 }
 
+/// Run `f`, a call into libbcachefs, with what it prints to stdout and stderr
+/// collected as lines instead of mixed into the tool's own output. If the
+/// output can't be redirected, e.g. for lack of a writable temporary
+/// directory, it goes where it would have gone.
+pub fn capture_output<T>(f: impl FnOnce() -> T) -> (T, Vec<String>) {
+	use gag::BufferRedirect;
+	use std::io::{Read, Write};
+
+	// anything already printed stays out of what is collected
+	let _ = std::io::stdout().flush();
+	unsafe { libc::fflush(stdout) };
+	let mut redirects = [BufferRedirect::stdout(), BufferRedirect::stderr()];
+	let ret = f();
+	// printk() is printf(), which buffers when stdout isn't a terminal
+	unsafe { libc::fflush(stdout) };
+
+	let mut lines = Vec::new();
+	for redirect in redirects.iter_mut().flatten() {
+		let mut output = String::new();
+		let _ = redirect.read_to_string(&mut output);
+		lines.extend(output.lines().filter(|l| !l.trim().is_empty()).map(str::to_owned));
+	}
+	(ret, lines)
+}
+
 #[tracing_attributes::instrument]
-pub fn read_super(path: &std::path::Path) -> RResult<bcachefs::bch_sb_handle> {
+pub fn read_super(path: &std::path::Path) -> RResult<bcachefs::SbHandle> {
 	let opts = bcachefs::bch_opts::default(); //unsafe {std::mem::MaybeUninit::zeroed().assume_init()};
 	read_super_opts(path, opts)
 }
+
+/// Like [`read_super`], with the device opened for reading only. Drivers of
+/// write-protected media, such as `sd` and `mmc`, refuse to open it for
+/// writing, and libbcachefs only falls back on EACCES.
+#[tracing_attributes::instrument]
+pub fn read_super_read_only(path: &std::path::Path) -> RResult<bcachefs::SbHandle> {
+	let mut opts = bcachefs::bch_opts::default();
+	opts.nochanges = 1;
+	opts.set_nochanges_defined(1);
+	read_super_opts(path, opts)
+}
+
+/// Read the superblock copy at sector `sb_offset`, e.g. a backup one of
+/// [`superblock_offsets`] lists, instead of the primary. libbcachefs doesn't
+/// fall back to the other copies then.
+#[tracing_attributes::instrument]
+pub fn read_super_at(path: &std::path::Path, sb_offset: u64) -> RResult<bcachefs::SbHandle> {
+	let mut opts = bcachefs::bch_opts::default();
+	opts.sb = sb_offset;
+	opts.set_sb_defined(1);
+	read_super_opts(path, opts)
+}
+
+/// Upstream bcachefs revision the linked libbcachefs was built from
+pub const LIBBCACHEFS_REVISION: &str = env!("LIBBCACHEFS_REVISION");
+
+/// Range of on-disk metadata versions libbcachefs can read
+pub fn metadata_versions() -> std::ops::RangeInclusive<u16> {
+	use bcachefs::bcachefs_metadata_version::*;
+	bcachefs_metadata_version_min as u16..=bcachefs_metadata_version_max as u16 - 1
+}
+
+/// Name of an on-disk metadata version, if libbcachefs knows it
+pub fn metadata_version_name(version: u16) -> Option<&'static str> {
+	if version > *metadata_versions().end() {
+		return None;
+	}
+	let name = unsafe { *bcachefs::bch2_metadata_versions.as_ptr().add(version as usize) };
+	if name.is_null() {
+		return None;
+	}
+	unsafe { std::ffi::CStr::from_ptr(name) }.to_str().ok()
+}
+
+/// Look up a filesystem option by name in the libbcachefs option table
+pub fn opt_lookup(name: &str) -> Option<&'static bcachefs::bch_option> {
+	let name = std::ffi::CString::new(name).ok()?;
+	let id = unsafe { bcachefs::bch2_opt_lookup(name.as_ptr()) };
+	if id < 0 {
+		return None;
+	}
+	Some(unsafe { &*bcachefs::bch2_opt_table.as_ptr().add(id as usize) })
+}
+
+const BYTE_SWAPPED: &str = "SuperBlock appears byte-swapped (foreign endian write?)";
+
+/// Check the magic, telling a superblock that was written byte-swapped, as a
+/// whole or per 64 bit word, apart from one that isn't bcachefs at all
+fn check_magic(sb: &bcachefs::bch_sb) -> std::io::Result<()> {
+	use std::io::{Error, ErrorKind};
+
+	let magic = SUPERBLOCK_MAGIC.as_bytes();
+	if &sb.magic.b == magic {
+		return Ok(());
+	}
+	let mut reversed = sb.magic.b;
+	reversed.reverse();
+	let mut words = sb.magic.b;
+	words[..8].reverse();
+	words[8..].reverse();
+	if &reversed == magic || &words == magic {
+		return Err(Error::new(ErrorKind::InvalidData, BYTE_SWAPPED));
+	}
+	Err(Error::new(ErrorKind::InvalidData, "Not a BCacheFS SuperBlock"))
+}
+
+/// Check that the versions of a superblock whose magic matches make sense.
+/// Versions that swapped back are known suggest the superblock was written
+/// on a machine of the other endianness; anything else that doesn't add up
+/// is reported with the offending values.
+fn check_versions(sb: &bcachefs::bch_sb) -> std::io::Result<()> {
+	use std::io::{Error, ErrorKind};
+
+	let (version, version_min) = (sb.version, sb.version_min);
+	let known = metadata_versions();
+	// known versions are all below 256, so swapped they end in a zero byte
+	let swapped = |v: u16| v & 0xff == 0 && known.contains(&v.swap_bytes());
+	if swapped(version) && swapped(version_min) {
+		return Err(Error::new(ErrorKind::InvalidData, BYTE_SWAPPED));
+	}
+	if version_min >= *known.start() && version_min <= version {
+		return Ok(());
+	}
+	Err(Error::new(
+		ErrorKind::InvalidData,
+		format!(
+			"corrupt SuperBlock: version {} with version_min {}, try a backup superblock with `bcachefs show-super`",
+			version, version_min
+		),
+	))
+}
+
+/// A superblock in memory whose fields have been checked to lie within it, so
+/// the `bch_sb` accessors can be used on a superblock that hasn't been through
+/// `bch2_read_super`, e.g. one read from untrusted media.
+pub struct SbBuf(Vec<u64>);
+
+impl SbBuf {
+	pub fn from_bytes(bytes: &[u8]) -> std::io::Result<Self> {
+		use bcachefs::{bch_sb, bch_sb_field, BCH_SB_MEMBERS_MAX};
+		use std::io::{Error, ErrorKind};
+
+		let invalid = |msg: String| Err(Error::new(ErrorKind::InvalidData, msg));
+
+		let hdr_u64s = std::mem::size_of::<bch_sb>() / 8;
+		if bytes.len() < hdr_u64s * 8 {
+			return invalid("SuperBlock truncated".to_owned());
+		}
+		// copy into a u64 buffer to get the alignment bch_sb requires
+		let mut buf = vec![0u64; bytes.len() / 8];
+		unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), buf.as_mut_ptr() as *mut u8, buf.len() * 8) };
+
+		let sb = unsafe { &*(buf.as_ptr() as *const bch_sb) };
+		check_magic(sb)?;
+		check_versions(sb)?;
+		// the same bounds bch2_sb_validate() enforces; anything else is
+		// corruption that would otherwise show up as nonsense member counts
+		if sb.nr_devices == 0 || sb.nr_devices as u32 > BCH_SB_MEMBERS_MAX {
+			return invalid(format!(
+				"corrupt SuperBlock: {} devices (max {}), try a backup superblock with `bcachefs show-super`",
+				sb.nr_devices, BCH_SB_MEMBERS_MAX
+			));
+		}
+		if sb.dev_idx >= sb.nr_devices {
+			return invalid(format!(
+				"corrupt SuperBlock: device index {} out of {} devices",
+				sb.dev_idx, sb.nr_devices
+			));
+		}
+		let end = hdr_u64s + sb.u64s as usize;
+		if end > buf.len() {
+			return invalid("SuperBlock truncated".to_owned());
+		}
+		// bch2_sb_field_get() trusts each field's size: an empty field would
+		// never be stepped over, and an oversized one runs off the end
+		let mut pos = hdr_u64s;
+		while pos < end {
+			let field = unsafe { &*(buf.as_ptr().add(pos) as *const bch_sb_field) };
+			let u64s = field.u64s as usize;
+			if u64s == 0 || u64s > end - pos {
+				return invalid("corrupt SuperBlock: field size out of bounds".to_owned());
+			}
+			pos += u64s;
+		}
+		buf.truncate(end);
+		Ok(Self(buf))
+	}
+
+	/// A copy of `sb`, fields and all, which unlike a `bch_sb_handle` can be
+	/// handed to another thread
+	pub fn copy_of(sb: &bcachefs::bch_sb) -> Self {
+		let u64s = std::mem::size_of::<bcachefs::bch_sb>() / 8 + { sb.u64s } as usize;
+		Self(unsafe { std::slice::from_raw_parts(sb as *const _ as *const u64, u64s) }.to_vec())
+	}
+
+	pub fn sb(&self) -> &bcachefs::bch_sb {
+		unsafe { &*(self.0.as_ptr() as *const bcachefs::bch_sb) }
+	}
+}
+
+/// Stored and recomputed checksum of a superblock
+#[derive(Debug)]
+pub struct SuperCsum {
+	pub csum_type: u64,
+	pub stored: bcachefs::bch_csum,
+	pub computed: bcachefs::bch_csum,
+}
+
+impl SuperCsum {
+	pub fn matches(&self) -> bool {
+		let (stored, computed) = (self.stored, self.computed);
+		stored.lo == computed.lo && stored.hi == computed.hi
+	}
+}
+
+/// Read into a u64 buffer to get the alignment bch_sb requires
+fn as_bytes(buf: &mut [u64]) -> &mut [u8] {
+	unsafe { std::slice::from_raw_parts_mut(buf.as_mut_ptr() as *mut u8, buf.len() * 8) }
+}
+
+/// Read the superblock starting at absolute byte position `at` of `path`
+/// directly from disk, without the validation `bch2_read_super` does and
+/// without opening the device exclusively
+fn read_super_u64s(path: &std::path::Path, at: u64) -> std::io::Result<Vec<u64>> {
+	use bcachefs::bch_sb;
+	use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+
+	let mut dev = std::fs::File::open(path)?;
+	dev.seek(SeekFrom::Start(at))?;
+
+	let hdr_u64s = std::mem::size_of::<bch_sb>() / 8;
+	let mut buf = vec![0u64; hdr_u64s];
+	dev.read_exact(as_bytes(&mut buf))?;
+
+	let sb = unsafe { &*(buf.as_ptr() as *const bch_sb) };
+	check_magic(sb)?;
+	let max_bytes = 512u64 << sb.layout.sb_max_size_bits.min(16);
+	let u64s = sb.u64s as usize;
+	if ((hdr_u64s + u64s) * 8) as u64 > max_bytes {
+		return Err(Error::new(ErrorKind::InvalidData, "SuperBlock size exceeds layout maximum"));
+	}
+
+	buf.resize(hdr_u64s + u64s, 0);
+	dev.read_exact(&mut as_bytes(&mut buf)[hdr_u64s * 8..])?;
+	Ok(buf)
+}
+
+/// Read the primary superblock of `path` directly from disk, checked only as
+/// far as [`SbBuf`] needs to access it safely. Unlike `bch2_read_super`, this
+/// works on mounted devices and on superblocks with a bad checksum.
+#[tracing_attributes::instrument]
+pub fn read_super_raw(path: &std::path::Path) -> std::io::Result<SbBuf> {
+	read_super_raw_at(path, 0)
+}
+
+/// Like [`read_super_raw`], for a filesystem starting `offset` bytes into
+/// `path`, e.g. a partition within a disk image
+#[tracing_attributes::instrument]
+pub fn read_super_raw_at(path: &std::path::Path, offset: u64) -> std::io::Result<SbBuf> {
+	let buf = read_super_u64s(path, offset + bcachefs::BCH_SB_SECTOR as u64 * 512)?;
+	SbBuf::from_bytes(unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, buf.len() * 8) })
+}
+
+/// Like [`read_super_raw_at`], for the `n`th of the superblock copies the
+/// layout lists rather than the primary, which is usually copy 0. This works
+/// when the primary is damaged, as long as the layout sector isn't.
+#[tracing_attributes::instrument]
+pub fn read_super_raw_copy(path: &std::path::Path, offset: u64, n: usize) -> std::io::Result<SbBuf> {
+	let offsets = superblock_offsets(path, offset)?;
+	let sector = offsets.get(n).ok_or_else(|| {
+		std::io::Error::new(
+			std::io::ErrorKind::InvalidInput,
+			format!("superblock copy {} doesn't exist, the layout lists {}", n, offsets.len()),
+		)
+	})?;
+	let buf = read_super_u64s(path, offset + sector * 512)?;
+	SbBuf::from_bytes(unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, buf.len() * 8) })
+}
+
+/// The sectors with a copy of the superblock in, according to the layout
+/// sector of the filesystem starting `offset` bytes into `path`
+fn read_layout(dev: &mut std::fs::File, offset: u64) -> std::io::Result<Vec<u64>> {
+	use bcachefs::bch_sb_layout;
+	use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+
+	let mut layout = vec![0u64; std::mem::size_of::<bch_sb_layout>() / 8];
+	dev.seek(SeekFrom::Start(offset + bcachefs::BCH_SB_LAYOUT_SECTOR as u64 * 512))?;
+	dev.read_exact(as_bytes(&mut layout))?;
+	let layout = unsafe { &*(layout.as_ptr() as *const bch_sb_layout) };
+	if layout.magic.b != *SUPERBLOCK_MAGIC.as_bytes() {
+		return Err(Error::new(ErrorKind::InvalidData, "Not a BCacheFS superblock layout"));
+	}
+	let offsets = layout.sb_offset;
+	let nr = (layout.nr_superblocks as usize).min(offsets.len());
+	Ok(offsets[..nr].to_vec())
+}
+
+/// Where the copies of the superblock are, in sectors from the start of the
+/// filesystem, which begins `offset` bytes into `path`
+pub fn superblock_offsets(path: &std::path::Path, offset: u64) -> std::io::Result<Vec<u64>> {
+	read_layout(&mut std::fs::File::open(path)?, offset)
+}
+
+/// Zero the magic of every superblock copy on `path` that ends below `limit`
+/// bytes, so that it isn't recognized as bcachefs anymore: that of the
+/// layout sector, the primary superblock and the backups the layout lists.
+/// Only bytes that hold the magic are overwritten; nothing else is written.
+/// Returns the byte offsets wiped.
+pub fn wipe_super_magic(path: &std::path::Path, limit: u64) -> std::io::Result<Vec<u64>> {
+	use bcachefs::bch_sb;
+	use std::io::{Read, Seek, SeekFrom, Write};
+
+	let magic = SUPERBLOCK_MAGIC.as_bytes();
+	let magic_offset = memoffset::offset_of!(bch_sb, magic) as u64;
+	let layout_at = bcachefs::BCH_SB_LAYOUT_SECTOR as u64 * 512;
+	let mut dev = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+
+	let mut wipe = vec![layout_at, bcachefs::BCH_SB_SECTOR as u64 * 512 + magic_offset];
+	let offsets = read_layout(&mut dev, 0).unwrap_or_default();
+	wipe.extend(offsets.iter().map(|sector| sector.saturating_mul(512).saturating_add(magic_offset)));
+	wipe.sort_unstable();
+	wipe.dedup();
+
+	let mut wiped = Vec::new();
+	for at in wipe {
+		if at.saturating_add(magic.len() as u64) > limit {
+			continue;
+		}
+		let mut found = [0u8; 16];
+		dev.seek(SeekFrom::Start(at))?;
+		if dev.read_exact(&mut found).is_err() || found != *magic {
+			continue;
+		}
+		dev.seek(SeekFrom::Start(at))?;
+		dev.write_all(&[0; 16])?;
+		wiped.push(at);
+	}
+	dev.sync_all()?;
+	Ok(wiped)
+}
+
+/// Read the primary superblock of `path` directly from disk and recompute its
+/// checksum, without going through `bch2_read_super` (which refuses
+/// superblocks with a bad checksum).
+#[tracing_attributes::instrument]
+pub fn verify_super_csum(path: &std::path::Path) -> std::io::Result<SuperCsum> {
+	verify_super_copy(path, 0, bcachefs::BCH_SB_SECTOR as u64).map(|copy| copy.csum)
+}
+
+/// A copy of the superblock as read from disk, and its checksum
+#[derive(Debug)]
+pub struct SuperCopy {
+	/// Sector the copy is at, from the start of the filesystem
+	pub sector: u64,
+	pub seq: u64,
+	pub csum: SuperCsum,
+}
+
+/// Like [`verify_super_csum`], for the copy at `sector` of the filesystem
+/// starting `offset` bytes into `path`, e.g. one [`superblock_offsets`] lists
+#[tracing_attributes::instrument]
+pub fn verify_super_copy(path: &std::path::Path, offset: u64, sector: u64) -> std::io::Result<SuperCopy> {
+	let buf = read_super_u64s(path, offset + sector * 512)?;
+	let sb = unsafe { &*(buf.as_ptr() as *const bcachefs::bch_sb) };
+	Ok(SuperCopy { sector, seq: sb.seq, csum: super_csum(&buf)? })
+}
+
+/// Stored and recomputed checksum of the superblock in `buf`
+fn super_csum(buf: &[u64]) -> std::io::Result<SuperCsum> {
+	use bcachefs::{bch_csum_type, bch_sb};
+	use std::io::{Error, ErrorKind};
+
+	let sb = unsafe { &*(buf.as_ptr() as *const bch_sb) };
+
+	let flags = sb.flags;
+	let csum_type = (flags[0] >> 2) & 0x3f; // BCH_SB_CSUM_TYPE
+	if csum_type >= bch_csum_type::BCH_CSUM_NR as u64
+		|| csum_type == bch_csum_type::BCH_CSUM_chacha20_poly1305_80 as u64
+		|| csum_type == bch_csum_type::BCH_CSUM_chacha20_poly1305_128 as u64
+	{
+		return Err(Error::new(
+			ErrorKind::InvalidData,
+			format!("unsupported SuperBlock checksum type {}", csum_type),
+		));
+	}
+
+	// the checksum covers everything after the csum field itself
+	let csum_bytes = std::mem::size_of::<bcachefs::bch_csum>();
+	let start = unsafe { (buf.as_ptr() as *const u8).add(csum_bytes) };
+	let len = buf.len() * 8 - csum_bytes;
+	// what bch2_checksum() returns for no checksum
+	let computed = if csum_type == bch_csum_type::BCH_CSUM_none as u64 {
+		bcachefs::bch_csum { lo: 0, hi: 0 }
+	} else {
+		unsafe {
+			bcachefs::bch2_checksum(
+				std::ptr::null_mut(),
+				csum_type as _,
+				bcachefs::nonce { d: [0; 4] },
+				start as *const _,
+				len as _,
+			)
+		}
+	};
+
+	Ok(SuperCsum {
+		csum_type,
+		stored: sb.csum,
+		computed,
+	})
+}
@@ -0,0 +1,19 @@
+//! What libbcachefs prints is collected rather than mixed into our output.
+//! In a test binary of its own, since the redirect is for the whole process.
+
+use bch_bindgen::rs::capture_output;
+
+#[test]
+fn collects_stdout_and_stderr() {
+	let (ret, lines) = capture_output(|| unsafe {
+		libc::printf(b"bcachefs (loop0): reading superblock\n\0".as_ptr() as *const libc::c_char);
+		libc::write(2, b"superblock invalid\n".as_ptr() as *const libc::c_void, 19);
+		7
+	});
+	assert_eq!(ret, 7);
+	assert_eq!(lines, ["bcachefs (loop0): reading superblock", "superblock invalid"]);
+
+	// and the redirects are gone again
+	let (_, lines) = capture_output(|| ());
+	assert!(lines.is_empty());
+}
@@ -0,0 +1,529 @@
+//! Checks SbBuf makes before handing out a superblock, on doctored fixtures.
+
+use bch_bindgen::bcachefs::{bch_disk_group, bch_member, bch_sb, CryptInfo, Kdf, Scrypt};
+use bch_bindgen::rs::{
+	metadata_versions, read_super_raw, read_super_raw_at, read_super_raw_copy, superblock_offsets, verify_super_copy,
+	verify_super_csum, wipe_super_magic, SbBuf, SUPERBLOCK_MAGIC,
+};
+
+/// A superblock with no fields, `extra` u64s of padding after it, and
+/// whatever `doctor` does to it
+fn fixture(extra: usize, doctor: impl FnOnce(&mut bch_sb, &mut [u64])) -> Vec<u8> {
+	let hdr_u64s = std::mem::size_of::<bch_sb>() / 8;
+	let mut buf = vec![0u64; hdr_u64s + extra];
+	let (hdr, fields) = buf.split_at_mut(hdr_u64s);
+	let sb = unsafe { &mut *(hdr.as_mut_ptr() as *mut bch_sb) };
+	sb.magic.b = *SUPERBLOCK_MAGIC.as_bytes();
+	sb.version = *metadata_versions().end();
+	sb.version_min = *metadata_versions().start();
+	sb.nr_devices = 2;
+	sb.dev_idx = 1;
+	doctor(sb, fields);
+	buf.iter().flat_map(|w| w.to_ne_bytes()).collect()
+}
+
+fn error(bytes: &[u8]) -> String {
+	match SbBuf::from_bytes(bytes) {
+		Ok(_) => panic!("doctored superblock accepted"),
+		Err(e) => e.to_string(),
+	}
+}
+
+#[test]
+fn accepts_minimal_superblock() {
+	let buf = SbBuf::from_bytes(&fixture(0, |_, _| {})).unwrap();
+	assert_eq!({ buf.sb().nr_devices }, 2);
+	assert!(buf.sb().members().is_empty());
+}
+
+#[test]
+fn rejects_bad_nr_devices() {
+	let err = error(&fixture(0, |sb, _| sb.nr_devices = 0xff));
+	assert!(err.contains("255 devices"), "{}", err);
+	assert!(err.contains("show-super"), "{}", err);
+
+	error(&fixture(0, |sb, _| sb.nr_devices = 0));
+	error(&fixture(0, |sb, _| sb.dev_idx = 2));
+}
+
+#[test]
+fn rejects_fields_out_of_bounds() {
+	// u64s claims a field that isn't there
+	error(&fixture(0, |sb, _| sb.u64s = 1));
+	// a zero sized field would never be stepped over
+	error(&fixture(1, |sb, _| sb.u64s = 1));
+	// a field running past the end of the superblock
+	error(&fixture(2, |sb, fields| {
+		sb.u64s = 2;
+		fields[0] = 3; // u64s = 3, type = 0
+	}));
+	// one that fits is fine
+	SbBuf::from_bytes(&fixture(2, |sb, fields| {
+		sb.u64s = 2;
+		fields[0] = 2;
+	}))
+	.unwrap();
+}
+
+#[test]
+fn rejects_truncated_and_foreign_data() {
+	error(&[0u8; 64]);
+	error(&fixture(0, |sb, _| sb.magic.b = [0; 16]));
+}
+
+#[test]
+fn reports_byte_swapped_superblocks() {
+	let byte_swapped = "SuperBlock appears byte-swapped (foreign endian write?)";
+	// the magic reversed as a whole, and per 64 bit word
+	assert_eq!(error(&fixture(0, |sb, _| sb.magic.b.reverse())), byte_swapped);
+	assert_eq!(
+		error(&fixture(0, |sb, _| {
+			sb.magic.b[..8].reverse();
+			sb.magic.b[8..].reverse();
+		})),
+		byte_swapped
+	);
+	// a matching magic with versions that only make sense swapped
+	assert_eq!(
+		error(&fixture(0, |sb, _| {
+			sb.version = sb.version.swap_bytes();
+			sb.version_min = sb.version_min.swap_bytes();
+		})),
+		byte_swapped
+	);
+}
+
+#[test]
+fn reports_wild_versions() {
+	let end = *metadata_versions().end();
+	let err = error(&fixture(0, |sb, _| sb.version_min = end + 1));
+	assert!(err.starts_with("corrupt SuperBlock"), "{}", err);
+	assert!(err.contains(&format!("version {} with version_min {}", end, end + 1)), "{}", err);
+
+	let err = error(&fixture(0, |sb, _| sb.version_min = 0));
+	assert!(err.contains("version_min 0"), "{}", err);
+}
+
+#[test]
+fn crypt_key_is_256_bits() {
+	let buf = SbBuf::from_bytes(&fixture(8, |sb, fields| {
+		sb.u64s = 8;
+		fields[0] = 8 | 2 << 32; // u64s = 8, type = BCH_SB_FIELD_crypt
+	}))
+	.unwrap();
+	assert_eq!(buf.sb().crypt().unwrap().key_len() * 8, 256);
+}
+
+/// The crypt field `bcachefs format --encrypted` writes, for which the C
+/// tool's show-super prints "scrypt n: 14", "scrypt r: 3" and "scrypt p: 4"
+#[test]
+fn crypt_info_decodes_scrypt_parameters() {
+	let key = 0x5a5a_5a5a_5a5a_5a5a_u64;
+	let buf = SbBuf::from_bytes(&fixture(8, |sb, fields| {
+		sb.u64s = 8;
+		fields[0] = 8 | 2 << 32;
+		fields[1] = 0; // BCH_KDF_SCRYPT
+		fields[2] = 14 | 3 << 16 | 4 << 32;
+		for w in &mut fields[3..] {
+			*w = key;
+		}
+	}))
+	.unwrap();
+	let info = buf.sb().crypt().unwrap().info();
+	let scrypt = Scrypt { n_log2: 14, r_log2: 3, p_log2: 4 };
+	assert_eq!(info, CryptInfo { kdf: Kdf::Scrypt(scrypt), key_len: 32 });
+	assert_eq!((scrypt.n(), scrypt.r(), scrypt.p()), (Some(16384), Some(8), Some(16)));
+	assert_eq!(info.to_string(), "scrypt (N=16384, r=8, p=16), 256-bit key");
+	for shown in &[info.to_string(), format!("{:?}", info), format!("{:?}", buf.sb())] {
+		assert!(!shown.to_lowercase().contains("5a5a") && !shown.contains(&key.to_string()), "{}", shown);
+	}
+
+	let huge = Scrypt { n_log2: 64, r_log2: 3, p_log2: 0 };
+	assert_eq!(huge.n(), None);
+	let info = CryptInfo { kdf: Kdf::Scrypt(huge), key_len: 32 };
+	assert_eq!(info.to_string(), "scrypt (N=2^64, r=8, p=1), 256-bit key");
+	let info = CryptInfo { kdf: Kdf::Unknown(5), key_len: 32 };
+	assert_eq!(info.to_string(), "unknown KDF type 5, 256-bit key");
+}
+
+/// A superblock with a field of type `ty` holding `entries`, zero padded
+fn replicas(ty: u64, entries: &[u8]) -> SbBuf {
+	let payload: Vec<u64> = entries
+		.chunks(8)
+		.map(|c| {
+			let mut w = [0; 8];
+			w[..c.len()].copy_from_slice(c);
+			u64::from_ne_bytes(w)
+		})
+		.collect();
+	let u64s = 1 + payload.len();
+	SbBuf::from_bytes(&fixture(u64s, |sb, fields| {
+		sb.u64s = u64s as u32;
+		fields[0] = u64s as u64 | ty << 32;
+		fields[1..].copy_from_slice(&payload);
+	}))
+	.unwrap()
+}
+
+#[test]
+fn replicas_entries() {
+	use bch_bindgen::bcachefs::ReplicasEntry;
+
+	assert_eq!(SbBuf::from_bytes(&fixture(0, |_, _| {})).unwrap().sb().replicas(), None);
+
+	// BCH_SB_FIELD_replicas: btree on 0 and 1, user data striped over 0 to 2
+	// needing two of them, and cached data on 2
+	let sb = replicas(7, &[3, 2, 1, 0, 1, 4, 3, 2, 0, 1, 2, 5, 1, 1, 2]);
+	assert_eq!(
+		sb.sb().replicas().unwrap(),
+		vec![
+			ReplicasEntry { data_type: 3, nr_required: 1, devs: vec![0, 1] },
+			ReplicasEntry { data_type: 4, nr_required: 2, devs: vec![0, 1, 2] },
+			ReplicasEntry { data_type: 5, nr_required: 1, devs: vec![2] },
+		]
+	);
+	assert!(sb.sb().replicas().unwrap()[2].is_cached());
+
+	// BCH_SB_FIELD_replicas_v0 has no nr_required
+	let sb = replicas(3, &[2, 1, 0, 4, 2, 0, 1]);
+	assert_eq!(
+		sb.sb().replicas().unwrap(),
+		vec![
+			ReplicasEntry { data_type: 2, nr_required: 1, devs: vec![0] },
+			ReplicasEntry { data_type: 4, nr_required: 1, devs: vec![0, 1] },
+		]
+	);
+
+	// an entry running past the end of the field is left out
+	assert_eq!(replicas(7, &[4, 9, 1, 0, 1, 2, 3, 4]).sb().replicas().unwrap(), vec![]);
+}
+
+/// A superblock with block size 8, btree node size 512 and a member for each
+/// of `bucket_sizes`, after whatever `doctor` does to it
+fn geometry(bucket_sizes: &[u16], doctor: impl FnOnce(&mut bch_sb)) -> Vec<String> {
+	let member_u64s = std::mem::size_of::<bch_member>() / 8;
+	let u64s = 1 + member_u64s * bucket_sizes.len();
+	let buf = SbBuf::from_bytes(&fixture(u64s, |sb, fields| {
+		sb.u64s = u64s as u32;
+		sb.nr_devices = bucket_sizes.len() as u8;
+		sb.dev_idx = 0;
+		sb.block_size = 8;
+		sb.flags[0] = 512 << 12; // BCH_SB_BTREE_NODE_SIZE
+		fields[0] = u64s as u64 | 1 << 32; // BCH_SB_FIELD_members
+		for (i, &bucket_size) in bucket_sizes.iter().enumerate() {
+			let m = unsafe { &mut *(fields[1 + i * member_u64s..].as_mut_ptr() as *mut bch_member) };
+			m.uuid.b = [i as u8 + 1; 16];
+			m.bucket_size = bucket_size;
+		}
+		doctor(sb);
+	}))
+	.unwrap();
+	buf.sb().geometry_problems()
+}
+
+#[test]
+fn geometry_accessors() {
+	let buf = SbBuf::from_bytes(&fixture(0, |sb, _| {
+		sb.block_size = 8;
+		sb.flags[0] = 1 << 1 | 512 << 12; // BCH_SB_CLEAN, BCH_SB_BTREE_NODE_SIZE
+	}))
+	.unwrap();
+	assert_eq!(buf.sb().block_size(), 8);
+	assert_eq!(buf.sb().btree_node_size(), 512);
+	assert!(buf.sb().is_clean());
+}
+
+#[test]
+fn consistent_geometry() {
+	assert_eq!(geometry(&[1024, 2048], |_| {}), Vec::<String>::new());
+}
+
+#[test]
+fn reports_mismatched_geometry() {
+	let problems = geometry(&[256, 1024], |_| {});
+	assert_eq!(problems, ["member 0: bucket size of 256 sectors is smaller than the btree node size of 512 sectors"]);
+
+	let problems = geometry(&[1028], |_| {});
+	assert_eq!(problems, ["member 0: bucket size of 1028 sectors is not a multiple of the block size of 8 sectors"]);
+
+	let problems = geometry(&[512, 512 * 64 + 8], |_| {});
+	assert_eq!(problems.len(), 1);
+	assert!(problems[0].starts_with("member 1 has buckets of 32776 sectors but member 0 of 512"), "{:?}", problems);
+
+	let problems = geometry(&[1024], |sb| sb.flags[0] = 3 << 12);
+	assert_eq!(problems, ["btree node size of 3 sectors is not supported"]);
+
+	let problems = geometry(&[1024], |sb| sb.flags[0] = 4 << 12);
+	assert_eq!(problems, ["btree node size of 4 sectors is smaller than the block size of 8 sectors"]);
+
+	let problems = geometry(&[1024], |sb| sb.block_size = 256);
+	assert_eq!(problems[0], "block size of 256 sectors is not supported");
+}
+
+#[test]
+fn superblock_at_an_offset() {
+	use std::io::Write;
+
+	let offset = 1 << 20;
+	let sb = fixture(0, |sb, _| sb.layout.sb_max_size_bits = 7);
+	let path = std::env::temp_dir().join(format!("bch_bindgen-offset.{}.img", std::process::id()));
+	let mut image = std::fs::File::create(&path).unwrap();
+	image.write_all(&vec![0; offset + 4096]).unwrap();
+	image.write_all(&sb).unwrap();
+	drop(image);
+
+	let found = read_super_raw_at(&path, offset as u64).map(|buf| buf.sb().nr_devices);
+	let at_start = read_super_raw(&path).map(|_| ());
+	std::fs::remove_file(&path).unwrap();
+	assert_eq!(found.unwrap(), 2);
+	assert_eq!(at_start.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn wipes_only_the_magic_below_the_limit() {
+	use std::io::Write;
+
+	let magic = SUPERBLOCK_MAGIC.as_bytes();
+	// backups at sectors 64 and 4096 (2 MiB), the latter beyond the limit
+	let sb = fixture(0, |sb, _| {
+		sb.layout.sb_max_size_bits = 7;
+		sb.layout.magic.b = *magic;
+		sb.layout.nr_superblocks = 3;
+		sb.layout.sb_offset[..3].copy_from_slice(&[8, 64, 4096]);
+	});
+	let layout = &sb[sb.len() - std::mem::size_of::<bch_bindgen::bcachefs::bch_sb_layout>()..];
+	let mut image = vec![0xaau8; 3 << 20];
+	image[3584..3584 + layout.len()].copy_from_slice(layout);
+	for sector in &[8, 64, 4096] {
+		image[sector * 512..sector * 512 + sb.len()].copy_from_slice(&sb);
+	}
+
+	let path = std::env::temp_dir().join(format!("bch_bindgen-wipe.{}.img", std::process::id()));
+	std::fs::File::create(&path).unwrap().write_all(&image).unwrap();
+	let wiped = wipe_super_magic(&path, 1 << 20);
+	let after = std::fs::read(&path).unwrap();
+	std::fs::remove_file(&path).unwrap();
+
+	assert_eq!(wiped.unwrap(), vec![3584, 4096 + 24, 64 * 512 + 24]);
+	let changed: Vec<usize> = (0..image.len()).filter(|&i| image[i] != after[i]).collect();
+	let expected: Vec<usize> = [3584, 4096 + 24, 64 * 512 + 24].iter().flat_map(|&at| at..at + 16).collect();
+	assert_eq!(changed, expected);
+	assert!(after[3584..3600].iter().all(|&b| b == 0));
+	assert_eq!(&after[4096 * 512 + 24..4096 * 512 + 40], magic);
+}
+
+#[test]
+fn handle_lends_its_device_fd() {
+	use bch_bindgen::bcachefs::{bch_sb_handle, block_device};
+
+	let mut handle: bch_sb_handle = unsafe { std::mem::zeroed() };
+	assert_eq!(handle.fd(), None);
+	let mut bdev: block_device = unsafe { std::mem::zeroed() };
+	bdev.bd_fd = 42;
+	handle.bdev = &mut bdev;
+	assert_eq!(handle.fd(), Some(42));
+}
+
+#[test]
+fn reads_backup_copies_past_a_damaged_primary() {
+	use std::io::{ErrorKind, Write};
+
+	let sb = |seq| {
+		fixture(0, |sb, _| {
+			sb.seq = seq;
+			sb.layout.sb_max_size_bits = 7;
+			sb.layout.magic.b = *SUPERBLOCK_MAGIC.as_bytes();
+			sb.layout.nr_superblocks = 2;
+			sb.layout.sb_offset[..2].copy_from_slice(&[8, 64]);
+		})
+	};
+	let (primary, backup) = (sb(1), sb(2));
+	let layout = &primary[primary.len() - std::mem::size_of::<bch_bindgen::bcachefs::bch_sb_layout>()..];
+	let mut image = vec![0u8; 64 * 512 + backup.len()];
+	image[3584..3584 + layout.len()].copy_from_slice(layout);
+	image[64 * 512..].copy_from_slice(&backup);
+	// the primary's magic is gone
+	image[8 * 512 + 40..8 * 512 + primary.len()].copy_from_slice(&primary[40..]);
+
+	let path = std::env::temp_dir().join(format!("bch_bindgen-copies.{}.img", std::process::id()));
+	std::fs::File::create(&path).unwrap().write_all(&image).unwrap();
+	let offsets = superblock_offsets(&path, 0);
+	let copies: Vec<_> = (0..3).map(|n| read_super_raw_copy(&path, 0, n).map(|buf| buf.sb().seq)).collect();
+	std::fs::remove_file(&path).unwrap();
+
+	assert_eq!(offsets.unwrap(), vec![8, 64]);
+	assert_eq!(copies[0].as_ref().unwrap_err().kind(), ErrorKind::InvalidData);
+	assert_eq!(*copies[1].as_ref().unwrap(), 2);
+	assert_eq!(copies[2].as_ref().unwrap_err().kind(), ErrorKind::InvalidInput);
+}
+
+/// An image with the layout of `copies` in its layout sector and a copy of
+/// the superblock at each of its sectors, with seqs `copies` gives and
+/// whatever `doctor` does to each afterwards
+fn image_with_copies(copies: &[(u64, u64)], doctor: impl Fn(usize, &mut [u8])) -> std::path::PathBuf {
+	use std::io::Write;
+
+	let sb = |seq| {
+		fixture(0, |sb, _| {
+			sb.seq = seq;
+			sb.layout.sb_max_size_bits = 7;
+			sb.layout.magic.b = *SUPERBLOCK_MAGIC.as_bytes();
+			sb.layout.nr_superblocks = copies.len() as u8;
+			for (n, (sector, _)) in copies.iter().enumerate() {
+				sb.layout.sb_offset[n] = *sector;
+			}
+		})
+	};
+	let end = copies.iter().map(|(sector, _)| *sector as usize * 512).max().unwrap() + sb(0).len();
+	let mut image = vec![0u8; end];
+	for (n, (sector, seq)) in copies.iter().enumerate() {
+		let mut copy = sb(*seq);
+		doctor(n, &mut copy);
+		let at = *sector as usize * 512;
+		image[at..at + copy.len()].copy_from_slice(&copy);
+	}
+	let layout_len = std::mem::size_of::<bch_bindgen::bcachefs::bch_sb_layout>();
+	let primary = sb(0);
+	image[3584..3584 + layout_len].copy_from_slice(&primary[primary.len() - layout_len..]);
+
+	let path = std::env::temp_dir().join(format!("bch_bindgen-copies-{}.{}.img", copies.len(), std::process::id()));
+	std::fs::File::create(&path).unwrap().write_all(&image).unwrap();
+	path
+}
+
+#[test]
+fn layout_lists_the_copies() {
+	let buf = SbBuf::from_bytes(&fixture(0, |sb, _| {
+		sb.layout.layout_type = 0;
+		sb.layout.sb_max_size_bits = 7;
+		sb.layout.nr_superblocks = 3;
+		sb.layout.sb_offset[..3].copy_from_slice(&[8, 136, 2048]);
+	}))
+	.unwrap();
+	let layout = buf.sb().layout();
+	assert_eq!(layout.sb_max_size_bits, 7);
+	assert_eq!(layout.offsets, vec![8, 136, 2048]);
+	assert_eq!(buf.sb().superblock_offsets(), layout.offsets);
+}
+
+#[test]
+fn verifies_each_copy() {
+	use std::io::ErrorKind;
+
+	let path = image_with_copies(&[(8, 5), (64, 5), (128, 4), (192, 5)], |n, copy| match n {
+		// a stored checksum where there should be none
+		1 => copy[0] = 1,
+		// the magic of the last copy is overwritten
+		3 => copy[24..40].copy_from_slice(&[0; 16]),
+		_ => {}
+	});
+	let copies: Vec<_> = [8, 64, 128, 192].iter().map(|&sector| verify_super_copy(&path, 0, sector)).collect();
+	let primary = verify_super_csum(&path).map(|csum| csum.matches());
+	std::fs::remove_file(&path).unwrap();
+
+	let intact = |n: usize| copies[n].as_ref().map(|c| (c.sector, c.seq, c.csum.matches())).unwrap();
+	assert_eq!(intact(0), (8, 5, true));
+	assert_eq!(intact(1), (64, 5, false));
+	assert_eq!(intact(2), (128, 4, true));
+	assert_eq!(copies[3].as_ref().unwrap_err().kind(), ErrorKind::InvalidData);
+	assert!(primary.unwrap());
+}
+
+/// Disk groups "ssd", "hdd", "ssd.fast", a deleted one and one that is its
+/// own parent, with members in "ssd.fast", "ssd", "hdd" and none; foreground
+/// on ssd, promote on ssd.fast, background on hdd and metadata on device 0
+fn tiered() -> SbBuf {
+	let member_u64s = std::mem::size_of::<bch_member>() / 8;
+	let group_u64s = 6;
+	let groups: [(&str, u64); 5] = [("ssd", 0), ("hdd", 0), ("fast", 1 << 6), ("old", 1), ("loop", 5 << 6)];
+	let member_groups = [3, 1, 2, 0];
+	let members_len = 1 + member_u64s * member_groups.len();
+	let groups_len = 1 + group_u64s * groups.len();
+	let u64s = members_len + groups_len;
+	SbBuf::from_bytes(&fixture(u64s, |sb, fields| {
+		sb.u64s = u64s as u32;
+		sb.nr_devices = member_groups.len() as u8;
+		sb.dev_idx = 0;
+		// BCH_SB_PROMOTE_TARGET, BCH_SB_FOREGROUND_TARGET, BCH_SB_BACKGROUND_TARGET
+		sb.flags[1] = (257 + 2) << 28 | 257 << 40 | (257 + 1) << 52;
+		sb.flags[3] = 1 << 16; // BCH_SB_METADATA_TARGET
+		fields[0] = members_len as u64 | 1 << 32; // BCH_SB_FIELD_members
+		for (i, &group) in member_groups.iter().enumerate() {
+			let m = unsafe { &mut *(fields[1 + i * member_u64s..].as_mut_ptr() as *mut bch_member) };
+			m.uuid.b = [i as u8 + 1; 16];
+			m.flags[0] = group << 20; // BCH_MEMBER_GROUP, biased by one
+		}
+		let fields = &mut fields[members_len..];
+		fields[0] = groups_len as u64 | 5 << 32; // BCH_SB_FIELD_disk_groups
+		for (i, (label, flags)) in groups.iter().enumerate() {
+			let g = unsafe { &mut *(fields[1 + i * group_u64s..].as_mut_ptr() as *mut bch_disk_group) };
+			g.label[..label.len()].copy_from_slice(label.as_bytes());
+			g.flags[0] = *flags;
+		}
+	}))
+	.unwrap()
+}
+
+#[test]
+fn disk_group_paths() {
+	use bch_bindgen::bcachefs::{DiskGroup, DiskGroups};
+
+	let buf = tiered();
+	let groups = buf.sb().groups();
+	assert_eq!(groups.0.len(), 5);
+	assert_eq!(groups.0[2], DiskGroup { label: "fast".to_owned(), deleted: false, parent: Some(0), data_allowed: 0 });
+	assert!(groups.0[3].deleted);
+	assert_eq!(groups.path(0).as_deref(), Some("ssd"));
+	assert_eq!(groups.path(2).as_deref(), Some("ssd.fast"));
+	// deleted, out of range, or going round in circles
+	assert_eq!(groups.path(3), None);
+	assert_eq!(groups.path(5), None);
+	assert_eq!(groups.path(4), None);
+	assert!(groups.is_within(2, 0) && groups.is_within(2, 2));
+	assert!(!groups.is_within(0, 2) && !groups.is_within(1, 0));
+
+	// a child of a deleted group is invalid too, as the C tool has it
+	let mut orphaned = groups.clone();
+	orphaned.0[2].parent = Some(3);
+	assert_eq!(orphaned.path(2), None);
+	assert_eq!(DiskGroups::default().path(0), None);
+	assert_eq!(SbBuf::from_bytes(&fixture(0, |_, _| {})).unwrap().sb().groups(), DiskGroups::default());
+}
+
+#[test]
+fn targets_and_roles() {
+	use bch_bindgen::bcachefs::{Role, Target};
+
+	assert_eq!(Target::decode(0), None);
+	assert_eq!(Target::decode(1), Some(Target::Device(0)));
+	assert_eq!(Target::decode(256), Some(Target::Device(255)));
+	assert_eq!(Target::decode(257), Some(Target::Group(0)));
+
+	let buf = tiered();
+	let sb = buf.sb();
+	assert_eq!(sb.target(Role::Foreground), Some(Target::Group(0)));
+	assert_eq!(sb.target(Role::Promote), Some(Target::Group(2)));
+	assert_eq!(sb.target(Role::Background), Some(Target::Group(1)));
+	assert_eq!(sb.target(Role::Metadata), Some(Target::Device(0)));
+	assert_eq!(sb.target_to_string(sb.target(Role::Promote)), "ssd.fast");
+	assert_eq!(
+		sb.target_to_string(sb.target(Role::Metadata)),
+		"Device 01010101-0101-0101-0101-010101010101 (0)"
+	);
+	assert_eq!(sb.target_to_string(None), "none");
+	assert_eq!(sb.target_to_string(Some(Target::Device(9))), "Bad device 9");
+	assert_eq!(sb.target_to_string(Some(Target::Group(3))), "invalid label 3");
+
+	let roles: Vec<Vec<Role>> = sb.members().iter().map(|m| sb.roles(m)).collect();
+	assert_eq!(
+		roles,
+		vec![
+			vec![Role::Foreground, Role::Promote, Role::Metadata],
+			vec![Role::Foreground],
+			vec![Role::Background],
+			vec![],
+		]
+	);
+	assert_eq!(sb.members()[0].group, Some(2));
+	assert_eq!(sb.members()[3].group, None);
+}
@@ -0,0 +1,8 @@
+fn main() {
+	// where bch_bindgen found libbcachefs, for tests/capi.rs to link a C
+	// program against the static library with
+	if let Ok(lib) = std::env::var("DEP_BCACHEFS_LIB") {
+		println!("cargo:rustc-env=LIBBCACHEFS_LIB={}", lib);
+	}
+	println!("cargo:rerun-if-env-changed=DEP_BCACHEFS_LIB");
+}
@@ -0,0 +1,13 @@
+//! List the bcachefs filesystems visible to this machine and their members.
+//!
+//! Run as root, since probing opens every block device:
+//! `cargo run --example probe`
+
+fn main() -> anyhow::Result<()> {
+	tracing_subscriber::fmt::init();
+
+	for (uuid, fs) in bcachefs_mount::filesystem::probe_filesystems(&Default::default())? {
+		println!("{} encrypted={} devices={}", uuid, fs.encrypted(), fs.device_string());
+	}
+	Ok(())
+}
@@ -0,0 +1,10 @@
+//! Mount option parsing on arbitrary strings, as found in a hostile fstab
+#![no_main]
+use bcachefs_mount::filesystem::Checking;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|options: &str| {
+	let _ = bcachefs_mount::filesystem::parse_mount_options(options, Checking::PassThrough);
+	let _ = bcachefs_mount::filesystem::parse_mount_options(options, Checking::Strict);
+	let _ = bcachefs_mount::filesystem::parse_mount_options(options, Checking::Sloppy);
+});
@@ -0,0 +1,19 @@
+//! Superblock accessors on arbitrary bytes, as found on a hostile device
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+	let buf = match bch_bindgen::rs::SbBuf::from_bytes(data) {
+		Ok(buf) => buf,
+		Err(_) => return,
+	};
+	let sb = buf.sb();
+	let _ = format!("{:?}", sb);
+	let _ = sb.uuid();
+	let _ = sb.is_clean();
+	let _ = sb.members();
+	let _ = sb.journal_size_bytes();
+	if let Some(crypt) = sb.crypt() {
+		let _ = crypt.scrypt_flags();
+	}
+});
@@ -0,0 +1,90 @@
+/*
+ * C interface of bcachefs-mount: find the member devices of a filesystem
+ * and unlock encrypted ones, without running the binary.
+ *
+ * Link with libbcachefs_mount.a, and with libbcachefs, libudev, -lpthread,
+ * -ldl and -lm, which it leaves to the linker.
+ *
+ * Ownership across the interface:
+ * - device arrays are allocated by the library and freed only with
+ *   bcachefs_mount_free(), which frees their paths too
+ * - error messages are allocated with malloc(); the caller free()s them
+ * - strings passed in are only borrowed for the duration of the call
+ *
+ * The functions don't keep state between calls, but they do scan the
+ * block devices on every call.
+ */
+
+#ifndef BCACHEFS_MOUNT_H
+#define BCACHEFS_MOUNT_H
+
+/* Generated by cbindgen from rust-src/mount/src/capi.rs; do not edit */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * A member device of a filesystem, as `bcachefs_mount_probe()` returns
+ * them
+ */
+struct bcachefs_mount_device {
+  /**
+   * Device node, NUL terminated; owned by the array
+   */
+  char *path;
+  /**
+   * Index of the device in the filesystem
+   */
+  uint8_t dev_idx;
+  /**
+   * Whether the block device is read-only
+   */
+  bool read_only;
+};
+
+/**
+ * Find the filesystem `spec` names, a UUID, a prefix of one or
+ * `LABEL=<label>`, among the block devices, and return its member devices.
+ * The number of devices goes to `*count`. The devices opened to read their
+ * superblocks are closed again before it returns.
+ *
+ * On failure, returns NULL with `*count` set to 0 and, if `error` isn't
+ * NULL, a message in `*error` for the caller to `free`.
+ *
+ * # Safety
+ *
+ * `spec` must be a NUL terminated string, `count` must point to a `size_t`
+ * and `error`, unless NULL, to a `char *`.
+ */
+struct bcachefs_mount_device *bcachefs_mount_probe(const char *spec, size_t *count, char **error);
+
+/**
+ * Free the `count` devices `bcachefs_mount_probe()` returned, with their
+ * paths. NULL is ignored.
+ *
+ * # Safety
+ *
+ * `devices` must be NULL or an array `bcachefs_mount_probe()` returned,
+ * with the count it returned, and not be used afterwards.
+ */
+void bcachefs_mount_free(struct bcachefs_mount_device *devices, size_t count);
+
+/**
+ * Add the key of the encrypted filesystem `spec` names to the keyring,
+ * unlocking it with `passphrase`. Does nothing if the key is there already
+ * or the filesystem isn't encrypted.
+ *
+ * Returns 0 on success, or else the exit status `bcachefs-mount` has for
+ * the failure, e.g. 3 if the filesystem wasn't found or 5 for a wrong
+ * passphrase, with a message in `*error` for the caller to `free` if
+ * `error` isn't NULL.
+ *
+ * # Safety
+ *
+ * `spec` and `passphrase` must be NUL terminated strings and `error`,
+ * unless NULL, must point to a `char *`.
+ */
+int bcachefs_mount_unlock(const char *spec, const char *passphrase, char **error);
+
+#endif /* BCACHEFS_MOUNT_H */
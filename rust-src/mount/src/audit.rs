@@ -0,0 +1,176 @@
+//! Audit records of security-relevant operations: a filesystem key added to
+//! or removed from the keyring, and an encrypted filesystem mounted or
+//! unmounted again. Each is one line with who did it and on which devices,
+//! logged as an event and, with `--audit`, also sent to the kernel's audit
+//! subsystem. Records are built from UUIDs, serials and paths only, so they
+//! can't carry key material.
+
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// `AUDIT_TRUSTED_APP` from linux/audit.h, for messages of userspace programs
+/// trusted to report what they do
+const AUDIT_TRUSTED_APP: u16 = 1121;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+	KeyAdded,
+	KeyRemoved,
+	EncryptedMount,
+	EncryptedUnmount,
+}
+
+impl Op {
+	pub fn name(self) -> &'static str {
+		match self {
+			Op::KeyAdded => "key-added",
+			Op::KeyRemoved => "key-removed",
+			Op::EncryptedMount => "encrypted-mount",
+			Op::EncryptedUnmount => "encrypted-unmount",
+		}
+	}
+}
+
+/// One audited operation, by the current process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+	pub op: Op,
+	pub uuid: Uuid,
+	/// Serial of the key in the user keyring, for key operations
+	pub serial: Option<i32>,
+	/// Mountpoint, for mounts and unmounts
+	pub target: Option<PathBuf>,
+	/// Member devices the filesystem was found on
+	pub devices: Vec<PathBuf>,
+	/// Real uid, which stays that of the invoking user under setuid or
+	/// --keyring-owner-uid
+	pub uid: libc::uid_t,
+	pub pid: libc::pid_t,
+}
+
+impl Record {
+	pub fn new(op: Op, uuid: Uuid) -> Self {
+		Record {
+			op,
+			uuid,
+			serial: None,
+			target: None,
+			devices: Vec::new(),
+			uid: unsafe { libc::getuid() },
+			pid: unsafe { libc::getpid() },
+		}
+	}
+}
+
+/// key=value pairs, as audit messages are, with paths quoted
+impl std::fmt::Display for Record {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "op={} uuid={}", self.op.name(), self.uuid)?;
+		if let Some(serial) = self.serial {
+			write!(f, " keyring=user serial={}", serial)?;
+		}
+		if let Some(target) = &self.target {
+			write!(f, " target={:?}", target.to_string_lossy())?;
+		}
+		if !self.devices.is_empty() {
+			let devices: Vec<_> = self.devices.iter().map(|d| d.to_string_lossy()).collect();
+			write!(f, " devices={:?}", devices.join(":"))?;
+		}
+		write!(f, " uid={} pid={}", self.uid, self.pid)
+	}
+}
+
+/// Where audit records go
+pub trait Sink {
+	fn record(&self, record: &Record);
+}
+
+/// Records as log events, with the rest of the log
+pub struct Log;
+
+impl Sink for Log {
+	fn record(&self, record: &Record) {
+		tracing::info!(msg="audit", op=record.op.name(), %record);
+	}
+}
+
+/// Records as log events and as messages to the kernel's audit subsystem,
+/// which takes them from root only
+pub struct Netlink(std::fs::File);
+
+impl Netlink {
+	pub fn open() -> std::io::Result<Self> {
+		let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_AUDIT) };
+		if fd < 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+		Ok(Netlink(unsafe { std::fs::File::from_raw_fd(fd) }))
+	}
+
+	fn send(&self, text: &str) -> std::io::Result<()> {
+		let message = netlink_message(text);
+		let mut kernel: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+		kernel.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+		let ret = unsafe {
+			libc::sendto(
+				self.0.as_raw_fd(),
+				message.as_ptr() as *const libc::c_void,
+				message.len(),
+				0,
+				&kernel as *const _ as *const libc::sockaddr,
+				std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+			)
+		};
+		if ret < 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+		Ok(())
+	}
+}
+
+impl Sink for Netlink {
+	fn record(&self, record: &Record) {
+		Log.record(record);
+		if let Err(e) = self.send(&record.to_string()) {
+			tracing::warn!(msg="audit record could not be sent to the kernel", error=%e);
+		}
+	}
+}
+
+/// `text` as an `AUDIT_TRUSTED_APP` netlink message: the header, then the
+/// text, NUL terminated and padded to 4 bytes
+pub fn netlink_message(text: &str) -> Vec<u8> {
+	let header = std::mem::size_of::<libc::nlmsghdr>();
+	let len = header + text.len() + 1;
+	let mut message = Vec::with_capacity((len + 3) & !3);
+	message.extend_from_slice(&(len as u32).to_ne_bytes());
+	message.extend_from_slice(&AUDIT_TRUSTED_APP.to_ne_bytes());
+	message.extend_from_slice(&(libc::NLM_F_REQUEST as u16).to_ne_bytes());
+	// sequence number and port; 0 for the kernel to fill in
+	message.extend_from_slice(&0u32.to_ne_bytes());
+	message.extend_from_slice(&0u32.to_ne_bytes());
+	message.extend_from_slice(text.as_bytes());
+	message.resize((len + 3) & !3, 0);
+	message
+}
+
+/// The sink for `--audit`, or just the log without it. Without root, or if
+/// the audit socket can't be opened, records are logged only, after a
+/// warning.
+pub fn sink(audit: bool) -> Box<dyn Sink> {
+	if !audit {
+		return Box::new(Log);
+	}
+	if unsafe { libc::geteuid() } != 0 {
+		tracing::warn!(msg="--audit needs root, audit records are only logged");
+		return Box::new(Log);
+	}
+	match Netlink::open() {
+		Ok(netlink) => Box::new(netlink),
+		Err(e) => {
+			tracing::warn!(msg="audit socket can't be opened, audit records are only logged", error=%e);
+			Box::new(Log)
+		}
+	}
+}
@@ -0,0 +1,3 @@
+fn main() {
+	std::process::exit(bcachefs_mount::cmd::multicall(std::env::args_os().collect()));
+}
@@ -0,0 +1,4 @@
+fn main() {
+	use bcachefs_mount::cmd;
+	std::process::exit(cmd::main(cmd::Command::ShowSuper, std::env::args_os().collect()));
+}
@@ -0,0 +1,122 @@
+//! `--use-cache`: remember which block devices hold bcachefs, so that mounts
+//! in quick succession probe just those rather than every block device.
+//!
+//! The cache is `probe-cache` in the runtime directory: the kernel's uevent
+//! sequence number when it was written, then the member devices found, one
+//! per line. Any uevent since, as for a device added, removed or changed,
+//! makes it stale, and so does age. It is replaced by a rename, so
+//! concurrent runs read either the old or the new one, never half of one.
+
+use crate::filesystem::{self, FileSystem, ResolveError};
+use crate::paths::Paths;
+use crate::FsSpec;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// How long a cache is used for, even without uevents
+pub const TTL: Duration = Duration::from_secs(60);
+
+const FILE: &str = "probe-cache";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProbeCache {
+	/// `/sys/kernel/uevent_seqnum` before probing
+	pub seqnum: u64,
+	pub devices: Vec<PathBuf>,
+}
+
+impl ProbeCache {
+	/// `None` for anything but a complete cache
+	pub fn parse(s: &str) -> Option<Self> {
+		let mut lines = s.strip_suffix('\n')?.split('\n');
+		let seqnum = lines.next()?.strip_prefix("seqnum ")?.parse().ok()?;
+		let devices = lines.map(PathBuf::from).collect();
+		Some(ProbeCache { seqnum, devices })
+	}
+}
+
+/// Devices with a newline in their path can't be listed, and are found by a
+/// full probe instead
+impl std::fmt::Display for ProbeCache {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		writeln!(f, "seqnum {}", self.seqnum)?;
+		for device in &self.devices {
+			let device = device.to_string_lossy();
+			if !device.contains('\n') {
+				writeln!(f, "{}", device)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Number of the last uevent the kernel sent
+pub fn uevent_seqnum(paths: &Paths) -> Option<u64> {
+	let seqnum = std::fs::read_to_string(paths.sys_root.join("kernel/uevent_seqnum")).ok()?;
+	seqnum.trim().parse().ok()
+}
+
+/// The devices the cache in `dir` lists, if it was written less than `ttl`
+/// before `now` and at uevent `seqnum`
+pub fn load(dir: &Path, seqnum: u64, ttl: Duration, now: SystemTime) -> Option<Vec<PathBuf>> {
+	let path = dir.join(FILE);
+	let written = std::fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+	// written "after" now is from a file system timestamp a bit ahead
+	if now.duration_since(written).unwrap_or_default() >= ttl {
+		tracing::debug!(msg="probe cache expired", path=%path.display());
+		return None;
+	}
+	let cache = ProbeCache::parse(&std::fs::read_to_string(&path).ok()?)?;
+	if cache.seqnum != seqnum {
+		tracing::debug!(msg="devices changed since the probe cache was written", path=%path.display());
+		return None;
+	}
+	Some(cache.devices)
+}
+
+/// Replace the cache in `dir` with `cache`. It is readable by everyone, as
+/// it only lists device paths.
+pub fn store(dir: &Path, cache: &ProbeCache) -> std::io::Result<()> {
+	use std::io::Write;
+	use std::os::unix::fs::PermissionsExt;
+
+	let tmp = dir.join(format!("{}.{}", FILE, std::process::id()));
+	std::fs::File::create(&tmp).and_then(|mut file| {
+		file.set_permissions(std::fs::Permissions::from_mode(0o644))?;
+		file.write_all(cache.to_string().as_bytes())
+	})?;
+	std::fs::rename(&tmp, dir.join(FILE)).map_err(|e| {
+		let _ = std::fs::remove_file(&tmp);
+		e
+	})
+}
+
+/// Take the filesystem `spec` names out of a probe of the devices the cache
+/// lists, if it is fresh and `refresh` isn't asked for. Otherwise, or if the
+/// filesystem isn't on those devices, probe every block device and cache
+/// what was found.
+pub fn resolve(spec: &FsSpec, paths: &Paths, refresh: bool) -> anyhow::Result<FileSystem> {
+	let dir = paths.state_dir();
+	// before probing, so that uevents during the probe make the cache stale
+	let seqnum = uevent_seqnum(paths);
+	if let (Some(dir), Some(seqnum), false) = (dir, seqnum, refresh) {
+		if let Some(devices) = load(dir, seqnum, TTL, SystemTime::now()) {
+			tracing::info!(msg="probing the devices in the probe cache", count=devices.len());
+			match filesystem::resolve(spec, &mut filesystem::probe_with(devices.as_slice(), paths)?) {
+				Err(ResolveError::NotFound) => tracing::info!(msg="filesystem not in the probe cache, probing all devices", %spec),
+				found => return Ok(found?),
+			}
+		}
+	}
+
+	let (mut fss, scan) = filesystem::probe_scan(&filesystem::Udev, paths)?;
+	if let (Some(dir), Some(seqnum)) = (dir, seqnum) {
+		let mut devices: Vec<PathBuf> =
+			fss.values().flat_map(|fs| fs.members().iter().map(|m| m.path().to_owned())).collect();
+		devices.sort();
+		if let Err(e) = store(dir, &ProbeCache { seqnum, devices }) {
+			tracing::warn!(msg="probe cache can't be written", dir=%dir.display(), error=%e);
+		}
+	}
+	filesystem::resolve(spec, &mut fss).map_err(|e| filesystem::not_found_in(e, scan, paths))
+}
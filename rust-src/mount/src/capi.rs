@@ -0,0 +1,174 @@
+//! A C interface to probing and unlocking, for the C parts of
+//! bcachefs-tools and for other programs, e.g. installers, that would
+//! otherwise have to run `bcachefs-mount` and parse what it prints.
+//!
+//! `include/bcachefs_mount.h` is generated from this file with cbindgen
+//! (`make mount-header`), and the library is built as
+//! `libbcachefs_mount.a`. Nothing allocated on one side of the boundary is
+//! freed on the other with a different allocator:
+//!
+//! - device arrays are allocated here and only freed by
+//!   `bcachefs_mount_free()`, paths and all
+//! - error messages are allocated with `malloc`, for the caller to `free`
+//! - strings passed in are only borrowed for the duration of the call
+
+use crate::filesystem::{probe_for, FileSystem, Udev};
+use crate::paths::Paths;
+use crate::FsSpec;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+
+/// A member device of a filesystem, as `bcachefs_mount_probe()` returns
+/// them
+#[repr(C)]
+#[derive(Debug)]
+pub struct Device {
+	/// Device node, NUL terminated; owned by the array
+	pub path: *mut c_char,
+	/// Index of the device in the filesystem
+	pub dev_idx: u8,
+	/// Whether the block device is read-only
+	pub read_only: bool,
+}
+
+/// The members of `fs` as an array for C, with the number of devices in it;
+/// to be freed with `bcachefs_mount_free()`
+pub fn devices(fs: &FileSystem) -> (*mut Device, usize) {
+	let devices: Box<[Device]> = fs
+		.members()
+		.iter()
+		.map(|m| Device {
+			// paths come from the kernel or udev, which don't have NULs in them
+			path: CString::new(m.path().as_os_str().as_bytes()).unwrap_or_default().into_raw(),
+			dev_idx: m.dev_idx(),
+			read_only: m.read_only(),
+		})
+		.collect();
+	let count = devices.len();
+	(Box::into_raw(devices) as *mut Device, count)
+}
+
+/// `ptr` as a string, or an error naming the `argument` if it is NULL or
+/// not UTF-8
+unsafe fn argument<'a>(ptr: *const c_char, argument: &str) -> anyhow::Result<&'a str> {
+	if ptr.is_null() {
+		return Err(err!(NullArgument, argument));
+	}
+	CStr::from_ptr(ptr).to_str().map_err(|_| err!(NotUtf8, argument))
+}
+
+/// Store a `malloc`ed copy of the message of `e` in `*error`, unless
+/// `error` is NULL
+unsafe fn set_error(error: *mut *mut c_char, e: &anyhow::Error) {
+	if error.is_null() {
+		return;
+	}
+	let message = CString::new(format!("{:#}", e).replace('\0', "")).unwrap_or_default();
+	*error = libc::strdup(message.as_ptr());
+}
+
+/// Run `f`, turning a panic into an error, as unwinding into C is undefined
+fn guarded<T>(f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+	std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|_| Err(err!(Panicked)))
+}
+
+/// Find the filesystem `spec` names, a UUID, a prefix of one or
+/// `LABEL=<label>`, among the block devices, and return its member devices.
+/// The number of devices goes to `*count`. The devices opened to read their
+/// superblocks are closed again before it returns.
+///
+/// On failure, returns NULL with `*count` set to 0 and, if `error` isn't
+/// NULL, a message in `*error` for the caller to `free`.
+///
+/// # Safety
+///
+/// `spec` must be a NUL terminated string, `count` must point to a `size_t`
+/// and `error`, unless NULL, to a `char *`.
+#[no_mangle]
+pub unsafe extern "C" fn bcachefs_mount_probe(
+	spec: *const c_char,
+	count: *mut usize,
+	error: *mut *mut c_char,
+) -> *mut Device {
+	let probed = guarded(|| {
+		if count.is_null() {
+			return Err(err!(NullArgument, "count"));
+		}
+		let spec: FsSpec = argument(spec, "spec")?.parse()?;
+		probe_for(&spec, &Udev, &Paths::default()).map(|fs| devices(&fs))
+	});
+	match probed {
+		Ok((devices, n)) => {
+			*count = n;
+			devices
+		}
+		Err(e) => {
+			if !count.is_null() {
+				*count = 0;
+			}
+			set_error(error, &e);
+			std::ptr::null_mut()
+		}
+	}
+}
+
+/// Free the `count` devices `bcachefs_mount_probe()` returned, with their
+/// paths. NULL is ignored.
+///
+/// # Safety
+///
+/// `devices` must be NULL or an array `bcachefs_mount_probe()` returned,
+/// with the count it returned, and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn bcachefs_mount_free(devices: *mut Device, count: usize) {
+	if devices.is_null() {
+		return;
+	}
+	let devices = Box::from_raw(std::ptr::slice_from_raw_parts_mut(devices, count));
+	for device in devices.iter() {
+		if !device.path.is_null() {
+			drop(CString::from_raw(device.path));
+		}
+	}
+}
+
+/// Add the key of the encrypted filesystem `spec` names to the keyring,
+/// unlocking it with `passphrase`. Does nothing if the key is there already
+/// or the filesystem isn't encrypted.
+///
+/// Returns 0 on success, or else the exit status `bcachefs-mount` has for
+/// the failure, e.g. 3 if the filesystem wasn't found or 5 for a wrong
+/// passphrase, with a message in `*error` for the caller to `free` if
+/// `error` isn't NULL.
+///
+/// # Safety
+///
+/// `spec` and `passphrase` must be NUL terminated strings and `error`,
+/// unless NULL, must point to a `char *`.
+#[no_mangle]
+pub unsafe extern "C" fn bcachefs_mount_unlock(
+	spec: *const c_char,
+	passphrase: *const c_char,
+	error: *mut *mut c_char,
+) -> c_int {
+	let unlocked = guarded(|| {
+		let spec: FsSpec = argument(spec, "spec")?.parse()?;
+		let passphrase = crate::Passphrase(argument(passphrase, "passphrase")?.to_owned());
+		let fs = probe_for(&spec, &Udev, &Paths::default())?;
+		if !fs.encrypted() {
+			return Ok(());
+		}
+		match crate::key::try_passphrases(&fs, &[passphrase], &crate::audit::Log)? {
+			true => Ok(()),
+			false => Err(err!(WrongPassphrase)),
+		}
+	});
+	match unlocked {
+		Ok(()) => 0,
+		Err(e) => {
+			set_error(error, &e);
+			crate::exit::kind(&e).exit_code()
+		}
+	}
+}
@@ -0,0 +1,1088 @@
+//! The commands the binaries run, as functions: mounting, for
+//! `bcachefs-mount`, and the tools `bcachefs-list`, `bcachefs-show-super` and
+//! `bcachefs-debug`, `bcachefs-forget-key`, `bcachefs-metrics` and
+//! `bcachefs-watch`. `bcachefs-rs` is all of them in one binary, busybox
+//! style, for initramfs and rescue images.
+
+use crate::paths::Paths;
+use crate::Options;
+use std::ffi::OsString;
+use std::path::Path;
+use structopt::StructOpt;
+
+/// Log to stderr at `level`, or as RUST_LOG says if it is `None`, and
+/// also to `trace_output` in Chrome's trace format
+pub fn init_logging(level: Option<tracing_subscriber::filter::LevelFilter>, trace_output: Option<&Path>) {
+	// convert existing log statements to tracing events
+	// tracing_log::LogTracer::init().expect("logtracer init failed!");
+	// format tracing log data to env_logger like stderr, -v/-q override RUST_LOG;
+	// stdout is kept for output scripts read, such as --status or --query
+	let subscriber = tracing_subscriber::fmt().with_writer(std::io::stderr);
+	let trace = trace_output.map(crate::trace::ChromeLayer::create);
+	match (level, trace) {
+		(Some(level), None) => subscriber.with_max_level(level).init(),
+		(None, None) => subscriber
+			.with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+			.init(),
+		(level, Some(trace)) => {
+			use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt};
+			// spans must be enabled to be traced, so the console filters for itself
+			let console = level.unwrap_or(LevelFilter::ERROR);
+			let (trace, error) = match trace {
+				Ok(trace) => (Some(trace), None),
+				Err(e) => (None, Some(e)),
+			};
+			subscriber
+				.with_max_level(console.max(LevelFilter::INFO))
+				.event_format(ConsoleLevel(console, tracing_subscriber::fmt::format()))
+				.finish()
+				.with(trace)
+				.init();
+			if let Some(e) = error {
+				tracing::warn!(msg="trace output can't be written", error=%e);
+			}
+		}
+	}
+}
+
+/// Everything `bcachefs-mount` does, from setting up logging to reporting a
+/// failure; returns the exit status
+pub fn mount_main(opt: Options) -> i32 {
+	init_logging(opt.log_level(), opt.trace_output.as_deref());
+
+	let (doctor, paths, json_errors) = (opt.doctor, opt.paths(), opt.json_errors);
+	let uuid = opt.uuid.as_ref().and_then(|spec| spec.uuid());
+	match mount(opt) {
+		Ok(()) => 0,
+		Err(e) => {
+			if json_errors {
+				eprintln!("{}", crate::exit::json(&e, uuid.as_ref()));
+			} else {
+				tracing::error!(fatal_error = ?e);
+			}
+			if !doctor {
+				crate::doctor::record_error(&e, &paths);
+			}
+			crate::exit::kind(&e).exit_code()
+		}
+	}
+}
+
+/// List the bcachefs filesystems on this system, a line each
+#[derive(StructOpt, Debug)]
+#[structopt(name = "bcachefs-list")]
+pub struct ListOptions {
+	/// Print a JSON object per line instead
+	#[structopt(long)]
+	pub json: bool,
+
+	/// Also write the filesystems to this file, as JSON any user can read
+	#[structopt(long, value_name = "path")]
+	pub status_file: Option<std::path::PathBuf>,
+
+	/// Also show the disk group and roles of each member device, then how
+	/// many devices were scanned and what each device that isn't a member
+	/// holds instead: another filesystem as udev sees it, nothing, or why it
+	/// was skipped
+	#[structopt(long)]
+	pub verbose: bool,
+}
+
+/// Print the superblock of a device or image file
+#[derive(StructOpt, Debug)]
+#[structopt(name = "bcachefs-show-super")]
+pub struct ShowSuperOptions {
+	#[structopt(value_name = "device")]
+	pub device: std::path::PathBuf,
+
+	/// Byte offset of the filesystem within an image file; a multiple of 512
+	#[structopt(long, value_name = "bytes", parse(try_from_str = crate::parse_offset))]
+	pub offset: Option<u64>,
+
+	/// Read the nth copy of the superblock the layout lists instead of the
+	/// primary one, e.g. when that is damaged
+	#[structopt(long = "sb", value_name = "n")]
+	pub sb_copy: Option<usize>,
+
+	/// Print the superblock as JSON
+	#[structopt(long)]
+	pub json: bool,
+}
+
+/// Gather what is needed to debug a failed mount
+///
+/// Versions, kernel support, block devices, filesystems found, keyring status
+/// and the last mount error. Key material is never included.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "bcachefs-debug")]
+pub struct DebugOptions {
+	/// Write the report to this .tar.gz instead of printing it
+	#[structopt(short, long, value_name = "bundle.tar.gz")]
+	pub output: Option<std::path::PathBuf>,
+
+	/// Scrub the hostname and drive serial numbers from the report
+	#[structopt(long)]
+	pub anonymize: bool,
+}
+
+/// Remove the key of a filesystem from the keyring
+///
+/// The key is revoked and unlinked from the user keyring, so that it doesn't
+/// stay resident after unmounting until the session ends. A filesystem whose
+/// key isn't loaded is left as is.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "bcachefs-forget-key")]
+pub struct ForgetKeyOptions {
+	/// External UUID of the filesystem
+	#[structopt(value_name = "uuid")]
+	pub uuid: uuid::Uuid,
+
+	/// Also send an audit record of the key removed to the kernel's audit
+	/// subsystem, as root; it is logged either way
+	#[structopt(long)]
+	pub audit: bool,
+}
+
+/// Print Prometheus gauges for the bcachefs filesystems on this system
+///
+/// Devices found and expected, encryption and whether each is mounted,
+/// labelled by uuid and label; e.g. for node_exporter's textfile collector.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "bcachefs-metrics")]
+pub struct MetricsOptions {}
+
+/// Follow the member devices of a mounted filesystem
+///
+/// Prints the members, then a line whenever one changes state or its read,
+/// write or checksum error count goes up. Exits when the filesystem is
+/// unmounted, or with status 10 when a member fails, e.g. to alert from a
+/// systemd service.
+#[derive(StructOpt, Debug)]
+#[structopt(name = "bcachefs-watch")]
+pub struct WatchOptions {
+	/// External UUID of the filesystem, or where it is mounted
+	#[structopt(value_name = "uuid|mountpoint")]
+	pub target: String,
+
+	/// Seconds between polls of sysfs
+	#[structopt(long, value_name = "seconds", default_value = "5")]
+	pub interval: u64,
+}
+
+/// What `bcachefs-rs` can run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+	Mount,
+	List,
+	ShowSuper,
+	Debug,
+	ForgetKey,
+	Metrics,
+	Watch,
+}
+
+impl Command {
+	pub const ALL: &'static [Command] = &[
+		Command::Mount,
+		Command::List,
+		Command::ShowSuper,
+		Command::Debug,
+		Command::ForgetKey,
+		Command::Metrics,
+		Command::Watch,
+	];
+
+	/// Name as a subcommand of `bcachefs-rs`
+	pub fn name(self) -> &'static str {
+		match self {
+			Command::Mount => "mount",
+			Command::List => "list",
+			Command::ShowSuper => "show-super",
+			Command::Debug => "debug",
+			Command::ForgetKey => "forget-key",
+			Command::Metrics => "metrics",
+			Command::Watch => "watch",
+		}
+	}
+
+	/// Name of the binary of its own
+	pub fn binary(self) -> String {
+		format!("bcachefs-{}", self.name())
+	}
+
+	/// The command `name` stands for: its subcommand name, the name of its
+	/// binary, or for mounting also mount.bcachefs, as mount(8) calls it
+	pub fn from_name(name: &str) -> Option<Self> {
+		Self::ALL
+			.iter()
+			.copied()
+			.find(|c| name == c.name() || name == c.binary() || (*c == Command::Mount && name == "mount.bcachefs"))
+	}
+}
+
+/// Run a tool other than mounting, logging as RUST_LOG says
+fn tool(run: impl FnOnce() -> anyhow::Result<()>) -> i32 {
+	init_logging(None, None);
+	match run() {
+		Ok(()) => 0,
+		Err(e) => {
+			tracing::error!(fatal_error = ?e);
+			crate::exit::kind(&e).exit_code()
+		}
+	}
+}
+
+/// Run `command` with the command line `args`, the first of which is the
+/// program name; returns the exit status
+pub fn main(command: Command, args: Vec<OsString>) -> i32 {
+	match command {
+		Command::Mount => mount_main(Options::from_iter(args)),
+		Command::List => {
+			let opt = ListOptions::from_iter(args);
+			tool(|| list(opt.json, opt.status_file.as_deref(), opt.verbose, &Paths::default()))
+		}
+		Command::ShowSuper => {
+			let opt = ShowSuperOptions::from_iter(args);
+			tool(|| show_super(&opt.device, opt.offset.unwrap_or(0), opt.sb_copy, opt.json))
+		}
+		Command::Debug => {
+			let opt = DebugOptions::from_iter(args);
+			tool(|| debug(opt.anonymize, opt.output.as_deref(), &Paths::default()))
+		}
+		Command::ForgetKey => {
+			let opt = ForgetKeyOptions::from_iter(args);
+			tool(|| forget_key(&opt.uuid, &*crate::audit::sink(opt.audit)))
+		}
+		Command::Metrics => {
+			MetricsOptions::from_iter(args);
+			tool(|| metrics(&Paths::default()))
+		}
+		Command::Watch => {
+			let opt = WatchOptions::from_iter(args);
+			let interval = std::time::Duration::from_secs(opt.interval);
+			tool(|| watch_members(&opt.target, interval, &Paths::default()))
+		}
+	}
+}
+
+/// Entry point of `bcachefs-rs`: runs the command it was invoked as, e.g.
+/// through a `bcachefs-list` link, or else the one its first argument names
+pub fn multicall(mut args: Vec<OsString>) -> i32 {
+	let invoked_as = args.first().map(Path::new).and_then(Path::file_name).and_then(|n| n.to_str());
+	if let Some(command) = invoked_as.and_then(Command::from_name) {
+		return main(command, args);
+	}
+	let name = args.get(1).map(|a| a.to_string_lossy().into_owned());
+	match name.as_deref().and_then(Command::from_name) {
+		Some(command) => {
+			// the subcommand stands in for the program name
+			args.remove(0);
+			main(command, args)
+		}
+		None => {
+			let names: Vec<_> = Command::ALL.iter().map(|c| c.name()).collect();
+			eprintln!("{}", msg!(UnknownCommand, name.unwrap_or_default(), names.join(", ")));
+			crate::exit::ErrorKind::InvalidArgument.exit_code()
+		}
+	}
+}
+
+/// One `--status` line per filesystem found, sorted by UUID, or a JSON
+/// object each; `verbose` adds the member devices' disk groups and roles,
+/// and the devices that aren't members after them
+pub fn list(json: bool, status_file: Option<&Path>, verbose: bool, paths: &Paths) -> anyhow::Result<()> {
+	let (fss, mut scan) = crate::filesystem::probe_scan(&crate::filesystem::Udev, paths)?;
+	let mut fss: Vec<_> = fss.into_iter().collect();
+	fss.sort_by_key(|(uuid, _)| *uuid);
+	if let Some(path) = status_file {
+		let statuses: Vec<_> = fss.iter().map(|(_, fs)| fs.status()).collect();
+		crate::statusfile::write(path, &statuses)?;
+	}
+	for (uuid, fs) in fss {
+		if json {
+			println!("{}", fs.status().to_json());
+		} else {
+			println!("{}", fs.status());
+		}
+		if verbose && json {
+			use crate::json::{array, object, string};
+
+			let members = array(fs.placements().iter().map(|p| p.to_json()));
+			println!("{}", object(&[("uuid", string(&uuid.to_string())), ("members", members)]));
+		} else if verbose {
+			for placement in fs.placements() {
+				println!("  {}", placement);
+			}
+		}
+	}
+	if verbose {
+		scan.classify(|dev| crate::mounts::device_fs_type(dev, paths));
+		if json {
+			println!("{}", scan.to_json());
+		} else {
+			println!("{}", scan);
+			for skipped in &scan.skipped {
+				println!("  {}", skipped);
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Prometheus gauges for every filesystem found, sorted by UUID
+pub fn metrics(paths: &Paths) -> anyhow::Result<()> {
+	let mut fss: Vec<_> = crate::filesystem::probe_filesystems(paths)?.into_iter().collect();
+	fss.sort_by_key(|(uuid, _)| *uuid);
+	let statuses: Vec<_> = fss.iter().map(|(_, fs)| fs.status()).collect();
+	print!("{}", crate::metrics::render(&statuses));
+	Ok(())
+}
+
+/// Follow the members of the filesystem `target`, given by UUID or where it
+/// is mounted, until it is unmounted or a member fails
+pub fn watch_members(target: &str, interval: std::time::Duration, paths: &Paths) -> anyhow::Result<()> {
+	let uuid = match target.parse::<uuid::Uuid>() {
+		Ok(uuid) => uuid,
+		Err(_) => crate::mounts::query(Path::new(target), paths)?.uuid.ok_or_else(|| err!(MountUuidUnknown, target))?,
+	};
+	crate::sysfs_monitor::watch(&mut std::io::stdout(), &uuid, interval, paths)
+}
+
+/// Forget the key of the filesystem `uuid`, saying whether there was one
+pub fn forget_key(uuid: &uuid::Uuid, audit: &dyn crate::audit::Sink) -> anyhow::Result<()> {
+	if crate::key::forget_key(uuid, audit)? {
+		println!("{}", msg!(KeyForgotten, uuid));
+	} else {
+		println!("{}", msg!(KeyNotLoaded, uuid));
+	}
+	Ok(())
+}
+
+/// The `--doctor` report, printed or written to the bundle `output`
+pub fn debug(anonymize: bool, output: Option<&Path>, paths: &Paths) -> anyhow::Result<()> {
+	let report = crate::doctor::gather(anonymize, paths);
+	match output {
+		Some(path) => report.write_bundle(path),
+		None => {
+			print!("{}", report);
+			Ok(())
+		}
+	}
+}
+
+pub fn query(mountpoint: &std::path::Path, json: bool, paths: &Paths) -> anyhow::Result<()> {
+	let fs = crate::mounts::query(mountpoint, paths)?;
+	let uuid = fs.uuid.map(|u| u.to_string());
+	let internal_uuid = fs.internal_uuid.map(|u| u.to_string());
+	if json {
+		use crate::json;
+		println!(
+			"{}",
+			json::object(&[
+				("uuid", json::nullable(uuid.as_deref(), json::string)),
+				("internal_uuid", json::nullable(internal_uuid.as_deref(), json::string)),
+				("label", json::nullable(fs.label.as_deref(), json::string)),
+				("devices", json::array(fs.devices.iter().map(|d| json::string(&d.to_string_lossy())))),
+				("subvolid", json::nullable(fs.subvolid, |id| id.to_string())),
+				("options", json::string(&fs.options)),
+			])
+		);
+	} else {
+		let devices: Vec<_> = fs.devices.iter().map(|d| d.display().to_string()).collect();
+		println!("{}", msg!(QueryUuid, uuid.as_deref().unwrap_or("unknown")));
+		if let Some(internal_uuid) = &internal_uuid {
+			println!("{}", msg!(QueryInternalUuid, internal_uuid));
+		}
+		if let Some(label) = &fs.label {
+			println!("{}", msg!(QueryLabel, label));
+		}
+		println!("{}", msg!(QueryDevices, devices.join(" ")));
+		if let Some(id) = fs.subvolid {
+			println!("{}", msg!(QuerySubvolume, id));
+		}
+		println!("{}", msg!(QueryOptions, fs.options));
+	}
+	Ok(())
+}
+
+/// Print the superblock of `device`, the primary one or copy number `copy`
+pub fn show_super(device: &std::path::Path, offset: u64, copy: Option<usize>, json: bool) -> anyhow::Result<()> {
+	use crate::json::{array, nullable, object, string};
+
+	let buf = match copy {
+		Some(n) => bch_bindgen::rs::read_super_raw_copy(device, offset, n)?,
+		None => bch_bindgen::rs::read_super_raw_at(device, offset)?,
+	};
+	let sb = buf.sb();
+	let members = sb.members();
+	for problem in sb.geometry_problems() {
+		tracing::warn!(msg="inconsistent geometry", problem=%problem);
+	}
+	let groups = sb.groups();
+	let roles = |m: &bch_bindgen::bcachefs::MemberInfo| sb.roles(m).iter().map(|r| r.name()).collect::<Vec<_>>();
+	if !json {
+		println!("{:#?}", sb);
+		for m in &members {
+			println!("{:?}", m);
+		}
+		for role in bch_bindgen::bcachefs::Role::ALL.iter() {
+			println!("{}_target: {}", role.name(), sb.target_to_string(sb.target(*role)));
+		}
+		for m in &members {
+			let label = match m.group {
+				Some(g) => groups.path(g).unwrap_or_else(|| format!("invalid label {}", g)),
+				None => "(none)".to_owned(),
+			};
+			println!("member {}: label {}, roles {}", m.dev_idx, label, roles(m).join(" "));
+		}
+		return Ok(());
+	}
+
+	// u64s beyond 2^53 don't survive JSON parsers, so the checksum is hex
+	let csum = sb.csum;
+	let crypt = sb.crypt().map(|c| c.info());
+	let scrypt = crypt.and_then(|c| match c.kdf {
+		bch_bindgen::bcachefs::Kdf::Scrypt(s) => Some(s),
+		_ => None,
+	});
+	let layout = sb.layout();
+	let targets: Vec<_> = bch_bindgen::bcachefs::Role::ALL
+		.iter()
+		.map(|&role| (role.name(), nullable(sb.target(role), |t| string(&sb.target_to_string(Some(t))))))
+		.collect();
+	let members = members.iter().map(|m| {
+		object(&[
+			("dev_idx", m.dev_idx.to_string()),
+			("uuid", string(&m.uuid.to_string())),
+			("nbuckets", m.nbuckets.to_string()),
+			("first_bucket", m.first_bucket.to_string()),
+			("bucket_size", m.bucket_size.to_string()),
+			("last_mount", m.last_mount.to_string()),
+			("state", m.state.to_string()),
+			("group", nullable(m.group, |g| g.to_string())),
+			("label", nullable(m.group.and_then(|g| groups.path(g)), |l| string(&l))),
+			("roles", array(roles(m).into_iter().map(string))),
+			("durability", m.durability.to_string()),
+		])
+	});
+	println!(
+		"{}",
+		object(&[
+			("uuid", string(&sb.uuid().to_string())),
+			("internal_uuid", string(&sb.internal_uuid().to_string())),
+			("version", { sb.version }.to_string()),
+			("version_min", { sb.version_min }.to_string()),
+			("block_size", { sb.block_size }.to_string()),
+			("btree_node_size", sb.btree_node_size().to_string()),
+			("seq", { sb.seq }.to_string()),
+			("csum", object(&[("hi", format!("\"{:016x}\"", { csum.hi })), ("lo", format!("\"{:016x}\"", { csum.lo }))])),
+			("offset", { sb.offset }.to_string()),
+			("sb_offsets", array(layout.offsets.iter().map(|o| o.to_string()))),
+			(
+				"layout",
+				object(&[
+					("type", layout.layout_type.to_string()),
+					("sb_max_size_bits", layout.sb_max_size_bits.to_string()),
+					("nr_superblocks", layout.offsets.len().to_string()),
+				]),
+			),
+			("dev_idx", { sb.dev_idx }.to_string()),
+			("nr_devices", { sb.nr_devices }.to_string()),
+			("encrypted", crypt.is_some().to_string()),
+			("key_bits", nullable(crypt, |c| (c.key_len * 8).to_string())),
+			("kdf", nullable(crypt, |c| string(c.kdf.name()))),
+			(
+				"scrypt",
+				nullable(scrypt, |s| {
+					let value = |v: Option<u64>| nullable(v, |v| v.to_string());
+					object(&[
+						("N", value(s.n())),
+						("r", value(s.r())),
+						("p", value(s.p())),
+						("N_log2", s.n_log2.to_string()),
+						("r_log2", s.r_log2.to_string()),
+						("p_log2", s.p_log2.to_string()),
+					])
+				}),
+			),
+			("label", nullable(sb.label(), |l| string(&l))),
+			("targets", object(&targets)),
+			("members", array(members)),
+		])
+	);
+	Ok(())
+}
+
+pub fn wipe_stale_sb(disk: &std::path::Path, paths: &Paths) -> anyhow::Result<()> {
+	use crate::messages;
+
+	let confirm = |description: &str| {
+		eprintln!("{}", description);
+		eprint!("{}", messages::Msg::WipeConfirmPrompt.text());
+		let mut answer = String::new();
+		std::io::stdin().read_line(&mut answer).is_ok() && std::path::Path::new(answer.trim_end_matches('\n')) == disk
+	};
+	for at in crate::stale::wipe(disk, paths, confirm)? {
+		println!("{}", msg!(Wiped, disk.display(), at));
+	}
+	Ok(())
+}
+
+pub fn verify(device: &std::path::Path) -> anyhow::Result<()> {
+	let csum = bch_bindgen::rs::verify_super_csum(device)?;
+	let (stored, computed) = (csum.stored, csum.computed);
+	if csum.matches() {
+		println!("{}", msg!(SuperblockChecksumOk, device.display()));
+		Ok(())
+	} else {
+		Err(err!(
+			SuperblockChecksumMismatch,
+			device.display(),
+			csum.csum_type,
+			format!("{:016x}{:016x}", { stored.hi }, { stored.lo }),
+			format!("{:016x}{:016x}", { computed.hi }, { computed.lo }),
+		))
+	}
+}
+
+/// Check every superblock copy on `devices`, members of one filesystem
+/// starting `offset` bytes into each: its checksum, and its seq against the
+/// primary's. Prints a line per copy, and fails if any isn't as it should be.
+pub fn check_sb_copies(devices: &[std::path::PathBuf], offset: u64) -> anyhow::Result<()> {
+	use bch_bindgen::rs::{superblock_offsets, verify_super_copy};
+
+	let mut damaged = Vec::new();
+	// seq of each intact primary, to compare the members' to each other
+	let mut primaries = Vec::new();
+	for device in devices {
+		let name = device.display();
+		let primary = verify_super_copy(device, offset, bch_bindgen::bcachefs::BCH_SB_SECTOR as u64)
+			.ok()
+			.filter(|p| p.csum.matches());
+		let offsets = match superblock_offsets(device, offset) {
+			Ok(offsets) => offsets,
+			Err(e) => {
+				println!("{}", msg!(SbLayoutUnreadable, name, e));
+				damaged.push(device);
+				continue;
+			}
+		};
+		let mut intact = Vec::new();
+		for (n, &sector) in offsets.iter().enumerate() {
+			match verify_super_copy(device, offset, sector) {
+				Err(e) => println!("{}", msg!(SbCopyUnreadable, name, n, sector, e)),
+				Ok(copy) if !copy.csum.matches() => println!("{}", msg!(SbCopyChecksumMismatch, name, n, sector)),
+				Ok(copy) => match &primary {
+					Some(p) if p.seq != copy.seq => println!("{}", msg!(SbCopySeqDiffers, name, n, sector, copy.seq, p.seq)),
+					_ => {
+						println!("{}", msg!(SbCopyOk, name, n, sector, copy.seq));
+						intact.push(n);
+					}
+				},
+			}
+		}
+		if intact.len() < offsets.len() {
+			damaged.push(device);
+		}
+		match &primary {
+			Some(p) => primaries.push((device, p.seq)),
+			// what recovery from a backup would start with
+			None => {
+				if let Some(n) = intact.first() {
+					let mut args = format!("--sb {}", n);
+					if offset != 0 {
+						args += &format!(" --offset {}", offset);
+					}
+					args += &format!(" {}", crate::filesystem::shell_quote(device.as_os_str()));
+					println!("{}", msg!(SbCopyIntact, name, n, args));
+				}
+			}
+		}
+	}
+
+	if let Some(newest) = primaries.iter().map(|(_, seq)| *seq).max() {
+		for (device, seq) in primaries.iter().filter(|(_, seq)| *seq < newest) {
+			println!("{}", msg!(SbMemberBehind, device.display(), seq, newest));
+			if !damaged.contains(device) {
+				damaged.push(device);
+			}
+		}
+	}
+	if !damaged.is_empty() {
+		return Err(err!(SbCopiesDamaged, damaged.len(), devices.len()));
+	}
+	Ok(())
+}
+
+/// Leaves events above a level off the console, which the subscriber's own
+/// level can't do once it is raised for --trace-output
+struct ConsoleLevel<F>(tracing_subscriber::filter::LevelFilter, F);
+
+impl<S, N, F> tracing_subscriber::fmt::FormatEvent<S, N> for ConsoleLevel<F>
+where
+	S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+	N: for<'a> tracing_subscriber::fmt::FormatFields<'a> + 'static,
+	F: tracing_subscriber::fmt::FormatEvent<S, N>,
+{
+	fn format_event(
+		&self,
+		ctx: &tracing_subscriber::fmt::FmtContext<'_, S, N>,
+		writer: &mut dyn std::fmt::Write,
+		event: &tracing::Event<'_>,
+	) -> std::fmt::Result {
+		if *event.metadata().level() <= self.0 {
+			self.1.format_event(ctx, writer, event)
+		} else {
+			Ok(())
+		}
+	}
+}
+
+/// Wall clock time spent in each phase of mounting, printed to stderr for
+/// --timings when dropped, so failed mounts are covered as well
+struct Timings {
+	enabled: bool,
+	phases: Vec<(&'static str, std::time::Duration)>,
+}
+
+impl Timings {
+	fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+		let start = std::time::Instant::now();
+		let ret = tracing::info_span!("phase", phase).in_scope(f);
+		self.phases.push((phase, start.elapsed()));
+		ret
+	}
+}
+
+/// Starts deriving the key from the candidate passphrases as soon as the
+/// probe comes across the filesystem, on a thread of its own, and ends the
+/// probe early if they can't unlock it
+struct EarlyKey<'a> {
+	opt: &'a Options,
+	derivation: Option<crate::key::Derivation>,
+}
+
+impl crate::filesystem::Progress for EarlyKey<'_> {
+	fn found(&mut self, fs: &crate::filesystem::FileSystem) {
+		if self.derivation.is_some() || !fs.encrypted() {
+			return;
+		}
+		// failing to read them is for the key phase to report
+		let passphrases = match self.opt.passphrases() {
+			Ok(passphrases) if !passphrases.is_empty() => passphrases,
+			_ => return,
+		};
+		// the keyring of another user is only looked at once probing is done
+		let last_resort = self.opt.keyring_owner_uid.is_none()
+			&& matches!(self.opt.key_location(), Ok(Some(crate::KeyLocation::Fail)));
+		match crate::key::Derivation::start(fs, passphrases, last_resort) {
+			Ok(derivation) => self.derivation = Some(derivation),
+			Err(e) => tracing::warn!(msg="could not derive the key while probing", error=%e),
+		}
+	}
+
+	fn proceed(&mut self) -> anyhow::Result<()> {
+		self.derivation.as_mut().map_or(Ok(()), crate::key::Derivation::check)
+	}
+}
+
+impl Drop for Timings {
+	fn drop(&mut self) {
+		if self.enabled {
+			for (phase, elapsed) in &self.phases {
+				eprintln!("{}", msg!(Timing, phase, format!("{:.3}", elapsed.as_secs_f64())));
+			}
+		}
+	}
+}
+
+#[tracing_attributes::instrument("main")]
+fn mount(opt: Options) -> anyhow::Result<()> {
+	use crate::{daemon, filesystem, health, key, lock, loopdev, mountpoint, mounts, messages, HealthCheck, KeyLocation};
+	unsafe {
+		libc::setvbuf(
+			filesystem::stdout,
+			std::ptr::null_mut(),
+			libc::_IONBF,
+			0,
+		);
+		// libc::fflush(filesystem::stdout);
+	}
+
+	tracing::trace!(?opt);
+	crate::conflicts::check(&opt)?;
+	let paths = opt.paths();
+
+	if opt.version {
+		print!("{}", crate::version_info());
+		return Ok(());
+	}
+	if opt.export_messages {
+		print!("{}", messages::gettext_template());
+		return Ok(());
+	}
+	if opt.doctor {
+		return debug(opt.anonymize, opt.output.as_deref(), &paths);
+	}
+	if opt.status {
+		return list(opt.json, opt.status_file.as_deref(), false, &paths);
+	}
+	if opt.watch {
+		return crate::udev_watch::watch(&mut std::io::stdout(), opt.status_file.as_deref(), &paths);
+	}
+	if let Some(options) = &opt.explain_options {
+		print!("{}", filesystem::explain_mount_options(options, opt.checking()));
+		return Ok(());
+	}
+	if let Some(mountpoint) = &opt.query {
+		return query(mountpoint, opt.json, &paths);
+	}
+	if let Some(device) = &opt.dump_super {
+		return show_super(device, opt.offset.unwrap_or(0), opt.sb_copy, opt.json);
+	}
+	if let Some(device) = &opt.verify {
+		return verify(device);
+	}
+	if !opt.check_sb_copies.is_empty() {
+		return check_sb_copies(&opt.check_sb_copies, opt.offset.unwrap_or(0));
+	}
+	if let Some(disk) = &opt.wipe_stale_sb {
+		return wipe_stale_sb(disk, &paths);
+	}
+	if let Some(uuid) = &opt.cancel_wait {
+		return daemon::cancel_wait(uuid, &paths);
+	}
+	let spec = opt.uuid.as_ref().expect("uuid is required unless exiting early for another option");
+
+	// a remount changes flags and options of what's mounted, without probing
+	if opt.mount_options().split(',').any(|o| o == "remount") {
+		let mountpoint = opt.mountpoint.as_ref().ok_or_else(|| err!(RemountNeedsMountpoint))?;
+		let uuid = spec.uuid().ok_or_else(|| err!(RemountNeedsUuid))?;
+		let options = filesystem::remount(&uuid, mountpoint, opt.mount_options(), opt.checking(), &opt.fstype, &paths)?;
+		tracing::info!(msg="remounted", %uuid, target=%mountpoint.display(), %options);
+		return Ok(());
+	}
+	// before unlocking anything, as there would be nowhere to mount it on
+	if let Some(mountpoint) = &opt.mountpoint {
+		mountpoint::check_dangling(mountpoint)?;
+	}
+
+	// image files are mounted through loop devices, which outlive this
+	// process for as long as they are mounted
+	let mut loop_devices = Vec::new();
+	let mut only_device = Vec::new();
+	for path in &opt.only_device {
+		if loopdev::is_image(path) {
+			let dev = loopdev::LoopDevice::attach(path, opt.offset.unwrap_or(0))?;
+			only_device.push(dev.path().to_owned());
+			loop_devices.push(dev);
+		} else {
+			only_device.push(path.clone());
+		}
+	}
+	if opt.offset.is_some() && loop_devices.is_empty() {
+		return Err(err!(OffsetNeedsImage));
+	}
+
+	let mut timings = Timings { enabled: opt.timings, phases: Vec::new() };
+	let mut early_key = EarlyKey { opt: &opt, derivation: None };
+	// with --only-device there's no need to look at every block device
+	let mut probe = || match only_device.as_slice() {
+		[] if opt.use_cache => crate::cache::resolve(spec, &paths, opt.refresh_cache),
+		[] => filesystem::probe_for_with(spec, &filesystem::Udev, &paths, &mut early_key),
+		only => {
+			let found = filesystem::probe_with(only, &paths)?;
+			filesystem::find_filtered(spec, "--only-device", found, || filesystem::probe_filesystems(&paths))
+		}
+	};
+	let retry = opt.retry.unwrap_or(crate::retry::Retry { count: 0, delay: std::time::Duration::from_secs(0) });
+	let not_found = |e: &anyhow::Error| {
+		e.downcast_ref::<filesystem::ResolveError>() == Some(&filesystem::ResolveError::NotFound)
+	};
+	let mut fs = timings.time("probe", || {
+		retry.run(not_found, |attempt| {
+			if attempt > 0 {
+				tracing::info!(msg="filesystem not found yet, probing again", %spec, attempt, of=retry.count);
+			}
+			probe()
+		})
+	})?;
+	let derivation = early_key.derivation;
+	let uuid = *fs.uuid();
+
+	let mut options = opt.mount_options();
+	let selected = !only_device.is_empty() || !opt.exclude_device.is_empty();
+	if selected {
+		fs.select_devices(&only_device, &opt.exclude_device, &paths)?;
+	}
+	if selected || opt.min_devices.is_some() {
+		use filesystem::{DeviceCount::*, Durability};
+
+		match fs.device_count(opt.min_devices) {
+			Complete => {}
+			Sufficient if fs.durability() == Durability::Replicated => {
+				tracing::info!(
+					msg="not all member devices found, but at least --min-devices, mounting degraded",
+					devices=%fs.device_string(),
+					missing=?fs.missing_devices(),
+					durability=%fs.durability()
+				);
+				options = [options.as_str(), "degraded"].join(",");
+			}
+			Sufficient | Degraded => {
+				tracing::warn!(
+					msg="not all member devices selected, mounting degraded",
+					devices=%fs.device_string(),
+					missing=?fs.missing_devices(),
+					durability=%fs.durability()
+				);
+				options = [options.as_str(), "degraded"].join(",");
+			}
+			Insufficient => {
+				let total = { fs.sb().sb().nr_devices };
+				return Err(err!(TooFewDevices, fs.members().len(), total, opt.min_devices.unwrap_or_default()));
+			}
+		}
+	}
+
+	tracing::info!(msg="found filesystem", %fs);
+	let rules = opt.policy_rules();
+	match crate::policy::evaluate(&rules, &uuid, &fs.internal_uuid(), fs.label().as_deref()) {
+		crate::policy::Decision::Allowed(Some(rule)) => tracing::info!(msg="filesystem allowed", %uuid, %rule),
+		crate::policy::Decision::Allowed(None) => {}
+		crate::policy::Decision::Denied(rule) => {
+			let rule = rule.map_or_else(|| "no --allow-uuid or --allow-label matches".to_owned(), |r| r.to_string());
+			tracing::info!(msg="filesystem denied", %uuid, %rule);
+			return Err(err!(PolicyDenied, uuid, rule));
+		}
+	}
+	if let Some(command) = &opt.policy_exec {
+		let timeout = std::time::Duration::from_secs(opt.policy_timeout);
+		if crate::policy::check(command, &fs.status().to_json(), timeout)? == crate::policy::Verdict::Skip {
+			tracing::info!(msg="policy command skipped the filesystem", %uuid);
+			return Ok(());
+		}
+	}
+	if opt.print_mount_command {
+		let mountpoint = opt.mountpoint.as_ref().expect("--print-mount-command requires a mountpoint");
+		println!("{}", fs.mount_command(mountpoint, &options, opt.checking(), &opt.fstype)?);
+		return Ok(());
+	}
+	if opt.fstype != "bcachefs" {
+		tracing::warn!(msg="mounting with a non-default filesystem type", fstype=%opt.fstype);
+	}
+	let _lock = lock::lock(&uuid, std::time::Duration::from_secs(opt.lock_timeout), &paths)?;
+	if let Some(given) = &opt.mountpoint {
+		use mounts::AlreadyMounted;
+
+		// mountinfo has them canonical, the command line may not
+		let target = mountpoint::canonical(given);
+		// rerunning a mount, as configuration management does, is fine
+		match mounts::already_mounted(&fs.mountpoints(), &target) {
+			AlreadyMounted::AtTarget if opt.fail_if_mounted => {
+				return Err(err!(AlreadyMountedAt, uuid, target.display()));
+			}
+			AlreadyMounted::AtTarget => {
+				tracing::warn!(msg="filesystem is already mounted at the mountpoint, nothing to do", target=%target.display());
+				return Ok(());
+			}
+			AlreadyMounted::Elsewhere(mounted) => {
+				let mountpoints: Vec<_> = mounted.iter().map(|p| p.display().to_string()).collect();
+				tracing::warn!(msg="filesystem is already mounted", mountpoints=%mountpoints.join(" "));
+			}
+			AlreadyMounted::No if fs.sb().sb().possibly_in_use() => {
+				tracing::warn!(msg="superblock says the filesystem is in use, possibly by another host; this is also the case after a crash");
+				if mounts::in_other_mount_namespace() {
+					tracing::warn!(msg="running in a mount namespace of its own, mounts made outside of it aren't visible here");
+				}
+			}
+			AlreadyMounted::No => {}
+		}
+	}
+	let mut _pidfile = None;
+	let audit = crate::audit::sink(opt.audit);
+	let mut derived = None;
+	timings.time("key", || -> anyhow::Result<()> {
+		if !fs.encrypted() {
+			return Ok(());
+		}
+		let _owner = match opt.keyring_owner_uid {
+			Some(uid) => Some(key::KeyringOwner::switch(uid)?),
+			None => {
+				key::check_user_namespace();
+				None
+			}
+		};
+		let unlocked = match derivation {
+			Some(derivation) => {
+				let (unlocked, took) = derivation.finish(&fs, &*audit)?;
+				derived = took;
+				unlocked
+			}
+			None => {
+				let passphrases = opt.passphrases()?;
+				!passphrases.is_empty() && key::try_passphrases(&fs, &passphrases, &*audit)?
+			}
+		};
+		if !unlocked {
+			let key = match opt.key_location()? {
+				Some(key) => key,
+				// the footgun of an encrypted filesystem in fstab without a
+				// key location: fine as long as the key is in the keyring
+				None if !opt.fork_wait => {
+					let prompt = key::TtyPrompt { force: opt.force_tty_prompt, insecure_echo: opt.insecure_echo_prompt };
+					return key::prepare_key(&fs, KeyLocation::Wait, 1, false, &prompt, &*audit).map_err(|e| {
+						match e.downcast_ref::<messages::MsgError>().map(|e| e.msg) {
+							Some(messages::Msg::KeyWaitTimedOut) => {
+								err!(NoKeyLocation, uuid, key::fstab_example(&uuid, opt.mountpoint.as_deref()))
+							}
+							_ => e,
+						}
+					});
+				}
+				None => return Err(err!(ForkWaitNeedsWait)),
+			};
+
+			if opt.fork_wait {
+				if !matches!(key, KeyLocation::Wait) {
+					return Err(err!(ForkWaitNeedsWait));
+				}
+				if opt.mountpoint.is_none() {
+					return Err(err!(ForkWaitNeedsMountpoint));
+				}
+				if !key::key_loaded(&fs)? {
+					_pidfile = daemon::daemonize(&uuid, &paths)?;
+				}
+			}
+			let prompt = key::TtyPrompt { force: opt.force_tty_prompt, insecure_echo: opt.insecure_echo_prompt };
+			let (attempts, allow_empty) = (opt.max_unlock_attempts, opt.allow_empty_passphrase);
+			key::prepare_key(&fs, key, attempts, allow_empty, &prompt, &*audit)?;
+		}
+		Ok(())
+	})?;
+	// how long it took alongside probing, of which "key" is what was left
+	if let Some(derived) = derived {
+		timings.phases.push(("key derivation during probe", derived));
+	}
+
+	// without a mountpoint, only unlock the filesystem
+	let mountpoint = match opt.mountpoint {
+		Some(mountpoint) => mountpoint,
+		None if fs.encrypted() => {
+			tracing::info!(msg="key loaded, not mounting since no mountpoint was given");
+			return Ok(());
+		}
+		None => {
+			return Err(err!(NothingToDo))
+		}
+	};
+	// like mount(8), mount on where symlinks lead, so that mountinfo and the
+	// summary show the same path
+	let given = mountpoint;
+	let mountpoint = mountpoint::canonical(&given);
+	if mountpoint != given {
+		tracing::info!(msg="mountpoint resolved", given=%given.display(), resolved=%mountpoint.display());
+	}
+
+	if opt.verbose > 0 {
+		println!("{:#?}", fs.sb().sb());
+		for m in fs.sb().sb().members() {
+			println!("{:?}", m);
+		}
+		for problem in fs.sb().sb().geometry_problems() {
+			tracing::warn!(msg="inconsistent geometry", problem=%problem);
+		}
+		println!("{}", msg!(JournalSize, fs.sb().sb().journal_size_bytes() >> 20));
+	}
+
+	if let Some(mode) = &opt.device_health_check {
+		let strict = mode == &Some(HealthCheck::Strict);
+		for m in fs.members() {
+			let h = health::check(m.path(), &paths);
+			println!("{}", h);
+			if h.problems.is_empty() {
+				continue;
+			}
+			if strict {
+				return Err(err!(HealthCheckFailed, m.path().display()));
+			}
+			tracing::warn!(msg="device health check found problems", device=%m.path().display());
+		}
+	}
+
+	if fs.needs_journal_replay() {
+		if options.split(',').any(|o| o == "norecovery") {
+			tracing::warn!("journal replay needed and norecovery was specified");
+		} else {
+			tracing::info!("journal replay will be performed (filesystem was not cleanly unmounted)");
+		}
+	}
+
+	if opt.mkdir {
+		let dir = mountpoint::dir_options(&options)?;
+		mountpoint::create(&mountpoint, &dir, opt.force_owner)?;
+	}
+
+	let checking = filesystem::Checking::new(opt.strict, opt.sloppy);
+	let (fstype, allow_upgrade) = (&opt.fstype, opt.allow_upgrade);
+	let options = timings.time("mount", || fs.mount(&mountpoint, &options, checking, fstype, allow_upgrade))?;
+	let mounted = match fs.mounted_options(&mountpoint) {
+		Ok(mounted) => {
+			for difference in filesystem::option_differences(&options, &mounted) {
+				tracing::warn!(msg="mount options differ from what was requested", %difference);
+			}
+			mounted
+		}
+		Err(e) => {
+			tracing::warn!(msg="could not read back mount options", error=%e);
+			options.clone()
+		}
+	};
+	tracing::info!(msg="mounted", uuid=%fs.uuid(), devices=fs.members().len(), target=%mountpoint.display(), requested=%options, %mounted);
+	let audit_record = |op| {
+		let mut record = crate::audit::Record::new(op, uuid);
+		record.target = Some(mountpoint.clone());
+		record.devices = fs.members().iter().map(|m| m.path().to_owned()).collect();
+		record
+	};
+	if fs.encrypted() {
+		audit.record(&audit_record(crate::audit::Op::EncryptedMount));
+	}
+	if !opt.quiet {
+		println!("{}", fs.mount_summary(&mountpoint, &mounted));
+	}
+
+	if let Some(mib) = opt.verify_after_mount {
+		use crate::readcheck;
+		use std::os::unix::ffi::OsStrExt;
+
+		let bytes = mib.unwrap_or(readcheck::DEFAULT_MIB).saturating_mul(1 << 20);
+		let report = timings.time("verify", || readcheck::check(&mountpoint, bytes, readcheck::TIME_LIMIT));
+		let timed_out = if report.timed_out { ", stopped at the time limit" } else { "" };
+		println!(
+			"{}",
+			msg!(
+				ReadBack,
+				mountpoint.display(),
+				format!("{:.1}", report.bytes as f64 / (1 << 20) as f64),
+				report.files,
+				format!("{:.3}", report.duration.as_secs_f64()),
+				format!("{:.1}", report.throughput()),
+				report.errors.len(),
+				timed_out
+			)
+		);
+		if opt.verify_strict && !report.errors.is_empty() {
+			let target = std::ffi::CString::new(mountpoint.as_os_str().as_bytes())?;
+			if unsafe { libc::umount(target.as_ptr()) } != 0 {
+				let error = errno::errno();
+				tracing::error!(msg="could not unmount after failed read back", target=%mountpoint.display(), %error);
+			} else if fs.encrypted() {
+				audit.record(&audit_record(crate::audit::Op::EncryptedUnmount));
+			}
+			return Err(err!(ReadBackFailed, mountpoint.display(), report.errors.len()));
+		}
+	}
+
+	Ok(())
+}
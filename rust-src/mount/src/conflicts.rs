@@ -0,0 +1,87 @@
+//! Flags that don't make sense together, checked in one place before anything
+//! is done, so that a flag quietly losing to another doesn't go unnoticed.
+//! A new flag declares here how it interacts with the others.
+
+use crate::Options;
+
+/// Whether a flag, or a combination of them, is given
+pub type Given = fn(&Options) -> bool;
+
+/// Flags that each make `bcachefs-mount` do something other than mounting,
+/// and exit; only one of them can be given
+pub const COMMANDS: &[(&str, Given)] = &[
+	("--version", |o| o.version),
+	("--export-messages", |o| o.export_messages),
+	("--doctor", |o| o.doctor),
+	("--status", |o| o.status),
+	("--watch", |o| o.watch),
+	("--explain-options", |o| o.explain_options.is_some()),
+	("--query", |o| o.query.is_some()),
+	("--dump-super", |o| o.dump_super.is_some()),
+	("--verify", |o| o.verify.is_some()),
+	("--check-sb-copies", |o| !o.check_sb_copies.is_empty()),
+	("--wipe-stale-sb", |o| o.wipe_stale_sb.is_some()),
+	("--cancel-wait", |o| o.cancel_wait.is_some()),
+];
+
+/// Two flags that conflict when both are given, and what to do about it
+pub struct Conflict {
+	pub flags: [&'static str; 2],
+	pub given: Given,
+	pub resolution: &'static str,
+}
+
+fn remount(opt: &Options) -> bool {
+	opt.mount_options().split(',').any(|o| o == "remount")
+}
+
+pub const CONFLICTS: &[Conflict] = &[
+	Conflict {
+		flags: ["--use-cache", "--only-device"],
+		given: |o| o.use_cache && !o.only_device.is_empty(),
+		resolution: "--only-device probes just the devices given, the cache isn't used; drop one of them",
+	},
+	Conflict {
+		flags: ["-o remount", "--only-device"],
+		given: |o| remount(o) && !o.only_device.is_empty(),
+		resolution: "a remount changes what is mounted without probing devices; drop --only-device",
+	},
+	Conflict {
+		flags: ["-o remount", "--exclude-device"],
+		given: |o| remount(o) && !o.exclude_device.is_empty(),
+		resolution: "a remount changes what is mounted without probing devices; drop --exclude-device",
+	},
+	Conflict {
+		flags: ["-o remount", "--fork-wait"],
+		given: |o| remount(o) && o.fork_wait,
+		resolution: "a remount loads no key; drop --fork-wait",
+	},
+	Conflict {
+		flags: ["-o remount", "--fail-if-mounted"],
+		given: |o| remount(o) && o.fail_if_mounted,
+		resolution: "a remount is of what is already mounted; drop --fail-if-mounted",
+	},
+	Conflict {
+		flags: ["--print-mount-command", "--fork-wait"],
+		given: |o| o.print_mount_command && o.fork_wait,
+		resolution: "the command is printed before any key is loaded; drop --fork-wait",
+	},
+	Conflict {
+		flags: ["--print-mount-command", "--verify-after-mount"],
+		given: |o| o.print_mount_command && o.verify_after_mount.is_some(),
+		resolution: "nothing is mounted to read back from; drop --verify-after-mount",
+	},
+];
+
+/// Check `opt` for more than one of the [`COMMANDS`] and for any of the
+/// [`CONFLICTS`]; the error names both flags
+pub fn check(opt: &Options) -> anyhow::Result<()> {
+	let commands: Vec<_> = COMMANDS.iter().filter(|(_, given)| given(opt)).map(|(flag, _)| *flag).collect();
+	if let [first, second, ..] = commands.as_slice() {
+		return Err(err!(ConflictingFlags, first, second, "they are separate commands; give one at a time"));
+	}
+	match CONFLICTS.iter().find(|c| (c.given)(opt)) {
+		Some(c) => Err(err!(ConflictingFlags, c.flags[0], c.flags[1], c.resolution)),
+		None => Ok(()),
+	}
+}
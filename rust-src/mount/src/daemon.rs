@@ -0,0 +1,140 @@
+//! Backgrounding of `--fork-wait` mounts, so that mount units don't time out
+//! while the key is being waited for.
+
+use crate::paths::Paths;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+fn pidfile_path(dir: &Path, uuid: &Uuid) -> PathBuf {
+	dir.join(format!("{}.pid", uuid))
+}
+
+/// Pid file of a background waiter, locked for as long as it waits so that
+/// `cancel_wait` can tell it from one left behind, and removed again when
+/// dropped
+pub struct PidFile {
+	path: PathBuf,
+	_file: std::fs::File,
+}
+impl Drop for PidFile {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_file(&self.path);
+	}
+}
+
+/// Record the pid of this process in the pid file for `uuid` in `dir`, and
+/// lock it until the returned `PidFile` is dropped
+pub fn write_pidfile(dir: &Path, uuid: &Uuid) -> anyhow::Result<PidFile> {
+	use std::io::Write;
+	use std::os::unix::io::AsRawFd;
+
+	let path = pidfile_path(dir, uuid);
+	// only truncated once locked, not to wipe the pid of another waiter
+	let mut file = std::fs::OpenOptions::new().create(true).write(true).truncate(false).open(&path)?;
+	if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+		return Err(std::io::Error::last_os_error().into());
+	}
+	file.set_len(0)?;
+	writeln!(file, "{}", std::process::id())?;
+	Ok(PidFile { path, _file: file })
+}
+
+fn fork() -> anyhow::Result<bool> {
+	match unsafe { libc::fork() } {
+		-1 => Err(err!(ForkFailed, errno::errno())),
+		0 => Ok(true),
+		_ => Ok(false),
+	}
+}
+
+/// Without a terminal, log to the kernel log (which ends up in the journal),
+/// or nowhere if that can't be opened.
+fn redirect_stdio() -> anyhow::Result<()> {
+	use std::os::unix::io::IntoRawFd;
+
+	let null = std::fs::File::open("/dev/null")?.into_raw_fd();
+	let log = std::fs::OpenOptions::new()
+		.write(true)
+		.open("/dev/kmsg")
+		.map(|f| f.into_raw_fd())
+		.unwrap_or(null);
+	unsafe {
+		libc::dup2(null, 0);
+		libc::dup2(log, 1);
+		libc::dup2(log, 2);
+	}
+	Ok(())
+}
+
+/// Detach from the terminal and the calling process: the foreground process
+/// exits successfully right away, while the returned-to process carries on
+/// in the background with its pid recorded for `cancel_wait`, if there is a
+/// usable runtime directory.
+#[tracing_attributes::instrument]
+pub fn daemonize(uuid: &Uuid, paths: &Paths) -> anyhow::Result<Option<PidFile>> {
+	tracing::info!(msg = "continuing to wait for key in the background");
+	if !fork()? {
+		std::process::exit(0);
+	}
+	if unsafe { libc::setsid() } < 0 {
+		return Err(err!(SetsidFailed, errno::errno()));
+	}
+	if !fork()? {
+		unsafe { libc::_exit(0) };
+	}
+
+	std::env::set_current_dir("/")?;
+	redirect_stdio()?;
+
+	let dir = match paths.state_dir() {
+		Some(dir) => dir,
+		None => return Ok(None),
+	};
+	write_pidfile(dir, uuid).map(Some)
+}
+
+/// Stop the background process waiting to mount `uuid`
+#[tracing_attributes::instrument]
+pub fn cancel_wait(uuid: &Uuid, paths: &Paths) -> anyhow::Result<()> {
+	cancel_wait_in(&paths.runtime_dir, uuid)
+}
+
+/// Like [`cancel_wait`], with the pid file in `dir`. Only a pid file its
+/// waiter still holds the lock on is trusted: the pid in one left behind
+/// by a waiter that was killed may belong to any process by now.
+pub fn cancel_wait_in(dir: &Path, uuid: &Uuid) -> anyhow::Result<()> {
+	use std::io::Read;
+	use std::os::unix::io::AsRawFd;
+
+	let path = pidfile_path(dir, uuid);
+	let mut file = match std::fs::File::open(&path) {
+		Ok(file) => file,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+			return Err(err!(NoBackgroundWait, uuid));
+		}
+		Err(e) => return Err(e.into()),
+	};
+	if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } == 0 {
+		tracing::info!(msg = "removing stale pid file", path = %path.display());
+		let _ = std::fs::remove_file(&path);
+		return Err(err!(NoBackgroundWait, uuid));
+	}
+	let err = std::io::Error::last_os_error();
+	if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+		return Err(err.into());
+	}
+	let mut pid = String::new();
+	file.read_to_string(&mut pid)?;
+	let pid: libc::pid_t = pid.trim().parse().map_err(|_| err!(InvalidPidFile, path.display()))?;
+
+	let ret = unsafe { libc::kill(pid, libc::SIGTERM) };
+	let _ = std::fs::remove_file(&path);
+	if ret < 0 {
+		if errno::errno().0 == libc::ESRCH {
+			return Err(err!(NoBackgroundWait, uuid));
+		}
+		return Err(crate::ErrnoError(errno::errno()).into());
+	}
+	tracing::info!(msg = "cancelled background wait", pid);
+	Ok(())
+}
@@ -0,0 +1,268 @@
+//! `--doctor`: what maintainers need to see in a bug report, gathered without
+//! needing the mount to succeed. Key material is never collected in the first
+//! place; with `--anonymize`, the hostname and drive serials are scrubbed too.
+
+use crate::filesystem::{self, FileSystem};
+use crate::paths::Paths;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Where the error of the last failed mount is kept for the report
+const LAST_ERROR: &str = "last-error";
+
+/// One part of the report, a file of its own in the bundle
+#[derive(Debug)]
+pub struct Section {
+	pub name: &'static str,
+	pub text: String,
+}
+
+#[derive(Debug)]
+pub struct Report(pub Vec<Section>);
+
+impl std::fmt::Display for Report {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		for s in &self.0 {
+			writeln!(f, "== {} ==", s.name)?;
+			writeln!(f, "{}", s.text.trim_end())?;
+		}
+		Ok(())
+	}
+}
+
+/// Replaces identifying strings, e.g. the hostname, with placeholders
+#[derive(Debug, Default)]
+pub struct Redactor(Vec<(String, &'static str)>);
+
+impl Redactor {
+	/// Replace `value` wherever it appears. Values shorter than 3 characters
+	/// are ignored, since they can't be told apart from everything else.
+	pub fn scrub(&mut self, value: &str, placeholder: &'static str) {
+		let value = value.trim();
+		if value.len() >= 3 && !self.0.iter().any(|(v, _)| v == value) {
+			self.0.push((value.to_owned(), placeholder));
+		}
+	}
+
+	pub fn apply(&self, text: &str) -> String {
+		// longest first, so a serial isn't half replaced by a shorter one it contains
+		let mut values: Vec<_> = self.0.iter().collect();
+		values.sort_by_key(|(v, _)| std::cmp::Reverse(v.len()));
+		values.iter().fold(text.to_owned(), |text, (v, placeholder)| text.replace(v.as_str(), placeholder))
+	}
+}
+
+/// The superblock fields worth knowing for a bug report. This deliberately
+/// leaves out the encrypted key and anything else from the crypt field.
+pub fn superblock_summary(sb: &bch_bindgen::bcachefs::bch_sb) -> String {
+	use bch_bindgen::rs::metadata_version_name;
+	use std::fmt::Write;
+
+	let version = |v: u16| match metadata_version_name(v) {
+		Some(name) => format!("{} ({})", v, name),
+		None => v.to_string(),
+	};
+	let mut out = String::new();
+	let _ = writeln!(out, "uuid: {}", sb.uuid());
+	let _ = writeln!(out, "label: {}", sb.label().unwrap_or_default());
+	let _ = writeln!(out, "version: {}", version(sb.version));
+	let _ = writeln!(out, "version_min: {}", version(sb.version_min));
+	let _ = writeln!(out, "block_size: {}", { sb.block_size });
+	let _ = writeln!(out, "btree_node_size: {}", sb.btree_node_size());
+	let _ = writeln!(out, "nr_devices: {}", { sb.nr_devices });
+	let _ = writeln!(out, "clean: {}", sb.is_clean());
+	let _ = match sb.crypt() {
+		Some(crypt) => writeln!(out, "encrypted: true ({})", crypt.info()),
+		None => writeln!(out, "encrypted: false"),
+	};
+	for m in sb.members() {
+		let _ = writeln!(
+			out,
+			"member {}: uuid {} nbuckets {} bucket_size {} state {}",
+			m.dev_idx, m.uuid, m.nbuckets, m.bucket_size, m.state
+		);
+	}
+	out
+}
+
+fn kernel(paths: &Paths) -> String {
+	let read = |p: &str| std::fs::read_to_string(p).unwrap_or_else(|e| format!("unreadable: {}", e));
+	let registered = read("/proc/filesystems").lines().any(|l| l.split_whitespace().last() == Some("bcachefs"));
+	format!(
+		"release: {}\nbcachefs module loaded: {}\nbcachefs registered: {}\n",
+		read("/proc/sys/kernel/osrelease").trim(),
+		paths.sys("module/bcachefs").exists(),
+		registered
+	)
+}
+
+/// Probe every block device, noting devices that couldn't be probed instead
+/// of giving up on them. Returns the report text and the filesystems found.
+fn filesystems(redactor: &mut Redactor, anonymize: bool, paths: &Paths) -> (String, BTreeMap<Uuid, FileSystem>) {
+	use std::cell::RefCell;
+
+	let found = RefCell::new(BTreeMap::<Uuid, FileSystem>::new());
+	let errors = RefCell::new(Vec::new());
+	let unusable = RefCell::new(Vec::new());
+	let ret = filesystem::probe_filesystems_with_callbacks(
+		paths,
+		|uuid, fs| {
+			let mut found = found.borrow_mut();
+			match found.get_mut(&uuid) {
+				Some(existing) => {
+					existing.merge(fs);
+				}
+				None => {
+					found.insert(uuid, fs);
+				}
+			}
+		},
+		|path, e| match e.downcast_ref().and_then(filesystem::UnusableSuperblock::of) {
+			Some(reason) => unusable.borrow_mut().push(format!("{}: {}", path.display(), reason.0)),
+			None => errors.borrow_mut().push(format!("{}: {:#}", path.display(), e)),
+		},
+	);
+	let found = found.into_inner();
+
+	let mut out = String::new();
+	if let Err(e) = ret {
+		out += &format!("probing failed: {:#}\n", e);
+	}
+	for (uuid, fs) in &found {
+		out += &format!("filesystem {}\ndevices: {}\n", uuid, fs.device_string());
+		out += &superblock_summary(fs.sb().sb());
+		out += "\n";
+		if anonymize {
+			for m in fs.members() {
+				for property in &["ID_SERIAL", "ID_SERIAL_SHORT", "ID_WWN"] {
+					if let Some(serial) = crate::mounts::udev_device(m.path(), paths)
+						.and_then(|d| Some(d.property_value(property)?.to_string_lossy().into_owned()))
+					{
+						redactor.scrub(&serial, "<serial>");
+					}
+				}
+			}
+		}
+	}
+	for e in unusable.into_inner() {
+		out += &format!("unusable: {}\n", e);
+	}
+	for e in errors.into_inner() {
+		out += &format!("not probed: {}\n", e);
+	}
+	(out, found)
+}
+
+fn keyring(filesystems: &BTreeMap<Uuid, FileSystem>) -> String {
+	filesystems
+		.iter()
+		.filter(|(_, fs)| fs.encrypted())
+		.map(|(uuid, fs)| match crate::key::key_loaded(fs) {
+			Ok(loaded) => format!("{}: key loaded: {}\n", uuid, loaded),
+			Err(e) => format!("{}: {:#}\n", uuid, e),
+		})
+		.collect()
+}
+
+fn mounts() -> String {
+	match crate::mounts::read() {
+		Ok(mountinfo) => crate::mounts::parse(&mountinfo)
+			.into_iter()
+			.filter(|m| m.fstype == "bcachefs")
+			.map(|m| {
+				format!(
+					"{} on {} ({},{})\n",
+					m.source.to_string_lossy(),
+					m.target.display(),
+					m.vfs_options,
+					m.fs_options
+				)
+			})
+			.collect(),
+		Err(e) => format!("unreadable: {}\n", e),
+	}
+}
+
+/// Keep `error` for the next report; failing to do so is not worth another
+/// error
+pub fn record_error(error: &anyhow::Error, paths: &Paths) {
+	if let Some(dir) = paths.state_dir() {
+		let _ = std::fs::write(dir.join(LAST_ERROR), format!("{:#}\n", error));
+	}
+}
+
+fn last_error(paths: &Paths) -> String {
+	std::fs::read_to_string(paths.runtime_dir.join(LAST_ERROR)).unwrap_or_else(|_| "none recorded\n".to_owned())
+}
+
+/// Gather the report, scrubbing the hostname and drive serials if `anonymize`
+#[tracing_attributes::instrument]
+pub fn gather(anonymize: bool, paths: &Paths) -> Report {
+	let mut redactor = Redactor::default();
+	if anonymize {
+		if let Ok(hostname) = std::fs::read_to_string("/proc/sys/kernel/hostname") {
+			redactor.scrub(&hostname, "<hostname>");
+		}
+	}
+
+	let (filesystems, found) = filesystems(&mut redactor, anonymize, paths);
+	let sections = vec![
+		Section { name: "version", text: crate::version_info() },
+		Section { name: "kernel", text: kernel(paths) },
+		Section { name: "filesystems", text: filesystems },
+		Section { name: "keyring", text: keyring(&found) },
+		Section { name: "mounts", text: mounts() },
+		Section { name: "last-error", text: last_error(paths) },
+	];
+	Report(
+		sections
+			.into_iter()
+			.map(|s| Section { text: redactor.apply(&s.text), ..s })
+			.collect(),
+	)
+}
+
+/// A new directory in the temporary directory, named `<prefix>.XXXXXX`, that
+/// only we can get at. mkdtemp(3) never hands out one that already existed,
+/// so nobody can have put a directory or a link there first.
+pub fn private_temp_dir(prefix: &str) -> std::io::Result<PathBuf> {
+	use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+	let template = std::env::temp_dir().join(format!("{}.XXXXXX", prefix));
+	let mut template = std::ffi::CString::new(template.as_os_str().as_bytes())?.into_bytes_with_nul();
+	if unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) }.is_null() {
+		return Err(std::io::Error::last_os_error());
+	}
+	template.pop();
+	Ok(PathBuf::from(std::ffi::OsString::from_vec(template)))
+}
+
+impl Report {
+	/// Write the report as a gzipped tarball with one file per section
+	pub fn write_bundle(&self, path: &Path) -> anyhow::Result<()> {
+		// tar runs in `dir`
+		let path = std::env::current_dir()?.join(path);
+		let dir = private_temp_dir("bcachefs-doctor")?;
+		let contents = dir.join("bcachefs-doctor");
+		let ret = (|| {
+			std::fs::create_dir(&contents)?;
+			for s in &self.0 {
+				std::fs::write(contents.join(format!("{}.txt", s.name)), &s.text)?;
+			}
+			let status = std::process::Command::new("tar")
+				.arg("-czf")
+				.arg(&path)
+				.arg("-C")
+				.arg(&dir)
+				.arg("bcachefs-doctor")
+				.status()?;
+			if !status.success() {
+				return Err(err!(BundleFailed, path.display(), status));
+			}
+			Ok(())
+		})();
+		let _ = std::fs::remove_dir_all(&dir);
+		ret
+	}
+}
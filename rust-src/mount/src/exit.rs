@@ -0,0 +1,169 @@
+//! How a failure is reported to whatever runs the tool: what kind of failure
+//! it was, the exit status for that kind, and the `--json-errors` object.
+
+use crate::messages::{Msg, MsgError};
+
+/// What kind of failure an error is, so callers can react to it without
+/// matching on the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+	Other,
+	InvalidArgument,
+	NotFound,
+	Permission,
+	WrongPassphrase,
+	KeyUnavailable,
+	Busy,
+	Unsupported,
+	Corrupt,
+	Device,
+	/// More than one filesystem matched a UUID prefix or label
+	Ambiguous,
+	/// Already mounted at the mountpoint, with --fail-if-mounted
+	AlreadyMounted,
+	/// Fewer member devices found than --min-devices asks for
+	Degraded,
+}
+
+impl ErrorKind {
+	pub const ALL: &'static [ErrorKind] = &[
+		ErrorKind::Other,
+		ErrorKind::InvalidArgument,
+		ErrorKind::NotFound,
+		ErrorKind::Permission,
+		ErrorKind::WrongPassphrase,
+		ErrorKind::KeyUnavailable,
+		ErrorKind::Busy,
+		ErrorKind::Unsupported,
+		ErrorKind::Corrupt,
+		ErrorKind::Device,
+		ErrorKind::Ambiguous,
+		ErrorKind::AlreadyMounted,
+		ErrorKind::Degraded,
+	];
+
+	/// Name of the kind in `--json-errors` output
+	pub fn name(self) -> &'static str {
+		match self {
+			ErrorKind::Other => "other",
+			ErrorKind::InvalidArgument => "invalid_argument",
+			ErrorKind::NotFound => "not_found",
+			ErrorKind::Permission => "permission",
+			ErrorKind::WrongPassphrase => "wrong_passphrase",
+			ErrorKind::KeyUnavailable => "key_unavailable",
+			ErrorKind::Busy => "busy",
+			ErrorKind::Unsupported => "unsupported",
+			ErrorKind::Corrupt => "corrupt",
+			ErrorKind::Device => "device",
+			ErrorKind::Ambiguous => "ambiguous",
+			ErrorKind::AlreadyMounted => "already_mounted",
+			ErrorKind::Degraded => "degraded",
+		}
+	}
+
+	/// Exit status for failures of this kind; anything unclassified exits 1.
+	/// Each kind has its own, none of them 32 or 64, which mount(8) exits with
+	/// for `-a`.
+	pub fn exit_code(self) -> i32 {
+		match self {
+			ErrorKind::Other => 1,
+			ErrorKind::InvalidArgument => 2,
+			ErrorKind::NotFound => 3,
+			ErrorKind::Permission => 4,
+			ErrorKind::WrongPassphrase => 5,
+			ErrorKind::KeyUnavailable => 6,
+			ErrorKind::Busy => 7,
+			ErrorKind::Unsupported => 8,
+			ErrorKind::Corrupt => 9,
+			ErrorKind::Device => 10,
+			ErrorKind::Ambiguous => 11,
+			ErrorKind::AlreadyMounted => 12,
+			ErrorKind::Degraded => 13,
+		}
+	}
+
+	fn of_msg(msg: Msg) -> Self {
+		use Msg::*;
+		match msg {
+			FsNotFound | FsNotFoundScanned | FsOutsideFilter | NotAMember | NotAMountpoint
+			| NoBackgroundWait | DanglingMountpoint | FsNotMounted | MountUuidUnknown => ErrorKind::NotFound,
+			AmbiguousPrefix | AmbiguousLabel | AmbiguousUuid => ErrorKind::Ambiguous,
+			InvalidKeyLocation | InvalidHealthCheckMode | InvalidRetry | UnknownCommand | NilUuid | MagicUuid | ForkWaitNeedsWait
+			| ForkWaitNeedsMountpoint | NothingToDo | ExcludedAllDevices | DevicePathHasColon | NotBcachefsMount
+			| RemountOtherFs | RemountNeedsMountpoint | UpgradeNotAllowed | RemountNeedsUuid | OffsetUnaligned | OffsetNeedsImage
+			| WipeNotPartitioned | WipeNoSuperblock | WipeNotConfirmed | ConflictingFlags
+			| UnknownOption | OptionNotMountable | OptionNeedsValue | OptionBadChoice | OptionOutOfRange
+			| OptionBadValue | UnknownUser | UnknownGroup | InvalidMode | NoKeyLocation | InvalidFsSpec | NullArgument
+			| NotUtf8 => {
+				ErrorKind::InvalidArgument
+			}
+			WrongPassphrase | PromptsExhausted | EmptyPassphrase => ErrorKind::WrongPassphrase,
+			NoKeyAvailable | KeyWaitTimedOut | NoTerminal => ErrorKind::KeyUnavailable,
+			KeyringOwnerFailed | PolicyRejected | PolicyDenied => ErrorKind::Permission,
+			MountInProgress => ErrorKind::Busy,
+			AlreadyMountedAt => ErrorKind::AlreadyMounted,
+			TooFewDevices => ErrorKind::Degraded,
+			VersionTooNew | SubvolidUnsupported | KeyringUnavailable | RecoveryPassUnsupported => ErrorKind::Unsupported,
+			SuperblockChecksumMismatch | SbCopiesDamaged => ErrorKind::Corrupt,
+			MemberReadOnly | HealthCheckFailed | ReadBackFailed | MemberFailed => ErrorKind::Device,
+			_ => ErrorKind::Other,
+		}
+	}
+
+	fn of_errno(errno: i32) -> Self {
+		match errno {
+			libc::ENOENT | libc::ENODEV | libc::ENXIO => ErrorKind::NotFound,
+			libc::EACCES | libc::EPERM => ErrorKind::Permission,
+			libc::EINVAL => ErrorKind::InvalidArgument,
+			libc::ENOKEY => ErrorKind::KeyUnavailable,
+			libc::EBUSY => ErrorKind::Busy,
+			libc::ENOSYS | libc::EOPNOTSUPP => ErrorKind::Unsupported,
+			libc::EROFS | libc::EIO => ErrorKind::Device,
+			_ => ErrorKind::Other,
+		}
+	}
+
+	fn of_cause(cause: &(dyn std::error::Error + 'static)) -> Self {
+		use crate::{filesystem::ResolveError, key::KeyringError};
+
+		if let Some(e) = cause.downcast_ref::<MsgError>() {
+			Self::of_msg(e.msg)
+		} else if let Some(e) = cause.downcast_ref::<ResolveError>() {
+			Self::of_msg(e.msg())
+		} else if let Some(e) = cause.downcast_ref::<crate::ErrnoError>() {
+			Self::of_errno(e.0 .0)
+		} else if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+			e.raw_os_error().map_or(ErrorKind::Other, Self::of_errno)
+		} else if let Some(KeyringError::Unavailable(_)) = cause.downcast_ref::<KeyringError>() {
+			ErrorKind::Unsupported
+		} else if let Some(KeyringError::Other(e)) = cause.downcast_ref::<KeyringError>() {
+			Self::of_errno(e.0)
+		} else {
+			ErrorKind::Other
+		}
+	}
+}
+
+/// The kind of `error`, from the outermost cause that has one
+pub fn kind(error: &anyhow::Error) -> ErrorKind {
+	error.chain().map(ErrorKind::of_cause).find(|k| *k != ErrorKind::Other).unwrap_or(ErrorKind::Other)
+}
+
+/// `error` as a JSON object for `--json-errors`: the message, its kind, the
+/// catalog identifier if it has one and the UUID of the filesystem concerned
+pub fn json(error: &anyhow::Error, uuid: Option<&uuid::Uuid>) -> String {
+	use crate::json::{nullable, object, string};
+
+	use crate::filesystem::ResolveError;
+
+	let id = error.chain().find_map(|c| match c.downcast_ref::<MsgError>() {
+		Some(e) => Some(e.msg.id()),
+		None => c.downcast_ref::<ResolveError>().map(|e| e.msg().id()),
+	});
+	object(&[
+		("error", string(&format!("{:#}", error))),
+		("kind", string(kind(error).name())),
+		("id", nullable(id, string)),
+		("uuid", nullable(uuid, |u| string(&u.to_string()))),
+	])
+}
@@ -2,8 +2,45 @@ extern "C" {
 	pub static stdout: *mut libc::FILE;
 }
 
+use crate::paths::Paths;
+use crate::FsSpec;
 use getset::{CopyGetters, Getters};
+use std::ffi::OsStr;
 use std::path::PathBuf;
+
+/// A member device of a bcachefs filesystem
+#[derive(Debug, Getters, CopyGetters)]
+pub struct Member {
+	/// Device node
+	#[getset(get = "pub")]
+	path: PathBuf,
+	/// Whether the block device is read-only (BLKROGET)
+	#[getset(get_copy = "pub")]
+	read_only: bool,
+	/// Whether the device sits on removable media
+	#[getset(get_copy = "pub")]
+	removable: bool,
+	/// Index of the device in the filesystem, from its own superblock
+	#[getset(get_copy = "pub")]
+	dev_idx: u8,
+	/// Sequence number of its own superblock, which goes up with every write
+	#[getset(get_copy = "pub")]
+	seq: u64,
+}
+
+impl Member {
+	/// A member whose index and sequence number are taken from the superblock
+	/// it is added to a `FileSystem` with
+	pub fn new(path: PathBuf, read_only: bool, removable: bool) -> Self {
+		Self { path, read_only, removable, dev_idx: 0, seq: 0 }
+	}
+}
+
+/// How many metadata versions behind the one libbcachefs writes a filesystem
+/// may be before a read-write mount, which would upgrade it, needs
+/// `--allow-upgrade`
+pub const UPGRADE_THRESHOLD: u16 = 2;
+
 #[derive(Getters, CopyGetters)]
 pub struct FileSystem {
 	/// External UUID of the bcachefs
@@ -12,69 +49,611 @@ pub struct FileSystem {
 	/// Whether filesystem is encrypted
 	#[getset(get_copy = "pub")]
 	encrypted: bool,
-	/// Super block
+	/// Super block, as read from the first member; freed, and the device
+	/// closed, along with the filesystem
 	#[getset(get = "pub")]
-	sb: bcachefs::bch_sb_handle,
+	sb: bcachefs::SbHandle,
 	/// Member devices for this filesystem
 	#[getset(get = "pub")]
-	devices: Vec<PathBuf>,
+	members: Vec<Member>,
+	/// Whether the key is in the keyring, once that has been checked
+	key_loaded: std::cell::Cell<Option<bool>>,
+	/// Where the filesystem is mounted on this host, once that has been
+	/// looked up
+	mounted: std::cell::RefCell<Option<Vec<PathBuf>>>,
+	/// Where device nodes and sysfs were when it was probed, to look that
+	/// up with
+	#[getset(get = "pub")]
+	paths: Paths,
 }
 impl std::fmt::Debug for FileSystem {
 	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
 		f.debug_struct("FileSystem")
 			.field("uuid", &self.uuid)
+			.field("label", &self.sb.sb().label())
 			.field("encrypted", &self.encrypted)
-			.field("devices", &self.device_string())
+			.field("key_loaded", &self.key_loaded.get())
+			.field("members", &self.members)
+			.field("nr_devices", &self.sb.sb().nr_devices)
+			.field("mounted", &self.mounted.borrow())
 			.finish()
 	}
 }
 use std::fmt;
+/// `<uuid> label="tank" devices=/dev/sda:/dev/sdb encrypted=yes key=loaded
+/// mounted=/srv/tank`, where the label is left out if there is none, and key
+/// (loaded or missing) and mounted (mountpoints separated by ',', or no) only
+/// appear once they have been looked up
 impl std::fmt::Display for FileSystem {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		let devs = self.device_string();
+		write!(f, "{}", self.uuid)?;
+		if let Some(label) = self.sb.sb().label() {
+			write!(f, " label={:?}", label)?;
+		}
+		write!(f, " devices={}", self.device_string())?;
+		write!(f, " encrypted={}", if self.encrypted { "yes" } else { "no" })?;
+		if let Some(loaded) = self.key_loaded.get() {
+			write!(f, " key={}", if loaded { "loaded" } else { "missing" })?;
+		}
+		match &*self.mounted.borrow() {
+			Some(m) if m.is_empty() => write!(f, " mounted=no"),
+			Some(m) => {
+				let m: Vec<_> = m.iter().map(|p| p.display().to_string()).collect();
+				write!(f, " mounted={}", m.join(","))
+			}
+			None => Ok(()),
+		}
+	}
+}
+
+/// How many of its member devices a filesystem is mounted with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceCount {
+	/// All the superblock lists
+	Complete,
+	/// Not all, but at least `--min-devices`
+	Sufficient,
+	/// Not all, and no `--min-devices` was given
+	Degraded,
+	/// Fewer than `--min-devices`
+	Insufficient,
+}
+
+impl DeviceCount {
+	pub fn of(found: usize, total: usize, min_devices: Option<usize>) -> Self {
+		match min_devices {
+			_ if found >= total => DeviceCount::Complete,
+			None => DeviceCount::Degraded,
+			Some(min) if found >= min => DeviceCount::Sufficient,
+			Some(_) => DeviceCount::Insufficient,
+		}
+	}
+}
+
+/// Whether the member devices present hold a copy of all the data, going by
+/// the replicas superblock field; that is, whether a degraded mount can read
+/// everything, or may run into I/O errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+	/// Every replicas entry has enough of its devices present
+	Replicated,
+	/// Some data has too few of its devices present
+	MayBeUnavailable,
+	/// The superblock has no replicas field to tell
+	Unknown,
+}
+
+impl Durability {
+	/// Cached data is left out, as losing it only costs cache misses; an
+	/// entry with `nr_required` 0 is taken to need one device.
+	pub fn of(replicas: Option<&[bcachefs::ReplicasEntry]>, present: &[u8]) -> Self {
+		let replicas = match replicas {
+			Some(replicas) => replicas,
+			None => return Durability::Unknown,
+		};
+		let readable = |r: &bcachefs::ReplicasEntry| {
+			let found = r.devs.iter().filter(|d| present.contains(d)).count();
+			found >= r.nr_required.max(1) as usize
+		};
+		if replicas.iter().filter(|r| !r.is_cached()).all(readable) {
+			Durability::Replicated
+		} else {
+			Durability::MayBeUnavailable
+		}
+	}
+}
+
+impl fmt::Display for Durability {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			Durability::Replicated => "data appears fully replicated on the remaining devices",
+			Durability::MayBeUnavailable => "data may be unavailable",
+			Durability::Unknown => "replication unknown, data may be unavailable",
+		})
+	}
+}
+
+/// One line of `--status` output for monitoring agents:
+///
+/// `uuid=<uuid> label=<label> state=ok|degraded devices=<found>/<total>
+/// encrypted=yes|no mounted=<mountpoint>[,<mountpoint>...]`
+///
+/// The keys and their order are fixed, so scripts can rely on them. Labels
+/// and mountpoints escape space, tab, newline, backslash and comma as octal,
+/// like mountinfo does; an empty value means none.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+	pub uuid: Uuid,
+	pub label: Option<String>,
+	pub devices_found: usize,
+	pub devices_total: usize,
+	pub encrypted: bool,
+	pub mounted: Vec<PathBuf>,
+}
+
+impl Status {
+	pub fn is_degraded(&self) -> bool {
+		self.devices_found < self.devices_total
+	}
+
+	/// The same as JSON, one object, for `--status --json`, `bcachefs-list
+	/// --json` and `--policy-exec`
+	pub fn to_json(&self) -> String {
+		use crate::json::{array, nullable, object, string};
+
+		object(&[
+			("uuid", string(&self.uuid.to_string())),
+			("label", nullable(self.label.as_deref(), string)),
+			("state", string(if self.is_degraded() { "degraded" } else { "ok" })),
+			("devices_found", self.devices_found.to_string()),
+			("devices_total", self.devices_total.to_string()),
+			("encrypted", self.encrypted.to_string()),
+			("mounted", array(self.mounted.iter().map(|p| string(&p.to_string_lossy())))),
+		])
+	}
+}
+
+impl fmt::Display for Status {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		fn escape(s: &str) -> String {
+			s.chars()
+				.map(|c| match c {
+					' ' | '\t' | '\n' | '\\' | ',' => format!("\\{:03o}", c as u32),
+					c => c.to_string(),
+				})
+				.collect()
+		}
+		let yes_no = |b| if b { "yes" } else { "no" };
+		let mounted: Vec<_> = self.mounted.iter().map(|p| escape(&p.to_string_lossy())).collect();
 		write!(
 			f,
-			"{:?}: locked?={lock} ({}) ",
+			"uuid={} label={} state={} devices={}/{} encrypted={} mounted={}",
 			self.uuid,
-			devs,
-			lock = self.encrypted
+			escape(self.label.as_deref().unwrap_or_default()),
+			if self.is_degraded() { "degraded" } else { "ok" },
+			self.devices_found,
+			self.devices_total,
+			yes_no(self.encrypted),
+			mounted.join(",")
 		)
 	}
 }
 
+/// The part a member device found plays in a tiered filesystem: the disk
+/// group it was given, e.g. with `bcachefs format --label`, and the target
+/// options covering it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placement {
+	pub device: PathBuf,
+	pub dev_idx: u8,
+	/// Path of the disk group, e.g. "ssd.fast"
+	pub label: Option<String>,
+	pub roles: Vec<bcachefs::Role>,
+}
+
+impl Placement {
+	pub fn to_json(&self) -> String {
+		use crate::json::{array, nullable, object, string};
+
+		object(&[
+			("device", string(&self.device.to_string_lossy())),
+			("dev_idx", self.dev_idx.to_string()),
+			("label", nullable(self.label.as_deref(), string)),
+			("roles", array(self.roles.iter().map(|r| string(r.name())))),
+		])
+	}
+}
+
+/// E.g. "/dev/sda (dev 0): ssd.fast, foreground promote"
+impl fmt::Display for Placement {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} (dev {}): {}", self.device.display(), self.dev_idx, self.label.as_deref().unwrap_or("no label"))?;
+		if !self.roles.is_empty() {
+			let roles: Vec<_> = self.roles.iter().map(|r| r.name()).collect();
+			write!(f, ", {}", roles.join(" "))?;
+		}
+		Ok(())
+	}
+}
+
 impl FileSystem {
-	pub(crate) fn new(sb: bcachefs::bch_sb_handle) -> Self {
+	/// A filesystem as found on its first member device; there is no way to
+	/// create one without any members. `uuid` and `encrypted` are read from
+	/// the superblock the handle points to, which stays put when the handle
+	/// is moved in here.
+	pub fn new(sb: bcachefs::SbHandle, first: Member) -> Self {
+		let first = Member { dev_idx: sb.sb().dev_idx, seq: sb.sb().seq, ..first };
 		Self {
 			uuid: sb.sb().uuid(),
 			encrypted: sb.sb().crypt().is_some(),
 			sb: sb,
-			devices: Vec::new(),
+			members: vec![first],
+			key_loaded: Default::default(),
+			mounted: Default::default(),
+			paths: Default::default(),
 		}
 	}
 
+	/// Remember whether the key is in the keyring, for `Display`
+	pub(crate) fn set_key_loaded(&self, loaded: bool) {
+		self.key_loaded.set(Some(loaded));
+	}
+
+	/// Take over the members of `other`, found elsewhere for the same
+	/// filesystem; its superblock handle is freed unless taken over with its
+	/// member. Members are kept in the order of their index, whichever order
+	/// they were found in.
+	///
+	/// Two devices with the same index, such as two paths to a multipath
+	/// disk or a disk and its copy, are one member: the one with the newer
+	/// superblock is kept, the one found first if neither is newer. Returns
+	/// the devices left out.
+	pub fn merge(&mut self, other: FileSystem) -> Vec<PathBuf> {
+		let FileSystem { sb: mut other_sb, members, .. } = other;
+		let mut left_out = Vec::new();
+		for m in members {
+			let i = match self.members.iter().position(|known| known.dev_idx == m.dev_idx) {
+				Some(i) => i,
+				None => {
+					let at = self.members.iter().position(|known| known.dev_idx > m.dev_idx);
+					self.members.insert(at.unwrap_or(self.members.len()), m);
+					continue;
+				}
+			};
+			let newer = m.seq > self.members[i].seq;
+			let (kept, dropped) = if newer { (&m, &self.members[i]) } else { (&self.members[i], &m) };
+			tracing::warn!(
+				msg="two devices hold the same member, using the one with the newer superblock",
+				uuid=%self.uuid,
+				dev_idx=m.dev_idx,
+				using=%kept.path.display(),
+				ignoring=%dropped.path.display()
+			);
+			left_out.push(dropped.path.clone());
+			if newer {
+				// the superblock read from the member replaced goes with it
+				if self.sb.sb().dev_idx == m.dev_idx && other_sb.sb().dev_idx == m.dev_idx {
+					std::mem::swap(&mut self.sb, &mut other_sb);
+				}
+				self.members[i] = m;
+			}
+		}
+		left_out
+	}
+
+	/// Forget the member at `path`, e.g. once it has been unplugged. Returns
+	/// whether it was a member.
+	pub(crate) fn remove_member(&mut self, path: &std::path::Path) -> bool {
+		let before = self.members.len();
+		self.members.retain(|m| m.path != path);
+		self.members.len() != before
+	}
+
 	pub fn device_string(&self) -> String {
 		use itertools::Itertools;
-		self.devices.iter().map(|d| d.display()).join(":")
+		self.members.iter().map(|m| m.path.display()).join(":")
+	}
+
+	/// The mount source: member device paths joined by ':'. Unlike
+	/// `device_string()` this keeps paths that aren't valid UTF-8 intact.
+	fn mount_source(&self) -> anyhow::Result<std::ffi::OsString> {
+		use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+		// the kernel's error for an empty source doesn't say what went wrong
+		if self.members.is_empty() {
+			return Err(err!(NoMembers, self.uuid));
+		}
+		let mut src = Vec::new();
+		for m in &self.members {
+			let path = m.path.as_os_str().as_bytes();
+			if path.contains(&b':') {
+				return Err(err!(DevicePathHasColon, m.path.display()));
+			}
+			if !src.is_empty() {
+				src.push(b':');
+			}
+			src.extend_from_slice(path);
+		}
+		Ok(std::ffi::OsString::from_vec(src))
+	}
+
+	/// Restrict the members used for mounting: if `only` is non-empty, keep just
+	/// those devices, then drop everything in `exclude`. Devices are compared
+	/// after resolving symlinks, so /dev/disk/by-* aliases work, with /dev
+	/// moved to `paths.dev_root`.
+	pub fn select_devices(&mut self, only: &[PathBuf], exclude: &[PathBuf], paths: &Paths) -> anyhow::Result<()> {
+		let canonical = |p: &std::path::Path| {
+			let p = paths.dev(p);
+			std::fs::canonicalize(&p).unwrap_or(p)
+		};
+		let only: Vec<_> = only.iter().map(|p| canonical(p)).collect();
+		let exclude: Vec<_> = exclude.iter().map(|p| canonical(p)).collect();
+
+		let members: Vec<_> = self.members.iter().map(|m| canonical(&m.path)).collect();
+		if let Some(p) = only.iter().chain(&exclude).find(|p| !members.contains(p)) {
+			return Err(err!(NotAMember, p.display(), self.uuid));
+		}
+
+		// decided before dropping any, so that the members are left alone on error
+		let keep: Vec<bool> =
+			members.iter().map(|p| (only.is_empty() || only.contains(p)) && !exclude.contains(p)).collect();
+		if !keep.contains(&true) {
+			return Err(err!(ExcludedAllDevices));
+		}
+		let mut keep = keep.into_iter();
+		self.members.retain(|_| keep.next().unwrap());
+		Ok(())
+	}
+
+	/// Whether the next mount will have to replay the journal
+	pub fn needs_journal_replay(&self) -> bool {
+		!self.sb.sb().is_clean()
+	}
+
+	/// Whether the filesystem looks like it is mounted already, here or on
+	/// another host sharing its devices
+	pub fn possibly_in_use(&self) -> bool {
+		self.sb.sb().possibly_in_use() || !self.mountpoints().is_empty()
+	}
+
+	/// Where the filesystem is mounted on this host
+	pub fn mountpoints(&self) -> Vec<PathBuf> {
+		let mountpoints = crate::mounts::mountpoints_for(self.uuid, &self.paths).unwrap_or_default();
+		*self.mounted.borrow_mut() = Some(mountpoints.clone());
+		mountpoints
+	}
+
+	/// Everything `--status` reports, including where it is mounted
+	pub fn status(&self) -> Status {
+		Status {
+			uuid: self.uuid,
+			label: self.sb.sb().label(),
+			devices_found: self.members.len(),
+			devices_total: self.sb.sb().nr_devices as usize,
+			encrypted: self.encrypted,
+			mounted: self.mountpoints(),
+		}
+	}
+
+	/// Filesystem label, `None` if it wasn't given one
+	pub fn label(&self) -> Option<String> {
+		self.sb.sb().label()
 	}
 
+	/// The UUID the kernel identifies the filesystem by internally
+	pub fn internal_uuid(&self) -> Uuid {
+		self.sb.sb().internal_uuid()
+	}
+
+	/// Whether fewer member devices were found than the superblock lists
+	pub fn is_degraded(&self) -> bool {
+		self.members.len() < self.sb.sb().nr_devices as usize
+	}
+
+	/// Indices of the member devices the superblock lists that weren't found
+	pub fn missing_devices(&self) -> Vec<u8> {
+		let present: Vec<u8> = self.members.iter().map(|m| m.dev_idx).collect();
+		let mut missing: Vec<u8> =
+			self.sb.sb().members().iter().map(|m| m.dev_idx).filter(|i| !present.contains(i)).collect();
+		missing.sort_unstable();
+		missing
+	}
+
+	/// Whether the member devices found hold all the data
+	pub fn durability(&self) -> Durability {
+		let present: Vec<u8> = self.members.iter().map(|m| m.dev_idx).collect();
+		Durability::of(self.sb.sb().replicas().as_deref(), &present)
+	}
+
+	/// Disk group and roles of each member device found, by index
+	pub fn placements(&self) -> Vec<Placement> {
+		let sb = self.sb.sb();
+		let (groups, infos) = (sb.groups(), sb.members());
+		let mut placements: Vec<Placement> = self
+			.members
+			.iter()
+			.map(|m| {
+				let info = infos.iter().find(|i| i.dev_idx == m.dev_idx);
+				Placement {
+					device: m.path.clone(),
+					dev_idx: m.dev_idx,
+					label: info.and_then(|i| groups.path(i.group?)),
+					roles: info.map(|i| sb.roles(i)).unwrap_or_default(),
+				}
+			})
+			.collect();
+		placements.sort_by_key(|p| p.dev_idx);
+		placements
+	}
+
+	/// How the member devices found measure up, against `--min-devices` if
+	/// given
+	pub fn device_count(&self, min_devices: Option<usize>) -> DeviceCount {
+		DeviceCount::of(self.members.len(), self.sb.sb().nr_devices as usize, min_devices)
+	}
+
+	/// The oldest metadata version in the filesystem (`version_min`) is the
+	/// hard floor of what the code handling it must understand.
+	fn check_version(&self) -> anyhow::Result<()> {
+		use bch_bindgen::bcachefs::bcachefs_metadata_version::bcachefs_metadata_version_max;
+
+		let version_min = self.sb.sb().version_min;
+		let current = bcachefs_metadata_version_max as u16 - 1;
+		if version_min > current {
+			return Err(err!(VersionTooNew, version_min, current));
+		}
+		Ok(())
+	}
+
+	/// The filesystem's on-disk version and the one libbcachefs writes, if
+	/// the former is more than [`UPGRADE_THRESHOLD`] versions behind
+	pub fn needs_upgrade(&self) -> Option<(u16, u16)> {
+		let version = self.sb.sb().version;
+		let current = *bch_bindgen::rs::metadata_versions().end();
+		if version.saturating_add(UPGRADE_THRESHOLD) < current {
+			Some((version, current))
+		} else {
+			None
+		}
+	}
+
+	/// A read-write mount upgrades an old filesystem's on-disk format, which
+	/// older tools then can't read anymore; that takes `allow_upgrade`.
+	/// Read-only mounts leave the filesystem as it is.
+	fn check_upgrade(&self, mountflags: u64, allow_upgrade: bool) -> anyhow::Result<()> {
+		let (version, current) = match self.needs_upgrade() {
+			Some(versions) if mountflags & libc::MS_RDONLY == 0 => versions,
+			_ => return Ok(()),
+		};
+		if !allow_upgrade {
+			return Err(err!(UpgradeNotAllowed, version, current));
+		}
+		tracing::warn!(msg="mounting read-write upgrades the on-disk format for good", version, current);
+		Ok(())
+	}
+
+	/// One line describing the mounted filesystem, for after a successful
+	/// mount with the options it was mounted with
+	pub fn mount_summary(&self, target: &std::path::Path, options: &str) -> String {
+		let sb = self.sb.sb();
+		let label = sb.label().map_or(String::new(), |l| format!(" (label \"{}\")", l));
+		msg!(Mounted, self.members.len(), self.uuid, label, target.display(), options)
+	}
+
+	/// Options the filesystem mounted at `target` actually got, generic ones
+	/// first, as the kernel reports them in mountinfo
+	pub fn mounted_options(&self, target: &std::path::Path) -> anyhow::Result<String> {
+		Ok(crate::mounts::mount_at(target)?.options())
+	}
+
+	/// Refuse read-write mounts when a member device is read-only, since the
+	/// kernel would only fail once it tries to write to it.
+	fn check_members(&self, mountflags: u64) -> anyhow::Result<()> {
+		for m in &self.members {
+			if m.removable {
+				tracing::warn!(msg="member device is on removable media", device=%m.path.display());
+			}
+			if m.read_only {
+				if mountflags & libc::MS_RDONLY == 0 {
+					return Err(err!(MemberReadOnly, m.path.display()));
+				}
+				tracing::warn!(msg="member device is read-only", device=%m.path.display());
+			}
+		}
+		Ok(())
+	}
+
+	/// Mount the filesystem, returning the options it was mounted with.
+	/// `allow_upgrade` lets a read-write mount upgrade an old filesystem.
+	/// `target` is mounted on in its [canonical](crate::mountpoint::canonical)
+	/// form, so that it matches the mountinfo entry afterwards.
 	pub fn mount(
 		&self,
 		target: impl AsRef<std::path::Path>,
 		options: impl AsRef<str>,
-	) -> anyhow::Result<()> {
-		tracing::info_span!("mount").in_scope(|| {
-			let src = self.device_string();
-			let (data, mountflags) = parse_mount_options(options);
-			// let fstype = c_str!("bcachefs");
-
-			tracing::info!(msg="mounting bcachefs filesystem", target=%target.as_ref().display());
-			mount_inner(src, target, "bcachefs", mountflags, data)
+		checking: Checking,
+		fstype: &str,
+		allow_upgrade: bool,
+	) -> anyhow::Result<String> {
+		let span = tracing::info_span!("mount", uuid = %self.uuid(), devices = %self.device_string());
+		span.in_scope(|| {
+			self.check_version()?;
+			let src = self.mount_source()?;
+			let (data, mountflags) = parse_mount_options(options, checking)?;
+			self.check_members(mountflags)?;
+			self.check_upgrade(mountflags, allow_upgrade)?;
+
+			let canonical = crate::mountpoint::canonical(target.as_ref());
+			tracing::info!(
+				msg="mounting bcachefs filesystem",
+				target=%target.as_ref().display(),
+				canonical=%canonical.display()
+			);
+			let options = format_mount_options(data.as_deref(), mountflags);
+			mount_inner(src, canonical, fstype, mountflags, data)?;
+			Ok(options)
 		})
 	}
+
+	/// The mount(8) command line doing what [`FileSystem::mount`] would, with
+	/// the same devices and options, quoted for the shell
+	pub fn mount_command(
+		&self,
+		target: impl AsRef<std::path::Path>,
+		options: impl AsRef<str>,
+		checking: Checking,
+		fstype: &str,
+	) -> anyhow::Result<String> {
+		let src = self.mount_source()?;
+		let (data, mountflags) = parse_mount_options(options, checking)?;
+		let options = format_mount_options(data.as_deref(), mountflags);
+		let args = [
+			OsStr::new("mount"),
+			OsStr::new("-t"),
+			OsStr::new(fstype),
+			&src,
+			target.as_ref().as_os_str(),
+			OsStr::new("-o"),
+			OsStr::new(&options),
+		]
+		.iter()
+		.map(|a| shell_quote(a))
+		.collect::<Vec<_>>();
+		Ok(args.join(" "))
+	}
+}
+
+/// Change the flags and options of the filesystem `uuid` mounted at `target`,
+/// like `mount -o remount`, without needing its devices. Returns the options
+/// it was remounted with.
+#[tracing_attributes::instrument(skip(options))]
+pub fn remount(
+	uuid: &Uuid,
+	target: &std::path::Path,
+	options: impl AsRef<str>,
+	checking: Checking,
+	fstype: &str,
+	paths: &Paths,
+) -> anyhow::Result<String> {
+	let mount = crate::mounts::mount_at(target)?;
+	if mount.fstype != fstype {
+		return Err(err!(NotBcachefsMount, target.display(), mount.fstype));
+	}
+	// the devices may not be known to udev, in which case there is nothing to check
+	let mounted = mount.source_devices().find_map(|dev| crate::mounts::device_fs_uuid(dev, paths));
+	if let Some(mounted) = mounted.filter(|m| m != uuid) {
+		return Err(err!(RemountOtherFs, target.display(), mounted));
+	}
+
+	let (data, mountflags) = parse_mount_options(options, checking)?;
+	let options = format_mount_options(data.as_deref(), mountflags);
+	tracing::info!(msg="remounting bcachefs filesystem", target=%target.display(), %options);
+	mount_inner(std::ffi::OsString::new(), crate::mountpoint::canonical(target), fstype, mountflags, data)?;
+	Ok(options)
 }
 
 fn mount_inner(
-	src: String,
+	src: std::ffi::OsString,
 	target: impl AsRef<std::path::Path>,
 	fstype: &str,
 	mountflags: u64,
@@ -82,12 +661,16 @@ fn mount_inner(
 ) -> anyhow::Result<()> {
 	use std::{
 		ffi::{c_void, CString},
-		os::{raw::c_char, unix::ffi::OsStrExt},
+		os::{
+			raw::c_char,
+			unix::ffi::{OsStrExt, OsStringExt},
+		},
 	};
 
 	// bind the CStrings to keep them alive
-	let src = CString::new(src)?;
+	let src = CString::new(src.into_vec())?;
 	let target = CString::new(target.as_ref().as_os_str().as_bytes())?;
+	let subvolid = data.as_deref().map_or(false, |d| d.split(',').any(|o| o.starts_with("subvolid=")));
 	let data = data.map(CString::new).transpose()?;
 	let fstype = CString::new(fstype)?;
 
@@ -106,102 +689,950 @@ fn mount_inner(
 	};
 	match ret {
 		0 => Ok(()),
+		// kernels that don't know subvolid reject the whole data string
+		_ if errno::errno().0 == libc::EINVAL && subvolid => Err(err!(SubvolidUnsupported)),
 		_ => Err(crate::ErrnoError(errno::errno()).into()),
 	}
 }
 
+/// A bcachefs mount option as the option table parses it
+#[derive(Debug, Clone, PartialEq)]
+pub struct FsOption {
+	/// Type of the option: bool, uint, str or fn, or u32 for subvolid
+	pub ty: &'static str,
+	/// What the value parses to; for a string option, the index of the
+	/// choice, which is in `choice`
+	pub value: u64,
+	pub choice: Option<&'static str>,
+}
+
+impl fmt::Display for FsOption {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.choice {
+			Some(choice) => write!(f, "{} {} ({})", self.ty, self.value, choice),
+			None => write!(f, "{} {}", self.ty, self.value),
+		}
+	}
+}
+
+/// Check a filesystem specific mount option against the libbcachefs option
+/// table, so that mistakes are reported by name instead of as EINVAL from the
+/// kernel.
+fn parse_fs_option(opt: &str) -> anyhow::Result<FsOption> {
+	use bch_bindgen::{bcachefs::opt_type, rs::opt_lookup};
+
+	let (name, val) = match opt.split_once('=') {
+		Some((name, val)) => (name, Some(val)),
+		None => (opt, None),
+	};
+	// handled by the kernel's mount code rather than the option table
+	if name == "subvolid" {
+		return match val.map(str::parse::<u32>) {
+			None => Err(err!(OptionNeedsValue, name)),
+			Some(Ok(id)) if id > 0 => Ok(FsOption { ty: "u32", value: id as u64, choice: None }),
+			Some(_) => Err(err!(OptionOutOfRange, name, val.unwrap(), 1, u32::MAX)),
+		};
+	}
+	let name = if name == "quota" { "usrquota" } else { name };
+
+	let (bopt, negated) = match (opt_lookup(name), name.strip_prefix("no")) {
+		(Some(o), _) => (o, false),
+		(None, Some(n)) if val.is_none() => match opt_lookup(n) {
+			Some(o) if o.type_ == opt_type::BCH_OPT_BOOL => (o, true),
+			_ => return Err(err!(UnknownOption, name)),
+		},
+		_ => return Err(err!(UnknownOption, name)),
+	};
+	if !bopt.is_mount_opt() {
+		return Err(err!(OptionNotMountable, bopt.name()));
+	}
+
+	let ty = match bopt.type_ {
+		opt_type::BCH_OPT_BOOL => "bool",
+		opt_type::BCH_OPT_UINT => "uint",
+		opt_type::BCH_OPT_STR => "str",
+		_ => "fn",
+	};
+	let parsed = |value| FsOption { ty, value, choice: None };
+	match val {
+		None if negated => Ok(parsed(0)),
+		None if bopt.type_ == opt_type::BCH_OPT_BOOL => Ok(parsed(1)),
+		None => Err(err!(OptionNeedsValue, bopt.name())),
+		Some(val) => match bopt.parse(val) {
+			Some(value) if bopt.type_ == opt_type::BCH_OPT_STR => {
+				Ok(FsOption { choice: bopt.choices().get(value as usize).copied(), ..parsed(value) })
+			}
+			Some(value) => Ok(parsed(value)),
+			None if bopt.type_ == opt_type::BCH_OPT_STR => Err(err!(
+				OptionBadChoice,
+				bopt.name(),
+				val,
+				bopt.choices().join(",")
+			)),
+			None if bopt.max > 0 => Err(err!(
+				OptionOutOfRange,
+				bopt.name(),
+				val,
+				bopt.min,
+				bopt.max - 1
+			)),
+			None => Err(err!(OptionBadValue, bopt.name(), val)),
+		},
+	}
+}
+
+/// Options that are generic mount flags rather than bcachefs options, with
+/// the names of their MS_* constants
+const MOUNT_FLAGS: &[(&str, u64, &str)] = &[
+	("ro", libc::MS_RDONLY, "MS_RDONLY"),
+	("dirsync", libc::MS_DIRSYNC, "MS_DIRSYNC"),
+	("lazytime", MS_LAZYTIME, "MS_LAZYTIME"),
+	("mand", libc::MS_MANDLOCK, "MS_MANDLOCK"),
+	("noatime", libc::MS_NOATIME, "MS_NOATIME"),
+	("nodev", libc::MS_NODEV, "MS_NODEV"),
+	("nodiratime", libc::MS_NODIRATIME, "MS_NODIRATIME"),
+	("noexec", libc::MS_NOEXEC, "MS_NOEXEC"),
+	("nosuid", libc::MS_NOSUID, "MS_NOSUID"),
+	("relatime", libc::MS_RELATIME, "MS_RELATIME"),
+	("remount", libc::MS_REMOUNT, "MS_REMOUNT"),
+	("strictatime", libc::MS_STRICTATIME, "MS_STRICTATIME"),
+	("sync", libc::MS_SYNCHRONOUS, "MS_SYNCHRONOUS"),
+];
+
+const MS_LAZYTIME: u64 = 1 << 25;
+
+/// Flags mountinfo never lists: remount isn't a property of the mount, and
+/// the kernel ignores mand these days
+const UNREPORTED_FLAGS: &[&str] = &["mand", "remount"];
+
+/// Options that undo a flag of [`MOUNT_FLAGS`], as in mount(8)
+const CLEARING_OPTIONS: &[(&str, u64)] = &[
+	("async", libc::MS_SYNCHRONOUS),
+	("atime", libc::MS_NOATIME),
+	("dev", libc::MS_NODEV),
+	("diratime", libc::MS_NODIRATIME),
+	("exec", libc::MS_NOEXEC),
+	("nolazytime", MS_LAZYTIME),
+	("nomand", libc::MS_MANDLOCK),
+	("norelatime", libc::MS_RELATIME),
+	("nostrictatime", libc::MS_STRICTATIME),
+	("suid", libc::MS_NOSUID),
+];
+
+/// Flags of which only one can be in effect, so each clears the others
+const EXCLUSIVE_FLAGS: &[u64] = &[libc::MS_NOATIME | libc::MS_RELATIME | libc::MS_STRICTATIME];
+
+/// Options that only mean something to mount(8) and fstab, and must not reach
+/// the kernel
+const USERSPACE_OPTIONS: &[&str] = &["defaults", "auto", "noauto", "nofail", "_netdev"];
+
+/// `s` in single quotes, unless it's safe to use in a shell as is. Bytes
+/// that aren't UTF-8 are kept by quoting as `$'...'`, with `\xHH` escapes.
+pub(crate) fn shell_quote(s: &OsStr) -> String {
+	use std::os::unix::ffi::OsStrExt;
+
+	let s = match s.to_str() {
+		Some(s) => s,
+		None => {
+			let mut quoted = String::from("$'");
+			for &b in s.as_bytes() {
+				match b {
+					b'\'' | b'\\' => quoted.extend(['\\', b as char].iter()),
+					b' '..=b'~' => quoted.push(b as char),
+					_ => quoted.push_str(&format!("\\x{:02x}", b)),
+				}
+			}
+			quoted.push('\'');
+			return quoted;
+		}
+	};
+	let safe = |c: char| c.is_ascii_alphanumeric() || "_-+=.,:/@%".contains(c);
+	if !s.is_empty() && s.chars().all(safe) {
+		s.to_owned()
+	} else {
+		format!("'{}'", s.replace('\'', "'\\''"))
+	}
+}
+
+/// Turn the result of [`parse_mount_options`] back into an option string,
+/// starting with "ro" or "rw"
+pub fn format_mount_options(data: Option<&str>, flags: u64) -> String {
+	let rw = if flags & libc::MS_RDONLY != 0 { "ro" } else { "rw" };
+	let names = MOUNT_FLAGS.iter().filter(|(_, f, _)| *f != libc::MS_RDONLY && flags & f != 0).map(|(n, _, _)| *n);
+	std::iter::once(rw).chain(names).chain(data).collect::<Vec<_>>().join(",")
+}
+
+/// Mount flags that were asked for in `requested` (as returned by
+/// [`format_mount_options`]) but aren't in effect according to `mounted`, e.g.
+/// because the kernel fell back to a read-only mount
+pub fn option_differences(requested: &str, mounted: &str) -> Vec<String> {
+	let mounted: Vec<&str> = mounted.split(',').collect();
+	let mut differences = Vec::new();
+	for o in requested.split(',') {
+		match o {
+			"ro" | "rw" => {
+				let other = if o == "ro" { "rw" } else { "ro" };
+				if mounted.contains(&other) {
+					differences.push(msg!(OptionDiffers, o, other));
+				}
+			}
+			// listed as neither of the other atime options
+			"strictatime" => {
+				if let Some(other) = ["noatime", "relatime"].iter().find(|a| mounted.contains(a)) {
+					differences.push(msg!(OptionDiffers, o, other));
+				}
+			}
+			o if UNREPORTED_FLAGS.contains(&o) => {}
+			o if MOUNT_FLAGS.iter().any(|(name, _, _)| *name == o) && !mounted.contains(&o) => {
+				differences.push(msg!(OptionNotInEffect, o));
+			}
+			// bcachefs only lists options that differ from their defaults
+			_ => {}
+		}
+	}
+	differences
+}
+
+/// What a single mount option is to [`parse_mount_options`]
+#[derive(Debug)]
+pub enum OptionClass {
+	/// A generic mount flag, e.g. "noatime"
+	Flag(u64),
+	/// "rw", which clears MS_RDONLY if it comes after any "ro"
+	ReadWrite,
+	/// An option that clears a generic mount flag set before it, e.g. "suid"
+	Clear(u64),
+	/// For mount(8), fstab or this tool, e.g. "noauto" or "x-mount.mode=",
+	/// and never passed to the kernel
+	Userspace,
+	/// For bcachefs itself, e.g. "discard"
+	Fs(FsOption),
+	/// Not a bcachefs option
+	Unknown(anyhow::Error),
+	/// A bcachefs option that can't be used like this, e.g. with a bad value
+	Invalid(anyhow::Error),
+}
+
+impl OptionClass {
+	pub fn of(option: &str) -> Self {
+		use crate::messages::{Msg, MsgError};
+
+		if let Some((_, flag)) = CLEARING_OPTIONS.iter().find(|(name, _)| *name == option) {
+			return OptionClass::Clear(*flag);
+		}
+		match MOUNT_FLAGS.iter().find(|(name, _, _)| *name == option) {
+			Some((_, flag, _)) => OptionClass::Flag(*flag),
+			None if option == "rw" => OptionClass::ReadWrite,
+			None if option == "" || USERSPACE_OPTIONS.contains(&option) => OptionClass::Userspace,
+			None if option.starts_with(crate::RECOVERY_PASS_OPTION) => {
+				OptionClass::Invalid(err!(RecoveryPassUnsupported, &option[crate::RECOVERY_PASS_OPTION.len()..]))
+			}
+			// x-* options are for userspace, e.g. x-mount.owner for --mkdir
+			None if option.starts_with("x-") => OptionClass::Userspace,
+			// everything else, e.g. discard, is for bcachefs itself
+			None => match parse_fs_option(option) {
+				Ok(parsed) => OptionClass::Fs(parsed),
+				Err(e) if e.downcast_ref::<MsgError>().map(|e| e.msg) == Some(Msg::UnknownOption) => {
+					OptionClass::Unknown(e)
+				}
+				Err(e) => OptionClass::Invalid(e),
+			},
+		}
+	}
+}
+
+/// What [`parse_mount_options`] does with filesystem options this
+/// libbcachefs doesn't know or that fail its checks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checking {
+	/// Pass them on for the kernel to accept or reject, so that options newer
+	/// than this libbcachefs work
+	PassThrough,
+	/// Refuse to mount, with --strict
+	Strict,
+	/// Leave them out with a warning, with --strict --sloppy
+	Sloppy,
+}
+
+impl Checking {
+	pub fn new(strict: bool, sloppy: bool) -> Self {
+		match (strict, sloppy) {
+			(false, _) => Checking::PassThrough,
+			(true, false) => Checking::Strict,
+			(true, true) => Checking::Sloppy,
+		}
+	}
+}
+
 /// Parse a comma-separated mount options and split out mountflags and filesystem
-/// specific options.
+/// specific options. As with mount(8), the last of "ro" and "rw" wins.
+/// Mounting a snapshot with "subvolid" implies "ro" unless "rw" is given.
+///
+/// Filesystem options bcachefs doesn't know, or with bad values, are passed
+/// on unless `checking` says otherwise; with [`Checking::Sloppy`], `errors=`
+/// is still passed on as is. x-* options never reach the kernel, so the ones
+/// that fail are errors regardless.
 #[tracing_attributes::instrument(skip(options))]
-fn parse_mount_options(options: impl AsRef<str>) -> (Option<String>, u64) {
-	use either::Either::*;
+pub fn parse_mount_options(options: impl AsRef<str>, checking: Checking) -> anyhow::Result<(Option<String>, u64)> {
 	tracing::debug!(msg="parsing mount options", options=?options.as_ref());
-	let (opts, flags) = options
-		.as_ref()
-		.split(",")
-		.map(|o| match o {
-			"dirsync" => Left(libc::MS_DIRSYNC),
-			"lazytime" => Left(1 << 25), // MS_LAZYTIME
-			"mand" => Left(libc::MS_MANDLOCK),
-			"noatime" => Left(libc::MS_NOATIME),
-			"nodev" => Left(libc::MS_NODEV),
-			"nodiratime" => Left(libc::MS_NODIRATIME),
-			"noexec" => Left(libc::MS_NOEXEC),
-			"nosuid" => Left(libc::MS_NOSUID),
-			"ro" => Left(libc::MS_RDONLY),
-			"rw" => Left(0),
-			"relatime" => Left(libc::MS_RELATIME),
-			"strictatime" => Left(libc::MS_STRICTATIME),
-			"sync" => Left(libc::MS_SYNCHRONOUS),
-			"" => Left(0),
-			o @ _ => Right(o),
-		})
-		.fold((Vec::new(), 0), |(mut opts, flags), next| match next {
-			Left(f) => (opts, flags | f),
-			Right(o) => {
+	let mut opts = Vec::new();
+	let mut flags = 0;
+	// later options win over earlier ones they conflict with, as in mount(8)
+	for o in options.as_ref().split(',') {
+		match OptionClass::of(o) {
+			OptionClass::Flag(f) => {
+				for exclusive in EXCLUSIVE_FLAGS.iter().filter(|e| *e & f != 0) {
+					flags &= !exclusive;
+				}
+				flags |= f
+			}
+			OptionClass::Clear(f) => flags &= !f,
+			OptionClass::ReadWrite | OptionClass::Userspace => {}
+			OptionClass::Fs(_) => opts.push(o),
+			OptionClass::Unknown(e) | OptionClass::Invalid(e)
+				if checking == Checking::Strict || (checking == Checking::PassThrough && o.starts_with("x-")) =>
+			{
+				return Err(e)
+			}
+			OptionClass::Unknown(e) | OptionClass::Invalid(e) if checking == Checking::PassThrough => {
+				tracing::debug!(msg="passing on mount option unchecked", option=%o, error=%e);
 				opts.push(o);
-				(opts, flags)
 			}
-		});
+			// the kernel may know error actions this libbcachefs doesn't
+			OptionClass::Unknown(e) | OptionClass::Invalid(e) if o.starts_with("errors=") => {
+				tracing::warn!(msg="passing on mount option unchecked", option=%o, error=%e);
+				opts.push(o);
+			}
+			OptionClass::Unknown(e) | OptionClass::Invalid(e) => {
+				tracing::warn!(msg="ignoring mount option", option=%o, error=%e);
+			}
+		}
+	}
 
-	use itertools::Itertools;
-	(
-		if opts.len() == 0 {
-			None
-		} else {
-			Some(opts.iter().join(","))
-		},
-		flags,
-	)
+	if options.as_ref().split(',').rev().find(|o| *o == "ro" || *o == "rw") == Some("rw") {
+		flags &= !libc::MS_RDONLY;
+	} else if opts.iter().any(|o| o.starts_with("subvolid=")) {
+		flags |= libc::MS_RDONLY;
+	}
+
+	Ok((if opts.is_empty() { None } else { Some(opts.join(",")) }, flags))
+}
+
+/// A table of how each of `options` is classified, followed by the flags
+/// and data [`parse_mount_options`] makes of them, or the error it fails
+/// with, for `--explain-options`:
+///
+/// ```text
+/// option    class      detail
+/// noatime   vfs flag   MS_NOATIME
+/// errors=ro fs option  str 1 (ro)
+/// flags: 0x00000400 MS_NOATIME
+/// data: errors=ro
+/// ```
+pub fn explain_mount_options(options: &str, checking: Checking) -> String {
+	use std::fmt::Write;
+
+	let rows: Vec<(&str, &str, String)> = options
+		.split(',')
+		.map(|o| match OptionClass::of(o) {
+			OptionClass::Flag(flag) => {
+				let name = MOUNT_FLAGS.iter().find(|(_, f, _)| *f == flag).map_or("", |(_, _, name)| *name);
+				(o, "vfs flag", name.to_owned())
+			}
+			OptionClass::ReadWrite => (o, "vfs flag", "clears MS_RDONLY".to_owned()),
+			OptionClass::Clear(flag) => {
+				let name = MOUNT_FLAGS.iter().find(|(_, f, _)| *f == flag).map_or("", |(_, _, name)| *name);
+				(o, "vfs flag", format!("clears {}", name))
+			}
+			OptionClass::Userspace => (o, "userspace", "not passed to the kernel".to_owned()),
+			OptionClass::Fs(parsed) => (o, "fs option", parsed.to_string()),
+			OptionClass::Unknown(e) => (o, "unknown", e.to_string()),
+			OptionClass::Invalid(e) => (o, "invalid", e.to_string()),
+		})
+		.collect();
+	let width = rows.iter().map(|(o, _, _)| o.len()).max().unwrap_or(0).max("option".len());
+
+	let mut out = String::new();
+	let _ = writeln!(out, "{:<w$} {:<9} detail", "option", "class", w = width);
+	for (o, class, detail) in &rows {
+		let _ = writeln!(out, "{:<w$} {:<9} {}", o, class, detail, w = width);
+	}
+	match parse_mount_options(options, checking) {
+		Ok((data, flags)) => {
+			let names: Vec<_> = MOUNT_FLAGS.iter().filter(|(_, f, _)| flags & f != 0).map(|(_, _, n)| *n).collect();
+			let _ = writeln!(out, "flags: {:#010x} {}", flags, names.join("|"));
+			let _ = writeln!(out, "data: {}", data.unwrap_or_default());
+		}
+		Err(e) => {
+			let _ = writeln!(out, "error: {}", e);
+		}
+	}
+	out
 }
 
 use bch_bindgen::bcachefs;
 use std::collections::HashMap;
 use uuid::Uuid;
 
-#[tracing_attributes::instrument]
-pub fn probe_filesystems() -> anyhow::Result<HashMap<Uuid, FileSystem>> {
-	tracing::trace!("enumerating udev devices");
-	let mut udev = udev::Enumerator::new()?;
+/// Where probing looks for bcachefs member devices
+pub trait DeviceSource {
+	/// Device nodes to probe
+	fn devices(&self) -> anyhow::Result<Vec<PathBuf>>;
+}
+
+/// All block devices known to udev
+#[derive(Debug)]
+pub struct Udev;
+
+impl DeviceSource for Udev {
+	fn devices(&self) -> anyhow::Result<Vec<PathBuf>> {
+		tracing::trace!("enumerating udev devices");
+		let mut udev = udev::Enumerator::new()?;
 
-	udev.match_subsystem("block")?; // find kernel block devices
+		udev.match_subsystem("block")?; // find kernel block devices
+
+		Ok(udev.scan_devices()?.filter_map(|dev| Some(dev.devnode()?.to_owned())).collect())
+	}
+}
+
+/// Exactly the given devices, e.g. from `--only-device`. A device given
+/// again, maybe by another name, is left out, as it would be a member twice.
+impl DeviceSource for [PathBuf] {
+	fn devices(&self) -> anyhow::Result<Vec<PathBuf>> {
+		let mut seen = std::collections::HashSet::new();
+		let canonical = |p: &PathBuf| std::fs::canonicalize(p).unwrap_or_else(|_| p.clone());
+		Ok(self.iter().filter(|p| seen.insert(canonical(p))).cloned().collect())
+	}
+}
+
+/// Why probing passed over a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Skip {
+	/// No bcachefs superblock; what is there instead is only looked up by
+	/// [`Scan::classify`], as that takes a udev query per device
+	NotBcachefs,
+	/// Another filesystem, as udev's ID_FS_TYPE says
+	OtherFs(String),
+	/// No filesystem udev knows of
+	Empty,
+	PermissionDenied,
+	/// Could not be read for another reason
+	Unreadable,
+	/// A bcachefs superblock left out, as stale or unusable
+	Filtered,
+	/// Gone between enumerating devices and probing it
+	Vanished,
+	/// Holds a member another device was found to hold too, with a superblock
+	/// no older
+	Duplicate,
+}
+
+impl Skip {
+	/// What a superblock that couldn't be read says about the device
+	pub fn of(e: &std::io::Error) -> Self {
+		use std::io::ErrorKind;
+		match e.kind() {
+			ErrorKind::InvalidData => Skip::NotBcachefs,
+			ErrorKind::NotFound => Skip::Vanished,
+			ErrorKind::PermissionDenied => Skip::PermissionDenied,
+			_ => Skip::Unreadable,
+		}
+	}
+
+	/// Name in JSON output
+	pub fn name(&self) -> &'static str {
+		match self {
+			Skip::NotBcachefs => "not_bcachefs",
+			Skip::OtherFs(_) => "other_filesystem",
+			Skip::Empty => "empty",
+			Skip::PermissionDenied => "permission_denied",
+			Skip::Unreadable => "unreadable",
+			Skip::Filtered => "filtered",
+			Skip::Vanished => "vanished",
+			Skip::Duplicate => "duplicate",
+		}
+	}
+}
+
+impl fmt::Display for Skip {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Skip::OtherFs(fs_type) => write!(f, "other filesystem ({})", fs_type),
+			Skip::NotBcachefs => f.write_str("not bcachefs"),
+			skip => f.write_str(&skip.name().replace('_', " ")),
+		}
+	}
+}
+
+/// A device probing passed over
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skipped {
+	pub device: PathBuf,
+	pub skip: Skip,
+}
+
+impl fmt::Display for Skipped {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}: {}", self.device.display(), self.skip)
+	}
+}
+
+/// What a probe saw of the devices that aren't members of any filesystem it
+/// found, to show users who are sure their disk is there
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Scan {
+	/// Devices probed, members or not
+	pub scanned: usize,
+	pub skipped: Vec<Skipped>,
+}
+
+impl Scan {
+	/// Tell other filesystems from empty devices among those without a
+	/// bcachefs superblock, by what `fs_type`, e.g. [`device_fs_type`],
+	/// finds on them
+	///
+	/// [`device_fs_type`]: crate::mounts::device_fs_type
+	pub fn classify(&mut self, fs_type: impl Fn(&std::path::Path) -> Option<String>) {
+		for skipped in self.skipped.iter_mut().filter(|s| s.skip == Skip::NotBcachefs) {
+			skipped.skip = fs_type(&skipped.device).map_or(Skip::Empty, Skip::OtherFs);
+		}
+	}
+
+	/// Number of devices skipped for each reason, other filesystems counted
+	/// together, in a fixed order
+	pub fn counts(&self) -> Vec<(&'static str, usize)> {
+		let names = [
+			"other_filesystem",
+			"not_bcachefs",
+			"empty",
+			"filtered",
+			"duplicate",
+			"vanished",
+			"permission_denied",
+			"unreadable",
+		];
+		names
+			.iter()
+			.map(|&name| (name, self.skipped.iter().filter(|s| s.skip.name() == name).count()))
+			.filter(|&(_, count)| count > 0)
+			.collect()
+	}
+
+	/// The scan as a JSON object, with every device skipped
+	pub fn to_json(&self) -> String {
+		use crate::json::{array, nullable, object, string};
+
+		let skipped = self.skipped.iter().map(|s| {
+			let fs_type = match &s.skip {
+				Skip::OtherFs(fs_type) => Some(fs_type.as_str()),
+				_ => None,
+			};
+			object(&[
+				("device", string(&s.device.display().to_string())),
+				("reason", string(s.skip.name())),
+				("fs_type", nullable(fs_type, string)),
+			])
+		});
+		object(&[("scanned", self.scanned.to_string()), ("skipped", array(skipped))])
+	}
+}
+
+/// E.g. "14 devices scanned: 2 bcachefs, 9 other filesystems, 3 empty"
+impl fmt::Display for Scan {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let plural = |count: usize| if count == 1 { "" } else { "s" };
+		write!(f, "{} device{} scanned", self.scanned, plural(self.scanned))?;
+		let mut counts = Vec::new();
+		let members = self.scanned.saturating_sub(self.skipped.len());
+		if members > 0 {
+			counts.push(format!("{} bcachefs", members));
+		}
+		for (name, count) in self.counts() {
+			match name {
+				"other_filesystem" => counts.push(format!("{} other filesystem{}", count, plural(count))),
+				name => counts.push(format!("{} {}", count, name.replace('_', " "))),
+			}
+		}
+		if !counts.is_empty() {
+			write!(f, ": {}", counts.join(", "))?;
+		}
+		Ok(())
+	}
+}
+
+#[tracing_attributes::instrument(skip(paths))]
+pub fn probe_filesystems(paths: &Paths) -> anyhow::Result<HashMap<Uuid, FileSystem>> {
+	probe_with(&Udev, paths)
+}
+
+/// Like [`probe_filesystems`], but probing the devices `source` yields
+#[tracing_attributes::instrument(skip(source, paths))]
+pub fn probe_with<S: DeviceSource + ?Sized>(source: &S, paths: &Paths) -> anyhow::Result<HashMap<Uuid, FileSystem>> {
+	Ok(probe_scan(source, paths)?.0)
+}
+
+/// Like [`probe_with`], but also returning what was seen of the devices that
+/// aren't members. Devices that can't be opened for lack of permission are
+/// skipped, unless none could be, which is an error as before.
+#[tracing_attributes::instrument(skip(source, paths))]
+pub fn probe_scan<S: DeviceSource + ?Sized>(
+	source: &S,
+	paths: &Paths,
+) -> anyhow::Result<(HashMap<Uuid, FileSystem>, Scan)> {
+	scan_for(None, source, paths, &mut ())
+}
+
+/// Told how a probe for a filesystem goes, to get going on that filesystem
+/// before every device has been looked at
+pub trait Progress {
+	/// The first member found of a filesystem the spec matches; the probe
+	/// may still end up with another, or with none if the spec turns out
+	/// to be ambiguous
+	fn found(&mut self, fs: &FileSystem);
+	/// Asked before each device is probed; an error ends the probe with it
+	fn proceed(&mut self) -> anyhow::Result<()>;
+}
+
+impl Progress for () {
+	fn found(&mut self, _: &FileSystem) {}
+
+	fn proceed(&mut self) -> anyhow::Result<()> {
+		Ok(())
+	}
+}
+
+/// [`probe_scan`], telling `progress` about the first filesystem `spec`
+/// matches
+fn scan_for<S: DeviceSource + ?Sized>(
+	spec: Option<&FsSpec>,
+	source: &S,
+	paths: &Paths,
+	progress: &mut dyn Progress,
+) -> anyhow::Result<(HashMap<Uuid, FileSystem>, Scan)> {
+	use std::collections::hash_map::Entry;
 
 	let mut fs_map = HashMap::new();
-	let devresults = 
-			udev.scan_devices()?
-			.into_iter()
-			.filter_map(|dev| dev.devnode().map(ToOwned::to_owned));
-	
-	for pathbuf in devresults {
-		match get_super_block_uuid(&pathbuf)? {
+	let mut scan = Scan::default();
+	let mut denied = None;
+	let mut matched = false;
+	for pathbuf in source.devices()? {
+		progress.proceed()?;
+		scan.scanned += 1;
+		let (uuid_key, found) = match probe_device(&pathbuf, paths) {
+			Ok(Ok(found)) => found,
+			Ok(Err(skip)) => {
+				scan.skipped.push(Skipped { device: pathbuf, skip });
+				continue;
+			}
+			Err(e) => {
+				let skip = match UnusableSuperblock::of(&e) {
+					Some(unusable) => {
+						tracing::warn!(msg="ignoring device", device=%pathbuf.display(), reason=%unusable);
+						Skip::Filtered
+					}
+					None if e.kind() == std::io::ErrorKind::PermissionDenied => {
+						tracing::debug!(msg="skipping device", device=%pathbuf.display(), error=%e);
+						denied.get_or_insert(e);
+						Skip::PermissionDenied
+					}
+					None => return Err(e.into()),
+				};
+				scan.skipped.push(Skipped { device: pathbuf, skip });
+				continue;
+			}
+		};
+		match fs_map.entry(uuid_key) {
+			Entry::Vacant(e) => {
+				tracing::info!(msg="found bcachefs pool", uuid=?uuid_key);
+				if !matched && spec.map_or(false, |spec| matches(spec, &uuid_key, &found)) {
+					matched = true;
+					progress.found(&found);
+				}
+				e.insert(found);
+			}
+			Entry::Occupied(mut e) => {
+				for device in e.get_mut().merge(found) {
+					scan.skipped.push(Skipped { device, skip: Skip::Duplicate });
+				}
+			}
+		}
+	}
+	if let Some(e) = denied {
+		if fs_map.is_empty() && scan.skipped.iter().all(|s| s.skip == Skip::PermissionDenied) {
+			return Err(e.into());
+		}
+	}
 
-				Ok((uuid_key, superblock)) => {
-					let fs = fs_map.entry(uuid_key).or_insert_with(|| {
-						tracing::info!(msg="found bcachefs pool", uuid=?uuid_key);
-						FileSystem::new(superblock)
-					});
+	for device in crate::stale::drop_stale(&mut fs_map, paths) {
+		scan.skipped.push(Skipped { device, skip: Skip::Filtered });
+	}
+	tracing::info!(msg = "found filesystems", count = fs_map.len(), skipped = scan.skipped.len());
+	Ok((fs_map, scan))
+}
 
-					fs.devices.push(pathbuf);
-				},
+/// Take the filesystem `spec` names out of a probe of the devices `source`
+/// yields. If it isn't there, the error says what the devices held instead.
+pub fn probe_for<S: DeviceSource + ?Sized>(spec: &FsSpec, source: &S, paths: &Paths) -> anyhow::Result<FileSystem> {
+	probe_for_with(spec, source, paths, &mut ())
+}
+
+/// [`probe_for`], telling `progress` how it goes
+pub fn probe_for_with<S: DeviceSource + ?Sized>(
+	spec: &FsSpec,
+	source: &S,
+	paths: &Paths,
+	progress: &mut dyn Progress,
+) -> anyhow::Result<FileSystem> {
+	let (mut fss, scan) = scan_for(Some(spec), source, paths, progress)?;
+	resolve(spec, &mut fss).map_err(|e| not_found_in(e, scan, paths))
+}
 
-				Err(e) => { tracing::debug!(inner2_error=?e);}
+/// `e`, saying what the devices of `scan` held if the filesystem wasn't
+/// found; a `ResolveError` still, to `downcast_ref`
+pub fn not_found_in(e: ResolveError, mut scan: Scan, paths: &Paths) -> anyhow::Error {
+	match e {
+		ResolveError::NotFound => {
+			scan.classify(|dev| crate::mounts::device_fs_type(dev, paths));
+			anyhow::Error::new(e).context(msg!(FsNotFoundScanned, scan))
 		}
+		e => e.into(),
 	}
+}
 
-	
-	tracing::info!(msg = "found filesystems", count = fs_map.len());
-	Ok(fs_map)
+/// Why [`resolve`] didn't come up with exactly one filesystem, with the
+/// candidates if there were several
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResolveError {
+	NotFound,
+	AmbiguousPrefix(Vec<Uuid>),
+	AmbiguousLabel(Vec<Uuid>),
+	/// The UUID is the external UUID of one filesystem and the internal one
+	/// of another
+	AmbiguousUuid(Vec<Uuid>),
+}
+
+impl ResolveError {
+	/// The catalog message the error is shown with
+	pub fn msg(&self) -> crate::messages::Msg {
+		use crate::messages::Msg;
+		match self {
+			ResolveError::NotFound => Msg::FsNotFound,
+			ResolveError::AmbiguousPrefix(_) => Msg::AmbiguousPrefix,
+			ResolveError::AmbiguousLabel(_) => Msg::AmbiguousLabel,
+			ResolveError::AmbiguousUuid(_) => Msg::AmbiguousUuid,
+		}
+	}
+}
+
+impl fmt::Display for ResolveError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		use itertools::Itertools;
+		match self {
+			ResolveError::NotFound => f.write_str(&msg!(FsNotFound)),
+			ResolveError::AmbiguousPrefix(c) | ResolveError::AmbiguousLabel(c) | ResolveError::AmbiguousUuid(c) => {
+				f.write_str(&self.msg().format(&[&c.len(), &c.iter().join(", ")]))
+			}
+		}
+	}
+}
+
+impl std::error::Error for ResolveError {}
+
+/// Whether `spec` names the filesystem `fs` with UUID `uuid`
+fn matches(spec: &FsSpec, uuid: &Uuid, fs: &FileSystem) -> bool {
+	match spec {
+		FsSpec::Uuid(u) => uuid == u || fs.internal_uuid() == *u,
+		FsSpec::Prefix(p) => [uuid.to_hyphenated().to_string(), uuid.to_simple().to_string()]
+			.iter()
+			.any(|u| u.starts_with(p.as_str())),
+		FsSpec::Label(l) => fs.sb().sb().label().as_deref() == Some(l.as_str()),
+	}
+}
+
+/// Take the filesystem `spec` names out of what probing found. A full UUID
+/// also matches internal UUIDs, as found in kernel log lines, with a
+/// warning that the filesystem goes by another one.
+pub fn resolve(spec: &FsSpec, fss: &mut HashMap<Uuid, FileSystem>) -> Result<FileSystem, ResolveError> {
+	let mut candidates: Vec<Uuid> = fss.iter().filter(|(u, fs)| matches(spec, u, fs)).map(|(u, _)| *u).collect();
+	candidates.sort();
+	match (candidates.as_slice(), spec) {
+		([], _) => Err(ResolveError::NotFound),
+		([uuid], _) => {
+			let fs = fss.remove(uuid).expect("candidates come from the map");
+			if let FsSpec::Uuid(given) = spec {
+				if given != uuid {
+					tracing::warn!(msg="an internal UUID was given, the filesystem's UUID is another", internal_uuid=%given, %uuid);
+				}
+			}
+			Ok(fs)
+		}
+		(_, FsSpec::Label(_)) => Err(ResolveError::AmbiguousLabel(candidates)),
+		(_, FsSpec::Uuid(_)) => Err(ResolveError::AmbiguousUuid(candidates)),
+		_ => Err(ResolveError::AmbiguousPrefix(candidates)),
+	}
+}
+
+/// Take the filesystem `spec` names out of what a scan of some devices
+/// found. If it isn't there, `unfiltered` scans the rest before giving up,
+/// so that the error can name the devices it is on that `filter` (the option
+/// choosing the devices, e.g. "--only-device") left out.
+pub fn find_filtered(
+	spec: &FsSpec,
+	filter: &str,
+	mut filtered: HashMap<Uuid, FileSystem>,
+	unfiltered: impl FnOnce() -> anyhow::Result<HashMap<Uuid, FileSystem>>,
+) -> anyhow::Result<FileSystem> {
+	use itertools::Itertools;
+
+	match resolve(spec, &mut filtered) {
+		Err(ResolveError::NotFound) => {}
+		found => return Ok(found?),
+	}
+	tracing::info!(msg="filesystem not found on the devices scanned, scanning all of them", %spec, filter);
+	match unfiltered() {
+		Ok(mut all) => match resolve(spec, &mut all) {
+			Ok(fs) => Err(err!(FsOutsideFilter, fs.members().iter().map(|m| m.path().display()).join(", "), filter)),
+			Err(_) => Err(ResolveError::NotFound.into()),
+		},
+		Err(e) => {
+			tracing::warn!(msg="could not scan all devices", error=%e);
+			Err(ResolveError::NotFound.into())
+		}
+	}
+}
+
+/// Streaming variant of [`probe_filesystems`]: `on_found` is called for every
+/// bcachefs device as soon as it has been probed, with a `FileSystem` holding
+/// just that one member, and devices that could not be probed are passed to
+/// `on_error` instead of aborting the scan.
+#[tracing_attributes::instrument(skip(paths, on_found, on_error))]
+pub fn probe_filesystems_with_callbacks<F, G>(paths: &Paths, on_found: F, on_error: G) -> anyhow::Result<()>
+where
+	F: Fn(Uuid, FileSystem),
+	G: Fn(&std::path::Path, anyhow::Error),
+{
+	for pathbuf in Udev.devices()? {
+		match probe_device(&pathbuf, paths) {
+			Ok(Ok((uuid, fs))) => on_found(uuid, fs),
+			Ok(Err(_)) => {}
+			Err(e) => on_error(&pathbuf, e.into()),
+		}
+	}
+	Ok(())
+}
+
+/// A bcachefs superblock that can't be mounted, as left behind by wipefs or
+/// an interrupted format. Probing reports these as errors rather than
+/// keying a filesystem on a bogus UUID.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnusableSuperblock(pub &'static str);
+
+impl fmt::Display for UnusableSuperblock {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "incomplete or invalid superblock: {}", self.0)
+	}
+}
+
+impl std::error::Error for UnusableSuperblock {}
+
+impl UnusableSuperblock {
+	/// Check the user UUID, which a superblock has to have to be mounted
+	pub fn check(sb: &bcachefs::bch_sb) -> Result<(), Self> {
+		match sb.uuid() {
+			uuid if uuid.is_nil() => Err(UnusableSuperblock("user UUID is nil")),
+			uuid if uuid == bch_bindgen::rs::SUPERBLOCK_MAGIC => {
+				Err(UnusableSuperblock("user UUID is the superblock magic"))
+			}
+			_ => Ok(()),
+		}
+	}
+
+	/// The superblock `e` was raised for by probing, if that's what it was
+	pub fn of(e: &std::io::Error) -> Option<&Self> {
+		e.get_ref()?.downcast_ref()
+	}
+}
+
+/// Probe a single device, returning a `FileSystem` with it as the only member
+/// if it carries a bcachefs superblock, or else why it was skipped.
+#[tracing_attributes::instrument(skip(path), fields(device = %path.display()))]
+fn probe_device(path: &std::path::Path, paths: &Paths) -> std::io::Result<Result<(Uuid, FileSystem), Skip>> {
+	match get_super_block_uuid(path)? {
+		Ok((uuid, superblock)) => {
+			let fd = superblock.fd().ok_or_else(|| std::io::Error::from_raw_os_error(libc::EBADF))?;
+			let read_only = is_read_only(fd)?;
+			let block_size = superblock.sb().block_size() as u32 * 512;
+			match logical_block_size(fd) {
+				Ok(logical) if block_size < logical => tracing::warn!(
+					msg="filesystem block size is smaller than the device's logical block size, the kernel will refuse to mount it",
+					device=%path.display(),
+					block_size,
+					logical
+				),
+				Ok(_) => {}
+				Err(e) => tracing::debug!(msg="could not get logical block size", device=%path.display(), error=%e),
+			}
+			let removable = crate::mounts::udev_device(path, paths).map_or(false, |dev| is_removable(&dev));
+			let fs = FileSystem::new(superblock, Member::new(path.to_owned(), read_only, removable));
+			let fs = FileSystem { paths: paths.clone(), ..fs };
+			Ok(Ok((uuid, fs)))
+		}
+		Err(e) => {
+			tracing::debug!(inner2_error=?e);
+			Ok(Err(Skip::of(&e)))
+		}
+	}
+}
+
+/// The `removable` sysfs attribute lives on the whole disk, not on its
+/// partitions.
+fn is_removable(dev: &udev::Device) -> bool {
+	let removable = |d: &udev::Device| d.attribute_value("removable").map_or(false, |v| v == "1");
+	match dev.devtype() {
+		Some(t) if t == "partition" => dev.parent().map_or(false, |p| removable(&p)),
+		_ => removable(dev),
+	}
+}
+
+const BLKROGET: libc::c_ulong = 0x125e; // _IO(0x12, 94)
+const BLKSSZGET: libc::c_ulong = 0x1268; // _IO(0x12, 104)
+
+/// Whether the block device open as `fd` is read-only. `fd` is borrowed,
+/// typically from the superblock handle (`bch_sb_handle::fd`), and left open.
+pub fn is_read_only(fd: std::os::unix::io::RawFd) -> std::io::Result<bool> {
+	let mut ro: libc::c_int = 0;
+	let ret = unsafe { libc::ioctl(fd, BLKROGET, &mut ro) };
+	if ret < 0 {
+		return Err(std::io::Error::last_os_error());
+	}
+	Ok(ro != 0)
+}
+
+/// Logical block size of the block device open as `fd`, in bytes; borrowed
+/// like for `is_read_only`
+pub fn logical_block_size(fd: std::os::unix::io::RawFd) -> std::io::Result<u32> {
+	let mut size: libc::c_int = 0;
+	let ret = unsafe { libc::ioctl(fd, BLKSSZGET, &mut size) };
+	if ret < 0 {
+		return Err(std::io::Error::last_os_error());
+	}
+	Ok(size as u32)
 }
 
 // #[tracing_attributes::instrument(skip(dev, fs_map))]
-fn get_super_block_uuid(path: &std::path::Path) -> std::io::Result<std::io::Result<(Uuid, bcachefs::bch_sb_handle)>> {
-	let sb = bch_bindgen::rs::read_super(&path)?;
+fn get_super_block_uuid(path: &std::path::Path) -> std::io::Result<std::io::Result<(Uuid, bcachefs::SbHandle)>> {
+	// probing never writes, and a write-protected member would fail to open
+	// for writing before it could be seen to be read-only
+	let sb = bch_bindgen::rs::read_super_read_only(&path)?;
 	let super_block = match sb { 
 		Err(e) => { return Ok(Err(e)); }
 		Ok(sb) => sb,
 	};
 
 	let uuid = (&super_block).sb().uuid();
+	if let Err(unusable) = UnusableSuperblock::check(super_block.sb()) {
+		return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, unusable));
+	}
 	tracing::debug!(found="bcachefs superblock", devnode=?path, ?uuid);
 
 	Ok(Ok((uuid, super_block)))
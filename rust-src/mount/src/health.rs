@@ -0,0 +1,108 @@
+//! Quick health check of member devices before mounting, from what the kernel
+//! exposes in sysfs and, with the `smart` feature, the drive's own SMART
+//! verdict. Devices that expose none of this (loop, virtio, ...) simply come
+//! up without findings.
+
+use crate::messages::Msg;
+use crate::paths::Paths;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub struct DeviceHealth {
+	pub device: PathBuf,
+	/// Anything that looks wrong with the device
+	pub problems: Vec<String>,
+	/// SMART overall health: `Some(true)` if passed, `None` if unknown
+	pub smart_passed: Option<bool>,
+}
+
+impl std::fmt::Display for DeviceHealth {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "{}: ", self.device.display())?;
+		if self.problems.is_empty() {
+			write!(f, "{}", Msg::HealthOk)?;
+		} else {
+			write!(f, "{}", self.problems.join("; "))?;
+		}
+		match self.smart_passed {
+			Some(true) => write!(f, ", {}", Msg::SmartPassed),
+			Some(false) => write!(f, ", {}", Msg::SmartFailed),
+			None => Ok(()),
+		}
+	}
+}
+
+/// sysfs directory of the whole disk `device` lives on
+fn sysfs_disk_dir(device: &Path, paths: &Paths) -> Option<PathBuf> {
+	let dev = std::fs::canonicalize(paths.dev(device)).ok()?;
+	let dir = std::fs::canonicalize(paths.sys("class/block").join(dev.file_name()?)).ok()?;
+	if dir.join("partition").exists() {
+		dir.parent().map(Path::to_owned)
+	} else {
+		Some(dir)
+	}
+}
+
+fn read_attr(dir: &Path, attr: &str) -> Option<String> {
+	std::fs::read_to_string(dir.join(attr)).ok().map(|s| s.trim().to_owned())
+}
+
+#[tracing_attributes::instrument]
+pub fn check(device: &Path, paths: &Paths) -> DeviceHealth {
+	let mut health = DeviceHealth {
+		device: device.to_owned(),
+		problems: Vec::new(),
+		smart_passed: None,
+	};
+	let dir = match sysfs_disk_dir(device, paths) {
+		Some(dir) => dir,
+		None => return health,
+	};
+
+	if let Some(state) = read_attr(&dir, "device/state") {
+		if state != "running" && state != "live" {
+			health.problems.push(msg!(DeviceState, state));
+		}
+	}
+	if read_attr(&dir, "ro").as_deref() == Some("1") {
+		health.problems.push(Msg::DeviceReadOnly.to_string());
+	}
+	if let Some(errors) = read_attr(&dir, "device/ioerr_cnt") {
+		let errors = u64::from_str_radix(errors.trim_start_matches("0x"), 16).unwrap_or(0);
+		if errors > 0 {
+			health.problems.push(msg!(DeviceIoErrors, errors));
+		}
+	}
+
+	#[cfg(feature = "smart")]
+	{
+		health.smart_passed = dir.file_name().and_then(|disk| smart_status(&paths.dev_root.join(disk)));
+		if health.smart_passed == Some(false) {
+			health.problems.push(Msg::SmartFailing.to_string());
+		}
+	}
+	health
+}
+
+/// SMART RETURN STATUS through the HDIO_DRIVE_TASK ioctl, which libata
+/// supports for ATA drives
+#[cfg(feature = "smart")]
+fn smart_status(disk: &Path) -> Option<bool> {
+	use std::os::unix::io::AsRawFd;
+	const HDIO_DRIVE_TASK: libc::c_ulong = 0x031e;
+	const ATA_CMD_SMART: u8 = 0xb0;
+	const SMART_STATUS: u8 = 0xda;
+
+	let dev = std::fs::File::open(disk).ok()?;
+	// command, feature, nsect, lbal, lbam, lbah, device
+	let mut args: [u8; 7] = [ATA_CMD_SMART, SMART_STATUS, 0, 0, 0x4f, 0xc2, 0];
+	let ret = unsafe { libc::ioctl(dev.as_raw_fd(), HDIO_DRIVE_TASK, args.as_mut_ptr()) };
+	if ret != 0 {
+		return None;
+	}
+	match (args[4], args[5]) {
+		(0x4f, 0xc2) => Some(true),
+		(0xf4, 0x2c) => Some(false),
+		_ => None,
+	}
+}
@@ -0,0 +1,30 @@
+//! Just enough JSON for --json output, without pulling in serde. Values are
+//! passed around already encoded.
+
+pub fn string(s: &str) -> String {
+	let mut out = String::from("\"");
+	for c in s.chars() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	out
+}
+
+/// `null` for `None`, so that fields are never left out
+pub fn nullable<T>(v: Option<T>, encode: impl FnOnce(T) -> String) -> String {
+	v.map_or_else(|| "null".to_owned(), encode)
+}
+
+pub fn array(items: impl IntoIterator<Item = String>) -> String {
+	format!("[{}]", items.into_iter().collect::<Vec<_>>().join(","))
+}
+
+pub fn object(fields: &[(&str, String)]) -> String {
+	let fields: Vec<_> = fields.iter().map(|(k, v)| format!("{}:{}", string(k), v)).collect();
+	format!("{{{}}}", fields.join(","))
+}
@@ -1,48 +1,106 @@
+use crate::messages::Msg;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
 use tracing::info;
 
-fn check_for_key(key_name: &std::ffi::CStr) -> anyhow::Result<bool> {
+/// Failure of a keyring syscall
+#[derive(Debug)]
+pub enum KeyringError {
+	/// The keyring syscalls don't exist (ENOSYS) or aren't permitted (EPERM),
+	/// as in containers that filter them out
+	Unavailable(errno::Errno),
+	Other(errno::Errno),
+}
+
+impl KeyringError {
+	fn last() -> Self {
+		let e = errno::errno();
+		match e.0 {
+			libc::ENOSYS | libc::EPERM => KeyringError::Unavailable(e),
+			_ => KeyringError::Other(e),
+		}
+	}
+}
+
+impl std::fmt::Display for KeyringError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			KeyringError::Unavailable(e) => write!(f, "{}", msg!(KeyringUnavailable, e)),
+			KeyringError::Other(e) => e.fmt(f),
+		}
+	}
+}
+
+impl std::error::Error for KeyringError {}
+
+/// Serial number of the key `key_name` in the user keyring, if it is there
+fn find_key(key_name: &std::ffi::CStr) -> anyhow::Result<Option<bch_bindgen::keyutils::key_serial_t>> {
 	use bch_bindgen::keyutils::{self, keyctl_search};
 	let key_name = key_name.to_bytes_with_nul().as_ptr() as *const _;
 	let key_type = c_str!("logon");
 
 	let key_id = unsafe { keyctl_search(keyutils::KEY_SPEC_USER_KEYRING, key_type, key_name, 0) };
 	if key_id > 0 {
-		info!("Key has became avaiable");
-		Ok(true)
+		Ok(Some(key_id as _))
 	} else if errno::errno().0 != libc::ENOKEY {
-		Err(crate::ErrnoError(errno::errno()).into())
+		Err(KeyringError::last().into())
 	} else {
-		Ok(false)
+		Ok(None)
 	}
 }
 
-fn wait_for_key(uuid: &uuid::Uuid) -> anyhow::Result<()> {
-	let key_name = std::ffi::CString::new(format!("bcachefs:{}", uuid)).unwrap();
-	loop {
-		if check_for_key(&key_name)? {
-			break Ok(());
-		}
+fn check_for_key(key_name: &std::ffi::CStr) -> anyhow::Result<bool> {
+	let found = find_key(key_name)?.is_some();
+	if found {
+		info!("Key has became avaiable");
+	}
+	Ok(found)
+}
 
-		std::thread::sleep(std::time::Duration::from_secs(1));
+/// Whether the key for `fs` is already in the keyring
+pub fn key_loaded(fs: &FileSystem) -> anyhow::Result<bool> {
+	let key_name = std::ffi::CString::new(format!("bcachefs:{}", fs.uuid())).unwrap();
+	let loaded = check_for_key(&key_name)?;
+	fs.set_key_loaded(loaded);
+	Ok(loaded)
+}
+
+/// Revoke the key for the filesystem `uuid` and unlink it from the user
+/// keyring, so that it doesn't stay resident after unmounting until the
+/// session ends. Returns whether there was a key to forget.
+pub fn forget_key(uuid: &uuid::Uuid, audit: &dyn crate::audit::Sink) -> anyhow::Result<bool> {
+	use bch_bindgen::keyutils::{keyctl_revoke, keyctl_unlink, KEY_SPEC_USER_KEYRING};
+
+	let key_name = std::ffi::CString::new(format!("bcachefs:{}", uuid)).unwrap();
+	let key_id = match find_key(&key_name)? {
+		Some(key_id) => key_id,
+		None => return Ok(false),
+	};
+	// revoked first, so that it is unusable even where it is linked elsewhere
+	if unsafe { keyctl_revoke(key_id) } < 0 {
+		return Err(anyhow::Error::new(KeyringError::last()).context(Msg::ForgetKeyFailed));
+	}
+	if unsafe { keyctl_unlink(key_id, KEY_SPEC_USER_KEYRING) } < 0 {
+		return Err(anyhow::Error::new(KeyringError::last()).context(Msg::ForgetKeyFailed));
 	}
+	info!(msg = "forgot key", %uuid, key_id);
+	let mut record = crate::audit::Record::new(crate::audit::Op::KeyRemoved, *uuid);
+	record.serial = Some(key_id);
+	audit.record(&record);
+	Ok(true)
 }
 
 const BCH_KEY_MAGIC: &str = "bch**key";
 use crate::filesystem::FileSystem;
-fn ask_for_key(fs: &FileSystem) -> anyhow::Result<()> {
-	use anyhow::anyhow;
+
+/// Derive the key from `pass` and check it against the encrypted key in the
+/// superblock `sb`.
+fn decrypt_key(sb: &bch_bindgen::bcachefs::bch_sb, pass: &str) -> anyhow::Result<bch_bindgen::bcachefs::bch_key> {
 	use byteorder::{LittleEndian, ReadBytesExt};
 	use bch_bindgen::bcachefs::{self, bch2_chacha_encrypt_key, bch_encrypted_key, bch_key};
-	use std::os::raw::c_char;
-
-	let key_name = std::ffi::CString::new(format!("bcachefs:{}", fs.uuid())).unwrap();
-	if check_for_key(&key_name)? {
-		return Ok(());
-	}
 
 	let bch_key_magic = BCH_KEY_MAGIC.as_bytes().read_u64::<LittleEndian>().unwrap();
-	let crypt = fs.sb().sb().crypt().unwrap();
-	let pass = rpassword::read_password_from_tty(Some("Enter passphrase: "))?;
+	let crypt = sb.crypt().unwrap();
 	let pass = std::ffi::CString::new(pass.trim_end())?; // bind to keep the CString alive
 	let mut output: bch_key = unsafe {
 		bcachefs::derive_passphrase(
@@ -55,43 +113,535 @@ fn ask_for_key(fs: &FileSystem) -> anyhow::Result<()> {
 	let ret = unsafe {
 		bch2_chacha_encrypt_key(
 			&mut output as *mut _,
-			fs.sb().sb().nonce(),
+			sb.nonce(),
 			&mut key as *mut _ as *mut _,
 			std::mem::size_of::<bch_encrypted_key>() as u64,
 		)
 	};
 	if ret != 0 {
-		Err(anyhow!("chacha decryption failure"))
+		Err(err!(ChachaFailure))
 	} else if key.magic != bch_key_magic {
-		Err(anyhow!("failed to verify the password"))
+		Err(err!(WrongPassphrase))
 	} else {
-		let key_type = c_str!("logon");
-		let ret = unsafe {
-			bch_bindgen::keyutils::add_key(
-				key_type,
-				key_name.as_c_str().to_bytes_with_nul() as *const _ as *const c_char,
-				&output as *const _ as *const _,
-				std::mem::size_of::<bch_key>() as u64,
-				bch_bindgen::keyutils::KEY_SPEC_USER_KEYRING,
-			)
+		Ok(output)
+	}
+}
+
+/// Add the key of `fs` to the user keyring, and record that with `audit`
+fn add_key(
+	fs: &FileSystem,
+	key: &bch_bindgen::bcachefs::bch_key,
+	audit: &dyn crate::audit::Sink,
+) -> anyhow::Result<()> {
+	use std::os::raw::c_char;
+
+	let key_name = std::ffi::CString::new(format!("bcachefs:{}", fs.uuid())).unwrap();
+	let key_type = c_str!("logon");
+	let ret = unsafe {
+		bch_bindgen::keyutils::add_key(
+			key_type,
+			key_name.to_bytes_with_nul() as *const _ as *const c_char,
+			key as *const _ as *const _,
+			std::mem::size_of::<bch_bindgen::bcachefs::bch_key>() as u64,
+			bch_bindgen::keyutils::KEY_SPEC_USER_KEYRING,
+		)
+	};
+	if ret == -1 {
+		return Err(anyhow::Error::new(KeyringError::last()).context(Msg::AddKeyFailed));
+	}
+	let mut record = crate::audit::Record::new(crate::audit::Op::KeyAdded, *fs.uuid());
+	record.serial = Some(ret);
+	record.devices = fs.members().iter().map(|m| m.path().to_owned()).collect();
+	audit.record(&record);
+	Ok(())
+}
+
+/// Which filesystem a passphrase prompt is for, so that users with several
+/// encrypted ones know which passphrase is asked for
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptContext {
+	pub uuid: uuid::Uuid,
+	pub label: Option<String>,
+	pub devices: Vec<std::path::PathBuf>,
+	/// The attempt this prompt is, counting from 1
+	pub attempt: u32,
+	pub attempts: u32,
+}
+
+impl PromptContext {
+	pub fn new(fs: &FileSystem, attempt: u32, attempts: u32) -> Self {
+		PromptContext {
+			uuid: *fs.uuid(),
+			label: fs.label(),
+			devices: fs.members().iter().map(|m| m.path().to_owned()).collect(),
+			attempt,
+			attempts,
+		}
+	}
+}
+
+/// The line shown above a prompt, e.g. `Unlocking "tank"
+/// (8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a) on /dev/sda /dev/sdb, attempt 1 of 3`
+impl std::fmt::Display for PromptContext {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		use itertools::Itertools;
+
+		let name = match &self.label {
+			Some(label) => format!("\"{}\" ({})", label, self.uuid),
+			None => self.uuid.to_string(),
+		};
+		let devices = self.devices.iter().map(|d| d.display()).join(" ");
+		f.write_str(&msg!(PassphraseContext, name, devices, self.attempt, self.attempts))
+	}
+}
+
+/// Where the passphrase comes from with `--key-location=ask`. The command
+/// line asks on the terminal (`TtyPrompt`); a frontend embedding the crate
+/// can bring its own, e.g. a dialog, and keep deriving the key and adding it
+/// to the keyring as is. Closures taking a `PromptContext` work too.
+pub trait PassphraseProvider {
+	/// Ask for the passphrase of the filesystem `context` describes. Called
+	/// again after a wrong one; an error, e.g. because the user cancelled,
+	/// ends the unlock attempt.
+	fn prompt(&self, context: &PromptContext) -> anyhow::Result<String>;
+}
+
+impl<F: Fn(&PromptContext) -> anyhow::Result<String>> PassphraseProvider for F {
+	fn prompt(&self, context: &PromptContext) -> anyhow::Result<String> {
+		self(context)
+	}
+}
+
+/// Prompts on the controlling terminal, below a line saying what for
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TtyPrompt {
+	/// Prompt even if stdin isn't a terminal
+	pub force: bool,
+	/// Without a terminal, read the passphrase from stdin as typed, shown
+	pub insecure_echo: bool,
+}
+
+impl TtyPrompt {
+	/// Fail unless `fd`, stdin when prompting, is a terminal or `force` is
+	/// set. Under a systemd unit or cron, the prompt would go to a pipe and
+	/// read EOF, and nobody would ever type the passphrase.
+	pub fn check_terminal(&self, fd: std::os::unix::io::RawFd) -> anyhow::Result<()> {
+		if !self.force && unsafe { libc::isatty(fd) } != 1 {
+			return Err(err!(NoTerminal));
+		}
+		Ok(())
+	}
+}
+
+impl TtyPrompt {
+	/// Show `prompt` and read a passphrase from the first of these that
+	/// works: the terminal `tty`, normally /dev/tty, or `stdin` if it is a
+	/// terminal, both with echo off; or with `insecure_echo`, a line from
+	/// `stdin` as is. Rescue shells may lack /dev/tty with the console on
+	/// stdin.
+	pub fn read(&self, prompt: &str, tty: &std::path::Path, stdin: RawFd) -> anyhow::Result<String> {
+		use std::os::unix::fs::OpenOptionsExt;
+
+		let stdin_is_tty = unsafe { libc::isatty(stdin) } == 1;
+		if !self.insecure_echo {
+			self.check_terminal(stdin)?;
+		}
+		if stdin_is_tty || self.force {
+			let opened = std::fs::OpenOptions::new().read(true).write(true).custom_flags(libc::O_NOCTTY).open(tty);
+			match opened {
+				Ok(mut terminal) => return read_hidden(terminal.as_raw_fd(), &mut terminal, prompt),
+				Err(e) => info!(msg = "terminal can't be opened for the prompt", tty = %tty.display(), error = %e),
+			}
+			if stdin_is_tty {
+				info!(msg = "prompting on stdin, a terminal");
+				return read_hidden(stdin, &mut std::io::stderr(), prompt);
+			}
+		}
+		if !self.insecure_echo {
+			return Err(err!(NoTerminal));
+		}
+		tracing::warn!(
+			msg = "no terminal to prompt on, the passphrase is shown as it is typed",
+			option = "--insecure-echo-prompt"
+		);
+		write!(std::io::stderr(), "{}", prompt)?;
+		read_line(stdin)
+	}
+}
+
+impl PassphraseProvider for TtyPrompt {
+	fn prompt(&self, context: &PromptContext) -> anyhow::Result<String> {
+		let prompt = format!("{}\n{}", context, Msg::PassphrasePrompt.text());
+		self.read(&prompt, std::path::Path::new("/dev/tty"), libc::STDIN_FILENO)
+	}
+}
+
+/// Echo turned off on the terminal `fd` until dropped, also when unwinding
+/// from a panic, so that a shell isn't left without it
+pub struct EchoOff {
+	fd: RawFd,
+	saved: libc::termios,
+}
+
+impl EchoOff {
+	pub fn new(fd: RawFd) -> std::io::Result<Self> {
+		let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+		if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+		let mut termios = saved;
+		// the newline still shows, so that what follows starts on a line
+		// of its own
+		termios.c_lflag &= !libc::ECHO;
+		termios.c_lflag |= libc::ECHONL;
+		if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) } != 0 {
+			return Err(std::io::Error::last_os_error());
+		}
+		Ok(EchoOff { fd, saved })
+	}
+}
+
+impl Drop for EchoOff {
+	fn drop(&mut self) {
+		if unsafe { libc::tcsetattr(self.fd, libc::TCSANOW, &self.saved) } != 0 {
+			tracing::error!(msg = "could not turn echo back on", error = %errno::errno());
+		}
+	}
+}
+
+/// Write `prompt` to `out` and read a line from the terminal `fd` with echo
+/// off
+fn read_hidden(fd: RawFd, out: &mut impl Write, prompt: &str) -> anyhow::Result<String> {
+	write!(out, "{}", prompt)?;
+	out.flush()?;
+	let _echo_off = EchoOff::new(fd)?;
+	read_line(fd)
+}
+
+/// A line from `fd`, a byte at a time so that nothing after it is taken
+/// from stdin, without the line end. EOF ends it too.
+fn read_line(fd: RawFd) -> anyhow::Result<String> {
+	use std::io::Read;
+	use std::os::unix::io::FromRawFd;
+
+	// borrowed, not to be closed
+	let mut file = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(fd) });
+	let mut line = Vec::new();
+	let mut byte = [0u8];
+	loop {
+		match file.read(&mut byte) {
+			Ok(0) => break,
+			Ok(_) if byte[0] == b'\n' => break,
+			Ok(_) => line.push(byte[0]),
+			Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+			Err(e) => return Err(e.into()),
+		}
+	}
+	if line.last() == Some(&b'\r') {
+		line.pop();
+	}
+	Ok(String::from_utf8(line)?)
+}
+
+/// Prompt for the passphrase once, as attempt `attempt` of `attempts`, and
+/// return whether it unlocked the filesystem. An empty one, as read at EOF,
+/// ends the prompts unless `allow_empty` is set.
+fn ask_for_key(
+	fs: &FileSystem,
+	attempt: u32,
+	attempts: u32,
+	allow_empty: bool,
+	provider: &dyn PassphraseProvider,
+	audit: &dyn crate::audit::Sink,
+) -> anyhow::Result<bool> {
+	let pass = provider.prompt(&PromptContext::new(fs, attempt, attempts))?;
+	// decrypt_key ignores the line ending too
+	if pass.trim_end().is_empty() && !allow_empty {
+		return Err(err!(EmptyPassphrase));
+	}
+	match decrypt_key(fs.sb().sb(), &pass) {
+		Ok(key) => add_key(fs, &key, audit).map(|()| true),
+		Err(e) => {
+			tracing::warn!(msg = "could not unlock filesystem", error = %e);
+			Ok(false)
+		}
+	}
+}
+
+/// Try each of `passphrases` in turn and add the key to the keyring for the
+/// first one that unlocks the filesystem. Returns whether any of them did (or
+/// the key was already loaded).
+#[tracing_attributes::instrument(skip(passphrases, audit))]
+pub fn try_passphrases(
+	fs: &FileSystem,
+	passphrases: &[crate::Passphrase],
+	audit: &dyn crate::audit::Sink,
+) -> anyhow::Result<bool> {
+	let key_name = std::ffi::CString::new(format!("bcachefs:{}", fs.uuid())).unwrap();
+	if check_for_key(&key_name)? {
+		fs.set_key_loaded(true);
+		return Ok(true);
+	}
+
+	match derive_candidate(fs.sb().sb(), passphrases) {
+		Some((i, key)) => use_candidate(fs, i, &key, audit),
+		None => {
+			info!(msg = "no candidate passphrase matched", count = passphrases.len());
+			Ok(false)
+		}
+	}
+}
+
+/// The key from the first of `passphrases` that unlocks `sb`, with its index
+fn derive_candidate(
+	sb: &bch_bindgen::bcachefs::bch_sb,
+	passphrases: &[crate::Passphrase],
+) -> Option<(usize, bch_bindgen::bcachefs::bch_key)> {
+	passphrases.iter().enumerate().find_map(|(i, pass)| Some((i, decrypt_key(sb, &pass.0).ok()?)))
+}
+
+/// Add `key`, from candidate passphrase `i`, to the keyring for `fs`
+fn use_candidate(
+	fs: &FileSystem,
+	i: usize,
+	key: &bch_bindgen::bcachefs::bch_key,
+	audit: &dyn crate::audit::Sink,
+) -> anyhow::Result<bool> {
+	info!(msg = "unlocked filesystem with candidate passphrase", index = i + 1);
+	add_key(fs, key, audit)?;
+	fs.set_key_loaded(true);
+	Ok(true)
+}
+
+/// Candidate passphrases tried on a thread of their own while probing goes
+/// on, against a copy of the superblock of the first member found. Only the
+/// key is derived there: it goes into the keyring, if it isn't there yet,
+/// once the probe is done, so a derivation that turns out not to be needed
+/// can be left to finish on its own.
+pub struct Derivation {
+	uuid: uuid::Uuid,
+	paths: crate::paths::Paths,
+	passphrases: Vec<crate::Passphrase>,
+	/// Whether there is nothing to fall back on if no passphrase matches,
+	/// as with --key-location=fail
+	last_resort: bool,
+	receiver: std::sync::mpsc::Receiver<(Option<(usize, bch_bindgen::bcachefs::bch_key)>, std::time::Duration)>,
+	derived: Option<(Option<(usize, bch_bindgen::bcachefs::bch_key)>, std::time::Duration)>,
+}
+
+impl Derivation {
+	/// Start deriving the key of `fs`, as found on its first member, from
+	/// `passphrases`
+	pub fn start(fs: &FileSystem, passphrases: Vec<crate::Passphrase>, last_resort: bool) -> std::io::Result<Self> {
+		let (sender, receiver) = std::sync::mpsc::channel();
+		let sb = bch_bindgen::rs::SbBuf::copy_of(fs.sb().sb());
+		let candidates = passphrases.clone();
+		let span = tracing::info_span!("derivation", uuid = %fs.uuid());
+		std::thread::Builder::new().name("key derivation".to_owned()).spawn(move || {
+			let _entered = span.enter();
+			let start = std::time::Instant::now();
+			let derived = derive_candidate(sb.sb(), &candidates);
+			// nobody listening any more if the probe failed
+			let _ = sender.send((derived, start.elapsed()));
+		})?;
+		info!(msg = "deriving the key while probing goes on", count = passphrases.len());
+		Ok(Derivation { uuid: *fs.uuid(), paths: fs.paths().clone(), passphrases, last_resort, receiver, derived: None })
+	}
+
+	/// Whether probing should go on: not if none of the passphrases matched,
+	/// with nothing to fall back on, the key isn't in the keyring and the
+	/// filesystem isn't mounted, which would make the mount fail anyway.
+	/// Doesn't wait for the derivation.
+	pub fn check(&mut self) -> anyhow::Result<()> {
+		if self.derived.is_none() {
+			self.derived = self.receiver.try_recv().ok();
+		}
+		if let Some((None, _)) = self.derived {
+			if std::mem::replace(&mut self.last_resort, false) {
+				let key_name = std::ffi::CString::new(format!("bcachefs:{}", self.uuid)).unwrap();
+				// when in doubt, the key phase finds out
+				let keyless = matches!(find_key(&key_name), Ok(None));
+				if keyless && matches!(crate::mounts::mountpoints_for(self.uuid, &self.paths), Ok(m) if m.is_empty()) {
+					let count = self.passphrases.len();
+					info!(msg = "no candidate passphrase matched, not probing any further", count);
+					return Err(err!(NoKeyAvailable));
+				}
+			}
+		}
+		Ok(())
+	}
+
+	/// [`try_passphrases`] for `fs`, as the probe found it, with the key
+	/// derived if it is the filesystem the derivation started on. Also
+	/// returns how long the derivation took.
+	pub fn finish(
+		self,
+		fs: &FileSystem,
+		audit: &dyn crate::audit::Sink,
+	) -> anyhow::Result<(bool, Option<std::time::Duration>)> {
+		if *fs.uuid() != self.uuid {
+			info!(msg = "probing settled on another filesystem than the key was derived for", derived_for = %self.uuid);
+			return Ok((try_passphrases(fs, &self.passphrases, audit)?, None));
+		}
+		let key_name = std::ffi::CString::new(format!("bcachefs:{}", fs.uuid())).unwrap();
+		if check_for_key(&key_name)? {
+			fs.set_key_loaded(true);
+			return Ok((true, None));
+		}
+		let (derived, took) = match self.derived {
+			Some(derived) => derived,
+			None => self.receiver.recv().map_err(|_| err!(Panicked))?,
 		};
-		if ret == -1 {
-			Err(anyhow!("failed to add key to keyring: {}", errno::errno()))
-		} else {
-			Ok(())
+		match derived {
+			Some((i, key)) => Ok((use_candidate(fs, i, &key, audit)?, Some(took))),
+			None => {
+				info!(msg = "no candidate passphrase matched", count = self.passphrases.len());
+				Ok((false, Some(took)))
+			}
 		}
 	}
 }
 
-#[tracing_attributes::instrument]
-pub fn prepare_key(fs: &FileSystem, password: crate::KeyLocation) -> anyhow::Result<()> {
+/// What each of the attempts of [`prepare_key`] does
+enum Unlock {
+	/// Look for the key in the keyring, a second after the last look
+	Poll,
+	/// Ask for the passphrase, unless the key was loaded elsewhere meanwhile
+	Prompt,
+}
+
+/// Get the key for `fs` into the keyring from `password`, asking `provider`
+/// for the passphrase if need be. Prompts and keyring polls are both charged
+/// against `max_attempts`, so an unattended boot can't hang here forever.
+/// Empty passphrases are only tried with `allow_empty`.
+///
+/// Nobody is asked for anything if the key is in the keyring already, or if
+/// the filesystem is mounted, as the kernel has unlocked it then.
+#[tracing_attributes::instrument(skip(provider, audit))]
+pub fn prepare_key(
+	fs: &FileSystem,
+	password: crate::KeyLocation,
+	max_attempts: u32,
+	allow_empty: bool,
+	provider: &dyn PassphraseProvider,
+	audit: &dyn crate::audit::Sink,
+) -> anyhow::Result<()> {
 	use crate::KeyLocation::*;
-	use anyhow::anyhow;
 
 	tracing::info!(msg = "checking if key exists for filesystem");
-	match password {
-		Fail => Err(anyhow!("no key available")),
-		Wait => Ok(wait_for_key(fs.uuid())?),
-		Ask => ask_for_key(fs),
+	if key_loaded(fs)? {
+		return Ok(());
+	}
+	if !fs.mountpoints().is_empty() {
+		info!(msg = "filesystem is mounted and so unlocked already, not loading its key");
+		return Ok(());
+	}
+	let step = match password {
+		Fail => return Err(err!(NoKeyAvailable)),
+		Wait => Unlock::Poll,
+		Ask => Unlock::Prompt,
+	};
+	let key_name = std::ffi::CString::new(format!("bcachefs:{}", fs.uuid())).unwrap();
+	for attempt in 1..=max_attempts {
+		let unlocked = match step {
+			Unlock::Poll => {
+				if attempt > 1 {
+					std::thread::sleep(std::time::Duration::from_secs(1));
+				}
+				check_for_key(&key_name)?
+			}
+			// the key may have been loaded elsewhere since the last prompt
+			Unlock::Prompt => {
+				(attempt > 1 && check_for_key(&key_name)?)
+					|| ask_for_key(fs, attempt, max_attempts, allow_empty, provider, audit)?
+			}
+		};
+		if unlocked {
+			fs.set_key_loaded(true);
+			return Ok(());
+		}
+	}
+	Err(match step {
+		Unlock::Poll => err!(KeyWaitTimedOut, max_attempts),
+		Unlock::Prompt => err!(PromptsExhausted, max_attempts),
+	})
+}
+
+/// An fstab line for the filesystem `uuid` that has it prompt for the key,
+/// with the mountpoint escaped as fstab wants it
+pub fn fstab_example(uuid: &uuid::Uuid, mountpoint: Option<&std::path::Path>) -> String {
+	let mountpoint = mountpoint.map_or_else(|| "/mnt".into(), |m| m.to_string_lossy());
+	let mountpoint = mountpoint.replace('\\', "\\134").replace(' ', "\\040").replace('\t', "\\011");
+	format!("UUID={} {} bcachefs defaults,{}ask 0 0", uuid, mountpoint, crate::KEY_LOCATION_OPTION)
+}
+
+/// The uid outside of a user namespace that `uid` inside it is mapped to by
+/// `uid_map`, in the format of /proc/self/uid_map
+pub fn outside_uid(uid_map: &str, uid: libc::uid_t) -> Option<libc::uid_t> {
+	uid_map.lines().find_map(|line| {
+		let mut fields = line.split_whitespace().map(|f| f.parse::<libc::uid_t>());
+		let (inside, outside, count) = match (fields.next()?, fields.next()?, fields.next()?) {
+			(Ok(inside), Ok(outside), Ok(count)) => (inside, outside, count),
+			_ => return None,
+		};
+		let offset = uid.checked_sub(inside).filter(|o| *o < count)?;
+		outside.checked_add(offset)
+	})
+}
+
+/// Warn if this process runs in a user namespace mapping its uid to another
+/// one outside: keys then go to the user keyring of the namespaced uid, which
+/// the kernel may not consult at mount time, failing it with ENOKEY.
+pub fn check_user_namespace() {
+	let uid = unsafe { libc::getuid() };
+	let uid_map = match std::fs::read_to_string("/proc/self/uid_map") {
+		Ok(uid_map) => uid_map,
+		Err(_) => return,
+	};
+	match outside_uid(&uid_map, uid) {
+		Some(outside) if outside != uid => {
+			tracing::warn!(
+				msg = "running in a user namespace, the key goes to the user keyring of the namespaced uid; if the mount fails with ENOKEY, try --keyring-owner-uid from outside the namespace",
+				uid,
+				outside_uid = outside
+			)
+		}
+		_ => {}
+	}
+}
+
+/// While held, keys go to the user keyring of another uid, by making it the
+/// real uid of this process. The effective uid is left alone.
+#[derive(Debug)]
+pub struct KeyringOwner {
+	saved: libc::uid_t,
+}
+
+impl KeyringOwner {
+	/// Switch to the user keyring of `uid`, which takes CAP_SETUID. From the
+	/// initial user namespace, this is the keyring the kernel consults for a
+	/// mount by that user.
+	pub fn switch(uid: libc::uid_t) -> anyhow::Result<Self> {
+		use bch_bindgen::keyutils::{keyctl_get_keyring_ID, KEY_SPEC_USER_KEYRING};
+
+		let saved = unsafe { libc::getuid() };
+		if unsafe { libc::setresuid(uid, !0, !0) } != 0 {
+			return Err(err!(KeyringOwnerFailed, uid, errno::errno()));
+		}
+		let owner = KeyringOwner { saved };
+		let keyring = unsafe { keyctl_get_keyring_ID(KEY_SPEC_USER_KEYRING, 1) };
+		if keyring < 0 {
+			return Err(err!(KeyringOwnerFailed, uid, KeyringError::last()));
+		}
+		info!(msg = "using the user keyring of another uid", uid, keyring);
+		Ok(owner)
+	}
+}
+
+impl Drop for KeyringOwner {
+	fn drop(&mut self) {
+		if unsafe { libc::setresuid(self.saved, !0, !0) } != 0 {
+			tracing::error!(msg = "could not restore the real uid", uid = self.saved, error = %errno::errno());
+		}
 	}
 }
@@ -1,4 +1,3 @@
-use anyhow::anyhow;
 use structopt::StructOpt;
 
 pub mod err {
@@ -22,6 +21,9 @@ macro_rules! c_str {
 	};
 }
 
+#[macro_use]
+pub mod messages;
+
 #[derive(Debug)]
 struct ErrnoError(errno::Errno);
 impl std::fmt::Display for ErrnoError {
@@ -31,13 +33,37 @@ impl std::fmt::Display for ErrnoError {
 }
 impl std::error::Error for ErrnoError {}
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum KeyLocation {
 	Fail,
 	Wait,
 	Ask,
 }
 
+#[derive(Debug, PartialEq)]
+pub enum HealthCheck {
+	Warn,
+	Strict,
+}
+impl std::str::FromStr for HealthCheck {
+	type Err = anyhow::Error;
+	fn from_str(s: &str) -> anyhow::Result<Self> {
+		match s {
+			"warn" => Ok(HealthCheck::Warn),
+			"strict" => Ok(HealthCheck::Strict),
+			_ => Err(err!(InvalidHealthCheckMode)),
+		}
+	}
+}
+
+/// Mount option for the key location, as fstab has no other way to give it
+pub const KEY_LOCATION_OPTION: &str = "x-bcachefs.key_location=";
+
+/// Mount option for a recovery pass to run; the libbcachefs of this tree
+/// predates recovery passes, so it is rejected rather than dropped with the
+/// other x-* options
+pub const RECOVERY_PASS_OPTION: &str = "x-bcachefs.recovery_pass=";
+
 #[derive(Debug)]
 pub struct KeyLoc(pub Option<KeyLocation>);
 impl std::ops::Deref for KeyLoc {
@@ -55,13 +81,97 @@ impl std::str::FromStr for KeyLoc {
 			"fail" => Ok(KeyLoc(Some(KeyLocation::Fail))),
 			"wait" => Ok(KeyLoc(Some(KeyLocation::Wait))),
 			"ask" => Ok(KeyLoc(Some(KeyLocation::Ask))),
-			_ => Err(anyhow!("invalid password option")),
+			_ => Err(err!(InvalidKeyLocation)),
+		}
+	}
+}
+
+/// A passphrase from the command line or a file, kept out of Debug output so
+/// it never ends up in the logs
+#[derive(Clone)]
+pub struct Passphrase(pub String);
+impl std::fmt::Debug for Passphrase {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.write_str("Passphrase(..)")
+	}
+}
+impl std::str::FromStr for Passphrase {
+	type Err = std::convert::Infallible;
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Ok(Passphrase(s.to_owned()))
+	}
+}
+
+fn parse_offset(s: &str) -> anyhow::Result<u64> {
+	let offset = s.parse()?;
+	loopdev::check_offset(offset)?;
+	Ok(offset)
+}
+
+/// Parse a filesystem UUID, rejecting ones that parse fine but can't identify
+/// a filesystem
+fn parse_fs_uuid(s: &str) -> anyhow::Result<uuid::Uuid> {
+	let uuid: uuid::Uuid = s.parse()?;
+	if uuid.is_nil() {
+		Err(err!(NilUuid))
+	} else if uuid == bch_bindgen::rs::SUPERBLOCK_MAGIC {
+		Err(err!(MagicUuid))
+	} else {
+		Ok(uuid)
+	}
+}
+
+/// Which filesystem to mount: its UUID, a prefix of it, or `LABEL=<label>`
+#[derive(Debug, Clone, PartialEq)]
+pub enum FsSpec {
+	Uuid(uuid::Uuid),
+	/// The start of the UUID as written, lowercase, with or without dashes
+	Prefix(String),
+	Label(String),
+}
+
+impl std::str::FromStr for FsSpec {
+	type Err = anyhow::Error;
+	fn from_str(s: &str) -> anyhow::Result<Self> {
+		if let Some(label) = s.strip_prefix("LABEL=") {
+			return match label {
+				"" => Err(err!(InvalidFsSpec, s)),
+				label => Ok(FsSpec::Label(label.to_owned())),
+			};
+		}
+		if s.parse::<uuid::Uuid>().is_ok() {
+			return parse_fs_uuid(s).map(FsSpec::Uuid);
+		}
+		if !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit() || c == '-') {
+			return Ok(FsSpec::Prefix(s.to_ascii_lowercase()));
+		}
+		Err(err!(InvalidFsSpec, s))
+	}
+}
+
+impl std::fmt::Display for FsSpec {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			FsSpec::Uuid(uuid) => write!(f, "{}", uuid),
+			FsSpec::Prefix(prefix) => write!(f, "{}", prefix),
+			FsSpec::Label(label) => write!(f, "LABEL={}", label),
+		}
+	}
+}
+
+impl FsSpec {
+	/// The UUID, if it was given in full
+	pub fn uuid(&self) -> Option<uuid::Uuid> {
+		match self {
+			FsSpec::Uuid(uuid) => Some(*uuid),
+			_ => None,
 		}
 	}
 }
 
 #[derive(StructOpt, Debug)]
 /// Mount a bcachefs filesystem by its UUID.
+#[structopt(global_setting = structopt::clap::AppSettings::DisableVersion)]
 pub struct Options {
 	/// Where the password would be loaded from.
 	///
@@ -69,11 +179,26 @@ pub struct Options {
 	/// "fail" - don't ask for password, fail if filesystem is encrypted;
 	/// "wait" - wait for password to become available before mounting;
 	/// "ask" -  prompt the user for password;
+	///
+	/// Can also be given as the x-bcachefs.key_location= mount option, e.g.
+	/// in fstab, which this overrides. Without either, a key already in the
+	/// keyring is used, and anything else fails.
 	#[structopt(short, long, default_value = "")]
 	pub key_location: KeyLoc,
 
-	/// External UUID of the bcachefs filesystem
-	pub uuid: uuid::Uuid,
+	/// External UUID of the bcachefs filesystem, enough of its start to tell
+	/// it apart from the others, or LABEL=<label>
+	///
+	/// A full internal UUID, as kernel log lines show, selects its filesystem
+	/// too.
+	#[structopt(
+		required_unless_one = &[
+			"verify", "cancel-wait", "export-messages", "version", "query", "dump-super", "doctor", "status", "watch",
+			"explain-options", "wipe-stale-sb", "check-sb-copies",
+		],
+		parse(try_from_str)
+	)]
+	pub uuid: Option<FsSpec>,
 
 	/// Where the filesystem should be mounted. If not set, then the filesystem
 	/// won't actually be mounted. But all steps preceeding mounting the
@@ -81,11 +206,537 @@ pub struct Options {
 	pub mountpoint: Option<std::path::PathBuf>,
 
 	/// Mount options
+	///
+	/// Options from the BCACHEFS_MOUNT_OPTIONS environment variable are
+	/// applied first, so these override them where they conflict: as with
+	/// mount(8), "rw" undoes "ro", "suid" undoes "nosuid" and so on, and of
+	/// noatime, relatime and strictatime the last one wins.
 	#[structopt(short, default_value = "")]
 	pub options: String,
+
+	/// Create the mountpoint if it doesn't exist
+	///
+	/// The x-mount.owner=, x-mount.group= and x-mount.mode= (octal) mount
+	/// options set the owner, group and mode of the created directory; they
+	/// don't change a directory that already exists.
+	#[structopt(long)]
+	pub mkdir: bool,
+
+	/// Apply x-mount.owner=, x-mount.group= and x-mount.mode= to an existing
+	/// mountpoint as well
+	#[structopt(long, requires = "mkdir")]
+	pub force_owner: bool,
+
+	/// Check filesystem mount options against what this libbcachefs knows,
+	/// including the values of numeric ones, and refuse to mount if one
+	/// fails. Without it, they are passed on for the kernel to judge, so that
+	/// options newer than this tool work.
+	#[structopt(long)]
+	pub strict: bool,
+
+	/// With --strict, leave out mount options that fail the check, with a
+	/// warning, instead of refusing to mount. This is what mount(8) asks for
+	/// with -s.
+	#[structopt(short, long)]
+	pub sloppy: bool,
+
+	/// Mount read-write even if that upgrades the on-disk format of an old
+	/// filesystem, which older tools then can't mount anymore
+	///
+	/// Without it, filesystems more than a couple of metadata versions behind
+	/// this bcachefs are only mounted read-only, with -o ro.
+	#[structopt(long)]
+	pub allow_upgrade: bool,
+
+	/// Increase log verbosity (-v: info, -vv: debug, -vvv: trace)
+	///
+	/// When given, this replaces any filter set in the RUST_LOG environment
+	/// variable; without it, RUST_LOG is honoured as usual. Also prints a
+	/// summary of the superblock before mounting. At debug level, libbcachefs
+	/// is made verbose too while reading superblocks, and what it prints is
+	/// logged with the device it was about.
+	#[structopt(short, long, parse(from_occurrences))]
+	pub verbose: u8,
+
+	/// Passphrase to try before falling back to --key-location; may be given
+	/// more than once
+	#[structopt(long, value_name = "passphrase", number_of_values = 1)]
+	pub try_passphrase: Vec<Passphrase>,
+
+	/// File with passphrases to try, one per line, after those given with
+	/// --try-passphrase
+	///
+	/// Useful during key rotation, when it isn't known which passphrase a
+	/// filesystem currently uses. The first one that matches is loaded into
+	/// the keyring.
+	#[structopt(long, value_name = "path")]
+	pub passphrase_file: Option<std::path::PathBuf>,
+
+	/// With --key-location=wait, fork into the background while waiting for
+	/// the key and mount once it arrives, so the caller doesn't block
+	///
+	/// The background process logs to the kernel log and records its pid in
+	/// <uuid>.pid in the runtime directory, which it keeps locked while it
+	/// waits.
+	#[structopt(long)]
+	pub fork_wait: bool,
+
+	/// Give up unlocking after this many passphrase prompts
+	/// (--key-location=ask) and keyring polls, one a second
+	/// (--key-location=wait), together
+	#[structopt(long, value_name = "n", default_value = "30")]
+	pub max_unlock_attempts: u32,
+
+	/// Prompt for the passphrase (--key-location=ask) even if stdin isn't a
+	/// terminal
+	///
+	/// Without it, asking fails when stdin is a pipe or /dev/null, as under a
+	/// systemd unit or cron, rather than reading nothing.
+	#[structopt(long)]
+	pub force_tty_prompt: bool,
+
+	/// Without a terminal to prompt on, neither /dev/tty nor stdin, read the
+	/// passphrase from stdin anyway, shown as it is typed
+	///
+	/// For consoles that aren't terminals, as in some rescue shells. Anyone
+	/// looking at the screen sees the passphrase.
+	#[structopt(long)]
+	pub insecure_echo_prompt: bool,
+
+	/// Try an empty passphrase when one is entered at the prompt, instead of
+	/// giving up
+	#[structopt(long)]
+	pub allow_empty_passphrase: bool,
+
+	/// Load the key into the user keyring of this uid rather than our own
+	///
+	/// In a container with a user namespace, the keyring the tool sees may
+	/// not be the one the kernel consults at mount time. Run from the initial
+	/// namespace with CAP_SETUID, this targets that user's keyring there.
+	#[structopt(long, value_name = "uid")]
+	pub keyring_owner_uid: Option<libc::uid_t>,
+
+	/// Also send audit records of keys added and encrypted filesystems
+	/// mounted to the kernel's audit subsystem, as root; they are logged
+	/// either way
+	#[structopt(long)]
+	pub audit: bool,
+
+	/// Stop the --fork-wait process waiting on the filesystem with this UUID
+	#[structopt(long, value_name = "uuid", parse(try_from_str = parse_fs_uuid))]
+	pub cancel_wait: Option<uuid::Uuid>,
+
+	/// Check the health of each member device before mounting
+	///
+	/// Reports the kernel's view of each device (state, read-only flag, I/O
+	/// error count) and, if built with the "smart" feature, the drive's SMART
+	/// status. Problems are warnings, or errors with
+	/// --device-health-check=strict.
+	#[structopt(long, value_name = "warn|strict", require_equals = true)]
+	pub device_health_check: Option<Option<HealthCheck>>,
+
+	/// Only log errors. Ignored if --verbose is also given.
+	#[structopt(short, long)]
+	pub quiet: bool,
+
+	/// Mount using only this member device; may be given more than once
+	#[structopt(long, value_name = "path", number_of_values = 1)]
+	pub only_device: Vec<std::path::PathBuf>,
+
+	/// Leave this member device out of the mount; may be given more than once
+	///
+	/// If fewer devices than the filesystem has remain after --only-device and
+	/// --exclude-device, the mount implies -o degraded.
+	#[structopt(long, value_name = "path", number_of_values = 1)]
+	pub exclude_device: Vec<std::path::PathBuf>,
+
+	/// Probe again while the filesystem isn't found at all, e.g. "5x2s" for up
+	/// to 5 more times, 2 seconds apart
+	///
+	/// For enclosures that take a while to show their disks. A filesystem
+	/// that is found with devices missing isn't waited for; see
+	/// --min-devices.
+	#[structopt(long, value_name = "count>x<delay")]
+	pub retry: Option<retry::Retry>,
+
+	/// Mount if at least this many member devices are found, even if the
+	/// superblock lists more; refuse if fewer are
+	///
+	/// For pools with cold spares that aren't expected at every boot. Short of
+	/// all the filesystem's devices the mount still implies -o degraded, as
+	/// the kernel won't mount without it, but that is only logged as a warning
+	/// below this threshold. Without --min-devices, any number short of all
+	/// of them mounts degraded with a warning.
+	#[structopt(long, value_name = "n")]
+	pub min_devices: Option<usize>,
+
+	/// Byte offset of the filesystem within image files, e.g. of a partition
+	/// within a disk image; a multiple of 512
+	///
+	/// Image files given with --only-device are attached to loop devices
+	/// starting at this offset, which go away again on unmount. Also applies
+	/// to --dump-super and --check-sb-copies.
+	#[structopt(long, value_name = "bytes", parse(try_from_str = parse_offset))]
+	pub offset: Option<u64>,
+
+	/// Zero the magic of a stale superblock on a whole disk whose partitions
+	/// hold bcachefs, after asking for confirmation, and exit
+	///
+	/// Only the sectors before the first partition are written to.
+	#[structopt(long, value_name = "device")]
+	pub wipe_stale_sb: Option<std::path::PathBuf>,
+
+	/// Check the superblock checksum of the given device and exit, without
+	/// mounting anything
+	#[structopt(long, value_name = "device")]
+	pub verify: Option<std::path::PathBuf>,
+
+	/// Check every superblock copy the layout of the given device lists and
+	/// exit; repeat for every member device of the filesystem
+	///
+	/// Each copy's checksum is verified and its seq compared to the primary
+	/// superblock's, and the primaries of the devices given are compared to
+	/// each other. Tells whether a damaged primary can be recovered from a
+	/// backup copy. Exits with the "corrupt" status if any copy is damaged
+	/// or out of date. Also honours --offset.
+	#[structopt(long, value_name = "device", number_of_values = 1)]
+	pub check_sb_copies: Vec<std::path::PathBuf>,
+
+	/// After mounting, read this many MiB (16 without a value) back from a
+	/// few files near the top of the filesystem and report throughput and
+	/// I/O errors
+	///
+	/// Catches filesystems that mount fine but fail reads. The walk looks
+	/// only two directories deep, at a bounded number of entries each,
+	/// reads only regular files and stops after 30 seconds.
+	#[structopt(long, value_name = "MiB", require_equals = true)]
+	pub verify_after_mount: Option<Option<u64>>,
+
+	/// With --verify-after-mount, unmount again and fail if reading back
+	/// ran into I/O errors
+	#[structopt(long, requires = "verify-after-mount")]
+	pub verify_strict: bool,
+
+	/// How long to wait for another mount of the same filesystem to finish
+	#[structopt(long, value_name = "seconds", default_value = "30")]
+	pub lock_timeout: u64,
+
+	/// Mount and unlock only filesystems with this UUID or one of the other
+	/// --allow-uuid or --allow-label ones; repeatable
+	///
+	/// Deny rules win over allow rules. Without allow rules, every filesystem
+	/// no deny rule matches is allowed. Rules are checked before
+	/// --policy-exec, which only runs for allowed filesystems.
+	#[structopt(long, value_name = "uuid", number_of_values = 1)]
+	pub allow_uuid: Vec<uuid::Uuid>,
+
+	/// Never mount or unlock the filesystem with this UUID; repeatable
+	#[structopt(long, value_name = "uuid", number_of_values = 1)]
+	pub deny_uuid: Vec<uuid::Uuid>,
+
+	/// Like --allow-uuid, for filesystems with a label matching this glob,
+	/// in which * and ? are wildcards; repeatable
+	#[structopt(long, value_name = "glob", number_of_values = 1)]
+	pub allow_label: Vec<String>,
+
+	/// Like --deny-uuid, for filesystems with a label matching this glob;
+	/// repeatable
+	#[structopt(long, value_name = "glob", number_of_values = 1)]
+	pub deny_label: Vec<String>,
+
+	/// Run this command through /bin/sh after probing, before loading the key
+	/// or mounting, to decide whether to go ahead
+	///
+	/// It gets the filesystem as a `--status --json` object on stdin. Exit
+	/// status 0 goes ahead, 3 skips the filesystem without an error, and
+	/// anything else fails the mount with what the command wrote to stderr.
+	/// What it writes to stdout is logged.
+	#[structopt(long, value_name = "command")]
+	pub policy_exec: Option<String>,
+
+	/// Kill the --policy-exec command and fail the mount if it takes longer
+	#[structopt(long, value_name = "seconds", default_value = "30")]
+	pub policy_timeout: u64,
+
+	/// Probe only the devices a recent run found bcachefs on
+	///
+	/// The list is kept in the runtime directory for a minute, and dropped as
+	/// soon as udev reports any change to devices. Filesystems not on those
+	/// devices are still found, by probing every device as usual.
+	#[structopt(long)]
+	pub use_cache: bool,
+
+	/// Probe every device and rewrite the list --use-cache keeps
+	#[structopt(long, requires = "use-cache")]
+	pub refresh_cache: bool,
+
+	/// Print the UUID, label and member devices of the bcachefs filesystem
+	/// mounted here, and exit
+	#[structopt(long, value_name = "mountpoint")]
+	pub query: Option<std::path::PathBuf>,
+
+	/// Print everything the superblock of this device says, and exit. Unlike
+	/// mounting, this also works on mounted devices and on superblocks with a
+	/// bad checksum.
+	#[structopt(long, value_name = "device")]
+	pub dump_super: Option<std::path::PathBuf>,
+
+	/// With --dump-super, read the nth copy of the superblock the layout
+	/// lists instead of the primary one, e.g. when that is damaged
+	///
+	/// --dump-super shows where the copies are.
+	#[structopt(long = "sb", value_name = "n", requires = "dump-super")]
+	pub sb_copy: Option<usize>,
+
+	/// Show how each of these mount options is classified (mount flag,
+	/// bcachefs option, userspace only, unknown) and the flags and data
+	/// mount(2) would get, and exit. Honors --strict and --sloppy.
+	#[structopt(long, value_name = "options")]
+	pub explain_options: Option<String>,
+
+	/// Print one line for each bcachefs filesystem found, for monitoring, and
+	/// exit
+	///
+	/// Lines look like `uuid=... label=tank state=ok|degraded devices=3/4
+	/// encrypted=yes|no mounted=/mnt`; the keys and their order don't change.
+	#[structopt(long)]
+	pub status: bool,
+
+	/// Print --query, --dump-super and --status results as JSON
+	#[structopt(long)]
+	pub json: bool,
+
+	/// Print the version of this tool and of libbcachefs, and the on-disk
+	/// format versions it supports
+	#[structopt(short = "V", long)]
+	pub version: bool,
+
+	/// Gather what a bug report needs: versions, kernel support, the
+	/// filesystems found, keyring status and the last mount error; then exit
+	///
+	/// Key material is never included.
+	#[structopt(long)]
+	pub doctor: bool,
+
+	/// Write the --doctor report to this .tar.gz instead of printing it
+	#[structopt(long, value_name = "bundle.tar.gz", requires = "doctor")]
+	pub output: Option<std::path::PathBuf>,
+
+	/// Scrub the hostname and drive serial numbers from the --doctor report
+	#[structopt(long, requires = "doctor")]
+	pub anonymize: bool,
+
+	/// Print a JSON line for every bcachefs member device found, then keep
+	/// running and print one whenever udev adds or removes a member
+	///
+	/// Lines look like {"event":"add","uuid":...,"device":"/dev/sdb",
+	/// "devices":[...],"devices_found":2,"devices_total":3,"degraded":true}.
+	#[structopt(long)]
+	pub watch: bool,
+
+	/// With --status or --watch, also write the filesystems found to this
+	/// file, as JSON any user can read; --watch keeps it up to date
+	///
+	/// The file is replaced atomically, for agents watching it with inotify.
+	/// See the README for its schema.
+	#[structopt(long, value_name = "path")]
+	pub status_file: Option<std::path::PathBuf>,
+
+	/// Print the mount(8) command line that would mount the filesystem with
+	/// the devices and options found, including implied ones such as
+	/// degraded, and exit without mounting
+	///
+	/// An encrypted filesystem still needs its key loaded for the command to
+	/// work.
+	#[structopt(long, requires = "mountpoint")]
+	pub print_mount_command: bool,
+
+	/// Fail with exit status 12 if the filesystem is already mounted at the
+	/// mountpoint, rather than succeed without doing anything
+	#[structopt(long, requires = "mountpoint")]
+	pub fail_if_mounted: bool,
+
+	/// On failure, print a JSON object with the error, its kind, message
+	/// identifier and filesystem UUID to stderr instead of a log line
+	///
+	/// The exit status follows the kind either way: 1 other, 2
+	/// invalid_argument, 3 not_found, 4 permission, 5 wrong_passphrase, 6
+	/// key_unavailable, 7 busy, 8 unsupported, 9 corrupt, 10 device, 11
+	/// ambiguous, 12 already_mounted, 13 degraded.
+	#[structopt(long)]
+	pub json_errors: bool,
+
+	/// Print how long probing, key preparation and mounting took
+	///
+	/// The key is derived from --try-passphrase and --passphrase-file
+	/// candidates while probing goes on, so "key" is only the wait left after
+	/// the probe; how long the derivation took is printed as well.
+	#[structopt(long)]
+	pub timings: bool,
+
+	/// Write spans as Chrome trace events to this file, for Perfetto or
+	/// chrome://tracing
+	///
+	/// Spans are traced at info level regardless of -q and RUST_LOG; what's
+	/// printed to stderr then follows -v and -q only.
+	#[structopt(long, value_name = "file")]
+	pub trace_output: Option<std::path::PathBuf>,
+
+	/// Filesystem type to pass to mount(2), for kernels that register a
+	/// development build of bcachefs under another name
+	#[structopt(long, hidden = true, default_value = "bcachefs")]
+	pub fstype: String,
+
+	/// Directory for lock files, pid files and the last error, created with
+	/// mode 0700 if missing
+	///
+	/// If it can't be written to, e.g. in an initramfs without /run, mounts
+	/// go ahead without locking and --fork-wait without a pid file.
+	#[structopt(long, value_name = "path", env = "BCACHEFS_MOUNT_RUNTIME_DIR", default_value = "/run/bcachefs-mount")]
+	pub runtime_dir: std::path::PathBuf,
+
+	/// Where device nodes are, if devtmpfs isn't mounted on /dev
+	#[structopt(long, value_name = "path", default_value = "/dev")]
+	pub dev_root: std::path::PathBuf,
+
+	/// Where sysfs is mounted
+	#[structopt(long, value_name = "path", default_value = "/sys")]
+	pub sys_root: std::path::PathBuf,
+
+	/// Print the message catalog as a gettext template and exit
+	#[structopt(long, hidden = true)]
+	pub export_messages: bool,
+}
+
+/// The version of this tool and of libbcachefs, and the on-disk format
+/// versions it supports
+pub fn version_info() -> String {
+	use bch_bindgen::rs::{metadata_version_name, metadata_versions, LIBBCACHEFS_REVISION};
+
+	let name = |v: u16| match metadata_version_name(v) {
+		Some(name) => format!("{} ({})", v, name),
+		None => v.to_string(),
+	};
+	let versions = metadata_versions();
+	[
+		msg!(VersionTool, env!("CARGO_PKG_VERSION")),
+		msg!(VersionLibrary, LIBBCACHEFS_REVISION),
+		msg!(VersionOnDisk, name(*versions.start()), name(*versions.end())),
+	]
+	.iter()
+	.map(|l| format!("{}\n", l))
+	.collect()
+}
+
+impl Options {
+	pub fn paths(&self) -> paths::Paths {
+		paths::Paths {
+			runtime_dir: self.runtime_dir.clone(),
+			dev_root: self.dev_root.clone(),
+			sys_root: self.sys_root.clone(),
+		}
+	}
+
+	/// Where the key comes from: --key-location, or else the last
+	/// x-bcachefs.key_location= of the mount options. `None` if neither says.
+	pub fn key_location(&self) -> anyhow::Result<Option<KeyLocation>> {
+		if let Some(key) = *self.key_location {
+			return Ok(Some(key));
+		}
+		let options = self.mount_options();
+		match options.split(',').rev().find_map(|o| o.strip_prefix(KEY_LOCATION_OPTION)) {
+			Some(key) => Ok(key.parse::<KeyLoc>()?.0),
+			None => Ok(None),
+		}
+	}
+
+	/// The --allow-* and --deny-* rules
+	pub fn policy_rules(&self) -> Vec<policy::Rule> {
+		use policy::Rule;
+
+		let uuids = |uuids: &[uuid::Uuid], rule: fn(uuid::Uuid) -> Rule| uuids.iter().copied().map(rule).collect::<Vec<_>>();
+		let labels = |globs: &[String], rule: fn(String) -> Rule| globs.iter().cloned().map(rule).collect::<Vec<_>>();
+		[
+			uuids(&self.allow_uuid, Rule::AllowUuid),
+			uuids(&self.deny_uuid, Rule::DenyUuid),
+			labels(&self.allow_label, Rule::AllowLabel),
+			labels(&self.deny_label, Rule::DenyLabel),
+		]
+		.concat()
+	}
+
+	/// Candidate passphrases from --try-passphrase and --passphrase-file
+	pub fn passphrases(&self) -> anyhow::Result<Vec<Passphrase>> {
+		let mut passphrases = self.try_passphrase.clone();
+		if let Some(path) = &self.passphrase_file {
+			let file = std::fs::read_to_string(path)
+				.map_err(|e| err!(PassphraseFileUnreadable, path.display(), e))?;
+			passphrases.extend(file.lines().filter(|l| !l.is_empty()).map(|l| Passphrase(l.to_owned())));
+		}
+		Ok(passphrases)
+	}
+
+	/// Mount options from BCACHEFS_MOUNT_OPTIONS merged with -o, which takes
+	/// precedence.
+	pub fn mount_options(&self) -> String {
+		let env = std::env::var("BCACHEFS_MOUNT_OPTIONS").unwrap_or_default();
+		merge_mount_options(&[env.as_str(), self.options.as_str()])
+	}
+
+	/// How mount options are checked, from --strict and --sloppy
+	pub fn checking(&self) -> filesystem::Checking {
+		filesystem::Checking::new(self.strict, self.sloppy)
+	}
+
+	/// Log level requested on the command line, or `None` if the filter
+	/// should be taken from RUST_LOG.
+	pub fn log_level(&self) -> Option<tracing_subscriber::filter::LevelFilter> {
+		use tracing::Level;
+		use tracing_subscriber::filter::LevelFilter;
+
+		let level = match self.verbose {
+			0 if self.quiet => Level::ERROR,
+			0 => return None,
+			1 => Level::INFO,
+			2 => Level::DEBUG,
+			_ => Level::TRACE,
+		};
+		Some(LevelFilter::from_level(level))
+	}
+}
+
+/// Join comma-separated option lists, lowest precedence first. Later options
+/// win when parsed, so this is all the merging needed.
+pub fn merge_mount_options(layers: &[&str]) -> String {
+	layers.iter().filter(|l| !l.is_empty()).copied().collect::<Vec<_>>().join(",")
 }
 
+pub mod audit;
+pub mod cache;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod cmd;
+pub mod conflicts;
+pub mod daemon;
+pub mod doctor;
+pub mod exit;
 pub mod filesystem;
+pub mod health;
+pub mod json;
 pub mod key;
+pub mod lock;
+pub mod loopdev;
+pub mod metrics;
+pub mod mountpoint;
+pub mod mounts;
+pub mod paths;
+pub mod policy;
+pub mod readcheck;
+pub mod retry;
+pub mod stale;
+pub mod statusfile;
+pub mod sysfs_monitor;
+pub mod trace;
+pub mod udev_watch;
 
 // pub fn mnt_in_use()
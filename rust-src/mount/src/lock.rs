@@ -0,0 +1,58 @@
+//! Per-filesystem lock, so that two mounts of the same filesystem racing each
+//! other don't both ask for the key and then trip over each other in
+//! mount(2).
+//!
+//! The lock is an flock(2) on <uuid>.lock in the runtime directory, which the
+//! kernel drops when its holder exits; a lock file left behind by a process
+//! that died is simply taken over.
+
+use crate::paths::Paths;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Held lock, released when dropped
+#[derive(Debug)]
+pub struct MountLock {
+	_file: std::fs::File,
+}
+
+/// Take the lock for `uuid`, waiting up to `timeout` for another mount to
+/// finish. Without a usable runtime directory there is no lock to take, and
+/// `None` is returned.
+pub fn lock(uuid: &Uuid, timeout: Duration, paths: &Paths) -> anyhow::Result<Option<MountLock>> {
+	match paths.state_dir() {
+		Some(dir) => lock_in(dir, uuid, timeout).map(Some),
+		None => Ok(None),
+	}
+}
+
+/// Like [`lock`], with the lock file in `dir`
+pub fn lock_in(dir: &Path, uuid: &Uuid, timeout: Duration) -> anyhow::Result<MountLock> {
+	std::fs::create_dir_all(dir)?;
+	lock_file(&dir.join(format!("{}.lock", uuid)), timeout)
+}
+
+/// Take a lock on the file `path` the same way, creating it if need be, for
+/// other things that mustn't be done by two processes at once
+pub fn lock_file(path: &Path, timeout: Duration) -> anyhow::Result<MountLock> {
+	use std::os::unix::io::AsRawFd;
+
+	let file = std::fs::OpenOptions::new().create(true).write(true).open(path)?;
+
+	let deadline = Instant::now() + timeout;
+	loop {
+		if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+			return Ok(MountLock { _file: file });
+		}
+		let err = std::io::Error::last_os_error();
+		if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+			return Err(err.into());
+		}
+		if Instant::now() >= deadline {
+			return Err(err!(MountInProgress));
+		}
+		tracing::debug!(msg="waiting for the lock", path=%path.display());
+		std::thread::sleep(Duration::from_millis(100));
+	}
+}
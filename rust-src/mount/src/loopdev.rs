@@ -0,0 +1,111 @@
+//! Loop devices for image files given in place of member devices, e.g. a
+//! partition within a full disk image at `--offset`.
+//!
+//! Loop devices are set up with LO_FLAGS_AUTOCLEAR, so the kernel detaches
+//! them once nothing uses them anymore: right away if the mount fails, and
+//! on unmount otherwise.
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+const LOOP_SET_FD: libc::c_ulong = 0x4c00;
+const LOOP_CLR_FD: libc::c_ulong = 0x4c01;
+const LOOP_SET_STATUS64: libc::c_ulong = 0x4c04;
+const LOOP_CTL_GET_FREE: libc::c_ulong = 0x4c82;
+
+const LO_FLAGS_READ_ONLY: u32 = 1;
+const LO_FLAGS_AUTOCLEAR: u32 = 4;
+
+/// `struct loop_info64` from linux/loop.h
+#[repr(C)]
+struct LoopInfo64 {
+	lo_device: u64,
+	lo_inode: u64,
+	lo_rdevice: u64,
+	lo_offset: u64,
+	lo_sizelimit: u64,
+	lo_number: u32,
+	lo_encrypt_type: u32,
+	lo_encrypt_key_size: u32,
+	lo_flags: u32,
+	lo_file_name: [u8; 64],
+	lo_crypt_name: [u8; 64],
+	lo_encrypt_key: [u8; 32],
+	lo_init: [u64; 2],
+}
+
+/// Offsets have to be whole 512 byte sectors, which is what the loop driver
+/// and the superblock layout count in
+pub fn check_offset(offset: u64) -> anyhow::Result<()> {
+	if offset % 512 != 0 {
+		return Err(err!(OffsetUnaligned, offset));
+	}
+	Ok(())
+}
+
+/// Whether `path` should be attached to a loop device rather than probed
+/// as is
+pub fn is_image(path: &Path) -> bool {
+	std::fs::metadata(path).map_or(false, |m| m.file_type().is_file())
+}
+
+/// An image file attached to a loop device. The loop device stays around
+/// while this is alive, and afterwards for as long as it is mounted.
+#[derive(Debug)]
+pub struct LoopDevice {
+	path: PathBuf,
+	_dev: File,
+}
+
+impl LoopDevice {
+	/// Attach `image` to a free loop device, starting `offset` bytes into it.
+	/// Images that can't be written to are attached read-only.
+	pub fn attach(image: &Path, offset: u64) -> anyhow::Result<Self> {
+		check_offset(offset)?;
+		let (backing, read_only) = match OpenOptions::new().read(true).write(true).open(image) {
+			Ok(f) => (f, false),
+			Err(e) if e.raw_os_error() == Some(libc::EACCES) || e.raw_os_error() == Some(libc::EROFS) => {
+				(File::open(image)?, true)
+			}
+			Err(e) => return Err(e.into()),
+		};
+
+		let control = OpenOptions::new().read(true).write(true).open("/dev/loop-control")?;
+		// another process may grab the same free device first
+		let (path, dev) = loop {
+			let nr = unsafe { libc::ioctl(control.as_raw_fd(), LOOP_CTL_GET_FREE) };
+			if nr < 0 {
+				return Err(std::io::Error::last_os_error().into());
+			}
+			let path = PathBuf::from(format!("/dev/loop{}", nr));
+			let dev = OpenOptions::new().read(true).write(!read_only).open(&path)?;
+			if unsafe { libc::ioctl(dev.as_raw_fd(), LOOP_SET_FD, backing.as_raw_fd()) } == 0 {
+				break (path, dev);
+			}
+			let e = std::io::Error::last_os_error();
+			if e.raw_os_error() != Some(libc::EBUSY) {
+				return Err(e.into());
+			}
+		};
+
+		let mut info: LoopInfo64 = unsafe { std::mem::zeroed() };
+		info.lo_offset = offset;
+		info.lo_flags = LO_FLAGS_AUTOCLEAR | if read_only { LO_FLAGS_READ_ONLY } else { 0 };
+		let name = image.as_os_str().to_string_lossy();
+		let len = name.len().min(info.lo_file_name.len() - 1);
+		info.lo_file_name[..len].copy_from_slice(&name.as_bytes()[..len]);
+		if unsafe { libc::ioctl(dev.as_raw_fd(), LOOP_SET_STATUS64, &info) } < 0 {
+			// without the status, autoclear isn't set either
+			let e = std::io::Error::last_os_error();
+			unsafe { libc::ioctl(dev.as_raw_fd(), LOOP_CLR_FD) };
+			return Err(e.into());
+		}
+		tracing::info!(msg="attached image to loop device", image=%image.display(), device=%path.display(), offset, read_only);
+		Ok(LoopDevice { path, _dev: dev })
+	}
+
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+}
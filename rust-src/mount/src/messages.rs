@@ -0,0 +1,256 @@
+//! Catalog of user-facing strings.
+//!
+//! Every message has a stable identifier, so scripts matching on output and
+//! translations can key on it even if the English wording changes. Arguments
+//! are marked with `{}` and filled in order by [`Msg::format`] or the `msg!`
+//! macro. Structured log events are not part of the catalog; their field
+//! names are already stable.
+
+macro_rules! messages {
+	($($id:ident = $text:literal,)*) => {
+		#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+		pub enum Msg {
+			$($id,)*
+		}
+
+		impl Msg {
+			pub const ALL: &'static [Msg] = &[$(Msg::$id,)*];
+
+			/// Stable identifier of the message
+			pub fn id(self) -> &'static str {
+				match self {
+					$(Msg::$id => stringify!($id),)*
+				}
+			}
+
+			/// English text of the message
+			pub fn text(self) -> &'static str {
+				match self {
+					$(Msg::$id => $text,)*
+				}
+			}
+		}
+	};
+}
+
+messages! {
+	// prompts and output
+	PassphrasePrompt = "Enter passphrase: ",
+	PassphraseContext = "Unlocking {} on {}, attempt {} of {}",
+	WipeConfirm = "This zeroes the magic of the stale superblock on {} (filesystem {}) within its first {} bytes, before any partition.",
+	WipeConfirmPrompt = "Type the device path again to go ahead: ",
+	Wiped = "{}: wiped superblock magic at byte {}",
+	KeyForgotten = "{}: key revoked and removed from the keyring",
+	KeyNotLoaded = "{}: no key in the keyring, nothing to forget",
+	SuperblockChecksumOk = "{}: superblock checksum ok",
+	SbCopyOk = "{}: superblock copy {} at sector {}: ok, seq {}",
+	SbCopyChecksumMismatch = "{}: superblock copy {} at sector {}: checksum mismatch",
+	SbCopySeqDiffers = "{}: superblock copy {} at sector {}: seq {}, the primary's is {}",
+	SbCopyUnreadable = "{}: superblock copy {} at sector {}: {}",
+	SbLayoutUnreadable = "{}: superblock layout unreadable: {}",
+	SbCopyIntact = "{}: superblock copy {} is intact, see `bcachefs-show-super {}`",
+	SbMemberBehind = "{}: superblock seq {} is behind the {} of other members",
+	JournalSize = "Journal: {} MiB",
+	Timing = "{}: {}s",
+	VersionTool = "bcachefs-mount {}",
+	VersionLibrary = "libbcachefs {}",
+	VersionOnDisk = "on-disk format versions {} to {}",
+	Mounted = "mounted bcachefs {}-device filesystem {}{} at {} ({})",
+	ReadBack = "{}: read back {} MiB from {} files in {}s ({} MiB/s), {} errors{}",
+	HealthOk = "ok",
+	SmartPassed = "SMART PASSED",
+	SmartFailed = "SMART FAILED",
+
+	// command line
+	InvalidKeyLocation = "invalid password option",
+	InvalidHealthCheckMode = "invalid health check mode",
+	InvalidRetry = "invalid retry {} (expected <count>x<delay>, e.g. 5x2s)",
+	PassphraseFileUnreadable = "failed to read passphrase file {}: {}",
+	NilUuid = "nil UUID is not a valid filesystem identifier",
+	MagicUuid = "this is the bcachefs superblock magic, not a filesystem UUID",
+	InvalidFsSpec = "{} is neither a filesystem UUID, the start of one, nor LABEL=<label>",
+	ForkWaitNeedsWait = "--fork-wait requires --key-location=wait",
+	ForkWaitNeedsMountpoint = "--fork-wait requires a mountpoint",
+	UnknownCommand = "unknown command '{}', expected one of: {}",
+	ConflictingFlags = "{} conflicts with {}: {}",
+
+	// probing and mounting
+	FsNotFound = "filesystem was not found",
+	FsNotFoundScanned = "filesystem was not found; {}",
+	AmbiguousPrefix = "UUID prefix matched {} filesystems: {}; give more of the UUID",
+	AmbiguousLabel = "label matched {} filesystems: {}; give the UUID instead",
+	AmbiguousUuid = "UUID matched {} filesystems, as the UUID of one and the internal UUID of another: {}",
+	RemountNeedsUuid = "-o remount needs the full filesystem UUID",
+	FsOutsideFilter = "filesystem was not found on the devices scanned, but on {}, which {} leaves out",
+	NothingToDo = "no mountpoint was specified and the filesystem is not encrypted, nothing to do",
+	SuperblockChecksumMismatch = "{}: superblock checksum mismatch (type {}): stored {}, computed {}",
+	SbCopiesDamaged = "damaged or out of date superblock copies on {} of {} devices",
+	NotAMember = "{} is not a member of filesystem {}",
+	NoMembers = "internal error: filesystem {} has no member devices left to mount",
+	ExcludedAllDevices = "refusing to exclude every member device",
+	TooFewDevices = "found {} of the filesystem's {} member devices, fewer than --min-devices={}",
+	DevicePathHasColon = "device path {} contains ':', which separates devices in the mount source; use an alias without one, e.g. from /dev/disk/by-id",
+	VersionTooNew = "this filesystem requires a newer bcachefs (min version {}), you have {}",
+	UpgradeNotAllowed = "filesystem has on-disk version {}, a read-write mount would upgrade it to {} for good and older tools couldn't mount it anymore; mount read-only, or pass --allow-upgrade",
+	OptionDiffers = "requested {}, mounted {}",
+	OptionNotInEffect = "requested {}, but it is not in effect",
+	SubvolidUnsupported = "the kernel rejected the mount options; it may be too old to support subvolid",
+	MemberReadOnly = "member device {} is read-only, mount with -o ro",
+	HealthCheckFailed = "device health check failed for {}",
+	ReadBackFailed = "reading back from {} ran into {} I/O errors, unmounted it again",
+	DeviceState = "device state is {}",
+	DeviceReadOnly = "device is read-only",
+	DeviceIoErrors = "{} I/O errors",
+	SmartFailing = "SMART reports the drive is failing",
+
+	NotAMountpoint = "{} is not a mountpoint",
+	DanglingMountpoint = "mountpoint {}: {} is a symlink to {}, which doesn't exist",
+	AlreadyMountedAt = "filesystem {} is already mounted at {}",
+	NotBcachefsMount = "{} is mounted, but is {} rather than bcachefs",
+	RemountOtherFs = "cannot remount {}: filesystem {} is mounted there",
+	RemountNeedsMountpoint = "-o remount requires a mountpoint",
+	WipeNotPartitioned = "{} is not a whole disk with partitions, refusing to wipe its superblock",
+	WipeNoSuperblock = "{}: no superblock to wipe: {}",
+	WipeNotConfirmed = "not confirmed, nothing was wiped",
+	OffsetUnaligned = "offset {} is not a multiple of 512 bytes",
+	OffsetNeedsImage = "--offset only applies to image files, given with --only-device, --dump-super or --check-sb-copies",
+	QueryUuid = "UUID: {}",
+	QueryInternalUuid = "Internal UUID: {}",
+	QueryLabel = "Label: {}",
+	QueryDevices = "Devices: {}",
+	QuerySubvolume = "Subvolume: {}",
+	QueryOptions = "Options: {}",
+
+	// watching a mounted filesystem's members
+	MemberState = "{}: {}",
+	MemberErrors = "{}: {}, I/O errors: {} read, {} write, {} checksum",
+	MemberAdded = "{}: added, {}",
+	MemberRemoved = "{}: removed",
+	MemberStateChanged = "{}: {} -> {}",
+	MemberErrorsChanged = "{}: {} errors {} -> {}",
+	MemberFailed = "member {} of filesystem {} failed",
+	FsUnmounted = "filesystem {} is no longer mounted",
+	FsNotMounted = "filesystem {} is not mounted",
+	MountUuidUnknown = "no filesystem UUID found for the mount at {}",
+
+	// mount options
+	UnknownOption = "unknown mount option {}",
+	OptionNotMountable = "{}: cannot be set at mount time",
+	OptionNeedsValue = "{}: requires a value",
+	OptionBadChoice = "{}: invalid value '{}' (expected one of {})",
+	OptionOutOfRange = "{}: invalid value '{}' (expected a number from {} to {})",
+	OptionBadValue = "{}: invalid value '{}'",
+
+	UnknownUser = "x-mount.owner: unknown user {}",
+	UnknownGroup = "x-mount.group: unknown group {}",
+	InvalidMode = "x-mount.mode: invalid mode {} (expected octal, e.g. 0755)",
+	RecoveryPassUnsupported = "x-bcachefs.recovery_pass: cannot run recovery pass '{}', this libbcachefs has no recovery passes; use its fsck, fix_errors or norecovery options instead",
+
+	MountInProgress = "another mount of this filesystem is in progress",
+
+	// keys
+	NoKeyLocation = "the key of locked filesystem {} is not in the keyring, and no key location was given; pass --key-location=ask (or wait or fail), or add x-bcachefs.key_location=ask to the -o options, BCACHEFS_MOUNT_OPTIONS or the fstab options, e.g. in /etc/fstab: {}",
+	NoKeyAvailable = "no key available",
+	ChachaFailure = "chacha decryption failure",
+	WrongPassphrase = "failed to verify the password",
+	AddKeyFailed = "failed to add key to keyring",
+	ForgetKeyFailed = "failed to remove key from keyring",
+	KeyringOwnerFailed = "cannot use the user keyring of uid {}: {}",
+	KeyWaitTimedOut = "the key did not become available after {} attempts",
+	PromptsExhausted = "giving up after {} passphrase prompts",
+	NoTerminal = "no terminal available for passphrase prompt; use --key-location=wait, --try-passphrase or --passphrase-file, or --force-tty-prompt",
+	EmptyPassphrase = "empty passphrase entered; pass --allow-empty-passphrase if that really is the passphrase",
+	KeyringUnavailable = "the kernel keyring is not available here ({}); unlock the filesystem where it is, e.g. with `bcachefs unlock` outside the container",
+
+	BundleFailed = "failed to write {}: tar {}",
+
+	// --policy-exec
+	PolicyFailed = "failed to run policy command {}: {}",
+	PolicyTimedOut = "policy command {} did not finish within {} seconds",
+	PolicyRejected = "policy command {} rejected the filesystem ({}): {}",
+	PolicyDenied = "filesystem {} is denied by {}",
+
+	// background waits
+	ForkFailed = "fork failed: {}",
+	SetsidFailed = "setsid failed: {}",
+	NoBackgroundWait = "no background wait for {} is in progress",
+	InvalidPidFile = "invalid pid file {}",
+
+	// C interface
+	NullArgument = "{} is NULL",
+	NotUtf8 = "{} is not valid UTF-8",
+	Panicked = "internal error: bcachefs-mount panicked",
+}
+
+impl Msg {
+	/// The text with its `{}` placeholders replaced by `args`, in order
+	pub fn format(self, args: &[&dyn std::fmt::Display]) -> String {
+		use std::fmt::Write;
+
+		let mut parts = self.text().split("{}");
+		let mut out = parts.next().unwrap_or_default().to_owned();
+		let mut args = args.iter();
+		for part in parts {
+			if let Some(arg) = args.next() {
+				let _ = write!(out, "{}", arg);
+			}
+			out.push_str(part);
+		}
+		out
+	}
+}
+
+impl std::fmt::Display for Msg {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.write_str(self.text())
+	}
+}
+
+/// Look up a message in the catalog and fill in its arguments
+#[macro_export]
+macro_rules! msg {
+	($id:ident $(, $arg:expr)* $(,)?) => {
+		$crate::messages::Msg::$id.format(&[$(&$arg as &dyn std::fmt::Display),*])
+	};
+}
+
+/// An error whose text comes from the catalog, so programs can tell what went
+/// wrong by its identifier rather than by the English text
+#[derive(Debug)]
+pub struct MsgError {
+	pub msg: Msg,
+	pub text: String,
+}
+
+impl std::fmt::Display for MsgError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		f.write_str(&self.text)
+	}
+}
+
+impl std::error::Error for MsgError {}
+
+/// Like `msg!`, but as an `anyhow::Error` that keeps the message identifier
+#[macro_export]
+macro_rules! err {
+	($id:ident $(, $arg:expr)* $(,)?) => {
+		::anyhow::Error::new($crate::messages::MsgError {
+			msg: $crate::messages::Msg::$id,
+			text: $crate::msg!($id $(, $arg)*),
+		})
+	};
+}
+
+/// The catalog as a gettext template (.pot), using the identifiers as
+/// message contexts
+pub fn gettext_template() -> String {
+	fn quote(s: &str) -> String {
+		s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+	}
+
+	Msg::ALL
+		.iter()
+		.map(|m| format!("msgctxt \"{}\"\nmsgid \"{}\"\nmsgstr \"\"\n", m.id(), quote(m.text())))
+		.collect::<Vec<_>>()
+		.join("\n")
+}
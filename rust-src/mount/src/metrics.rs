@@ -0,0 +1,32 @@
+//! The `--status` data as Prometheus gauges, for node_exporter's textfile
+//! collector. Series are labelled by uuid and label only, so the number of
+//! them stays bounded by the number of filesystems.
+
+use crate::filesystem::Status;
+use std::fmt::Write;
+
+/// Label value escaped as the text format wants it
+fn escape(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Prometheus text format for `statuses`: a gauge each for the devices found
+/// and expected, encryption and whether the filesystem is mounted
+pub fn render(statuses: &[Status]) -> String {
+	let gauges: [(&str, &str, fn(&Status) -> usize); 4] = [
+		("bcachefs_devices_present", "Member devices found", |s| s.devices_found),
+		("bcachefs_devices_expected", "Member devices the superblock lists", |s| s.devices_total),
+		("bcachefs_encrypted", "Whether the filesystem is encrypted", |s| s.encrypted as usize),
+		("bcachefs_mounted", "Whether the filesystem is mounted on this host", |s| !s.mounted.is_empty() as usize),
+	];
+	let mut out = String::new();
+	for (name, help, value) in gauges.iter() {
+		let _ = writeln!(out, "# HELP {} {}", name, help);
+		let _ = writeln!(out, "# TYPE {} gauge", name);
+		for s in statuses {
+			let label = escape(s.label.as_deref().unwrap_or_default());
+			let _ = writeln!(out, "{}{{uuid=\"{}\",label=\"{}\"}} {}", name, s.uuid, label, value(s));
+		}
+	}
+	out
+}
@@ -0,0 +1,142 @@
+//! Creating the mountpoint for `--mkdir`, owned and permissioned as the
+//! `x-mount.owner=`, `x-mount.group=` and `x-mount.mode=` options say.
+
+use std::path::{Path, PathBuf};
+
+/// How a created mountpoint should look; `None` leaves it as created
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DirOptions {
+	pub owner: Option<libc::uid_t>,
+	pub group: Option<libc::gid_t>,
+	pub mode: Option<u32>,
+}
+
+/// Look up a user or group name with a `get*nam_r` function, growing the
+/// buffer as long as it reports ERANGE
+fn lookup<T, R>(
+	name: &str,
+	get: unsafe extern "C" fn(*const libc::c_char, *mut T, *mut libc::c_char, libc::size_t, *mut *mut T) -> libc::c_int,
+	id: impl Fn(&T) -> R,
+) -> anyhow::Result<Option<R>> {
+	let cname = std::ffi::CString::new(name)?;
+	let mut buf = vec![0 as libc::c_char; 1024];
+	loop {
+		let mut entry: T = unsafe { std::mem::zeroed() };
+		let mut result = std::ptr::null_mut();
+		let ret = unsafe { get(cname.as_ptr(), &mut entry, buf.as_mut_ptr(), buf.len(), &mut result) };
+		match ret {
+			0 if result.is_null() => return Ok(None),
+			0 => return Ok(Some(id(&entry))),
+			libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+			e => return Err(crate::ErrnoError(errno::Errno(e)).into()),
+		}
+	}
+}
+
+/// A user by name or number
+pub fn parse_user(user: &str) -> anyhow::Result<libc::uid_t> {
+	if let Ok(uid) = user.parse() {
+		return Ok(uid);
+	}
+	lookup(user, libc::getpwnam_r, |p: &libc::passwd| p.pw_uid)?.ok_or_else(|| err!(UnknownUser, user))
+}
+
+/// A group by name or number
+pub fn parse_group(group: &str) -> anyhow::Result<libc::gid_t> {
+	if let Ok(gid) = group.parse() {
+		return Ok(gid);
+	}
+	lookup(group, libc::getgrnam_r, |g: &libc::group| g.gr_gid)?.ok_or_else(|| err!(UnknownGroup, group))
+}
+
+/// The `x-mount.owner=`, `x-mount.group=` and `x-mount.mode=` options in
+/// `options`, with user and group names resolved. The last of each wins.
+pub fn dir_options(options: &str) -> anyhow::Result<DirOptions> {
+	let mut dir = DirOptions::default();
+	for o in options.split(',') {
+		if let Some(user) = o.strip_prefix("x-mount.owner=") {
+			dir.owner = Some(parse_user(user)?);
+		} else if let Some(group) = o.strip_prefix("x-mount.group=") {
+			dir.group = Some(parse_group(group)?);
+		} else if let Some(mode) = o.strip_prefix("x-mount.mode=") {
+			dir.mode = match u32::from_str_radix(mode, 8) {
+				Ok(m) if m <= 0o7777 => Some(m),
+				_ => return Err(err!(InvalidMode, mode)),
+			};
+		}
+	}
+	Ok(dir)
+}
+
+/// Refuse a mountpoint that is, or goes through, a symlink to something that
+/// doesn't exist: mount(8) mounts on where a symlink leads, and there is
+/// nowhere to mount on then, nor to create with `--mkdir`
+pub fn check_dangling(path: &Path) -> anyhow::Result<()> {
+	let mut prefix = PathBuf::new();
+	for c in path.components() {
+		prefix.push(c);
+		let is_link = std::fs::symlink_metadata(&prefix).map_or(false, |m| m.file_type().is_symlink());
+		if is_link && std::fs::metadata(&prefix).is_err() {
+			let to = std::fs::read_link(&prefix).unwrap_or_default();
+			return Err(err!(DanglingMountpoint, path.display(), prefix.display(), to.display()));
+		}
+	}
+	Ok(())
+}
+
+/// `path` as mountinfo lists mountpoints: absolute, with symlinks resolved
+/// and without trailing slashes, `.` or `..`. A part that doesn't exist yet,
+/// as before `--mkdir`, is only cleaned up.
+pub fn canonical(path: &Path) -> PathBuf {
+	use std::path::Component;
+
+	let components: Vec<Component> = path.components().collect();
+	for existing in (0..=components.len()).rev() {
+		let prefix: PathBuf = components[..existing].iter().collect();
+		let base = if prefix.as_os_str().is_empty() { std::env::current_dir() } else { prefix.canonicalize() };
+		if let Ok(mut canonical) = base {
+			for c in &components[existing..] {
+				match c {
+					Component::ParentDir => {
+						canonical.pop();
+					}
+					Component::Normal(name) => canonical.push(name),
+					_ => {}
+				}
+			}
+			return canonical;
+		}
+	}
+	components.iter().collect()
+}
+
+/// Create the mountpoint `path` (and its parents) if it doesn't exist, and
+/// give it the owner, group and mode from `dir`. The mode is applied as is,
+/// regardless of the umask. A directory that already existed is left alone
+/// unless `force`.
+#[tracing_attributes::instrument]
+pub fn create(path: &Path, dir: &DirOptions, force: bool) -> anyhow::Result<()> {
+	use std::os::unix::{ffi::OsStrExt, fs::DirBuilderExt, fs::PermissionsExt};
+
+	if path.exists() && !force {
+		return Ok(());
+	}
+	if !path.exists() {
+		tracing::info!(msg = "creating mountpoint", path = %path.display());
+		std::fs::DirBuilder::new().recursive(true).mode(0o755).create(path)?;
+	}
+
+	if dir.owner.is_some() || dir.group.is_some() {
+		let cpath = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+		// -1 leaves the owner or group unchanged
+		let uid = dir.owner.unwrap_or(!0);
+		let gid = dir.group.unwrap_or(!0);
+		if unsafe { libc::chown(cpath.as_ptr(), uid, gid) } < 0 {
+			return Err(crate::ErrnoError(errno::errno()).into());
+		}
+	}
+	if let Some(mode) = dir.mode {
+		std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+	}
+	Ok(())
+}
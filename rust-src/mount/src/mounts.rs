@@ -0,0 +1,214 @@
+//! Where filesystems are mounted, from /proc/self/mountinfo.
+//!
+//! mountinfo only lists the mounts of the caller's mount namespace, so
+//! everything here is scoped to it: run inside a container, a filesystem
+//! mounted on the host isn't seen as mounted, and one mounted in the
+//! container is found at its path inside the container. A namespace that
+//! mounts nothing has an empty table, not an error.
+
+use crate::paths::Paths;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// A line of mountinfo, as much of it as we need
+#[derive(Debug, Clone, PartialEq)]
+pub struct MountInfo {
+	pub target: PathBuf,
+	/// Generic mount options, e.g. "rw,noatime"
+	pub vfs_options: String,
+	pub fstype: String,
+	pub source: OsString,
+	/// Options of the filesystem itself
+	pub fs_options: String,
+}
+
+impl MountInfo {
+	/// Generic options and then those of the filesystem, as one list
+	pub fn options(&self) -> String {
+		[self.vfs_options.as_str(), self.fs_options.as_str()].join(",")
+	}
+
+	/// The subvolume mounted, if it was chosen with "subvolid"
+	pub fn subvolid(&self) -> Option<u32> {
+		self.fs_options.split(',').find_map(|o| o.strip_prefix("subvolid=")?.parse().ok())
+	}
+
+	/// The devices of a bcachefs mount source, which lists them separated
+	/// by ':', or else "UUID=<uuid>" as it is
+	pub fn source_devices(&self) -> impl Iterator<Item = &Path> {
+		self.source.as_bytes().split(|&b| b == b':').map(|dev| Path::new(OsStr::from_bytes(dev)))
+	}
+}
+
+/// mountinfo escapes space, tab, newline and backslash as octal
+fn unescape(bytes: &[u8]) -> OsString {
+	let mut out = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		let octal = bytes.get(i + 1..i + 4).and_then(|d| std::str::from_utf8(d).ok());
+		match octal.and_then(|d| u8::from_str_radix(d, 8).ok()) {
+			Some(b) if bytes[i] == b'\\' => {
+				out.push(b);
+				i += 4;
+			}
+			_ => {
+				out.push(bytes[i]);
+				i += 1;
+			}
+		}
+	}
+	OsString::from_vec(out)
+}
+
+/// Parse the contents of a mountinfo file, skipping malformed lines. Paths
+/// are taken as they are, the kernel doesn't promise they are UTF-8.
+pub fn parse(mountinfo: &[u8]) -> Vec<MountInfo> {
+	let text = |field: &[u8]| String::from_utf8_lossy(field).into_owned();
+	mountinfo
+		.split(|&b| b == b'\n')
+		.filter_map(|line| {
+			let fields: Vec<&[u8]> = line.split(|&b| b == b' ').collect();
+			// optional fields run up to the "-" separator
+			let sep = fields.iter().skip(6).position(|f| *f == b"-")? + 6;
+			Some(MountInfo {
+				target: unescape(fields.get(4)?).into(),
+				vfs_options: text(fields.get(5)?),
+				fstype: text(fields.get(sep + 1)?),
+				source: unescape(fields.get(sep + 2)?),
+				fs_options: text(fields.get(sep + 3)?),
+			})
+		})
+		.collect()
+}
+
+/// This process's mountinfo, as [`parse`] takes it
+pub fn read() -> std::io::Result<Vec<u8>> {
+	std::fs::read("/proc/self/mountinfo")
+}
+
+/// Mountpoints in `mountinfo` of the bcachefs filesystem `uuid`. The mount
+/// source is a list of member devices separated by ':', or UUID=<uuid>;
+/// `fs_uuid_of` tells which filesystem a device belongs to.
+pub fn mountpoints_in(mountinfo: &[u8], uuid: Uuid, fs_uuid_of: impl Fn(&Path) -> Option<Uuid>) -> Vec<PathBuf> {
+	parse(mountinfo)
+		.into_iter()
+		.filter(|m| m.fstype == "bcachefs")
+		.filter(|m| {
+			m.source_devices().any(|dev| match dev.to_str().and_then(|d| d.strip_prefix("UUID=")) {
+				Some(u) => Uuid::parse_str(u).map_or(false, |u| u == uuid),
+				None => fs_uuid_of(dev) == Some(uuid),
+			})
+		})
+		.map(|m| m.target)
+		.collect()
+}
+
+/// Where a filesystem is mounted, as far as mounting it at a target goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AlreadyMounted {
+	No,
+	/// At the target, so there is nothing left to do
+	AtTarget,
+	/// Only at other mountpoints
+	Elsewhere(Vec<PathBuf>),
+}
+
+/// How the `mountpoints` of a filesystem, as [`mountpoints_in`] finds them,
+/// relate to mounting it at `target`. mountinfo has mountpoints canonical, so
+/// `target` should be too.
+pub fn already_mounted(mountpoints: &[PathBuf], target: &Path) -> AlreadyMounted {
+	if mountpoints.iter().any(|m| m == target) {
+		AlreadyMounted::AtTarget
+	} else if mountpoints.is_empty() {
+		AlreadyMounted::No
+	} else {
+		AlreadyMounted::Elsewhere(mountpoints.to_vec())
+	}
+}
+
+/// The udev device for the block device node `dev`, which is looked up as
+/// given: mount sources are as the kernel saw them
+pub(crate) fn udev_device(dev: &Path, paths: &Paths) -> Option<udev::Device> {
+	use std::os::unix::fs::MetadataExt;
+
+	let rdev = std::fs::metadata(dev).ok()?.rdev();
+	let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+	let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+	let syspath = paths.sys(format!("dev/block/{}:{}", major, minor));
+	udev::Device::from_syspath(&syspath).ok()
+}
+
+/// A udev property of a block device, like what udev's blkid builtin found
+/// on it; superblocks themselves can't be read while the device is mounted
+fn device_property(dev: &Path, paths: &Paths, property: &str) -> Option<String> {
+	let device = udev_device(dev, paths)?;
+	Some(device.property_value(property)?.to_str()?.to_owned())
+}
+
+pub(crate) fn device_fs_uuid(dev: &Path, paths: &Paths) -> Option<Uuid> {
+	Uuid::parse_str(&device_property(dev, paths, "ID_FS_UUID")?).ok()
+}
+
+/// The filesystem udev found on `dev`, e.g. "ext4", if any
+pub fn device_fs_type(dev: &Path, paths: &Paths) -> Option<String> {
+	device_property(dev, paths, "ID_FS_TYPE").filter(|t| !t.is_empty())
+}
+
+/// Whether this process has a mount namespace of its own, i.e. not that of
+/// init, so that mounts made elsewhere may not be visible to it. `false`
+/// when that can't be told, e.g. without permission to look at init.
+pub fn in_other_mount_namespace() -> bool {
+	match (std::fs::read_link("/proc/self/ns/mnt"), std::fs::read_link("/proc/1/ns/mnt")) {
+		(Ok(own), Ok(init)) => own != init,
+		_ => false,
+	}
+}
+
+/// Where the bcachefs filesystem `uuid` is currently mounted, as seen from
+/// this process's mount namespace
+pub fn mountpoints_for(uuid: Uuid, paths: &Paths) -> std::io::Result<Vec<PathBuf>> {
+	Ok(mountpoints_in(&read()?, uuid, |dev| device_fs_uuid(dev, paths)))
+}
+
+/// A mounted bcachefs filesystem, as far as it can be told from the outside
+#[derive(Debug)]
+pub struct MountedFs {
+	pub uuid: Option<Uuid>,
+	/// As the kernel logs it; read from a member's superblock
+	pub internal_uuid: Option<Uuid>,
+	pub label: Option<String>,
+	pub devices: Vec<PathBuf>,
+	pub subvolid: Option<u32>,
+	/// As the kernel reports them, see [`MountInfo::options`]
+	pub options: String,
+}
+
+/// The mount visible at `path`, i.e. the last one mounted there
+pub fn mount_at(path: &Path) -> anyhow::Result<MountInfo> {
+	let target = path.canonicalize()?;
+	parse(&read()?)
+		.into_iter()
+		.rev()
+		.find(|m| m.target == target)
+		.ok_or_else(|| err!(NotAMountpoint, path.display()))
+}
+
+/// Find the bcachefs filesystem mounted at `path`
+pub fn query(path: &Path, paths: &Paths) -> anyhow::Result<MountedFs> {
+	let mount = mount_at(path)?;
+	if mount.fstype != "bcachefs" {
+		return Err(err!(NotBcachefsMount, path.display(), mount.fstype));
+	}
+
+	let devices: Vec<PathBuf> = mount.source_devices().map(Path::to_path_buf).collect();
+	Ok(MountedFs {
+		uuid: devices.iter().find_map(|d| device_fs_uuid(d, paths)),
+		internal_uuid: devices.iter().find_map(|d| bch_bindgen::rs::read_super_raw(d).ok()).map(|sb| sb.sb().internal_uuid()),
+		label: devices.iter().find_map(|d| device_property(d, paths, "ID_FS_LABEL")),
+		devices,
+		subvolid: mount.subvolid(),
+		options: mount.options(),
+	})
+}
@@ -0,0 +1,58 @@
+//! Where device nodes, sysfs and our own state files are. An initramfs or
+//! chroot may have devtmpfs mounted somewhere other than /dev, or no /run at
+//! all, so all of these can be overridden.
+
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Paths {
+	/// Lock files, pid files and the last error
+	pub runtime_dir: PathBuf,
+	pub dev_root: PathBuf,
+	pub sys_root: PathBuf,
+}
+
+impl Default for Paths {
+	fn default() -> Self {
+		Paths {
+			runtime_dir: PathBuf::from("/run/bcachefs-mount"),
+			dev_root: PathBuf::from("/dev"),
+			sys_root: PathBuf::from("/sys"),
+		}
+	}
+}
+
+impl Paths {
+	/// The runtime directory, created with mode 0700 if it doesn't exist.
+	/// `None`, after a warning, if it can't be written to; whatever wanted to
+	/// keep state there then does without.
+	pub fn state_dir(&self) -> Option<&Path> {
+		use std::os::unix::{ffi::OsStrExt, fs::DirBuilderExt};
+
+		let dir = &self.runtime_dir;
+		if let Err(e) = std::fs::DirBuilder::new().recursive(true).mode(0o700).create(dir) {
+			tracing::warn!(msg="runtime directory can't be created, keeping no state", dir=%dir.display(), error=%e);
+			return None;
+		}
+		let cdir = std::ffi::CString::new(dir.as_os_str().as_bytes()).ok()?;
+		if unsafe { libc::access(cdir.as_ptr(), libc::W_OK | libc::X_OK) } != 0 {
+			let e = errno::errno();
+			tracing::warn!(msg="runtime directory isn't writable, keeping no state", dir=%dir.display(), error=%e);
+			return None;
+		}
+		Some(dir)
+	}
+
+	/// `path` with a leading /dev moved to the device root
+	pub fn dev(&self, path: &Path) -> PathBuf {
+		match path.strip_prefix("/dev") {
+			Ok(rest) => self.dev_root.join(rest),
+			Err(_) => path.to_owned(),
+		}
+	}
+
+	/// `path` relative to the sysfs root, e.g. `sys("class/block")`
+	pub fn sys(&self, path: impl AsRef<Path>) -> PathBuf {
+		self.sys_root.join(path)
+	}
+}
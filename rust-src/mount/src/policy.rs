@@ -0,0 +1,196 @@
+//! Deciding whether a filesystem may be mounted, after probing and before any
+//! key is loaded or anything is mounted: first by the `--allow-*` and
+//! `--deny-*` rules, then, for filesystems they allow, by `--policy-exec`.
+//!
+//! A deny rule that matches wins over any allow rule. If there are allow
+//! rules, one of them has to match; without any, everything not denied is
+//! allowed. The order rules are given in doesn't matter.
+//!
+//! `--policy-exec` lets a site-specific command veto a mount. The command is
+//! run by `/bin/sh -c` with the filesystem's `--status --json` object on
+//! stdin. Exit status 0 lets the mount go ahead, [`SKIP`] quietly skips it,
+//! and anything else fails it with what the command wrote to stderr. Lines it
+//! writes to stdout are logged, to annotate the mount.
+
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// An allow or deny rule, by UUID or by a glob on the label
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rule {
+	AllowUuid(Uuid),
+	DenyUuid(Uuid),
+	AllowLabel(String),
+	DenyLabel(String),
+}
+
+impl Rule {
+	fn is_deny(&self) -> bool {
+		matches!(self, Rule::DenyUuid(_) | Rule::DenyLabel(_))
+	}
+
+	/// The internal UUID counts too, as it does when picking the filesystem
+	fn matches(&self, uuid: &Uuid, internal_uuid: &Uuid, label: Option<&str>) -> bool {
+		match self {
+			Rule::AllowUuid(u) | Rule::DenyUuid(u) => u == uuid || u == internal_uuid,
+			Rule::AllowLabel(glob) | Rule::DenyLabel(glob) => label.map_or(false, |l| glob_match(glob, l)),
+		}
+	}
+}
+
+/// The rule as given on the command line
+impl std::fmt::Display for Rule {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Rule::AllowUuid(u) => write!(f, "--allow-uuid={}", u),
+			Rule::DenyUuid(u) => write!(f, "--deny-uuid={}", u),
+			Rule::AllowLabel(l) => write!(f, "--allow-label={}", l),
+			Rule::DenyLabel(l) => write!(f, "--deny-label={}", l),
+		}
+	}
+}
+
+/// What the rules decided, and by which one. A filesystem no allow rule
+/// matches is denied by none in particular.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision<'a> {
+	Allowed(Option<&'a Rule>),
+	Denied(Option<&'a Rule>),
+}
+
+/// Apply `rules` to a filesystem, as described for the module
+pub fn evaluate<'a>(rules: &'a [Rule], uuid: &Uuid, internal_uuid: &Uuid, label: Option<&str>) -> Decision<'a> {
+	let matching = |deny: bool| rules.iter().find(|r| r.is_deny() == deny && r.matches(uuid, internal_uuid, label));
+	if let Some(rule) = matching(true) {
+		return Decision::Denied(Some(rule));
+	}
+	match matching(false) {
+		Some(rule) => Decision::Allowed(Some(rule)),
+		None if rules.iter().any(|r| !r.is_deny()) => Decision::Denied(None),
+		None => Decision::Allowed(None),
+	}
+}
+
+/// Shell-style glob matching of all of `text`: `*` is any run of characters,
+/// `?` any one character, and `\` makes the next character literal
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+	let (pattern, text): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+	// where to resume after a mismatch: just after the last `*`, with it
+	// taking one more character of the text
+	let mut star = None;
+	let (mut p, mut t) = (0, 0);
+	while t < text.len() {
+		match pattern.get(p) {
+			Some('*') => {
+				star = Some((p + 1, t));
+				p += 1;
+				continue;
+			}
+			Some('?') => {
+				p += 1;
+				t += 1;
+				continue;
+			}
+			Some('\\') if pattern.get(p + 1) == Some(&text[t]) => {
+				p += 2;
+				t += 1;
+				continue;
+			}
+			Some(c) if *c != '\\' && *c == text[t] => {
+				p += 1;
+				t += 1;
+				continue;
+			}
+			_ => {}
+		}
+		match star {
+			Some((sp, st)) => {
+				star = Some((sp, st + 1));
+				p = sp;
+				t = st + 1;
+			}
+			None => return false,
+		}
+	}
+	pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Exit status of the policy command for skipping the filesystem
+pub const SKIP: i32 = 3;
+
+/// What the policy command decided
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+	Proceed,
+	Skip,
+}
+
+/// Run the policy `command` on the filesystem `description`, killing it if
+/// it takes longer than `timeout`
+#[tracing_attributes::instrument(skip(description))]
+pub fn check(command: &str, description: &str, timeout: Duration) -> anyhow::Result<Verdict> {
+	use std::os::unix::process::ExitStatusExt;
+
+	let mut child = Command::new("/bin/sh")
+		.arg("-c")
+		.arg(command)
+		.stdin(Stdio::piped())
+		.stdout(Stdio::piped())
+		.stderr(Stdio::piped())
+		.spawn()
+		.map_err(|e| err!(PolicyFailed, command, e))?;
+
+	// in threads, so that a command that doesn't read all of its input or
+	// writes a lot can't block either side
+	let mut stdin = child.stdin.take().expect("stdin is piped");
+	let input = format!("{}\n", description);
+	std::thread::spawn(move || {
+		// a command that doesn't care for the description may exit first
+		let _ = stdin.write_all(input.as_bytes());
+	});
+	let read = |mut pipe: Box<dyn Read + Send>| {
+		let (tx, rx) = std::sync::mpsc::channel();
+		std::thread::spawn(move || {
+			let mut out = Vec::new();
+			let _ = pipe.read_to_end(&mut out);
+			let _ = tx.send(String::from_utf8_lossy(&out).into_owned());
+		});
+		rx
+	};
+	let stdout = read(Box::new(child.stdout.take().expect("stdout is piped")));
+	let stderr = read(Box::new(child.stderr.take().expect("stderr is piped")));
+
+	let deadline = Instant::now() + timeout;
+	let status = loop {
+		if let Some(status) = child.try_wait()? {
+			break status;
+		}
+		if Instant::now() >= deadline {
+			let _ = child.kill();
+			let _ = child.wait();
+			return Err(err!(PolicyTimedOut, command, timeout.as_secs()));
+		}
+		std::thread::sleep(Duration::from_millis(10));
+	};
+	// the command may have left children behind that hold the pipes open
+	let output = |rx: std::sync::mpsc::Receiver<String>| {
+		rx.recv_timeout(deadline.saturating_duration_since(Instant::now())).unwrap_or_default()
+	};
+	let stdout = output(stdout);
+	let stderr = output(stderr);
+
+	for line in stdout.lines() {
+		tracing::info!(msg="policy command says", %line);
+	}
+	match (status.code(), status.signal()) {
+		(Some(0), _) => Ok(Verdict::Proceed),
+		(Some(SKIP), _) => Ok(Verdict::Skip),
+		(Some(code), _) => Err(err!(PolicyRejected, command, format!("exit status {}", code), stderr.trim_end())),
+		(None, signal) => {
+			let signal = signal.map_or_else(String::new, |s| s.to_string());
+			Err(err!(PolicyRejected, command, format!("killed by signal {}", signal), stderr.trim_end()))
+		}
+	}
+}
@@ -0,0 +1,156 @@
+//! `--verify-after-mount`: read some data back right after mounting, to catch
+//! filesystems that mount fine and then return EIO on reads.
+//!
+//! Files are found by a shallow walk from the mountpoint, bounded in depth,
+//! in entries looked at per directory and in files picked, so a huge tree
+//! costs no more than a small one. Only regular files are read; symlinks,
+//! device nodes, fifos and sockets are skipped, and so is anything mounted
+//! below the mountpoint. Walk and reads stop at a deadline.
+
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Default amount to read, in MiB
+pub const DEFAULT_MIB: u64 = 16;
+/// How long walk and reads may take together
+pub const TIME_LIMIT: Duration = Duration::from_secs(30);
+/// Directory levels below the mountpoint that are looked into
+const MAX_DEPTH: usize = 2;
+/// Entries looked at per directory
+const MAX_ENTRIES: usize = 64;
+/// Files read from
+const MAX_FILES: usize = 16;
+const CHUNK: usize = 1 << 20;
+
+/// What reading back found
+#[derive(Debug, Default)]
+pub struct Report {
+	pub files: usize,
+	pub bytes: u64,
+	pub duration: Duration,
+	/// Paths that failed to be listed or read, other than for lack of
+	/// permission, and how
+	pub errors: Vec<(PathBuf, std::io::Error)>,
+	/// Whether the deadline cut walk or reads short
+	pub timed_out: bool,
+}
+
+impl Report {
+	/// Read throughput in MiB/s
+	pub fn throughput(&self) -> f64 {
+		let secs = self.duration.as_secs_f64();
+		if secs > 0.0 {
+			self.bytes as f64 / (1 << 20) as f64 / secs
+		} else {
+			0.0
+		}
+	}
+}
+
+/// Record a failure to list or read `path`, unless it's only that this
+/// process may not or that it went away in the meantime
+fn record(errors: &mut Vec<(PathBuf, std::io::Error)>, path: &Path, e: std::io::Error) {
+	use std::io::ErrorKind;
+
+	if let ErrorKind::PermissionDenied | ErrorKind::NotFound = e.kind() {
+		tracing::debug!(msg="skipped for read back", path=%path.display(), error=%e);
+		return;
+	}
+	tracing::warn!(msg="read back failed", path=%path.display(), error=%e);
+	errors.push((path.to_owned(), e));
+}
+
+/// Up to [`MAX_FILES`] regular files under `root`, breadth first, with
+/// directories that can't be listed recorded in `errors`
+fn pick_files(root: &Path, deadline: Instant, errors: &mut Vec<(PathBuf, std::io::Error)>) -> Vec<(PathBuf, u64)> {
+	let dev = match std::fs::symlink_metadata(root) {
+		Ok(m) => m.dev(),
+		Err(e) => {
+			record(errors, root, e);
+			return Vec::new();
+		}
+	};
+	let mut files = Vec::new();
+	let mut dirs = vec![(root.to_owned(), 0)];
+	let mut next = 0;
+	while next < dirs.len() && files.len() < MAX_FILES && Instant::now() < deadline {
+		let (dir, depth) = dirs[next].clone();
+		next += 1;
+		let entries = match std::fs::read_dir(&dir) {
+			Ok(entries) => entries,
+			Err(e) => {
+				record(errors, &dir, e);
+				continue;
+			}
+		};
+		for entry in entries.take(MAX_ENTRIES) {
+			let found = entry.and_then(|e| {
+				let path = e.path();
+				let meta = std::fs::symlink_metadata(&path)?;
+				Ok((path, meta))
+			});
+			let (path, meta) = match found {
+				Ok(found) => found,
+				Err(e) => {
+					record(errors, &dir, e);
+					continue;
+				}
+			};
+			if meta.dev() != dev {
+				continue;
+			}
+			if meta.is_file() && meta.len() > 0 && files.len() < MAX_FILES {
+				files.push((path, meta.len()));
+			} else if meta.is_dir() && depth < MAX_DEPTH {
+				dirs.push((path, depth + 1));
+			}
+		}
+	}
+	files
+}
+
+/// Read up to `bytes` from files under `root`, spread evenly over them, and
+/// give up at `time_limit`
+#[tracing_attributes::instrument]
+pub fn check(root: &Path, bytes: u64, time_limit: Duration) -> Report {
+	let start = Instant::now();
+	let deadline = start + time_limit;
+	let mut report = Report::default();
+	let mut files = pick_files(root, deadline, &mut report.errors);
+	// smallest first, so that what they don't use goes to the larger ones
+	files.sort_by_key(|(_, len)| *len);
+	tracing::debug!(msg="files to read back", count=files.len());
+
+	let mut buf = vec![0u8; CHUNK];
+	let mut left = bytes;
+	for (n, (path, len)) in files.iter().enumerate() {
+		let share = (left / (files.len() - n) as u64).min(*len);
+		let read = std::fs::File::open(path).and_then(|mut file| {
+			let mut read = 0;
+			while read < share && Instant::now() < deadline {
+				let want = (share - read).min(CHUNK as u64) as usize;
+				match file.read(&mut buf[..want])? {
+					0 => break,
+					got => read += got as u64,
+				}
+			}
+			Ok(read)
+		});
+		match read {
+			Ok(read) => {
+				report.files += 1;
+				report.bytes += read;
+				left -= read;
+			}
+			Err(e) => record(&mut report.errors, path, e),
+		}
+		if Instant::now() >= deadline {
+			break;
+		}
+	}
+	report.timed_out = Instant::now() >= deadline;
+	report.duration = start.elapsed();
+	report
+}
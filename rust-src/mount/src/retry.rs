@@ -0,0 +1,59 @@
+//! Trying something again after a delay, a fixed number of times: for
+//! `--retry`, which probes again while the filesystem hasn't shown up yet,
+//! and for keyring polls with `--key-location=wait`.
+
+use std::time::Duration;
+
+/// Retries after a first attempt, e.g. "5x2s" for up to 5 more, 2 seconds
+/// apart. The delay takes an "ms", "s" or "m" suffix; without one, it is in
+/// seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+	pub count: u32,
+	pub delay: Duration,
+}
+
+impl Retry {
+	/// Call `attempt` with the attempt number, from 0, until it succeeds, it
+	/// fails in a way that isn't `retryable`, or the retries are used up.
+	/// The last failure is returned then.
+	pub fn run<T, E>(
+		&self,
+		retryable: impl Fn(&E) -> bool,
+		mut attempt: impl FnMut(u32) -> Result<T, E>,
+	) -> Result<T, E> {
+		let mut n = 0;
+		loop {
+			match attempt(n) {
+				Err(e) if n < self.count && retryable(&e) => {
+					n += 1;
+					std::thread::sleep(self.delay);
+				}
+				result => return result,
+			}
+		}
+	}
+}
+
+impl std::str::FromStr for Retry {
+	type Err = anyhow::Error;
+
+	fn from_str(s: &str) -> anyhow::Result<Self> {
+		let invalid = || err!(InvalidRetry, s);
+		let mut parts = s.splitn(2, 'x');
+		let count = parts.next().and_then(|c| c.parse().ok()).ok_or_else(invalid)?;
+		let delay = parts.next().ok_or_else(invalid)?;
+		let (number, unit) = match delay.find(|c: char| !c.is_ascii_digit()) {
+			Some(i) => delay.split_at(i),
+			None => (delay, "s"),
+		};
+		let number: u64 = number.parse().map_err(|_| invalid())?;
+		let delay = match unit {
+			"ms" => Duration::from_millis(number),
+			"s" => Duration::from_secs(number),
+			"m" => Duration::from_secs(number.checked_mul(60).ok_or_else(invalid)?),
+			_ => return Err(invalid()),
+		};
+		Ok(Retry { count, delay })
+	}
+}
@@ -0,0 +1,103 @@
+//! Superblocks left behind on a whole disk that was partitioned afterwards.
+//!
+//! A partition table can't coexist with a bcachefs filesystem on the whole
+//! disk, as both live in the first sectors; so when a disk and one of its
+//! partitions both carry a superblock, the one on the disk is stale. Probing
+//! ignores it with a warning, and `--wipe-stale-sb` removes it for good.
+
+use crate::filesystem::FileSystem;
+use crate::paths::Paths;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// sysfs directory of the block device `dev`
+fn sysfs_dir(dev: &Path, paths: &Paths) -> Option<PathBuf> {
+	let name = paths.dev(dev).canonicalize().ok()?.file_name()?.to_owned();
+	Some(paths.sys("class/block").join(name))
+}
+
+/// Partitions of the whole disk `disk`, according to sysfs; empty for
+/// anything else
+pub fn partitions(disk: &Path, paths: &Paths) -> Vec<PathBuf> {
+	let dir = match sysfs_dir(disk, paths) {
+		Some(dir) => dir,
+		None => return Vec::new(),
+	};
+	let mut parts: Vec<PathBuf> = std::fs::read_dir(&dir)
+		.map(|entries| {
+			entries
+				.filter_map(|e| e.ok())
+				.filter(|e| e.path().join("partition").exists())
+				.map(|e| paths.dev_root.join(e.file_name()))
+				.collect()
+		})
+		.unwrap_or_default();
+	parts.sort();
+	parts
+}
+
+/// Where the partition `part` starts on its disk, in bytes
+fn partition_start(part: &Path, paths: &Paths) -> Option<u64> {
+	let start = std::fs::read_to_string(sysfs_dir(part, paths)?.join("start")).ok()?;
+	start.trim().parse::<u64>().ok()?.checked_mul(512)
+}
+
+fn has_superblock(dev: &Path) -> bool {
+	bch_bindgen::rs::read_super_raw(dev).is_ok()
+}
+
+/// Those of `devices` with a superblock that are whole disks with a
+/// partition that has one too
+pub fn stale_whole_disks(
+	devices: &[PathBuf],
+	partitions_of: impl Fn(&Path) -> Vec<PathBuf>,
+	has_superblock: impl Fn(&Path) -> bool,
+) -> Vec<PathBuf> {
+	devices.iter().filter(|d| partitions_of(d).iter().any(|p| has_superblock(p))).cloned().collect()
+}
+
+/// Drop the members of `filesystems` whose superblock is stale, and the
+/// filesystems left without members; returns the devices dropped
+pub fn drop_stale(filesystems: &mut HashMap<Uuid, FileSystem>, paths: &Paths) -> Vec<PathBuf> {
+	let devices: Vec<PathBuf> =
+		filesystems.values().flat_map(|fs| fs.members().iter().map(|m| m.path().to_owned())).collect();
+	let stale = stale_whole_disks(&devices, |disk| partitions(disk, paths), has_superblock);
+	for disk in &stale {
+		tracing::warn!(
+			msg="ignoring stale superblock on a whole disk whose partitions hold bcachefs; remove it with --wipe-stale-sb",
+			device=%disk.display()
+		);
+		for fs in filesystems.values_mut() {
+			fs.remove_member(disk);
+		}
+	}
+	filesystems.retain(|_, fs| !fs.members().is_empty());
+	stale
+}
+
+/// Zero the superblock magic on `disk`, which must have partitions, in the
+/// sectors before its first partition; the partitions themselves are never
+/// touched. `confirm` is asked with a description of what is about to be
+/// wiped, and nothing is written unless it agrees. Returns the byte offsets
+/// wiped.
+pub fn wipe(disk: &Path, paths: &Paths, confirm: impl FnOnce(&str) -> bool) -> anyhow::Result<Vec<u64>> {
+	let parts = partitions(disk, paths);
+	if parts.is_empty() {
+		return Err(err!(WipeNotPartitioned, disk.display()));
+	}
+	let limit = match parts.iter().map(|p| partition_start(p, paths)).collect::<Option<Vec<u64>>>() {
+		Some(starts) => starts.into_iter().min().unwrap_or(0),
+		None => return Err(err!(WipeNotPartitioned, disk.display())),
+	};
+	let sb = bch_bindgen::rs::read_super_raw(disk).map_err(|e| err!(WipeNoSuperblock, disk.display(), e))?;
+	let description = msg!(WipeConfirm, disk.display(), sb.sb().uuid(), limit);
+	if !confirm(&description) {
+		return Err(err!(WipeNotConfirmed));
+	}
+	let wiped = bch_bindgen::rs::wipe_super_magic(disk, limit)?;
+	for at in &wiped {
+		tracing::info!(msg="wiped superblock magic", device=%disk.display(), offset=at);
+	}
+	Ok(wiped)
+}
@@ -0,0 +1,60 @@
+//! `--status-file`: the `--status --json` objects of every filesystem found,
+//! in a file that monitoring agents without privileges can read and watch
+//! with inotify, rather than probing devices themselves.
+//!
+//! The file is one JSON object and a newline:
+//!
+//! `{"schema_version":1,"filesystems":[<status>,...]}`
+//!
+//! with the filesystems sorted by UUID, each as `--status --json` prints it.
+//! Only those fields are written: no device paths, nothing from the key or
+//! the crypt field beyond whether there is one. `schema_version` is raised
+//! when fields change meaning or go away; new fields may be added without.
+//!
+//! The file is replaced by a rename, so readers see the old or the new one,
+//! never half of one, and inotify reports IN_MOVED_TO on its directory.
+//! Writers take `<file>.lock` first, so two of them can't interleave.
+
+use crate::filesystem::Status;
+use std::path::Path;
+use std::time::Duration;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// How long to wait for another writer
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The file's contents for `statuses`, in the order given
+pub fn render(statuses: &[Status]) -> String {
+	use crate::json::{array, object};
+
+	let filesystems = array(statuses.iter().map(Status::to_json));
+	format!("{}\n", object(&[("schema_version", SCHEMA_VERSION.to_string()), ("filesystems", filesystems)]))
+}
+
+/// Replace the file at `path` with one for `statuses`, readable by everyone
+/// regardless of the umask
+#[tracing_attributes::instrument(skip(statuses))]
+pub fn write(path: &Path, statuses: &[Status]) -> anyhow::Result<()> {
+	use std::io::Write;
+	use std::os::unix::fs::PermissionsExt;
+
+	let mut lock = path.as_os_str().to_owned();
+	lock.push(".lock");
+	let _lock = crate::lock::lock_file(Path::new(&lock), LOCK_TIMEOUT)?;
+
+	let mut tmp = path.as_os_str().to_owned();
+	tmp.push(format!(".{}", std::process::id()));
+	let tmp = Path::new(&tmp);
+	let written = std::fs::File::create(tmp).and_then(|mut file| {
+		file.set_permissions(std::fs::Permissions::from_mode(0o644))?;
+		file.write_all(render(statuses).as_bytes())?;
+		file.sync_all()
+	});
+	if let Err(e) = written.and_then(|()| std::fs::rename(tmp, path)) {
+		let _ = std::fs::remove_file(tmp);
+		return Err(e.into());
+	}
+	tracing::debug!(msg="status file written", path=%path.display(), filesystems=statuses.len());
+	Ok(())
+}
@@ -0,0 +1,206 @@
+//! `bcachefs-watch`: follow the member devices of a mounted filesystem, as the
+//! kernel shows them under /sys/fs/bcachefs/<uuid>/dev-<n>/, and report when
+//! one changes state or its I/O error counters go up.
+//!
+//! sysfs attributes don't notify inotify watchers of changes, so they are
+//! polled. Only newer kernels have the `io_errors` attribute; with older ones,
+//! just the state is followed.
+
+use crate::paths::Paths;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// I/O errors of a member since the filesystem was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IoErrors {
+	pub read: u64,
+	pub write: u64,
+	pub checksum: u64,
+}
+
+/// A member device as the kernel shows it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+	/// Index in the superblock, from the name of its directory
+	pub index: u32,
+	/// Block device, from the `block` link
+	pub device: Option<PathBuf>,
+	/// "rw", "ro", "failed" or "spare"
+	pub state: String,
+	pub errors: Option<IoErrors>,
+}
+
+impl std::fmt::Display for Member {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "dev-{}", self.index)?;
+		match &self.device {
+			Some(device) => write!(f, " ({})", device.display()),
+			None => Ok(()),
+		}
+	}
+}
+
+/// The current state in the `state` attribute: all of it, or, as older
+/// kernels write it, the one in brackets in the list of them, e.g. "rw" of
+/// "[rw] ro failed spare"
+pub fn parse_state(attr: &str) -> String {
+	let attr = attr.trim();
+	match (attr.find('['), attr.find(']')) {
+		(Some(start), Some(end)) if start < end => attr[start + 1..end].to_owned(),
+		_ => attr.to_owned(),
+	}
+}
+
+/// The counters in the first section of the `io_errors` attribute, the one
+/// since the filesystem was created; a later one counts since they were last
+/// reset
+pub fn parse_io_errors(attr: &str) -> IoErrors {
+	let mut errors = IoErrors::default();
+	let section = attr.lines().skip(1).take_while(|l| l.starts_with(char::is_whitespace));
+	for line in section {
+		let mut parts = line.splitn(2, ':');
+		let name = parts.next().unwrap_or_default().trim();
+		let count = parts.next().and_then(|c| c.trim().parse().ok()).unwrap_or(0);
+		match name {
+			"read" => errors.read = count,
+			"write" => errors.write = count,
+			"checksum" => errors.checksum = count,
+			_ => {}
+		}
+	}
+	errors
+}
+
+/// The members of the mounted filesystem `uuid` by index, read from sysfs
+pub fn read_members(uuid: &Uuid, paths: &Paths) -> std::io::Result<Vec<Member>> {
+	use std::io::ErrorKind::NotFound;
+
+	let dir = paths.sys(format!("fs/bcachefs/{}", uuid));
+	let mut members = Vec::new();
+	for entry in std::fs::read_dir(&dir)? {
+		let entry = entry?;
+		let name = entry.file_name();
+		let index = match name.to_str().and_then(|n| n.strip_prefix("dev-")).and_then(|i| i.parse().ok()) {
+			Some(index) => index,
+			None => continue,
+		};
+		let dev_dir = entry.path();
+		// a member removed while it is read is simply not there anymore
+		let state = match std::fs::read_to_string(dev_dir.join("state")) {
+			Ok(state) => parse_state(&state),
+			Err(e) if e.kind() == NotFound => continue,
+			Err(e) => return Err(e),
+		};
+		let device = std::fs::read_link(dev_dir.join("block"))
+			.ok()
+			.and_then(|link| link.file_name().map(|name| paths.dev_root.join(name)));
+		let errors = std::fs::read_to_string(dev_dir.join("io_errors")).ok().map(|e| parse_io_errors(&e));
+		members.push(Member { index, device, state, errors });
+	}
+	members.sort_by_key(|m| m.index);
+	Ok(members)
+}
+
+/// What changed about a member between two polls
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+	Added(Member),
+	Removed(Member),
+	State { member: Member, from: String },
+	Errors { member: Member, kind: &'static str, from: u64, to: u64 },
+}
+
+impl Change {
+	/// Whether this is a member going to the failed state
+	pub fn failed(&self) -> bool {
+		matches!(self, Change::State { member, from } if member.state == "failed" && from != "failed")
+	}
+}
+
+impl std::fmt::Display for Change {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			Change::Added(member) => write!(f, "{}", msg!(MemberAdded, member, member.state)),
+			Change::Removed(member) => write!(f, "{}", msg!(MemberRemoved, member)),
+			Change::State { member, from } => write!(f, "{}", msg!(MemberStateChanged, member, from, member.state)),
+			Change::Errors { member, kind, from, to } => {
+				write!(f, "{}", msg!(MemberErrorsChanged, member, kind, from, to))
+			}
+		}
+	}
+}
+
+/// How the members went from `old` to `new`, by index
+pub fn changes(old: &[Member], new: &[Member]) -> Vec<Change> {
+	let mut changes = Vec::new();
+	for member in old.iter().filter(|o| !new.iter().any(|n| n.index == o.index)) {
+		changes.push(Change::Removed(member.clone()));
+	}
+	for member in new {
+		let before = match old.iter().find(|o| o.index == member.index) {
+			Some(before) => before,
+			None => {
+				changes.push(Change::Added(member.clone()));
+				continue;
+			}
+		};
+		if before.state != member.state {
+			changes.push(Change::State { member: member.clone(), from: before.state.clone() });
+		}
+		if let (Some(from), Some(to)) = (before.errors, member.errors) {
+			let counts =
+				[("read", from.read, to.read), ("write", from.write, to.write), ("checksum", from.checksum, to.checksum)];
+			for &(kind, from, to) in counts.iter().filter(|(_, from, to)| from != to) {
+				changes.push(Change::Errors { member: member.clone(), kind, from, to });
+			}
+		}
+	}
+	changes
+}
+
+/// Print the members of the mounted filesystem `uuid` to `out`, then a line
+/// for each change, polling every `interval`. Returns when the filesystem is
+/// unmounted, or with an error when a member fails.
+#[tracing_attributes::instrument(skip(out, paths))]
+pub fn watch(out: &mut impl Write, uuid: &Uuid, interval: Duration, paths: &Paths) -> anyhow::Result<()> {
+	use std::io::ErrorKind::NotFound;
+
+	let mut members = match read_members(uuid, paths) {
+		Ok(members) => members,
+		Err(e) if e.kind() == NotFound => return Err(err!(FsNotMounted, uuid)),
+		Err(e) => return Err(e.into()),
+	};
+	for member in &members {
+		match member.errors {
+			Some(e) => writeln!(out, "{}", msg!(MemberErrors, member, member.state, e.read, e.write, e.checksum))?,
+			None => writeln!(out, "{}", msg!(MemberState, member, member.state))?,
+		}
+		if member.state == "failed" {
+			tracing::warn!(msg="member has already failed", %member);
+		}
+	}
+	out.flush()?;
+
+	loop {
+		std::thread::sleep(interval);
+		let now = match read_members(uuid, paths) {
+			Ok(now) => now,
+			Err(e) if e.kind() == NotFound => {
+				writeln!(out, "{}", msg!(FsUnmounted, uuid))?;
+				return Ok(());
+			}
+			Err(e) => return Err(e.into()),
+		};
+		let changes = changes(&members, &now);
+		for change in &changes {
+			writeln!(out, "{}", change)?;
+		}
+		out.flush()?;
+		if let Some(Change::State { member, .. }) = changes.iter().find(|c| c.failed()) {
+			return Err(err!(MemberFailed, member, uuid));
+		}
+		members = now;
+	}
+}
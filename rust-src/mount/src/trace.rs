@@ -0,0 +1,117 @@
+//! Spans as Chrome trace events, for loading the mounts of a whole boot into
+//! Perfetto or chrome://tracing.
+//!
+//! Every time a span is entered and exited, a "B" and an "E" event are
+//! written, with the span's fields (e.g. uuid and device) as args.
+//! Timestamps are CLOCK_MONOTONIC microseconds, so traces written by several
+//! processes during the same boot line up. The JSON array is never closed,
+//! which the trace event format allows, so a trace cut short by a crash is
+//! still readable.
+
+use std::io::Write;
+use std::sync::Mutex;
+use tracing::{field::Field, span, Subscriber};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// Fields of a span, kept in its extensions until it is entered
+#[derive(Debug, Default)]
+struct Args(Vec<(&'static str, String)>);
+
+impl tracing::field::Visit for Args {
+	fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+		self.0.push((field.name(), format!("{:?}", value)));
+	}
+
+	fn record_str(&mut self, field: &Field, value: &str) {
+		self.0.push((field.name(), value.to_owned()));
+	}
+}
+
+fn monotonic_us() -> u64 {
+	let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+	unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+	ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1000
+}
+
+/// A `tracing_subscriber` layer writing Chrome trace events to `W`
+pub struct ChromeLayer<W> {
+	out: Mutex<W>,
+	clock: fn() -> u64,
+}
+
+impl ChromeLayer<std::io::BufWriter<std::fs::File>> {
+	/// Trace to the file at `path`, replacing it
+	pub fn create(path: &std::path::Path) -> std::io::Result<Self> {
+		Self::new(std::io::BufWriter::new(std::fs::File::create(path)?))
+	}
+}
+
+impl<W: Write> ChromeLayer<W> {
+	pub fn new(out: W) -> std::io::Result<Self> {
+		Self::with_clock(out, monotonic_us)
+	}
+
+	/// Like [`ChromeLayer::new`], taking timestamps in microseconds from `clock`
+	pub fn with_clock(mut out: W, clock: fn() -> u64) -> std::io::Result<Self> {
+		out.write_all(b"[\n")?;
+		out.flush()?;
+		Ok(ChromeLayer { out: Mutex::new(out), clock })
+	}
+
+	fn write_event(&self, name: &str, ph: &str, args: Option<&Args>) {
+		use crate::json::{object, string};
+
+		// the kernel's thread id, which std doesn't give
+		let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+		let mut fields = vec![
+			("name", string(name)),
+			("ph", string(ph)),
+			("ts", (self.clock)().to_string()),
+			("pid", std::process::id().to_string()),
+			("tid", tid.to_string()),
+		];
+		if let Some(args) = args {
+			let args: Vec<_> = args.0.iter().map(|(k, v)| (*k, string(v))).collect();
+			fields.push(("args", object(&args)));
+		}
+		if let Ok(mut out) = self.out.lock() {
+			// losing the trace is no reason to fail the mount
+			let _ = writeln!(out, "{},", object(&fields));
+			let _ = out.flush();
+		}
+	}
+}
+
+impl<S, W> Layer<S> for ChromeLayer<W>
+where
+	S: Subscriber + for<'a> LookupSpan<'a>,
+	W: Write + 'static,
+{
+	fn new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+		let mut args = Args::default();
+		attrs.record(&mut args);
+		if let Some(span) = ctx.span(id) {
+			span.extensions_mut().insert(args);
+		}
+	}
+
+	fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+		if let Some(span) = ctx.span(id) {
+			if let Some(args) = span.extensions_mut().get_mut::<Args>() {
+				values.record(args);
+			}
+		}
+	}
+
+	fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+		if let Some(span) = ctx.span(id) {
+			self.write_event(span.name(), "B", span.extensions().get::<Args>());
+		}
+	}
+
+	fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+		if let Some(span) = ctx.span(id) {
+			self.write_event(span.name(), "E", None);
+		}
+	}
+}
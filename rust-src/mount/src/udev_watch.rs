@@ -0,0 +1,180 @@
+//! `--watch`: follow bcachefs member devices as udev adds and removes them.
+//!
+//! Block devices are probed once at startup; after that, only the device an
+//! event is about is probed and merged into (or dropped from) the
+//! filesystems found so far. A JSON line is printed whenever a filesystem
+//! gains or loses a member, and not for events that change nothing.
+
+use crate::filesystem::{self, FileSystem};
+use crate::paths::Paths;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// A filesystem gained or lost a member device
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+	pub added: bool,
+	pub uuid: Uuid,
+	pub device: PathBuf,
+	/// Members known after the change
+	pub devices: Vec<PathBuf>,
+	pub devices_total: usize,
+}
+
+impl Change {
+	fn new(added: bool, fs: &FileSystem, device: &Path) -> Self {
+		Change {
+			added,
+			uuid: *fs.uuid(),
+			device: device.to_owned(),
+			devices: fs.members().iter().map(|m| m.path().to_owned()).collect(),
+			devices_total: fs.sb().sb().nr_devices as usize,
+		}
+	}
+
+	pub fn degraded(&self) -> bool {
+		self.devices.len() < self.devices_total
+	}
+
+	pub fn to_json(&self) -> String {
+		use crate::json::{array, object, string};
+
+		let path = |p: &PathBuf| string(&p.to_string_lossy());
+		object(&[
+			("event", string(if self.added { "add" } else { "remove" })),
+			("uuid", string(&self.uuid.to_string())),
+			("device", path(&self.device)),
+			("devices", array(self.devices.iter().map(path))),
+			("devices_found", self.devices.len().to_string()),
+			("devices_total", self.devices_total.to_string()),
+			("degraded", self.degraded().to_string()),
+		])
+	}
+}
+
+/// The bcachefs filesystems found so far, kept up to date one device at a
+/// time
+#[derive(Debug, Default)]
+pub struct Inventory(HashMap<Uuid, FileSystem>);
+
+impl Inventory {
+	pub fn new(filesystems: HashMap<Uuid, FileSystem>) -> Self {
+		Inventory(filesystems)
+	}
+
+	pub fn filesystems(&self) -> &HashMap<Uuid, FileSystem> {
+		&self.0
+	}
+
+	/// Write `--status-file` for what is known, or warn if it can't be
+	fn write_status_file(&self, path: &Path) {
+		let mut statuses: Vec<_> = self.0.values().map(FileSystem::status).collect();
+		statuses.sort_by_key(|s| s.uuid);
+		if let Err(e) = crate::statusfile::write(path, &statuses) {
+			tracing::warn!(msg="status file can't be written", path=%path.display(), error=%e);
+		}
+	}
+
+	/// Merge in `fs`, found by probing a single device. `None` if that device
+	/// was known already, or left out as holding a member known already.
+	pub fn add(&mut self, uuid: Uuid, fs: FileSystem) -> Option<Change> {
+		let device = fs.members().first()?.path().to_owned();
+		match self.0.get_mut(&uuid) {
+			Some(existing) if existing.members().iter().any(|m| m.path() == &device) => None,
+			Some(existing) => match existing.merge(fs).contains(&device) {
+				true => None,
+				false => Some(Change::new(true, existing, &device)),
+			},
+			None => {
+				let change = Change::new(true, &fs, &device);
+				self.0.insert(uuid, fs);
+				Some(change)
+			}
+		}
+	}
+
+	/// Drop `device` from the filesystem it belongs to, and the filesystem
+	/// once it has no members left. `None` if it wasn't a member of any.
+	pub fn remove(&mut self, device: &Path) -> Option<Change> {
+		let fs = self.0.values_mut().find(|fs| fs.members().iter().any(|m| m.path() == device))?;
+		fs.remove_member(device);
+		let change = Change::new(false, fs, device);
+		if fs.members().is_empty() {
+			self.0.remove(&change.uuid);
+		}
+		Some(change)
+	}
+
+	/// Probe `device` again after it was added or changed, e.g. reformatted,
+	/// and return what changed
+	pub fn update(&mut self, device: &Path, paths: &Paths) -> anyhow::Result<Vec<Change>> {
+		let found = filesystem::probe_with([device.to_owned()].as_slice(), paths)?.into_iter().next();
+		let current = self.0.iter().find(|(_, fs)| fs.members().iter().any(|m| m.path() == device)).map(|(u, _)| *u);
+		match (current, found) {
+			(Some(current), Some((uuid, _))) if current == uuid => Ok(Vec::new()),
+			(current, found) => {
+				let removed = current.and_then(|_| self.remove(device));
+				let added = found.and_then(|(uuid, fs)| self.add(uuid, fs));
+				Ok(removed.into_iter().chain(added).collect())
+			}
+		}
+	}
+}
+
+/// Probe every block device, print an add event for each member found, then
+/// follow udev events for block devices until an error occurs. `status_file`
+/// is kept up to date with the filesystems found.
+pub fn watch(out: &mut impl std::io::Write, status_file: Option<&Path>, paths: &Paths) -> anyhow::Result<()> {
+	use std::os::unix::io::AsRawFd;
+
+	// listen before probing, so devices appearing in between aren't missed
+	let mut socket = udev::MonitorBuilder::new()?.match_subsystem("block")?.listen()?;
+
+	let mut inventory = Inventory::new(filesystem::probe_filesystems(paths)?);
+	for fs in inventory.filesystems().values() {
+		for m in fs.members() {
+			writeln!(out, "{}", Change::new(true, fs, m.path()).to_json())?;
+		}
+	}
+	out.flush()?;
+	if let Some(path) = status_file {
+		inventory.write_status_file(path);
+	}
+
+	loop {
+		let mut pollfd = libc::pollfd { fd: socket.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+		if unsafe { libc::poll(&mut pollfd, 1, -1) } < 0 {
+			let e = errno::errno();
+			if e.0 == libc::EINTR {
+				continue;
+			}
+			return Err(crate::ErrnoError(e).into());
+		}
+		for event in &mut socket {
+			let device = match event.devnode() {
+				Some(device) => device.to_owned(),
+				None => continue,
+			};
+			let changes = match event.event_type() {
+				udev::EventType::Add | udev::EventType::Change => match inventory.update(&device, paths) {
+					Ok(changes) => changes,
+					Err(e) => {
+						tracing::warn!(msg="could not probe device", device=%device.display(), error=%e);
+						continue;
+					}
+				},
+				udev::EventType::Remove => inventory.remove(&device).into_iter().collect(),
+				_ => continue,
+			};
+			if let (Some(path), false) = (status_file, changes.is_empty()) {
+				inventory.write_status_file(path);
+			}
+			for change in changes {
+				tracing::info!(msg="filesystem membership changed", uuid=%change.uuid, device=%change.device.display(), added=change.added);
+				writeln!(out, "{}", change.to_json())?;
+			}
+		}
+		out.flush()?;
+	}
+}
@@ -0,0 +1,65 @@
+//! Audit records as they are logged and sent to the kernel.
+
+mod common;
+
+use bcachefs_mount::audit::{netlink_message, Op, Record, Sink};
+use common::UUID;
+use std::cell::RefCell;
+use std::path::PathBuf;
+
+#[test]
+fn records_are_key_value_lines() {
+	let mut record = Record::new(Op::KeyAdded, UUID);
+	assert_eq!(record.uid, unsafe { libc::getuid() });
+	assert_eq!(record.pid, std::process::id() as libc::pid_t);
+
+	record.uid = 1000;
+	record.pid = 42;
+	record.serial = Some(123456);
+	record.devices = vec![PathBuf::from("/dev/sda"), PathBuf::from("/dev/sdb")];
+	assert_eq!(
+		record.to_string(),
+		format!("op=key-added uuid={} keyring=user serial=123456 devices=\"/dev/sda:/dev/sdb\" uid=1000 pid=42", UUID)
+	);
+
+	let mut record = Record::new(Op::EncryptedMount, UUID);
+	record.uid = 0;
+	record.pid = 7;
+	record.target = Some(PathBuf::from("/srv/my tank"));
+	assert_eq!(record.to_string(), format!("op=encrypted-mount uuid={} target=\"/srv/my tank\" uid=0 pid=7", UUID));
+}
+
+#[test]
+fn netlink_message_layout() {
+	let text = "op=key-removed";
+	let message = netlink_message(text);
+	let header = std::mem::size_of::<libc::nlmsghdr>();
+	assert_eq!(header, 16);
+	// the length leaves out the padding, which rounds up to 4 bytes
+	assert_eq!(u32::from_ne_bytes([message[0], message[1], message[2], message[3]]) as usize, header + text.len() + 1);
+	assert_eq!(message.len(), 32);
+	assert_eq!(u16::from_ne_bytes([message[4], message[5]]), 1121);
+	assert_eq!(u16::from_ne_bytes([message[6], message[7]]), libc::NLM_F_REQUEST as u16);
+	assert_eq!(&message[header..header + text.len()], text.as_bytes());
+	assert!(message[header + text.len()..].iter().all(|&b| b == 0));
+}
+
+/// Keeps records, as a test would of what a command records
+#[derive(Default)]
+struct Collect(RefCell<Vec<Record>>);
+
+impl Sink for Collect {
+	fn record(&self, record: &Record) {
+		self.0.borrow_mut().push(record.clone());
+	}
+}
+
+#[test]
+fn sinks_take_records_by_reference() {
+	let collect = Collect::default();
+	let sink: &dyn Sink = &collect;
+	sink.record(&Record::new(Op::KeyRemoved, UUID));
+	sink.record(&Record::new(Op::EncryptedUnmount, UUID));
+	let ops: Vec<_> = collect.0.borrow().iter().map(|r| r.op.name()).collect();
+	assert_eq!(ops, ["key-removed", "encrypted-unmount"]);
+}
@@ -0,0 +1,88 @@
+//! Smoke tests of the binaries, each built only with its cargo feature, and
+//! of how `bcachefs-rs` picks the command to run.
+
+mod common;
+
+use std::process::{Command, Output};
+
+fn run(bin: impl AsRef<std::ffi::OsStr>, args: &[&str]) -> Output {
+	Command::new(bin).args(args).output().unwrap()
+}
+
+fn stdout(out: &Output) -> String {
+	String::from_utf8_lossy(&out.stdout).into_owned()
+}
+
+#[cfg(feature = "mount")]
+#[test]
+fn mount_prints_its_version() {
+	let out = run(env!("CARGO_BIN_EXE_bcachefs-mount"), &["--version"]);
+	assert!(out.status.success());
+	assert!(stdout(&out).starts_with("bcachefs-mount "));
+}
+
+#[cfg(feature = "tools")]
+#[test]
+fn tools_have_their_own_usage() {
+	for (bin, name) in vec![
+		(env!("CARGO_BIN_EXE_bcachefs-list"), "bcachefs-list"),
+		(env!("CARGO_BIN_EXE_bcachefs-show-super"), "bcachefs-show-super"),
+		(env!("CARGO_BIN_EXE_bcachefs-debug"), "bcachefs-debug"),
+		(env!("CARGO_BIN_EXE_bcachefs-forget-key"), "bcachefs-forget-key"),
+		(env!("CARGO_BIN_EXE_bcachefs-metrics"), "bcachefs-metrics"),
+		(env!("CARGO_BIN_EXE_bcachefs-watch"), "bcachefs-watch"),
+	] {
+		let out = run(bin, &["--help"]);
+		assert!(out.status.success(), "{}", name);
+		assert!(stdout(&out).starts_with(name), "{}", stdout(&out));
+	}
+}
+
+#[cfg(feature = "tools")]
+#[test]
+fn show_super_exits_by_error_kind() {
+	let out = run(env!("CARGO_BIN_EXE_bcachefs-show-super"), &["/nonexistent/device"]);
+	assert_eq!(out.status.code(), Some(3));
+}
+
+#[cfg(feature = "mount")]
+#[test]
+fn errors_go_to_stderr() {
+	let out = run(env!("CARGO_BIN_EXE_bcachefs-mount"), &["-o", "remount", "LABEL=x"]);
+	assert!(!out.status.success());
+	assert_eq!(stdout(&out), "");
+	assert!(String::from_utf8_lossy(&out.stderr).contains("ERROR"));
+}
+
+#[cfg(feature = "tools")]
+#[test]
+fn multicall_dispatches_on_first_argument() {
+	let bin = env!("CARGO_BIN_EXE_bcachefs-rs");
+	let out = run(bin, &["mount", "--version"]);
+	assert!(out.status.success());
+	assert!(stdout(&out).starts_with("bcachefs-mount "));
+	assert_eq!(run(bin, &["show-super", "/nonexistent/device"]).status.code(), Some(3));
+
+	let out = run(bin, &["frobnicate"]);
+	assert_eq!(out.status.code(), Some(2));
+	assert_eq!(
+		String::from_utf8_lossy(&out.stderr),
+		"unknown command 'frobnicate', expected one of: mount, list, show-super, debug, forget-key, metrics, watch\n"
+	);
+}
+
+#[cfg(feature = "tools")]
+#[test]
+fn multicall_dispatches_on_its_name() {
+	let dir = common::TempDir::new("binaries");
+	let link = |name: &str| {
+		let path = dir.join(name);
+		std::os::unix::fs::symlink(env!("CARGO_BIN_EXE_bcachefs-rs"), &path).unwrap();
+		path
+	};
+	let show_super = run(link("bcachefs-show-super"), &["--help"]);
+	let mount = run(link("mount.bcachefs"), &["--version"]);
+
+	assert!(stdout(&show_super).starts_with("bcachefs-show-super"));
+	assert!(stdout(&mount).starts_with("bcachefs-mount "));
+}
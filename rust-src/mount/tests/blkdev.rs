@@ -0,0 +1,35 @@
+//! Block device queries borrow the descriptor the superblock handle holds
+//! rather than opening the device again, and never close it. A single test,
+//! as descriptors opened by tests running alongside would throw off the
+//! count.
+
+mod common;
+
+use bcachefs_mount::filesystem::{is_read_only, logical_block_size};
+use bch_bindgen::bcachefs::{bch_sb_handle, block_device};
+use std::os::unix::io::AsRawFd;
+
+fn open_fds() -> usize {
+	std::fs::read_dir("/proc/self/fd").unwrap().count()
+}
+
+#[test]
+fn queries_borrow_the_handle_fd() {
+	let dir = common::TempDir::new("blkdev");
+	let file = std::fs::File::create(dir.join("image")).unwrap();
+
+	let mut bdev: block_device = unsafe { std::mem::zeroed() };
+	bdev.bd_fd = file.as_raw_fd();
+	let mut handle: bch_sb_handle = unsafe { std::mem::zeroed() };
+	handle.bdev = &mut bdev;
+
+	let before = open_fds();
+	let fd = handle.fd().unwrap();
+	for _ in 0..100 {
+		// not a block device, but the ioctl is still made on the borrowed fd
+		assert_eq!(is_read_only(fd).unwrap_err().raw_os_error(), Some(libc::ENOTTY));
+		assert_eq!(logical_block_size(fd).unwrap_err().raw_os_error(), Some(libc::ENOTTY));
+	}
+	assert_eq!(open_fds(), before);
+	assert!(unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0, "borrowed fd was closed");
+}
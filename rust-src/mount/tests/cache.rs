@@ -0,0 +1,44 @@
+//! The probe cache: its format and when it is fresh enough to use.
+
+mod common;
+
+use bcachefs_mount::cache::{load, store, ProbeCache, TTL};
+use common::TempDir;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+#[test]
+fn round_trip() {
+	let cache = ProbeCache { seqnum: 4711, devices: vec!["/dev/sda".into(), "/dev/disk with space".into()] };
+	assert_eq!(cache.to_string(), "seqnum 4711\n/dev/sda\n/dev/disk with space\n");
+	assert_eq!(ProbeCache::parse(&cache.to_string()), Some(cache));
+	assert_eq!(ProbeCache::parse("seqnum 1\n"), Some(ProbeCache { seqnum: 1, devices: Vec::new() }));
+}
+
+#[test]
+fn partial_or_garbled_caches_are_ignored() {
+	assert_eq!(ProbeCache::parse(""), None);
+	assert_eq!(ProbeCache::parse("seqnum 1\n/dev/sd"), None);
+	assert_eq!(ProbeCache::parse("seqnum x\n"), None);
+	assert_eq!(ProbeCache::parse("/dev/sda\n"), None);
+}
+
+#[test]
+fn fresh_until_a_uevent_or_expiry() {
+	let dir = TempDir::new("cache.fresh");
+	let now = SystemTime::now();
+	let missing = load(&dir, 7, TTL, now);
+	store(&dir, &ProbeCache { seqnum: 7, devices: vec!["/dev/sdb".into()] }).unwrap();
+	let fresh = load(&dir, 7, TTL, now);
+	let changed = load(&dir, 8, TTL, now);
+	let expired = load(&dir, 7, TTL, now + TTL + Duration::from_secs(1));
+	let mode = std::fs::metadata(dir.join("probe-cache")).map(|m| m.permissions().mode());
+
+	assert_eq!(missing, None);
+	assert_eq!(fresh, Some(vec![PathBuf::from("/dev/sdb")]));
+	assert_eq!(changed, None);
+	assert_eq!(expired, None);
+	// for agents without privileges
+	assert_eq!(mode.unwrap() & 0o777, 0o644);
+}
@@ -0,0 +1,68 @@
+/*
+ * Uses the C interface the way a C program would, against whatever block
+ * devices there are: none of them is expected to hold a filesystem labelled
+ * "bcachefs-mount capi test", or, without root, to be readable at all.
+ * Prints what it checked and exits non-zero on the first thing that isn't
+ * as the header says.
+ */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "bcachefs_mount.h"
+
+#define MISSING "LABEL=bcachefs-mount capi test"
+
+#define check(cond)								\
+	do {									\
+		if (!(cond)) {							\
+			fprintf(stderr, "%s:%d: %s\n", __FILE__, __LINE__, #cond);	\
+			return 1;						\
+		}								\
+		printf("ok %s\n", #cond);					\
+	} while (0)
+
+int main(void)
+{
+	struct bcachefs_mount_device *devices;
+	size_t count = 42;
+	char *error = NULL;
+	int ret;
+
+	devices = bcachefs_mount_probe(MISSING, &count, &error);
+	check(devices == NULL);
+	check(count == 0);
+	check(error != NULL && *error != '\0');
+	/* malloc()ed, so free() it is */
+	free(error);
+	error = NULL;
+
+	/* errors are optional */
+	check(bcachefs_mount_probe(MISSING, &count, NULL) == NULL);
+
+	check(bcachefs_mount_probe("not a uuid!", &count, &error) == NULL);
+	check(error != NULL);
+	free(error);
+	error = NULL;
+
+	check(bcachefs_mount_probe(NULL, &count, &error) == NULL);
+	check(error != NULL && strcmp(error, "spec is NULL") == 0);
+	free(error);
+	error = NULL;
+
+	check(bcachefs_mount_probe(MISSING, NULL, NULL) == NULL);
+
+	/* the exit status of bcachefs-mount: 3 for not found, 4 for no permission
+	 * to read any device, 2 for bad arguments */
+	ret = bcachefs_mount_unlock(MISSING, "passphrase", &error);
+	check(ret == 3 || ret == 4);
+	check(error != NULL);
+	free(error);
+	error = NULL;
+
+	check(bcachefs_mount_unlock(MISSING, NULL, NULL) == 2);
+
+	bcachefs_mount_free(NULL, 0);
+	return 0;
+}
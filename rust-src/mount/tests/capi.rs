@@ -0,0 +1,70 @@
+//! The C interface: a C program built against the header and the static
+//! library, and the device arrays it hands out.
+
+#![cfg(feature = "capi")]
+
+mod common;
+
+use bcachefs_mount::capi::{bcachefs_mount_free, devices};
+use bcachefs_mount::filesystem::{FileSystem, Member};
+use common::{Superblock, TempDir};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The static library cargo built along with this test, which it leaves in
+/// target/<profile>/deps with a hash in its name, next to the test itself
+fn static_library() -> PathBuf {
+	let exe = std::env::current_exe().unwrap();
+	let deps = exe.parent().unwrap();
+	std::fs::read_dir(deps)
+		.unwrap()
+		.map(|e| e.unwrap().path())
+		.filter(|p| {
+			let name = p.file_name().unwrap().to_string_lossy();
+			name.starts_with("libbcachefs_mount-") && name.ends_with(".a")
+		})
+		.max_by_key(|p| p.metadata().unwrap().modified().unwrap())
+		.expect("libbcachefs_mount.a was not built")
+}
+
+#[test]
+fn c_program_links_and_runs() {
+	let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+	let dir = TempDir::new("capi");
+	let out = dir.join("capi");
+
+	let mut cc = Command::new(std::env::var_os("CC").unwrap_or_else(|| "cc".into()));
+	cc.args(&["-Wall", "-Werror", "-o"])
+		.arg(&out)
+		.arg("-I")
+		.arg(manifest.join("include"))
+		.arg(manifest.join("tests").join("capi.c"))
+		.arg(static_library());
+	// what the static library leaves to the linker; libbcachefs is where
+	// bch_bindgen was told to find it, which build.rs passes on
+	cc.arg(concat!("-L", env!("LIBBCACHEFS_LIB")));
+	cc.args(&["-lbcachefs", "-ludev", "-lpthread", "-ldl", "-lm"]);
+	let built = cc.output().unwrap();
+	assert!(built.status.success(), "{}", String::from_utf8_lossy(&built.stderr));
+
+	let ran = Command::new(&out).output().unwrap();
+	assert!(
+		ran.status.success(),
+		"{}{}",
+		String::from_utf8_lossy(&ran.stdout),
+		String::from_utf8_lossy(&ran.stderr)
+	);
+}
+
+#[test]
+fn device_arrays_have_a_path_per_member() {
+	let sb = Superblock::default().build();
+	let fs = FileSystem::new(common::handle(&sb), Member::new(PathBuf::from("/dev/sdb"), true, false));
+
+	let (array, count) = devices(&fs);
+	assert_eq!(count, 1);
+	let device = unsafe { &*array };
+	assert_eq!(unsafe { std::ffi::CStr::from_ptr(device.path) }.to_str().unwrap(), "/dev/sdb");
+	assert!(device.read_only);
+	unsafe { bcachefs_mount_free(array, count) };
+}
@@ -0,0 +1,150 @@
+//! Doctored superblocks, filesystems made of them without any devices, and
+//! scratch directories, for the tests to share. Not every test uses all of
+//! it.
+
+#![allow(dead_code)]
+
+use bcachefs_mount::filesystem::{FileSystem, Member};
+use bch_bindgen::bcachefs::{bch_sb, bch_sb_handle, block_device, SbHandle};
+use bch_bindgen::rs::{metadata_versions, SbBuf, SUPERBLOCK_MAGIC};
+use std::os::unix::io::IntoRawFd;
+use std::path::{Path, PathBuf};
+
+/// The UUID of the filesystem the tests are about, unless they need several
+pub const UUID: uuid::Uuid = uuid::Uuid::from_u128(0x8b1c7a3e_5f0e_4d0a_9b5e_3c2a1d0e9f8a);
+
+/// BCH_SB_FIELD_members
+pub const FIELD_MEMBERS: u32 = 1;
+/// BCH_SB_FIELD_crypt
+pub const FIELD_CRYPT: u32 = 2;
+/// BCH_SB_FIELD_replicas
+pub const FIELD_REPLICAS: u32 = 7;
+
+/// A superblock being built: the header of a single device filesystem
+/// with UUID `UUID` in the current metadata version, then any fields added
+pub struct Superblock {
+	words: Vec<u64>,
+}
+
+impl Default for Superblock {
+	fn default() -> Self {
+		let mut sb = Superblock { words: vec![0; std::mem::size_of::<bch_sb>() / 8] };
+		let hdr = sb.hdr();
+		hdr.magic.b = *SUPERBLOCK_MAGIC.as_bytes();
+		hdr.version = *metadata_versions().end();
+		hdr.version_min = *metadata_versions().start();
+		hdr.user_uuid.b = *UUID.as_bytes();
+		hdr.nr_devices = 1;
+		sb
+	}
+}
+
+impl Superblock {
+	fn hdr(&mut self) -> &mut bch_sb {
+		unsafe { &mut *(self.words.as_mut_ptr() as *mut bch_sb) }
+	}
+
+	pub fn uuid(self, uuid: uuid::Uuid) -> Self {
+		self.doctor(|sb| sb.user_uuid.b = *uuid.as_bytes())
+	}
+
+	pub fn internal_uuid(self, uuid: uuid::Uuid) -> Self {
+		self.doctor(|sb| sb.uuid.b = *uuid.as_bytes())
+	}
+
+	pub fn label(self, label: &str) -> Self {
+		self.doctor(|sb| sb.label[..label.len()].copy_from_slice(label.as_bytes()))
+	}
+
+	/// An empty crypt field, as encrypted filesystems have
+	pub fn encrypted(self) -> Self {
+		// flags, kdf_flags, magic and a 4 u64 key
+		self.field(FIELD_CRYPT, &[0; 7])
+	}
+
+	/// Append a field of type `kind` holding `body`, after its header
+	pub fn field(mut self, kind: u32, body: &[u64]) -> Self {
+		let u64s = 1 + body.len();
+		self.words.push(u64s as u64 | (kind as u64) << 32);
+		self.words.extend_from_slice(body);
+		self.hdr().u64s += u64s as u32;
+		self
+	}
+
+	/// Whatever else the test needs done to the header
+	pub fn doctor(mut self, doctor: impl FnOnce(&mut bch_sb)) -> Self {
+		doctor(self.hdr());
+		self
+	}
+
+	pub fn bytes(&self) -> Vec<u8> {
+		self.words.iter().flat_map(|w| w.to_ne_bytes()).collect()
+	}
+
+	pub fn build(&self) -> SbBuf {
+		SbBuf::from_bytes(&self.bytes()).unwrap()
+	}
+}
+
+/// A handle lent `sb`, with no device behind it
+pub fn handle(sb: &SbBuf) -> SbHandle {
+	let mut handle: bch_sb_handle = unsafe { std::mem::zeroed() };
+	handle.sb = sb.sb() as *const _ as *mut _;
+	SbHandle::borrowed(handle)
+}
+
+/// A handle to `sb` as `bch2_read_super` leaves one, owning a `malloc`ed
+/// copy of the superblock and a device opened twice, here /dev/null
+pub fn read(sb: &Superblock) -> SbHandle {
+	let bytes = sb.bytes();
+	let open = || std::fs::File::open("/dev/null").unwrap().into_raw_fd();
+	unsafe {
+		let mut handle: bch_sb_handle = std::mem::zeroed();
+		handle.sb = libc::malloc(bytes.len()) as *mut bch_sb;
+		std::ptr::copy_nonoverlapping(bytes.as_ptr(), handle.sb as *mut u8, bytes.len());
+		handle.bdev = libc::calloc(1, std::mem::size_of::<block_device>()) as *mut block_device;
+		(*handle.bdev).bd_fd = open();
+		(*handle.bdev).bd_sync_fd = open();
+		SbHandle::from_raw(handle)
+	}
+}
+
+/// What probing `device` alone would find, with the superblock `sb`
+pub fn filesystem(sb: &SbBuf, device: &str) -> FileSystem {
+	FileSystem::new(handle(sb), Member::new(PathBuf::from(device), false, false))
+}
+
+/// A fresh, empty directory in the system's temporary one, removed with all
+/// that is in it when dropped, so also when the test panics
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+	/// `name` keeps apart the directories of tests running at the same time
+	pub fn new(name: &str) -> Self {
+		let path = std::env::temp_dir().join(format!("bcachefs-mount-{}.{}", name, std::process::id()));
+		// left behind by a run killed before it could clean up
+		let _ = std::fs::remove_dir_all(&path);
+		std::fs::create_dir_all(&path).unwrap();
+		TempDir(path)
+	}
+}
+
+impl std::ops::Deref for TempDir {
+	type Target = Path;
+
+	fn deref(&self) -> &Path {
+		&self.0
+	}
+}
+
+impl AsRef<Path> for TempDir {
+	fn as_ref(&self) -> &Path {
+		&self.0
+	}
+}
+
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		let _ = std::fs::remove_dir_all(&self.0);
+	}
+}
@@ -0,0 +1,49 @@
+//! The flags that can't be given together, as a table of command lines.
+
+use bcachefs_mount::conflicts::{check, CONFLICTS};
+use bcachefs_mount::Options;
+use structopt::StructOpt;
+
+/// Command lines, and the two flags the error should name if they conflict
+const CASES: &[(&[&str], Option<[&str; 2]>)] = &[
+	(&["LABEL=x", "/mnt"], None),
+	(&["--status", "--json"], None),
+	(&["--status", "--watch"], Some(["--status", "--watch"])),
+	(&["--doctor", "--query", "/mnt"], Some(["--doctor", "--query"])),
+	(&["--use-cache", "LABEL=x"], None),
+	(&["--use-cache", "--only-device", "/dev/sda", "LABEL=x"], Some(["--use-cache", "--only-device"])),
+	(&["-o", "noatime,remount", "LABEL=x", "/mnt"], None),
+	(&["-o", "remount", "--only-device", "/dev/sda", "LABEL=x", "/mnt"], Some(["-o remount", "--only-device"])),
+	(&["-o", "remount", "--exclude-device", "/dev/sda", "LABEL=x", "/mnt"], Some(["-o remount", "--exclude-device"])),
+	(&["-o", "remount", "--fork-wait", "LABEL=x", "/mnt"], Some(["-o remount", "--fork-wait"])),
+	(&["-o", "remount", "--fail-if-mounted", "LABEL=x", "/mnt"], Some(["-o remount", "--fail-if-mounted"])),
+	(&["--fail-if-mounted", "LABEL=x", "/mnt"], None),
+	(&["--print-mount-command", "LABEL=x", "/mnt"], None),
+	(&["--print-mount-command", "--fork-wait", "LABEL=x", "/mnt"], Some(["--print-mount-command", "--fork-wait"])),
+	(
+		&["--print-mount-command", "--verify-after-mount", "LABEL=x", "/mnt"],
+		Some(["--print-mount-command", "--verify-after-mount"]),
+	),
+];
+
+#[test]
+fn conflicting_flags_are_named() {
+	for (args, conflict) in CASES {
+		let opt = Options::from_iter(["bcachefs-mount"].iter().chain(args.iter()));
+		match (check(&opt), conflict) {
+			(Ok(()), None) => {}
+			(Err(e), Some([first, second])) => {
+				let e = e.to_string();
+				assert!(e.starts_with(&format!("{} conflicts with {}: ", first, second)), "{:?}: {}", args, e);
+			}
+			(result, _) => panic!("{:?}: expected {:?}, got {:?}", args, conflict, result),
+		}
+	}
+}
+
+#[test]
+fn every_conflict_has_a_case() {
+	for c in CONFLICTS {
+		assert!(CASES.iter().any(|(_, conflict)| *conflict == Some(c.flags)), "no case for {:?}", c.flags);
+	}
+}
@@ -0,0 +1,54 @@
+//! --cancel-wait, which must only ever signal the waiter it was meant for.
+
+mod common;
+
+use bcachefs_mount::daemon::{cancel_wait_in, write_pidfile};
+use common::{TempDir, UUID};
+use std::process::{Child, Command};
+
+fn sleeper() -> Child {
+	Command::new("sleep").arg("30").spawn().unwrap()
+}
+
+#[test]
+fn nothing_to_cancel() {
+	let dir = TempDir::new("daemon.none");
+	let err = cancel_wait_in(&dir, &UUID).unwrap_err();
+	assert_eq!(err.to_string(), format!("no background wait for {} is in progress", UUID));
+}
+
+#[test]
+fn stale_pid_files_are_not_trusted() {
+	let dir = TempDir::new("daemon.stale");
+	let mut bystander = sleeper();
+	// left behind by a waiter that was killed, its pid since reused
+	let path = dir.join(format!("{}.pid", UUID));
+	std::fs::write(&path, format!("{}\n", bystander.id())).unwrap();
+
+	let err = cancel_wait_in(&dir, &UUID).unwrap_err();
+	assert_eq!(err.to_string(), format!("no background wait for {} is in progress", UUID));
+	assert!(!path.exists());
+	assert!(bystander.try_wait().unwrap().is_none(), "an unrelated process was signalled");
+
+	bystander.kill().unwrap();
+	bystander.wait().unwrap();
+}
+
+#[test]
+fn locked_pid_files_are_signalled() {
+	use std::os::unix::process::ExitStatusExt;
+
+	let dir = TempDir::new("daemon.locked");
+	let mut waiter = sleeper();
+	// the lock is held here, on behalf of the waiter
+	let pidfile = write_pidfile(&dir, &UUID).unwrap();
+	let path = dir.join(format!("{}.pid", UUID));
+	assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+	std::fs::write(&path, format!("{}\n", waiter.id())).unwrap();
+
+	cancel_wait_in(&dir, &UUID).unwrap();
+	assert_eq!(waiter.wait().unwrap().signal(), Some(libc::SIGTERM));
+
+	drop(pidfile);
+	assert!(!path.exists());
+}
@@ -0,0 +1,71 @@
+//! What the --doctor report leaves out.
+
+mod common;
+
+use bcachefs_mount::doctor::{superblock_summary, Redactor};
+use bch_bindgen::rs::SbBuf;
+use common::{Superblock, TempDir};
+
+const KEY: u64 = 0x5a5a_5a5a_5a5a_5a5a;
+
+/// A superblock with a crypt field whose encrypted key is all `KEY`
+fn encrypted_superblock() -> SbBuf {
+	// flags, kdf_flags, magic and a 4 u64 key
+	Superblock::default().label("pool").field(common::FIELD_CRYPT, &[0, 0, KEY, KEY, KEY, KEY, KEY]).build()
+}
+
+#[test]
+fn summary_has_no_key_material() {
+	let summary = superblock_summary(encrypted_superblock().sb());
+	assert!(summary.contains("label: pool"), "{}", summary);
+	assert!(!summary.to_lowercase().contains("5a5a"), "{}", summary);
+	assert!(!summary.contains(&KEY.to_string()), "{}", summary);
+	// KEY as text
+	assert!(!summary.contains("ZZZZ"), "{}", summary);
+}
+
+#[test]
+fn redactor_scrubs_every_occurrence() {
+	let mut r = Redactor::default();
+	r.scrub("buildhost\n", "<hostname>");
+	assert_eq!(
+		r.apply("buildhost: mounted on buildhost.example.com"),
+		"<hostname>: mounted on <hostname>.example.com"
+	);
+}
+
+#[test]
+fn redactor_replaces_longest_first() {
+	let mut r = Redactor::default();
+	r.scrub("S3Z9NB0K", "<serial>");
+	r.scrub("Samsung_SSD_860_S3Z9NB0K", "<serial>");
+	assert_eq!(r.apply("/dev/disk/by-id/ata-Samsung_SSD_860_S3Z9NB0K-part1"), "/dev/disk/by-id/ata-<serial>-part1");
+}
+
+#[test]
+fn redactor_ignores_short_values() {
+	let mut r = Redactor::default();
+	r.scrub("", "<serial>");
+	r.scrub("a", "<hostname>");
+	assert_eq!(r.apply("a bcachefs filesystem"), "a bcachefs filesystem");
+}
+
+#[test]
+fn bundles_are_put_together_in_a_private_directory() {
+	use bcachefs_mount::doctor::{private_temp_dir, Report, Section};
+	use std::os::unix::fs::PermissionsExt;
+
+	let dirs = [private_temp_dir("bcachefs-mount-doctor").unwrap(), private_temp_dir("bcachefs-mount-doctor").unwrap()];
+	let modes: Vec<u32> = dirs.iter().map(|d| std::fs::metadata(d).unwrap().permissions().mode() & 0o777).collect();
+	for dir in &dirs {
+		std::fs::remove_dir(dir).unwrap();
+	}
+	assert_ne!(dirs[0], dirs[1]);
+	assert_eq!(modes, [0o700, 0o700]);
+
+	let dir = TempDir::new("doctor");
+	let bundle = dir.join("bundle.tar.gz");
+	Report(vec![Section { name: "version", text: "1.0\n".to_owned() }]).write_bundle(&bundle).unwrap();
+	let listed = std::process::Command::new("tar").arg("-tzf").arg(&bundle).output().unwrap();
+	assert_eq!(String::from_utf8_lossy(&listed.stdout), "bcachefs-doctor/\nbcachefs-doctor/version.txt\n");
+}
@@ -0,0 +1,68 @@
+//! Kinds of failures, their exit status and their --json-errors form.
+
+use bcachefs_mount::exit::{json, kind, ErrorKind};
+use bcachefs_mount::filesystem::{parse_mount_options, Checking};
+
+#[test]
+fn catalog_errors_have_kinds() {
+	let e = parse_mount_options("no_such_option", Checking::Strict).unwrap_err();
+	assert_eq!(kind(&e), ErrorKind::InvalidArgument);
+	assert_eq!(kind(&e).exit_code(), 2);
+
+	let e = bcachefs_mount::err!(FsNotFound);
+	assert_eq!(kind(&e), ErrorKind::NotFound);
+	assert_eq!(kind(&e.context("while mounting")), ErrorKind::NotFound);
+
+	let e = bcachefs_mount::err!(AlreadyMountedAt, uuid::Uuid::from_u128(1), "/mnt");
+	assert_eq!(kind(&e), ErrorKind::AlreadyMounted);
+	assert_eq!(kind(&e).exit_code(), 12);
+
+	// without a mountpoint, and no key to load either
+	let e = bcachefs_mount::err!(NothingToDo);
+	assert_eq!(kind(&e), ErrorKind::InvalidArgument);
+
+	let e = bcachefs_mount::err!(TooFewDevices, 1, 3, 2);
+	assert_eq!(kind(&e), ErrorKind::Degraded);
+	assert_eq!(kind(&e).exit_code(), 13);
+}
+
+#[test]
+fn every_kind_has_its_own_exit_status() {
+	let mut codes: Vec<i32> = ErrorKind::ALL.iter().map(|k| k.exit_code()).collect();
+	codes.sort_unstable();
+	codes.dedup();
+	assert_eq!(codes.len(), ErrorKind::ALL.len());
+	// 32 and 64 mean some or all filesystems failed to mount(8) -a
+	assert!(codes.iter().all(|c| ![0, 32, 64].contains(c)), "{:?}", codes);
+
+	let mut names: Vec<&str> = ErrorKind::ALL.iter().map(|k| k.name()).collect();
+	names.sort_unstable();
+	names.dedup();
+	assert_eq!(names.len(), ErrorKind::ALL.len());
+}
+
+#[test]
+fn os_errors_have_kinds() {
+	let e = bcachefs_mount::mounts::mount_at(std::path::Path::new("/nonexistent/bcachefs-mount")).unwrap_err();
+	assert_eq!(kind(&e), ErrorKind::NotFound);
+
+	let e = anyhow::Error::new(std::io::Error::from_raw_os_error(libc::EACCES));
+	assert_eq!(kind(&e), ErrorKind::Permission);
+
+	assert_eq!(kind(&anyhow::anyhow!("something else")), ErrorKind::Other);
+	assert_eq!(ErrorKind::Other.exit_code(), 1);
+}
+
+#[test]
+fn json_object() {
+	let uuid = uuid::Uuid::from_u128(1);
+	let e = parse_mount_options("noatime,no_such_option", Checking::Strict).unwrap_err();
+	assert_eq!(
+		json(&e, Some(&uuid)),
+		r#"{"error":"unknown mount option no_such_option","kind":"invalid_argument","id":"UnknownOption","uuid":"00000000-0000-0000-0000-000000000001"}"#
+	);
+	assert_eq!(
+		json(&anyhow::anyhow!("a \"quoted\" failure"), None),
+		r#"{"error":"a \"quoted\" failure","kind":"other","id":null,"uuid":null}"#
+	);
+}
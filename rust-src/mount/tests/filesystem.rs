@@ -0,0 +1,292 @@
+//! FileSystem built from doctored superblocks, including snapshots of how it
+//! is displayed, which scripts scrape from the logs.
+
+mod common;
+
+use bcachefs_mount::filesystem::{Checking, FileSystem, UnusableSuperblock};
+use bch_bindgen::bcachefs::bch_member;
+use bch_bindgen::rs::{metadata_versions, SbBuf, SUPERBLOCK_MAGIC};
+use common::{Superblock, TempDir, UUID};
+
+fn superblock(label: &str, encrypted: bool) -> SbBuf {
+	let sb = Superblock::default().label(label);
+	if encrypted { sb.encrypted() } else { sb }.build()
+}
+
+fn filesystem(sb: &SbBuf) -> FileSystem {
+	common::filesystem(sb, "/dev/sda")
+}
+
+#[test]
+fn labelled() {
+	let sb = superblock("tank", false);
+	assert_eq!(
+		filesystem(&sb).to_string(),
+		"8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a label=\"tank\" devices=/dev/sda encrypted=no"
+	);
+}
+
+#[test]
+fn unlabelled_encrypted() {
+	let sb = superblock("", true);
+	assert_eq!(
+		filesystem(&sb).to_string(),
+		"8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a devices=/dev/sda encrypted=yes"
+	);
+}
+
+#[test]
+fn mountpoints_once_looked_up() {
+	let sb = superblock("tank", false);
+	let fs = filesystem(&sb);
+	assert!(fs.mountpoints().is_empty());
+	assert_eq!(
+		fs.to_string(),
+		"8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a label=\"tank\" devices=/dev/sda encrypted=no mounted=no"
+	);
+}
+
+#[test]
+fn survives_being_moved() {
+	let sb = superblock("tank", true);
+	let mut fss = std::collections::HashMap::new();
+	fss.insert(UUID, filesystem(&sb));
+	let fss = Box::new(fss);
+
+	let fs = &fss[&UUID];
+	assert_eq!(*fs.uuid(), UUID);
+	assert_eq!(fs.sb().sb().uuid(), UUID);
+	assert!(fs.encrypted());
+	assert!(fs.sb().sb().crypt().is_some());
+	assert_eq!(fs.sb().sb().label().as_deref(), Some("tank"));
+}
+
+#[test]
+fn mount_command() {
+	let sb = superblock("tank", false);
+	let fs = filesystem(&sb);
+	assert_eq!(
+		fs.mount_command("/mnt/it's here", "noatime,degraded,x-mount.mkdir", Checking::Strict, "bcachefs").unwrap(),
+		"mount -t bcachefs /dev/sda '/mnt/it'\\''s here' -o rw,noatime,degraded"
+	);
+	// not UTF-8, so it can only be quoted with escapes
+	use std::os::unix::ffi::OsStrExt;
+	let target = std::path::Path::new(std::ffi::OsStr::from_bytes(b"/mnt/\xff's"));
+	assert_eq!(
+		fs.mount_command(target, "", Checking::Strict, "bcachefs").unwrap(),
+		"mount -t bcachefs /dev/sda $'/mnt/\\xff\\'s' -o rw"
+	);
+}
+
+#[test]
+fn wiped_uuids_are_unusable() {
+	let nil = Superblock::default().uuid(uuid::Uuid::nil()).build();
+	assert_eq!(UnusableSuperblock::check(nil.sb()), Err(UnusableSuperblock("user UUID is nil")));
+
+	let magic = Superblock::default().uuid(SUPERBLOCK_MAGIC).build();
+	let err = UnusableSuperblock::check(magic.sb()).unwrap_err();
+	assert_eq!(err.to_string(), "incomplete or invalid superblock: user UUID is the superblock magic");
+
+	assert_eq!(UnusableSuperblock::check(superblock("tank", false).sb()), Ok(()));
+}
+
+#[test]
+fn unusable_superblocks_are_recognized_in_probe_errors() {
+	let e = std::io::Error::new(std::io::ErrorKind::InvalidData, UnusableSuperblock("user UUID is nil"));
+	assert_eq!(UnusableSuperblock::of(&e), Some(&UnusableSuperblock("user UUID is nil")));
+	let e = std::io::Error::new(std::io::ErrorKind::InvalidData, "Not a BCacheFS SuperBlock");
+	assert_eq!(UnusableSuperblock::of(&e), None);
+}
+
+#[test]
+fn min_devices_sets_the_threshold() {
+	use bcachefs_mount::filesystem::DeviceCount::{self, *};
+
+	assert_eq!(DeviceCount::of(3, 3, None), Complete);
+	assert_eq!(DeviceCount::of(2, 3, None), Degraded);
+	assert_eq!(DeviceCount::of(3, 3, Some(4)), Complete);
+	assert_eq!(DeviceCount::of(2, 4, Some(2)), Sufficient);
+	assert_eq!(DeviceCount::of(1, 4, Some(2)), Insufficient);
+	assert_eq!(DeviceCount::of(0, 4, Some(0)), Sufficient);
+}
+
+#[test]
+fn old_versions_need_an_upgrade() {
+	use bcachefs_mount::filesystem::UPGRADE_THRESHOLD;
+
+	let current = *metadata_versions().end();
+	let at = |version: u16| Superblock::default().doctor(|sb| sb.version = version).build();
+	assert_eq!(filesystem(&at(current)).needs_upgrade(), None);
+	assert_eq!(filesystem(&at(current - UPGRADE_THRESHOLD)).needs_upgrade(), None);
+	let old = current - UPGRADE_THRESHOLD - 1;
+	assert_eq!(filesystem(&at(old)).needs_upgrade(), Some((old, current)));
+}
+
+/// The members field of a filesystem with members 0 to 2
+fn three_members() -> Vec<u64> {
+	let member_u64s = std::mem::size_of::<bch_member>() / 8;
+	let mut members = vec![0u64; 3 * member_u64s];
+	for i in 0..3 {
+		let m = unsafe { &mut *(members[i * member_u64s..].as_mut_ptr() as *mut bch_member) };
+		m.uuid.b = [i as u8 + 1; 16];
+	}
+	members
+}
+
+/// Superblock of member 0 of a filesystem with members 0 to 2, whose
+/// replicas field holds `entries`
+fn replicated(entries: &[u8]) -> SbBuf {
+	let mut replicas = vec![0u64; (entries.len() + 7) / 8];
+	for (i, b) in entries.iter().enumerate() {
+		replicas[i / 8] |= (*b as u64) << (i % 8 * 8);
+	}
+	Superblock::default()
+		.doctor(|sb| sb.nr_devices = 3)
+		.field(common::FIELD_MEMBERS, &three_members())
+		.field(common::FIELD_REPLICAS, &replicas)
+		.build()
+}
+
+#[test]
+fn durability_of_a_degraded_filesystem() {
+	use bcachefs_mount::filesystem::Durability;
+
+	// found on member 0 only; btree and user data on 0 and 1, cached data on 2
+	let sb = replicated(&[3, 2, 1, 0, 1, 4, 2, 1, 0, 1, 5, 1, 1, 2]);
+	let fs = filesystem(&sb);
+	assert_eq!(fs.missing_devices(), vec![1, 2]);
+	assert_eq!(fs.durability(), Durability::Replicated);
+	assert_eq!(fs.durability().to_string(), "data appears fully replicated on the remaining devices");
+
+	// user data on 1 and 2 only
+	let sb = replicated(&[3, 2, 1, 0, 1, 4, 2, 1, 1, 2]);
+	assert_eq!(filesystem(&sb).durability(), Durability::MayBeUnavailable);
+	// striped over 0 to 2, needing two of them
+	let sb = replicated(&[4, 3, 2, 0, 1, 2]);
+	assert_eq!(filesystem(&sb).durability(), Durability::MayBeUnavailable);
+
+	let sb = superblock("tank", false);
+	assert_eq!(filesystem(&sb).durability(), Durability::Unknown);
+}
+
+/// Superblock of member `dev_idx` of a filesystem with two members
+fn member_of_two(dev_idx: u8) -> SbBuf {
+	Superblock::default()
+		.doctor(|sb| {
+			sb.nr_devices = 2;
+			sb.dev_idx = dev_idx;
+		})
+		.build()
+}
+
+#[test]
+fn degraded_until_every_member_is_found() {
+	let (a, b) = (member_of_two(0), member_of_two(1));
+	let mut fs = common::filesystem(&a, "/dev/sda");
+	assert!(fs.is_degraded());
+	assert!(fs.status().is_degraded());
+
+	fs.merge(common::filesystem(&b, "/dev/sdb"));
+	assert!(!fs.is_degraded());
+	let status = fs.status();
+	assert!(!status.is_degraded());
+	assert_eq!((status.devices_found, status.devices_total), (2, 2));
+}
+
+#[test]
+fn selected_devices() {
+	use bcachefs_mount::paths::Paths;
+	use std::path::PathBuf;
+
+	// members are compared as found under the device root, aliases resolved
+	let base = TempDir::new("select");
+	std::fs::create_dir_all(base.join("dev/disk/by-id")).unwrap();
+	std::fs::write(base.join("dev/sda"), "").unwrap();
+	std::fs::write(base.join("dev/sdb"), "").unwrap();
+	std::os::unix::fs::symlink("../../sdb", base.join("dev/disk/by-id/wwn-b")).unwrap();
+	let paths = Paths { dev_root: base.join("dev"), ..Paths::default() };
+
+	let (a, b) = (member_of_two(0), member_of_two(1));
+	let both = || {
+		let mut fs = common::filesystem(&a, "/dev/sda");
+		fs.merge(common::filesystem(&b, "/dev/sdb"));
+		fs
+	};
+	let select = |only: &[&str], exclude: &[&str]| {
+		let (only, exclude): (Vec<PathBuf>, Vec<PathBuf>) =
+			(only.iter().map(PathBuf::from).collect(), exclude.iter().map(PathBuf::from).collect());
+		let mut fs = both();
+		fs.select_devices(&only, &exclude, &paths).map(|()| (fs.device_string(), fs.is_degraded()))
+	};
+
+	assert_eq!(select(&[], &[]).unwrap(), ("/dev/sda:/dev/sdb".to_owned(), false));
+	assert_eq!(select(&["/dev/disk/by-id/wwn-b"], &[]).unwrap(), ("/dev/sdb".to_owned(), true));
+	assert_eq!(select(&[], &["/dev/sdb"]).unwrap(), ("/dev/sda".to_owned(), true));
+	assert_eq!(select(&["/dev/sda", "/dev/sdb"], &["/dev/disk/by-id/wwn-b"]).unwrap(), ("/dev/sda".to_owned(), true));
+
+	let err = select(&[], &["/dev/sda", "/dev/sdb"]).unwrap_err();
+	assert_eq!(err.to_string(), "refusing to exclude every member device");
+	// a filesystem is never left without members, not even on error
+	let mut fs = both();
+	fs.select_devices(&[PathBuf::from("/dev/sda")], &[PathBuf::from("/dev/sda")], &paths).unwrap_err();
+	assert_eq!(fs.device_string(), "/dev/sda:/dev/sdb");
+	let err = select(&["/dev/sdc"], &[]).unwrap_err();
+	assert_eq!(err.to_string(), format!("{} is not a member of filesystem {}", base.join("dev/sdc").display(), UUID));
+}
+
+#[test]
+fn assembly_does_not_depend_on_discovery_order() {
+	use itertools::Itertools;
+	use std::path::PathBuf;
+
+	// members 0 to 2, then a newer copy of member 1 and one as old of member
+	// 2, like a disk and its clone, or two paths to a multipath disk
+	let devices = [("/dev/sda", 0, 5), ("/dev/sdb", 1, 5), ("/dev/sdc", 2, 5), ("/dev/sdd", 1, 6), ("/dev/sde", 2, 5)];
+	let sbs: Vec<SbBuf> = devices
+		.iter()
+		.map(|&(_, dev_idx, seq)| {
+			Superblock::default()
+				.doctor(|sb| {
+					sb.nr_devices = 3;
+					sb.dev_idx = dev_idx;
+					sb.seq = seq;
+				})
+				.field(common::FIELD_MEMBERS, &three_members())
+				.build()
+		})
+		.collect();
+	for found in 1..=devices.len() {
+		for order in (0..devices.len()).permutations(found) {
+			let mut members = order.iter().map(|&i| common::filesystem(&sbs[i], devices[i].0));
+			let mut fs = members.next().unwrap();
+			let mut left_out: Vec<PathBuf> = members.flat_map(|m| fs.merge(m)).collect();
+
+			// one device for each member, in the order of their index
+			let indices: Vec<u8> = fs.members().iter().map(|m| m.dev_idx()).collect();
+			let mut expected: Vec<u8> = order.iter().map(|&i| devices[i].1).unique().collect();
+			expected.sort_unstable();
+			assert_eq!(indices, expected, "{:?}", order);
+			let missing: Vec<u8> = (0..3).filter(|i| !expected.contains(i)).collect();
+			assert_eq!(fs.missing_devices(), missing, "{:?}", order);
+			let status = fs.status();
+			assert!(status.devices_found <= status.devices_total, "{:?}", order);
+			assert_eq!((status.devices_found, status.devices_total), (expected.len(), 3));
+			assert_eq!(fs.is_degraded(), expected.len() < 3);
+
+			// the newer superblock wins wherever it was found, and the first
+			// found of two as old
+			let kept = |dev_idx| fs.members().iter().find(|m| m.dev_idx() == dev_idx).map(|m| m.path().to_owned());
+			if order.contains(&3) {
+				assert_eq!(kept(1), Some(PathBuf::from("/dev/sdd")), "{:?}", order);
+			}
+			if let Some(&first) = order.iter().find(|&&i| devices[i].1 == 2) {
+				assert_eq!(kept(2), Some(PathBuf::from(devices[first].0)), "{:?}", order);
+			}
+			let mut all: Vec<PathBuf> = fs.members().iter().map(|m| m.path().to_owned()).collect();
+			all.append(&mut left_out);
+			all.sort();
+			let given: Vec<PathBuf> = order.iter().map(|&i| PathBuf::from(devices[i].0)).sorted().collect();
+			assert_eq!(all, given, "{:?}", order);
+		}
+	}
+}
@@ -0,0 +1,78 @@
+//! Superblock handles are freed, closing the device they hold open, along
+//! with the filesystem they were read for, including those --watch replaces
+//! or forgets. A single test, as descriptors
+//! opened by tests running alongside would throw off the count.
+
+mod common;
+
+use bcachefs_mount::filesystem::{FileSystem, Member};
+use bcachefs_mount::udev_watch::Inventory;
+use common::Superblock;
+use std::path::{Path, PathBuf};
+
+fn open_fds() -> usize {
+	std::fs::read_dir("/proc/self/fd").unwrap().count()
+}
+
+fn probed(sb: &Superblock, device: &str) -> FileSystem {
+	FileSystem::new(common::read(sb), Member::new(PathBuf::from(device), false, false))
+}
+
+#[test]
+fn handles_close_their_device() {
+	let member = |dev_idx: u8, seq: u64| {
+		Superblock::default().doctor(|sb| {
+			sb.nr_devices = 10;
+			sb.dev_idx = dev_idx;
+			sb.seq = seq;
+		})
+	};
+	let sb = member(0, 1);
+	let before = open_fds();
+
+	let fs = probed(&sb, "/dev/sda");
+	assert_eq!(open_fds(), before + 2);
+	drop(fs);
+	assert_eq!(open_fds(), before);
+
+	// what a probe does with each member found after the first
+	let found = (0..10).map(|i| probed(&member(i, 1), &format!("/dev/sd{}", (b'a' + i) as char))).collect::<Vec<_>>();
+	assert_eq!(open_fds(), before + 20);
+	let mut found = found.into_iter();
+	let mut fs = found.next().unwrap();
+	for other in found {
+		fs.merge(other);
+	}
+	assert_eq!(open_fds(), before + 2);
+	assert_eq!(fs.members().len(), 10);
+	// a newer copy of the member the superblock was read from takes its place
+	assert_eq!(fs.merge(probed(&member(0, 2), "/dev/sdz")), vec![PathBuf::from("/dev/sda")]);
+	assert_eq!(fs.merge(probed(&member(0, 2), "/dev/sdy")), vec![PathBuf::from("/dev/sdy")]);
+	assert_eq!(open_fds(), before + 2);
+	assert_eq!(fs.sb().sb().seq, 2);
+	drop(fs);
+	assert_eq!(open_fds(), before);
+
+	// --watch seeing devices come, come again and go
+	let mut inventory = Inventory::default();
+	for _ in 0..10 {
+		inventory.add(common::UUID, probed(&sb, "/dev/sda"));
+		inventory.add(common::UUID, probed(&member(1, 1), "/dev/sdb"));
+	}
+	assert_eq!(open_fds(), before + 2);
+	inventory.remove(Path::new("/dev/sdb")).unwrap();
+	inventory.remove(Path::new("/dev/sda")).unwrap();
+	assert_eq!(open_fds(), before);
+
+	// the C interface, probing whatever bcachefs devices there are for a
+	// filesystem that isn't among them
+	#[cfg(feature = "capi")]
+	for _ in 0..10 {
+		let spec = std::ffi::CString::new("LABEL=no such filesystem").unwrap();
+		let (mut count, mut error) = (0, std::ptr::null_mut());
+		let devices = unsafe { bcachefs_mount::capi::bcachefs_mount_probe(spec.as_ptr(), &mut count, &mut error) };
+		assert!(devices.is_null());
+		unsafe { libc::free(error as *mut libc::c_void) };
+		assert_eq!(open_fds(), before);
+	}
+}
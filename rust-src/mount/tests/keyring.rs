@@ -0,0 +1,100 @@
+//! Telling whether keys go to the user keyring the kernel will consult, when
+//! running in a user namespace.
+
+mod common;
+
+use bcachefs_mount::key::outside_uid;
+use common::UUID;
+
+#[test]
+fn initial_namespace_maps_every_uid_to_itself() {
+	let uid_map = "         0          0 4294967295\n";
+	assert_eq!(outside_uid(uid_map, 0), Some(0));
+	assert_eq!(outside_uid(uid_map, 1000), Some(1000));
+}
+
+#[test]
+fn container_maps_to_a_range_outside() {
+	// as set up by e.g. podman with subordinate uids
+	let uid_map = "         0       1000          1\n         1     100000      65536\n";
+	assert_eq!(outside_uid(uid_map, 0), Some(1000));
+	assert_eq!(outside_uid(uid_map, 1), Some(100000));
+	assert_eq!(outside_uid(uid_map, 65536), Some(165535));
+	assert_eq!(outside_uid(uid_map, 65537), None);
+}
+
+#[test]
+fn unmapped_or_garbled() {
+	assert_eq!(outside_uid("", 0), None);
+	assert_eq!(outside_uid("0 x 1\n", 0), None);
+}
+
+/// Simulate the mismatch with unshare(1): root in a new user namespace is
+/// our own uid outside it, so a key added there would land in the keyring of
+/// namespaced uid 0. Needs unprivileged user namespaces, which not every
+/// system allows, so it only runs when asked for: `cargo test -- --ignored`
+#[test]
+#[ignore]
+fn unshare_maps_root_to_us() {
+	let out = std::process::Command::new("unshare")
+		.args(&["--user", "--map-root-user", "cat", "/proc/self/uid_map"])
+		.output()
+		.unwrap();
+	assert!(out.status.success(), "unshare failed: {}", String::from_utf8_lossy(&out.stderr));
+	let uid_map = String::from_utf8(out.stdout).unwrap();
+	assert_eq!(outside_uid(&uid_map, 0), Some(unsafe { libc::getuid() }));
+}
+
+#[test]
+fn prompt_says_which_filesystem() {
+	use bcachefs_mount::key::PromptContext;
+
+	let mut context = PromptContext {
+		uuid: UUID,
+		label: Some("tank".to_owned()),
+		devices: vec!["/dev/sda".into(), "/dev/sdb".into()],
+		attempt: 1,
+		attempts: 3,
+	};
+	assert_eq!(
+		context.to_string(),
+		"Unlocking \"tank\" (8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a) on /dev/sda /dev/sdb, attempt 1 of 3"
+	);
+	context.label = None;
+	context.attempt = 2;
+	assert_eq!(context.to_string(), "Unlocking 8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a on /dev/sda /dev/sdb, attempt 2 of 3");
+}
+
+#[test]
+fn prompt_needs_a_terminal() {
+	use bcachefs_mount::exit::{kind, ErrorKind};
+	use bcachefs_mount::key::TtyPrompt;
+
+	let mut fds = [0; 2];
+	assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+	let e = TtyPrompt::default().check_terminal(fds[0]).unwrap_err();
+	assert_eq!(kind(&e), ErrorKind::KeyUnavailable);
+	assert!(e.to_string().starts_with("no terminal available for passphrase prompt"));
+	assert!(TtyPrompt { force: true, ..TtyPrompt::default() }.check_terminal(fds[0]).is_ok());
+	unsafe {
+		libc::close(fds[0]);
+		libc::close(fds[1]);
+	}
+}
+
+#[test]
+fn missing_key_location_says_where_to_give_one() {
+	use bcachefs_mount::exit::{kind, ErrorKind};
+	use bcachefs_mount::key::fstab_example;
+
+	let e = bcachefs_mount::err!(NoKeyLocation, UUID, fstab_example(&UUID, Some(std::path::Path::new("/srv/my data"))));
+	assert_eq!(kind(&e), ErrorKind::InvalidArgument);
+	assert_eq!(
+		e.to_string(),
+		"the key of locked filesystem 8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a is not in the keyring, and no key location \
+		 was given; pass --key-location=ask (or wait or fail), or add x-bcachefs.key_location=ask to the -o options, \
+		 BCACHEFS_MOUNT_OPTIONS or the fstab options, e.g. in /etc/fstab: \
+		 UUID=8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a /srv/my\\040data bcachefs defaults,x-bcachefs.key_location=ask 0 0"
+	);
+	assert!(fstab_example(&UUID, None).contains(" /mnt bcachefs "));
+}
@@ -0,0 +1,38 @@
+//! Serializing mounts of the same filesystem.
+
+mod common;
+
+use bcachefs_mount::lock::lock_in;
+use common::{TempDir, UUID};
+use std::time::Duration;
+
+#[test]
+fn contended_lock_times_out() {
+	let dir = TempDir::new("lock.contended");
+	let held = lock_in(&dir, &UUID, Duration::from_secs(0)).unwrap();
+
+	let err = lock_in(&dir, &UUID, Duration::from_millis(200)).unwrap_err();
+	assert!(err.to_string().contains("in progress"), "{}", err);
+
+	// other filesystems are not affected
+	lock_in(&dir, &uuid::Uuid::from_u128(1), Duration::from_secs(0)).unwrap();
+
+	drop(held);
+	lock_in(&dir, &UUID, Duration::from_secs(0)).unwrap();
+}
+
+#[test]
+fn stale_lock_file_is_taken_over() {
+	let dir = TempDir::new("lock.stale");
+
+	// left behind by a holder that has since exited
+	let status = std::process::Command::new("flock")
+		.arg(dir.join(format!("{}.lock", UUID)))
+		.arg("true")
+		.status();
+	if !matches!(status, Ok(s) if s.success()) {
+		std::fs::write(dir.join(format!("{}.lock", UUID)), "").unwrap();
+	}
+
+	lock_in(&dir, &UUID, Duration::from_secs(0)).unwrap();
+}
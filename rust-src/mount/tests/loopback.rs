@@ -0,0 +1,233 @@
+//! End to end tests of probing and mounting, using loop devices backed by
+//! temporary image files, and of setting those up with `loopdev`.
+//!
+//! The end to end tests need root, the `bcachefs` tool in $PATH (or in
+//! $BCACHEFS) and the bcachefs kernel module, so they only run when asked
+//! for: `cargo test -- --ignored`
+
+mod common;
+
+use bcachefs_mount::filesystem::Checking;
+use common::TempDir;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn run(cmd: &mut Command) -> String {
+	let out = cmd.output().unwrap_or_else(|e| panic!("failed to run {:?}: {}", cmd, e));
+	assert!(out.status.success(), "{:?} failed: {}", cmd, String::from_utf8_lossy(&out.stderr));
+	String::from_utf8(out.stdout).unwrap()
+}
+
+/// Image file attached to a loop device, torn down on drop
+struct LoopImage {
+	image: PathBuf,
+	dev: PathBuf,
+	mountpoint: PathBuf,
+	/// Removed only after the loop device is detached
+	_dir: TempDir,
+}
+
+impl LoopImage {
+	fn new(size: u64) -> Self {
+		Self::formatted(size, None)
+	}
+
+	/// With a `passphrase`, the filesystem is encrypted
+	fn formatted(size: u64, passphrase: Option<&str>) -> Self {
+		let dir = TempDir::new("test");
+		let image = dir.join("image");
+		let mountpoint = dir.join("mnt");
+		std::fs::create_dir(&mountpoint).unwrap();
+
+		std::fs::File::create(&image).unwrap().set_len(size).unwrap();
+		let bcachefs = std::env::var_os("BCACHEFS").unwrap_or_else(|| "bcachefs".into());
+		let mut format = Command::new(bcachefs);
+		format.arg("format").arg(&image);
+		match passphrase {
+			None => {
+				run(&mut format);
+			}
+			Some(passphrase) => {
+				use std::io::Write;
+				// asked for twice, and read from stdin without a terminal
+				let mut child = format.arg("--encrypted").stdin(std::process::Stdio::piped()).spawn().unwrap();
+				write!(child.stdin.take().unwrap(), "{}\n{}\n", passphrase, passphrase).unwrap();
+				assert!(child.wait().unwrap().success(), "{:?} failed", format);
+			}
+		}
+
+		let dev = run(Command::new("losetup").args(&["--find", "--show"]).arg(&image));
+		let dev = PathBuf::from(dev.trim());
+		Self { image, dev, mountpoint, _dir: dir }
+	}
+}
+
+impl Drop for LoopImage {
+	fn drop(&mut self) {
+		let _ = Command::new("umount").arg(&self.mountpoint).status();
+		let _ = Command::new("losetup").arg("-d").arg(&self.dev).status();
+	}
+}
+
+fn is_mounted(path: &Path) -> bool {
+	let path = path.to_str().unwrap();
+	std::fs::read_to_string("/proc/self/mounts")
+		.unwrap()
+		.lines()
+		.any(|l| l.split(' ').nth(1) == Some(path))
+}
+
+#[test]
+#[ignore]
+fn probe_and_mount_loop_device() {
+	let img = LoopImage::new(512 << 20);
+
+	let sb = bch_bindgen::rs::read_super(&img.dev)
+		.expect("failed to open loop device")
+		.expect("loop device has no bcachefs superblock");
+	let uuid = sb.sb().uuid();
+
+	let fss = bcachefs_mount::filesystem::probe_filesystems(&Default::default()).unwrap();
+	let fs = fss.get(&uuid).expect("probe did not find the new filesystem");
+	assert_eq!(fs.members().len(), 1);
+	assert_eq!(fs.members()[0].path(), &img.dev);
+	assert!(!fs.encrypted());
+
+	fs.mount(&img.mountpoint, "", Checking::PassThrough, "bcachefs", false).unwrap();
+	assert!(is_mounted(&img.mountpoint));
+	run(Command::new("umount").arg(&img.mountpoint));
+	assert!(!is_mounted(&img.mountpoint));
+}
+
+/// A member set read-only, as write-protected media are, is found and
+/// marked read-only, and only mounted read-only
+#[test]
+#[ignore]
+fn read_only_member_needs_a_read_only_mount() {
+	use bcachefs_mount::messages::{Msg, MsgError};
+	use std::os::unix::io::AsRawFd;
+
+	const BLKROSET: libc::c_ulong = 0x125d; // _IO(0x12, 93)
+	let img = LoopImage::new(512 << 20);
+	let set_read_only = |ro: libc::c_int| {
+		let dev = std::fs::File::open(&img.dev).unwrap();
+		assert_eq!(unsafe { libc::ioctl(dev.as_raw_fd(), BLKROSET, &ro) }, 0, "{}", std::io::Error::last_os_error());
+	};
+	set_read_only(1);
+
+	let fss = bcachefs_mount::filesystem::probe_with(&[img.dev.clone()][..], &Default::default()).unwrap();
+	let fs = fss.values().next().expect("probe did not find the read-only device");
+	assert!(fs.members()[0].read_only());
+	let err = fs.mount(&img.mountpoint, "", Checking::PassThrough, "bcachefs", false).unwrap_err();
+	assert_eq!(err.downcast_ref::<MsgError>().map(|e| e.msg), Some(Msg::MemberReadOnly), "{}", err);
+	assert!(!is_mounted(&img.mountpoint));
+
+	fs.mount(&img.mountpoint, "ro", Checking::PassThrough, "bcachefs", false).unwrap();
+	assert!(is_mounted(&img.mountpoint));
+	run(Command::new("umount").arg(&img.mountpoint));
+	set_read_only(0);
+}
+
+/// Probing closes every device it opened once the filesystems it found are
+/// dropped. Counts descriptors, so run it on its own:
+/// `cargo test --test loopback -- --ignored --test-threads=1`
+#[test]
+#[ignore]
+fn probing_leaves_no_descriptors_open() {
+	let img = LoopImage::new(512 << 20);
+	let open_fds = || std::fs::read_dir("/proc/self/fd").unwrap().count();
+
+	let before = open_fds();
+	let mut uuid = None;
+	for _ in 0..20 {
+		let fss = bcachefs_mount::filesystem::probe_with(&[img.dev.clone()][..], &Default::default()).unwrap();
+		assert_eq!(fss.len(), 1);
+		uuid = fss.keys().next().copied();
+	}
+	assert_eq!(open_fds(), before);
+
+	#[cfg(feature = "capi")]
+	{
+		use bcachefs_mount::capi::{bcachefs_mount_free, bcachefs_mount_probe};
+
+		let spec = std::ffi::CString::new(uuid.unwrap().to_string()).unwrap();
+		for _ in 0..20 {
+			let mut count = 0;
+			let devices = unsafe { bcachefs_mount_probe(spec.as_ptr(), &mut count, std::ptr::null_mut()) };
+			assert!(!devices.is_null());
+			unsafe { bcachefs_mount_free(devices, count) };
+		}
+		assert_eq!(open_fds(), before);
+	}
+}
+
+/// Without a mountpoint an encrypted filesystem is only unlocked, and an
+/// unencrypted one leaves nothing to do
+#[cfg(feature = "mount")]
+#[test]
+#[ignore]
+fn unlock_only_without_a_mountpoint() {
+	let unlock_only = |img: &LoopImage, args: &[&std::ffi::OsStr]| {
+		let uuid = bch_bindgen::rs::read_super(&img.dev).unwrap().unwrap().sb().uuid().to_string();
+		let out = Command::new(env!("CARGO_BIN_EXE_bcachefs-mount")).arg(uuid).args(args).output().unwrap();
+		(out.status.code(), String::from_utf8_lossy(&out.stderr).into_owned())
+	};
+
+	let img = LoopImage::formatted(512 << 20, Some("correct horse"));
+	let passphrases = img.image.with_file_name("passphrases");
+	std::fs::write(&passphrases, "correct horse\n").unwrap();
+	let (code, stderr) = unlock_only(&img, &["--passphrase-file".as_ref(), passphrases.as_ref()]);
+	assert_eq!(code, Some(0), "{}", stderr);
+	assert!(!is_mounted(&img.mountpoint));
+	drop(img);
+
+	let img = LoopImage::new(512 << 20);
+	let (code, stderr) = unlock_only(&img, &[]);
+	assert_eq!(code, Some(2), "{}", stderr);
+	assert!(stderr.contains("nothing to do"), "{}", stderr);
+}
+
+#[test]
+fn offsets_are_whole_sectors() {
+	use bcachefs_mount::loopdev::check_offset;
+
+	check_offset(0).unwrap();
+	check_offset(1 << 20).unwrap();
+	let err = check_offset(1000).unwrap_err();
+	assert_eq!(err.to_string(), "offset 1000 is not a multiple of 512 bytes");
+}
+
+#[test]
+fn only_regular_files_are_images() {
+	use bcachefs_mount::loopdev::is_image;
+
+	let dir = TempDir::new("image");
+	let image = dir.join("image");
+	std::fs::File::create(&image).unwrap();
+	assert!(is_image(&image));
+	std::fs::remove_file(&image).unwrap();
+	assert!(!is_image(&image));
+	assert!(!is_image(Path::new("/dev/null")));
+	assert!(!is_image(&dir));
+}
+
+#[test]
+#[ignore]
+fn attach_image_at_offset() {
+	let offset = 1 << 20;
+	let dir = TempDir::new("offset");
+	let image = dir.join("disk.img");
+	std::fs::File::create(&image).unwrap().set_len(offset + (512 << 20)).unwrap();
+
+	// format a "partition" starting at the offset
+	let part = run(Command::new("losetup").args(&["--find", "--show", "--offset", &offset.to_string()]).arg(&image));
+	let bcachefs = std::env::var_os("BCACHEFS").unwrap_or_else(|| "bcachefs".into());
+	let formatted = Command::new(bcachefs).arg("format").arg(part.trim()).status();
+	run(Command::new("losetup").arg("-d").arg(part.trim()));
+	assert!(formatted.unwrap().success());
+
+	let raw = bch_bindgen::rs::read_super_raw_at(&image, offset).unwrap();
+	let dev = bcachefs_mount::loopdev::LoopDevice::attach(&image, offset).unwrap();
+	let sb = bch_bindgen::rs::read_super(dev.path()).unwrap().unwrap();
+	assert_eq!(sb.sb().uuid(), raw.sb().uuid());
+}
@@ -0,0 +1,60 @@
+//! Prometheus gauges for node_exporter's textfile collector.
+
+mod common;
+
+use bcachefs_mount::filesystem::Status;
+use bcachefs_mount::metrics::render;
+use common::UUID;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+#[test]
+fn gauges_per_filesystem() {
+	let tank = Status {
+		uuid: UUID,
+		label: Some("tank".to_owned()),
+		devices_found: 3,
+		devices_total: 4,
+		encrypted: true,
+		mounted: vec![PathBuf::from("/srv/tank")],
+	};
+	let odd = Status {
+		uuid: Uuid::from_u128(1),
+		label: Some("a \"b\"\\c".to_owned()),
+		devices_found: 1,
+		devices_total: 1,
+		encrypted: false,
+		mounted: Vec::new(),
+	};
+	let unlabelled = Status { label: None, ..odd.clone() };
+
+	assert_eq!(
+		render(&[tank, odd, unlabelled]),
+		r#"# HELP bcachefs_devices_present Member devices found
+# TYPE bcachefs_devices_present gauge
+bcachefs_devices_present{uuid="8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a",label="tank"} 3
+bcachefs_devices_present{uuid="00000000-0000-0000-0000-000000000001",label="a \"b\"\\c"} 1
+bcachefs_devices_present{uuid="00000000-0000-0000-0000-000000000001",label=""} 1
+# HELP bcachefs_devices_expected Member devices the superblock lists
+# TYPE bcachefs_devices_expected gauge
+bcachefs_devices_expected{uuid="8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a",label="tank"} 4
+bcachefs_devices_expected{uuid="00000000-0000-0000-0000-000000000001",label="a \"b\"\\c"} 1
+bcachefs_devices_expected{uuid="00000000-0000-0000-0000-000000000001",label=""} 1
+# HELP bcachefs_encrypted Whether the filesystem is encrypted
+# TYPE bcachefs_encrypted gauge
+bcachefs_encrypted{uuid="8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a",label="tank"} 1
+bcachefs_encrypted{uuid="00000000-0000-0000-0000-000000000001",label="a \"b\"\\c"} 0
+bcachefs_encrypted{uuid="00000000-0000-0000-0000-000000000001",label=""} 0
+# HELP bcachefs_mounted Whether the filesystem is mounted on this host
+# TYPE bcachefs_mounted gauge
+bcachefs_mounted{uuid="8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a",label="tank"} 1
+bcachefs_mounted{uuid="00000000-0000-0000-0000-000000000001",label="a \"b\"\\c"} 0
+bcachefs_mounted{uuid="00000000-0000-0000-0000-000000000001",label=""} 0
+"#
+	);
+}
+
+#[test]
+fn nothing_found() {
+	assert_eq!(render(&[]).lines().filter(|l| !l.starts_with('#')).count(), 0);
+}
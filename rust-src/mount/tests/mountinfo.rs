@@ -0,0 +1,107 @@
+//! Finding mountpoints in a synthetic mountinfo file.
+
+use bcachefs_mount::mounts::{already_mounted, mountpoints_in, parse, AlreadyMounted};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+const FS: &str = "8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a";
+const OTHER: &str = "0d6f5e4c-3b2a-4918-8776-5a4b3c2d1e0f";
+
+const MOUNTINFO: &[u8] = b"\
+22 1 259:2 / / rw,relatime shared:1 - ext4 /dev/nvme0n1p2 rw
+40 22 0:36 / /mnt/pool rw,relatime shared:20 - bcachefs /dev/sda:/dev/sdb rw
+41 22 0:36 / /srv/with\\040space rw,relatime - bcachefs /dev/sdb:/dev/sda rw
+42 22 0:37 / /mnt/other rw,relatime shared:21 master:3 - bcachefs /dev/sdc rw
+43 22 0:38 / /mnt/by-uuid rw,relatime - bcachefs UUID=8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a rw
+44 22 0:39 / /tmp rw - tmpfs /dev/sda rw
+45 22 0:40 / /mnt/snapshot ro,relatime - bcachefs /dev/sdc ro,subvolid=42
+garbage
+";
+
+fn fs_uuid_of(dev: &Path) -> Option<Uuid> {
+	match dev.to_str()? {
+		"/dev/sda" | "/dev/sdb" => Uuid::parse_str(FS).ok(),
+		"/dev/sdc" => Uuid::parse_str(OTHER).ok(),
+		_ => None,
+	}
+}
+
+#[test]
+fn parses_optional_fields_and_escapes() {
+	let mounts = parse(MOUNTINFO);
+	assert_eq!(mounts.len(), 7);
+	assert_eq!(mounts[2].target, PathBuf::from("/srv/with space"));
+	assert_eq!(mounts[3].fstype, "bcachefs");
+	assert_eq!(mounts[3].source, "/dev/sdc");
+	assert_eq!(mounts[1].vfs_options, "rw,relatime");
+	assert_eq!(mounts[1].fs_options, "rw");
+	assert_eq!(mounts[1].options(), "rw,relatime,rw");
+	assert_eq!(mounts[1].subvolid(), None);
+	assert_eq!(mounts[6].subvolid(), Some(42));
+}
+
+#[test]
+fn finds_multi_device_and_uuid_mounts() {
+	let found = mountpoints_in(MOUNTINFO, Uuid::parse_str(FS).unwrap(), fs_uuid_of);
+	assert_eq!(
+		found,
+		vec![PathBuf::from("/mnt/pool"), PathBuf::from("/srv/with space"), PathBuf::from("/mnt/by-uuid")]
+	);
+
+	let found = mountpoints_in(MOUNTINFO, Uuid::parse_str(OTHER).unwrap(), fs_uuid_of);
+	assert_eq!(found, vec![PathBuf::from("/mnt/other"), PathBuf::from("/mnt/snapshot")]);
+}
+
+/// mountinfo as seen inside a container: the root's parent is outside the
+/// namespace, and the filesystem is bind mounted from a subdirectory
+const CONTAINER_MOUNTINFO: &[u8] = b"\
+812 640 0:81 / / rw,relatime master:290 - overlay overlay rw,lowerdir=/var/lib/l,upperdir=/var/lib/u
+813 812 0:36 /containers/web /data rw,relatime - bcachefs /dev/sda:/dev/sdb rw
+814 812 0:83 / /proc rw,nosuid,nodev,noexec,relatime - proc proc rw
+";
+
+#[test]
+fn namespace_local_view() {
+	let mounts = parse(CONTAINER_MOUNTINFO);
+	assert_eq!(mounts.len(), 3);
+	assert_eq!(mounts[0].target, PathBuf::from("/"));
+	assert_eq!(mounts[0].fs_options, "rw,lowerdir=/var/lib/l,upperdir=/var/lib/u");
+	assert_eq!(mounts[1].target, PathBuf::from("/data"));
+
+	let uuid = Uuid::parse_str(FS).unwrap();
+	assert_eq!(mountpoints_in(CONTAINER_MOUNTINFO, uuid, fs_uuid_of), vec![PathBuf::from("/data")]);
+	// a namespace mounting nothing of ours, e.g. the host's mounts being out of sight
+	assert!(mountpoints_in(CONTAINER_MOUNTINFO, Uuid::parse_str(OTHER).unwrap(), fs_uuid_of).is_empty());
+	assert!(mountpoints_in(b"", uuid, fs_uuid_of).is_empty());
+}
+
+#[test]
+fn already_mounted_at_the_target_or_elsewhere() {
+	let mounted = |fs: &str, target: &str| {
+		let mountpoints = mountpoints_in(MOUNTINFO, Uuid::parse_str(fs).unwrap(), fs_uuid_of);
+		already_mounted(&mountpoints, Path::new(target))
+	};
+	assert_eq!(mounted(FS, "/srv/with space"), AlreadyMounted::AtTarget);
+	assert_eq!(mounted(FS, "/mnt/by-uuid"), AlreadyMounted::AtTarget);
+	assert_eq!(
+		mounted(OTHER, "/mnt/pool"),
+		AlreadyMounted::Elsewhere(vec![PathBuf::from("/mnt/other"), PathBuf::from("/mnt/snapshot")])
+	);
+	let unmounted = Uuid::from_u128(1).to_string();
+	assert_eq!(mounted(&unmounted, "/mnt/pool"), AlreadyMounted::No);
+}
+
+#[test]
+fn paths_need_not_be_utf8() {
+	use std::ffi::OsStr;
+	use std::os::unix::ffi::OsStrExt;
+
+	let mountinfo = b"46 22 0:41 / /mnt/\xff\\040x rw,relatime - bcachefs /dev/disk/by-id/\xfe:/dev/sdc rw\n";
+	let mounts = parse(mountinfo);
+	assert_eq!(mounts[0].target, Path::new(OsStr::from_bytes(b"/mnt/\xff x")));
+	let devices: Vec<&Path> = mounts[0].source_devices().collect();
+	assert_eq!(devices, [Path::new(OsStr::from_bytes(b"/dev/disk/by-id/\xfe")), Path::new("/dev/sdc")]);
+
+	let uuid = Uuid::parse_str(OTHER).unwrap();
+	assert_eq!(mountpoints_in(mountinfo, uuid, fs_uuid_of), vec![PathBuf::from(OsStr::from_bytes(b"/mnt/\xff x"))]);
+}
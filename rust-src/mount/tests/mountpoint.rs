@@ -0,0 +1,123 @@
+//! Creating mountpoints for --mkdir, and comparing them with mountinfo.
+
+mod common;
+
+use bcachefs_mount::mountpoint::{canonical, check_dangling, create, dir_options, DirOptions};
+use common::TempDir;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+
+fn mode(path: &std::path::Path) -> u32 {
+	std::fs::metadata(path).unwrap().permissions().mode() & 0o7777
+}
+
+#[test]
+fn parses_owner_group_and_mode() {
+	let dir = dir_options("noatime,x-mount.owner=root,x-mount.group=0,x-mount.mode=0750").unwrap();
+	assert_eq!(dir, DirOptions { owner: Some(0), group: Some(0), mode: Some(0o750) });
+	assert_eq!(dir_options("noatime").unwrap(), DirOptions::default());
+
+	let err = dir_options("x-mount.owner=no-such-user-here").unwrap_err().to_string();
+	assert!(err.contains("unknown user no-such-user-here"), "{}", err);
+	assert!(dir_options("x-mount.group=no-such-group-here").is_err());
+	assert!(dir_options("x-mount.mode=0999").is_err());
+	assert!(dir_options("x-mount.mode=17777").is_err());
+}
+
+#[test]
+fn x_options_are_not_passed_to_the_kernel() {
+	use bcachefs_mount::filesystem::{parse_mount_options, Checking};
+	let (data, flags) = parse_mount_options("x-mount.mode=0700,noatime", Checking::PassThrough).unwrap();
+	assert_eq!(data, None);
+	assert_eq!(flags, libc::MS_NOATIME);
+}
+
+#[test]
+fn created_directory_gets_mode_regardless_of_umask() {
+	let base = TempDir::new("mkdir.created");
+	let path = base.join("a/mnt");
+	let uid = unsafe { libc::getuid() };
+	let dir = DirOptions { owner: Some(uid), group: None, mode: Some(0o777) };
+	create(&path, &dir, false).unwrap();
+	assert_eq!(mode(&path), 0o777);
+	assert_eq!(std::fs::metadata(&path).unwrap().uid(), uid);
+}
+
+#[test]
+fn existing_directory_is_left_alone_unless_forced() {
+	let base = TempDir::new("mkdir.existing");
+	let path = base.join("mnt");
+	std::fs::create_dir(&path).unwrap();
+	std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o700)).unwrap();
+
+	let dir = DirOptions { mode: Some(0o755), ..DirOptions::default() };
+	create(&path, &dir, false).unwrap();
+	assert_eq!(mode(&path), 0o700);
+
+	create(&path, &dir, true).unwrap();
+	assert_eq!(mode(&path), 0o755);
+}
+
+#[test]
+fn canonical_like_mountinfo() {
+	let path = TempDir::new("mkdir.canonical");
+	std::fs::create_dir_all(path.join("data")).unwrap();
+	std::os::unix::fs::symlink("data", path.join("link")).unwrap();
+	// the temporary directory itself may be behind a symlink
+	let real = path.canonicalize().unwrap();
+
+	assert_eq!(canonical(&path.join("data/")), real.join("data"));
+	assert_eq!(canonical(&path.join("link/")), real.join("data"));
+	assert_eq!(canonical(&path.join("link/./../data")), real.join("data"));
+	// not created yet
+	assert_eq!(canonical(&path.join("link/new/")), real.join("data/new"));
+	assert_eq!(canonical(&path.join("new/sub/../x")), real.join("new/x"));
+	assert!(canonical(std::path::Path::new("relative")).is_absolute());
+}
+
+#[test]
+fn dangling_symlinks_are_refused() {
+	use bcachefs_mount::exit::{kind, ErrorKind};
+
+	let path = TempDir::new("mkdir.dangling");
+	std::fs::create_dir_all(path.join("data")).unwrap();
+	std::os::unix::fs::symlink("data", path.join("link")).unwrap();
+	std::os::unix::fs::symlink("gone", path.join("dangling")).unwrap();
+
+	let direct = check_dangling(&path.join("dangling"));
+	let through = check_dangling(&path.join("dangling/sub"));
+	let fine = [path.join("data"), path.join("link"), path.join("link/new"), path.join("new/sub")];
+	let fine: Vec<_> = fine.iter().map(|p| check_dangling(p).is_ok()).collect();
+
+	let e = direct.unwrap_err();
+	assert_eq!(kind(&e), ErrorKind::NotFound);
+	let link = path.join("dangling");
+	assert_eq!(
+		e.to_string(),
+		format!("mountpoint {}: {} is a symlink to gone, which doesn't exist", link.display(), link.display())
+	);
+	assert!(through.unwrap_err().to_string().ends_with("is a symlink to gone, which doesn't exist"));
+	assert_eq!(fine, [true; 4]);
+}
+
+#[test]
+fn mountpoint_names_need_not_be_utf8() {
+	use bcachefs_mount::mounts::{already_mounted, parse, AlreadyMounted};
+	use std::ffi::OsStr;
+	use std::os::unix::ffi::OsStrExt;
+
+	let base = TempDir::new("mkdir.utf8");
+	let path = base.join(OsStr::from_bytes(b"\xff/mnt"));
+	create(&path, &DirOptions::default(), false).unwrap();
+	assert!(path.is_dir());
+	check_dangling(&path).unwrap();
+
+	let target = canonical(&path);
+	assert!(target.as_os_str().as_bytes().ends_with(b"/\xff/mnt"));
+	// as the kernel would list it in mountinfo
+	let mut mountinfo = b"46 22 0:41 / ".to_vec();
+	mountinfo.extend_from_slice(target.as_os_str().as_bytes());
+	mountinfo.extend_from_slice(b" rw,relatime - bcachefs /dev/sda rw\n");
+	let mounted: Vec<PathBuf> = parse(&mountinfo).into_iter().map(|m| m.target).collect();
+	assert_eq!(already_mounted(&mounted, &target), AlreadyMounted::AtTarget);
+}
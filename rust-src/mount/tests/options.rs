@@ -0,0 +1,316 @@
+//! Mount option handling that doesn't need a filesystem.
+
+mod common;
+
+use bcachefs_mount::{
+	filesystem::{format_mount_options, option_differences, parse_mount_options, Checking},
+	merge_mount_options,
+};
+
+#[test]
+fn command_line_overrides_env() {
+	let options = merge_mount_options(&["ro,noatime", "rw"]);
+	assert_eq!(options, "ro,noatime,rw");
+
+	let (data, flags) = parse_mount_options(&options, Checking::Strict).unwrap();
+	assert_eq!(data, None);
+	assert_eq!(flags & libc::MS_RDONLY, 0);
+	assert_ne!(flags & libc::MS_NOATIME, 0);
+}
+
+/// Flags with the options setting and clearing them, as mount(8) has them
+const FLAG_PAIRS: &[(&str, &str, u64)] = &[
+	("ro", "rw", libc::MS_RDONLY),
+	("sync", "async", libc::MS_SYNCHRONOUS),
+	("noatime", "atime", libc::MS_NOATIME),
+	("nodev", "dev", libc::MS_NODEV),
+	("nodiratime", "diratime", libc::MS_NODIRATIME),
+	("noexec", "exec", libc::MS_NOEXEC),
+	("lazytime", "nolazytime", 1 << 25),
+	("mand", "nomand", libc::MS_MANDLOCK),
+	("relatime", "norelatime", libc::MS_RELATIME),
+	("strictatime", "nostrictatime", libc::MS_STRICTATIME),
+	("nosuid", "suid", libc::MS_NOSUID),
+];
+
+#[test]
+fn command_line_overrides_env_for_every_flag() {
+	let flags = |env: &str, cli: &str| {
+		let options = merge_mount_options(&[env, cli]);
+		parse_mount_options(&options, Checking::Strict).unwrap().1
+	};
+	for (set, clear, flag) in FLAG_PAIRS {
+		assert_eq!(flags(set, clear) & flag, 0, "{} after {}", clear, set);
+		assert_eq!(flags(clear, set) & flag, *flag, "{} after {}", set, clear);
+	}
+}
+
+#[test]
+fn last_atime_option_wins() {
+	let atime = [("noatime", libc::MS_NOATIME), ("relatime", libc::MS_RELATIME), ("strictatime", libc::MS_STRICTATIME)];
+	let all = libc::MS_NOATIME | libc::MS_RELATIME | libc::MS_STRICTATIME;
+	for (env, _) in &atime {
+		for (cli, flag) in &atime {
+			let (_, flags) = parse_mount_options(merge_mount_options(&[env, cli]), Checking::Strict).unwrap();
+			assert_eq!(flags & all, *flag, "{} after {}", cli, env);
+		}
+	}
+	// other flags are left alone
+	let (_, flags) = parse_mount_options("nodiratime,noatime,relatime", Checking::Strict).unwrap();
+	assert_eq!(flags, libc::MS_NODIRATIME | libc::MS_RELATIME);
+}
+
+#[test]
+fn empty_layers_are_skipped() {
+	assert_eq!(merge_mount_options(&["", "ro", ""]), "ro");
+	assert_eq!(merge_mount_options(&["", ""]), "");
+}
+
+#[test]
+fn discard_is_passed_to_the_filesystem() {
+	let (data, flags) = parse_mount_options("noatime,discard", Checking::Strict).unwrap();
+	assert_eq!(data.as_deref(), Some("discard"));
+	assert_eq!(flags, libc::MS_NOATIME);
+
+	let (data, flags) = parse_mount_options("nodiscard,ro", Checking::Strict).unwrap();
+	assert_eq!(data.as_deref(), Some("nodiscard"));
+	assert_eq!(flags, libc::MS_RDONLY);
+}
+
+#[test]
+fn unknown_options_are_passed_on_unless_strict() {
+	let options = "noatime,no_such_option,journal_reclaim_delay=abc";
+	let (data, flags) = parse_mount_options(options, Checking::PassThrough).unwrap();
+	assert_eq!(data.as_deref(), Some("no_such_option,journal_reclaim_delay=abc"));
+	assert_eq!(flags, libc::MS_NOATIME);
+
+	// x-* options are still not for the kernel
+	let (data, _) = parse_mount_options("x-mount.mode=0700,discard", Checking::PassThrough).unwrap();
+	assert_eq!(data.as_deref(), Some("discard"));
+
+	assert_eq!(Checking::new(false, true), Checking::PassThrough);
+	assert_eq!(Checking::new(true, false), Checking::Strict);
+	assert_eq!(Checking::new(true, true), Checking::Sloppy);
+}
+
+#[test]
+fn sloppy_drops_unknown_options() {
+	assert!(parse_mount_options("noatime,no_such_option", Checking::Strict).is_err());
+
+	let (data, flags) = parse_mount_options("noatime,no_such_option", Checking::Sloppy).unwrap();
+	assert_eq!(data, None);
+	assert_eq!(flags, libc::MS_NOATIME);
+}
+
+#[test]
+fn formatted_options_start_with_ro_or_rw() {
+	assert_eq!(format_mount_options(None, 0), "rw");
+	assert_eq!(format_mount_options(Some("degraded"), libc::MS_RDONLY | libc::MS_NOATIME), "ro,noatime,degraded");
+}
+
+#[test]
+fn numeric_options_are_range_checked() {
+	let (data, _) = parse_mount_options("journal_reclaim_delay=50", Checking::Strict).unwrap();
+	assert_eq!(data.as_deref(), Some("journal_reclaim_delay=50"));
+
+	let err = parse_mount_options("journal_reclaim_delay=abc", Checking::Strict).unwrap_err().to_string();
+	assert!(err.contains("journal_reclaim_delay") && err.contains("'abc'"), "{}", err);
+	assert!(parse_mount_options("journal_flush_delay=0", Checking::Strict).is_err());
+
+	let (data, _) = parse_mount_options("journal_reclaim_delay=abc", Checking::Sloppy).unwrap();
+	assert_eq!(data, None);
+}
+
+#[test]
+fn fstab_only_options_are_stripped() {
+	let (data, flags) = parse_mount_options("noauto,defaults", Checking::Strict).unwrap();
+	assert_eq!(data, None);
+	assert_eq!(flags, 0);
+
+	let (data, flags) = parse_mount_options("auto,nofail,_netdev,noatime", Checking::Strict).unwrap();
+	assert_eq!(data, None);
+	assert_eq!(flags, libc::MS_NOATIME);
+}
+
+#[test]
+fn differences_from_requested_options() {
+	assert!(option_differences("rw,noatime,compression=zstd", "rw,noatime,compression=zstd").is_empty());
+	// atime defaults and unlisted bcachefs options are not differences
+	assert!(option_differences("rw,degraded", "rw,relatime").is_empty());
+
+	assert_eq!(option_differences("rw,noatime", "ro,noatime"), vec!["requested rw, mounted ro"]);
+	assert_eq!(
+		option_differences("ro,nodev,nosuid", "ro,nosuid,relatime"),
+		vec!["requested nodev, but it is not in effect"]
+	);
+
+	// strictatime only shows as the absence of the other atime options
+	assert!(option_differences("rw,strictatime", "rw").is_empty());
+	assert_eq!(option_differences("rw,strictatime", "rw,relatime"), vec!["requested strictatime, mounted relatime"]);
+	// and some flags don't show at all
+	assert!(option_differences("rw,remount,mand", "rw,relatime").is_empty());
+}
+
+#[test]
+fn subvolid_implies_read_only() {
+	let (data, flags) = parse_mount_options("subvolid=42", Checking::Strict).unwrap();
+	assert_eq!(data.as_deref(), Some("subvolid=42"));
+	assert_eq!(flags, libc::MS_RDONLY);
+
+	let (_, flags) = parse_mount_options("subvolid=42,rw", Checking::Strict).unwrap();
+	assert_eq!(flags, 0);
+
+	assert!(parse_mount_options("subvolid", Checking::Strict).is_err());
+	assert!(parse_mount_options("subvolid=0", Checking::Strict).is_err());
+	assert!(parse_mount_options("subvolid=4294967296", Checking::Strict).is_err());
+	assert!(parse_mount_options("subvolid=-1", Checking::Strict).is_err());
+}
+
+#[test]
+fn errors_option_is_validated() {
+	let (data, _) = parse_mount_options("errors=ro", Checking::Strict).unwrap();
+	assert_eq!(data.as_deref(), Some("errors=ro"));
+
+	let err = parse_mount_options("errors=explode", Checking::Strict).unwrap_err().to_string();
+	assert!(err.contains("'explode'") && err.contains("continue,ro,panic"), "{}", err);
+
+	// passed on for kernels that know more error actions
+	let (data, _) = parse_mount_options("errors=explode", Checking::Sloppy).unwrap();
+	assert_eq!(data.as_deref(), Some("errors=explode"));
+}
+
+#[test]
+fn remount_is_a_mount_flag() {
+	let (data, flags) = parse_mount_options("remount,rw,discard", Checking::Strict).unwrap();
+	assert_eq!(data.as_deref(), Some("discard"));
+	assert_eq!(flags, libc::MS_REMOUNT);
+	assert_eq!(format_mount_options(data.as_deref(), flags), "rw,remount,discard");
+}
+
+#[test]
+fn remount_needs_a_bcachefs_mount() {
+	let uuid = uuid::Uuid::from_u128(1);
+	let dir = common::TempDir::new("remount");
+	let paths = Default::default();
+	let remount = |target: &std::path::Path| {
+		bcachefs_mount::filesystem::remount(&uuid, target, "remount,ro", Checking::Strict, "bcachefs", &paths)
+			.unwrap_err()
+	};
+	let e = remount(&dir);
+	assert!(e.to_string().ends_with("is not a mountpoint"), "{}", e);
+
+	let e = remount(std::path::Path::new("/proc"));
+	assert_eq!(e.to_string(), "/proc is mounted, but is proc rather than bcachefs");
+}
+
+#[test]
+fn explained_options() {
+	use bcachefs_mount::filesystem::explain_mount_options;
+
+	let options = "ro,noatime,discard,errors=ro,noauto,x-mount.mode=0755,subvolid=42,rw";
+	let table = explain_mount_options(options, Checking::Strict);
+	assert_eq!(
+		table,
+		"\
+option            class     detail
+ro                vfs flag  MS_RDONLY
+noatime           vfs flag  MS_NOATIME
+discard           fs option bool 1
+errors=ro         fs option str 1 (ro)
+noauto            userspace not passed to the kernel
+x-mount.mode=0755 userspace not passed to the kernel
+subvolid=42       fs option u32 42
+rw                vfs flag  clears MS_RDONLY
+flags: 0x00000400 MS_NOATIME
+data: discard,errors=ro,subvolid=42
+"
+	);
+}
+
+#[test]
+fn explained_bad_options() {
+	use bcachefs_mount::filesystem::explain_mount_options;
+
+	let table = explain_mount_options("nodiscard,bogus,journal_flush_delay=0", Checking::Strict);
+	assert_eq!(
+		table,
+		"\
+option                class     detail
+nodiscard             fs option bool 0
+bogus                 unknown   unknown mount option bogus
+journal_flush_delay=0 invalid   journal_flush_delay: invalid value '0' (expected a number from 1 to 4294967294)
+error: unknown mount option bogus
+"
+	);
+
+	let table = explain_mount_options("bogus,sync", Checking::Sloppy);
+	assert!(table.ends_with("flags: 0x00000010 MS_SYNCHRONOUS\ndata: \n"), "{}", table);
+}
+
+#[test]
+fn key_location_from_flag_or_option() {
+	use bcachefs_mount::Options;
+	use structopt::StructOpt;
+
+	let key = |args: &[&str]| {
+		let opt = Options::from_iter(["bcachefs-mount"].iter().chain(args).chain(&["LABEL=x"]));
+		opt.key_location().map(|k| k.map(|k| format!("{:?}", k)))
+	};
+	assert_eq!(key(&[]).unwrap(), None);
+	assert_eq!(key(&["-o", "noatime,x-bcachefs.key_location=ask"]).unwrap().as_deref(), Some("Ask"));
+	// the last one wins, and the flag over all of them
+	let options = ["-o", "x-bcachefs.key_location=ask,x-bcachefs.key_location=wait"];
+	assert_eq!(key(&options).unwrap().as_deref(), Some("Wait"));
+	assert_eq!(key(&["-k", "fail", options[0], options[1]]).unwrap().as_deref(), Some("Fail"));
+	assert!(key(&["-o", "x-bcachefs.key_location=later"]).is_err());
+}
+
+#[test]
+fn unlock_attempts_are_bounded_by_default() {
+	use bcachefs_mount::Options;
+	use structopt::StructOpt;
+
+	let attempts = |args: &[&str]| {
+		Options::from_iter(["bcachefs-mount"].iter().chain(args).chain(&["LABEL=x"])).max_unlock_attempts
+	};
+	// half a minute of polls, or as many prompts, before a boot goes on
+	assert_eq!(attempts(&[]), 30);
+	assert_eq!(attempts(&["--max-unlock-attempts", "3"]), 3);
+}
+
+#[test]
+fn key_location_option_stays_in_userspace() {
+	let (data, _) = parse_mount_options("x-bcachefs.key_location=ask,discard", Checking::Strict).unwrap();
+	assert_eq!(data.as_deref(), Some("discard"));
+}
+
+#[test]
+fn recovery_pass_option_is_rejected() {
+	use bcachefs_mount::exit::{kind, ErrorKind};
+
+	let err = parse_mount_options("ro,x-bcachefs.recovery_pass=check_alloc_info", Checking::PassThrough).unwrap_err();
+	assert_eq!(kind(&err), ErrorKind::Unsupported);
+	assert!(err.to_string().contains("'check_alloc_info'"), "{}", err);
+
+	// not silently dropped with the other x-* options, unless sloppy
+	let (data, _) = parse_mount_options("x-bcachefs.recovery_pass=check_alloc_info,discard", Checking::Sloppy).unwrap();
+	assert_eq!(data.as_deref(), Some("discard"));
+}
+
+#[test]
+fn optional_values_need_an_equals_sign() {
+	use bcachefs_mount::{HealthCheck, Options};
+	use structopt::StructOpt;
+
+	let parse = |args: &[&str]| {
+		Options::from_iter_safe(["bcachefs-mount"].iter().chain(args))
+			.map(|o| (o.device_health_check, o.uuid.map(|u| u.to_string()), o.mountpoint))
+	};
+	// the filesystem isn't taken for the mode, nor the mode for the filesystem
+	let (health, uuid, mountpoint) = parse(&["--device-health-check", "LABEL=x", "/mnt"]).unwrap();
+	assert_eq!(health, Some(None));
+	assert_eq!(uuid.as_deref(), Some("LABEL=x"));
+	assert_eq!(mountpoint, Some("/mnt".into()));
+	assert_eq!(parse(&["--device-health-check=strict", "LABEL=x"]).unwrap().0, Some(Some(HealthCheck::Strict)));
+	assert_eq!(parse(&["--device-health-check", "8b1c"]).unwrap().1.as_deref(), Some("8b1c"));
+}
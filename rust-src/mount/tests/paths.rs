@@ -0,0 +1,71 @@
+//! Non-standard /dev, /sys and runtime directories, as in an initramfs.
+
+mod common;
+
+use bcachefs_mount::{health, lock, paths::Paths, stale};
+use common::TempDir;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[test]
+fn runtime_dir_is_created_private() {
+	let base = TempDir::new("paths.runtime");
+	let paths = Paths { runtime_dir: base.join("run/bcachefs-mount"), ..Paths::default() };
+	let dir = paths.state_dir().unwrap();
+	assert_eq!(std::fs::metadata(dir).unwrap().permissions().mode() & 0o777, 0o700);
+
+	let held = lock::lock(&uuid::Uuid::from_u128(1), Duration::from_secs(0), &paths).unwrap();
+	assert!(held.is_some());
+	assert!(dir.join("00000000-0000-0000-0000-000000000001.lock").exists());
+}
+
+#[test]
+fn unusable_runtime_dir_means_no_state() {
+	let base = TempDir::new("paths.unusable");
+	std::fs::write(base.join("file"), "").unwrap();
+	let paths = Paths { runtime_dir: base.join("file/bcachefs-mount"), ..Paths::default() };
+	assert_eq!(paths.state_dir(), None);
+	assert!(lock::lock(&uuid::Uuid::from_u128(1), Duration::from_secs(0), &paths).unwrap().is_none());
+}
+
+#[test]
+fn dev_paths_move_to_dev_root() {
+	let paths = Paths { dev_root: PathBuf::from("/newroot/dev"), ..Paths::default() };
+	assert_eq!(paths.dev(Path::new("/dev/sda1")), Path::new("/newroot/dev/sda1"));
+	assert_eq!(paths.dev(Path::new("/devices/sda1")), Path::new("/devices/sda1"));
+	assert_eq!(Paths::default().dev(Path::new("/dev/sda1")), Path::new("/dev/sda1"));
+}
+
+#[test]
+fn health_check_reads_sys_root() {
+	let base = TempDir::new("paths.health");
+	let (dev, sys) = (base.join("dev"), base.join("sys"));
+	std::fs::create_dir_all(&dev).unwrap();
+	std::fs::write(dev.join("sdz"), "").unwrap();
+	let disk = sys.join("class/block/sdz");
+	std::fs::create_dir_all(disk.join("device")).unwrap();
+	std::fs::write(disk.join("ro"), "1\n").unwrap();
+	std::fs::write(disk.join("device/state"), "offline\n").unwrap();
+
+	let paths = Paths { dev_root: dev, sys_root: sys, ..Paths::default() };
+	let h = health::check(Path::new("/dev/sdz"), &paths);
+	assert_eq!(h.problems, vec!["device state is offline", "device is read-only"]);
+}
+
+#[test]
+fn partitions_are_found_under_sys_root() {
+	let base = TempDir::new("paths.partitions");
+	let (dev, sys) = (base.join("dev"), base.join("sys"));
+	std::fs::create_dir_all(&dev).unwrap();
+	std::fs::write(dev.join("sdz"), "").unwrap();
+	let disk = sys.join("class/block/sdz");
+	for part in &["sdz2", "sdz1"] {
+		std::fs::create_dir_all(disk.join(part)).unwrap();
+		std::fs::write(disk.join(part).join("partition"), "1\n").unwrap();
+	}
+	std::fs::create_dir_all(disk.join("queue")).unwrap();
+
+	let paths = Paths { dev_root: dev.clone(), sys_root: sys, ..Paths::default() };
+	assert_eq!(stale::partitions(Path::new("/dev/sdz"), &paths), vec![dev.join("sdz1"), dev.join("sdz2")]);
+}
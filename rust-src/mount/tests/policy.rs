@@ -0,0 +1,118 @@
+//! Allow and deny rules, and --policy-exec commands and what their exit status
+//! means.
+
+use bcachefs_mount::exit::{kind, ErrorKind};
+use bcachefs_mount::policy::{check, Verdict};
+use std::time::{Duration, Instant};
+
+const TANK: &str = r#"{"uuid":"8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a","label":"tank"}"#;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[test]
+fn exit_status_decides() {
+	assert_eq!(check("true", TANK, TIMEOUT).unwrap(), Verdict::Proceed);
+	assert_eq!(check("exit 3", TANK, TIMEOUT).unwrap(), Verdict::Skip);
+
+	let e = check("echo not in the asset database >&2; exit 1", TANK, TIMEOUT).unwrap_err();
+	assert_eq!(kind(&e), ErrorKind::Permission);
+	assert!(e.to_string().ends_with("(exit status 1): not in the asset database"), "{}", e);
+
+	let e = check("kill -9 $$", TANK, TIMEOUT).unwrap_err();
+	assert!(e.to_string().contains("killed by signal 9"), "{}", e);
+}
+
+#[test]
+fn gets_the_description_on_stdin() {
+	let command = r#"grep -q '"label":"tank"' || exit 1"#;
+	assert_eq!(check(command, TANK, TIMEOUT).unwrap(), Verdict::Proceed);
+	assert!(check(command, r#"{"label":"other"}"#, TIMEOUT).is_err());
+	// not reading it is fine too
+	assert_eq!(check("exit 0", &"x".repeat(1 << 20), TIMEOUT).unwrap(), Verdict::Proceed);
+}
+
+#[test]
+fn slow_commands_are_killed() {
+	let start = Instant::now();
+	let e = check("sleep 30", TANK, Duration::from_millis(200)).unwrap_err();
+	assert!(e.to_string().contains("did not finish"), "{}", e);
+	assert!(start.elapsed() < Duration::from_secs(10));
+
+	// children left behind holding stdout don't hold up the verdict
+	let start = Instant::now();
+	assert_eq!(check("sleep 30 & exit 0", TANK, Duration::from_secs(1)).unwrap(), Verdict::Proceed);
+	assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn glob_semantics() {
+	use bcachefs_mount::policy::glob_match;
+
+	assert!(glob_match("tank", "tank"));
+	assert!(!glob_match("tank", "tanks"));
+	assert!(glob_match("san-*", "san-lun7"));
+	assert!(glob_match("san-*", "san-"));
+	assert!(!glob_match("san-*", "nas-lun7"));
+	assert!(glob_match("*-backup", "home-backup"));
+	assert!(glob_match("*a*b*", "xxaxxbxx"));
+	assert!(!glob_match("*a*b", "xxaxxbxx"));
+	assert!(glob_match("lun?", "lun7"));
+	assert!(!glob_match("lun?", "lun"));
+	assert!(glob_match("", ""));
+	assert!(glob_match("*", ""));
+	assert!(glob_match(r"star\*", "star*"));
+	assert!(!glob_match(r"star\*", "starry"));
+	assert!(glob_match("日本?", "日本語"));
+}
+
+#[test]
+fn deny_wins_and_allow_rules_restrict() {
+	use bcachefs_mount::policy::{evaluate, Decision, Rule};
+
+	let (ours, theirs) = (uuid::Uuid::from_u128(1), uuid::Uuid::from_u128(2));
+	let internal = uuid::Uuid::from_u128(3);
+
+	assert_eq!(evaluate(&[], &ours, &internal, None), Decision::Allowed(None));
+
+	let rules = [Rule::DenyLabel("san-*".to_owned()), Rule::AllowUuid(ours)];
+	assert_eq!(evaluate(&rules, &ours, &internal, Some("san-lun1")), Decision::Denied(Some(&rules[0])));
+	assert_eq!(evaluate(&rules, &ours, &internal, Some("home")), Decision::Allowed(Some(&rules[1])));
+	assert_eq!(evaluate(&rules, &ours, &internal, None), Decision::Allowed(Some(&rules[1])));
+	// with an allow rule, anything it doesn't match is denied
+	assert_eq!(evaluate(&rules, &theirs, &internal, Some("home")), Decision::Denied(None));
+
+	// only deny rules: the rest is allowed, order doesn't matter
+	let rules = [Rule::DenyUuid(theirs), Rule::DenyLabel("scratch".to_owned())];
+	assert_eq!(evaluate(&rules, &ours, &internal, Some("home")), Decision::Allowed(None));
+	assert_eq!(evaluate(&rules, &theirs, &internal, None), Decision::Denied(Some(&rules[0])));
+	let reversed = [rules[1].clone(), rules[0].clone()];
+	assert_eq!(evaluate(&reversed, &ours, &internal, Some("scratch")), Decision::Denied(Some(&reversed[0])));
+
+	// the internal UUID matches too
+	let rules = [Rule::DenyUuid(internal)];
+	assert_eq!(evaluate(&rules, &ours, &internal, None), Decision::Denied(Some(&rules[0])));
+	assert_eq!(rules[0].to_string(), "--deny-uuid=00000000-0000-0000-0000-000000000003");
+}
+
+#[test]
+fn rules_from_the_command_line() {
+	use bcachefs_mount::{policy::Rule, Options};
+	use structopt::StructOpt;
+
+	let opt = Options::from_iter(&[
+		"bcachefs-mount",
+		"--deny-label",
+		"san-*",
+		"--allow-uuid",
+		"00000000-0000-0000-0000-000000000001",
+		"--deny-label=scratch",
+		"LABEL=home",
+	]);
+	assert_eq!(
+		opt.policy_rules(),
+		vec![
+			Rule::AllowUuid(uuid::Uuid::from_u128(1)),
+			Rule::DenyLabel("san-*".to_owned()),
+			Rule::DenyLabel("scratch".to_owned()),
+		]
+	);
+}
@@ -0,0 +1,327 @@
+//! Probing through a `DeviceSource` other than udev.
+
+mod common;
+
+use bcachefs_mount::exit;
+use bcachefs_mount::filesystem::{
+	find_filtered, not_found_in, probe_for, probe_for_with, probe_scan, probe_with, resolve, DeviceSource, FileSystem,
+	Progress, ResolveError, Scan, Skip, Skipped,
+};
+use bcachefs_mount::paths::Paths;
+use bcachefs_mount::FsSpec;
+use bch_bindgen::rs::SbBuf;
+use common::{filesystem, Superblock, TempDir, UUID};
+use std::path::PathBuf;
+
+struct Fake(Vec<PathBuf>);
+
+impl DeviceSource for Fake {
+	fn devices(&self) -> anyhow::Result<Vec<PathBuf>> {
+		Ok(self.0.clone())
+	}
+}
+
+struct Unreachable;
+
+impl DeviceSource for Unreachable {
+	fn devices(&self) -> anyhow::Result<Vec<PathBuf>> {
+		Err(anyhow::anyhow!("backend unreachable"))
+	}
+}
+
+#[test]
+fn no_devices_no_filesystems() {
+	assert!(probe_with(&Fake(Vec::new()), &Paths::default()).unwrap().is_empty());
+	assert!(probe_with(&[][..], &Paths::default()).unwrap().is_empty());
+}
+
+#[test]
+fn devices_given_twice_are_probed_once() {
+	let dir = TempDir::new("twice");
+	std::fs::write(dir.join("sda"), "").unwrap();
+	std::fs::write(dir.join("sdb"), "").unwrap();
+	std::os::unix::fs::symlink("sda", dir.join("alias")).unwrap();
+
+	let given: Vec<PathBuf> =
+		["sda", "alias", "sdb", "sda", "gone", "gone"].iter().map(|name| dir.join(name)).collect();
+	let devices = given.devices().unwrap();
+	assert_eq!(devices, vec![dir.join("sda"), dir.join("sdb"), dir.join("gone")]);
+}
+
+#[test]
+fn enumeration_errors_are_returned() {
+	let err = probe_with(&Unreachable, &Paths::default()).unwrap_err();
+	assert_eq!(err.to_string(), "backend unreachable");
+}
+
+/// Counts the scans made through it
+struct Counting<'a>(&'a std::cell::Cell<usize>);
+
+impl DeviceSource for Counting<'_> {
+	fn devices(&self) -> anyhow::Result<Vec<PathBuf>> {
+		self.0.set(self.0.get() + 1);
+		Ok(Vec::new())
+	}
+}
+
+fn superblock() -> SbBuf {
+	Superblock::default().build()
+}
+
+fn labelled(uuid: uuid::Uuid, label: &str) -> SbBuf {
+	Superblock::default().uuid(uuid).label(label).build()
+}
+
+fn with_internal_uuid(uuid: uuid::Uuid, internal_uuid: uuid::Uuid) -> SbBuf {
+	Superblock::default().uuid(uuid).internal_uuid(internal_uuid).build()
+}
+
+#[test]
+fn unfiltered_scan_only_when_not_found() {
+	let sb = superblock();
+	let scans = std::cell::Cell::new(0);
+	let scan = || probe_with(&Counting(&scans), &Paths::default());
+	let spec = FsSpec::Uuid(UUID);
+
+	let found = vec![(UUID, filesystem(&sb, "/dev/sda"))].into_iter().collect();
+	assert_eq!(find_filtered(&spec, "--only-device", found, scan).unwrap().device_string(), "/dev/sda");
+	assert_eq!(scans.get(), 0);
+
+	let found = probe_with(&Counting(&scans), &Paths::default()).unwrap();
+	let err = find_filtered(&spec, "--only-device", found, scan).unwrap_err();
+	assert_eq!(err.to_string(), "filesystem was not found");
+	assert_eq!(scans.get(), 2);
+}
+
+#[test]
+fn names_devices_left_out() {
+	let sb = superblock();
+	let spec = FsSpec::Uuid(UUID);
+	let everything = || Ok(vec![(UUID, filesystem(&sb, "/dev/zram0"))].into_iter().collect());
+	let err = find_filtered(&spec, "--only-device", Default::default(), everything).unwrap_err();
+	assert_eq!(
+		err.to_string(),
+		"filesystem was not found on the devices scanned, but on /dev/zram0, which --only-device leaves out"
+	);
+	assert_eq!(exit::kind(&err), exit::ErrorKind::NotFound);
+}
+
+#[test]
+fn failed_unfiltered_scan_is_not_found() {
+	let spec = FsSpec::Uuid(UUID);
+	let unreachable = || probe_with(&Unreachable, &Paths::default());
+	let err = find_filtered(&spec, "--only-device", Default::default(), unreachable).unwrap_err();
+	assert_eq!(err.to_string(), "filesystem was not found");
+}
+
+#[test]
+fn filesystems_by_prefix_and_label() {
+	let uuids = [
+		UUID,
+		uuid::Uuid::from_u128(0x8b1c0000_0000_4000_8000_000000000001),
+		uuid::Uuid::from_u128(0x0d6f5e4c_3b2a_4918_8776_5a4b3c2d1e0f),
+	];
+	let sbs = [labelled(uuids[0], "tank"), labelled(uuids[1], "tank"), labelled(uuids[2], "scratch")];
+	let fss = || -> std::collections::HashMap<_, _> {
+		uuids.iter().zip(&sbs).map(|(u, sb)| (*u, filesystem(sb, "/dev/sda"))).collect()
+	};
+	let uuid_of = |spec: &str| resolve(&spec.parse().unwrap(), &mut fss()).map(|fs| *fs.uuid());
+
+	assert_eq!(uuid_of("8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a"), Ok(uuids[0]));
+	assert_eq!(uuid_of("8B1C7A"), Ok(uuids[0]));
+	assert_eq!(uuid_of("8b1c7a3e-5f"), Ok(uuids[0]));
+	assert_eq!(uuid_of("8b1c7a3e5f"), Ok(uuids[0]));
+	assert_eq!(uuid_of("LABEL=scratch"), Ok(uuids[2]));
+	assert_eq!(uuid_of("ff"), Err(ResolveError::NotFound));
+	assert_eq!(uuid_of("LABEL=pool"), Err(ResolveError::NotFound));
+	assert_eq!(uuid_of("8b1c"), Err(ResolveError::AmbiguousPrefix(vec![uuids[1], uuids[0]])));
+	assert_eq!(uuid_of("LABEL=tank"), Err(ResolveError::AmbiguousLabel(vec![uuids[1], uuids[0]])));
+}
+
+#[test]
+fn filesystems_by_internal_uuid() {
+	let uuids = [
+		UUID,
+		uuid::Uuid::from_u128(0x0d6f5e4c_3b2a_4918_8776_5a4b3c2d1e0f),
+	];
+	let internal = [
+		uuid::Uuid::from_u128(0x5a4b3c2d_1e0f_4a1b_9c2d_3e4f5a6b7c8d),
+		// as unlikely as it is, the other filesystem's external UUID
+		uuids[0],
+	];
+	let sbs = [with_internal_uuid(uuids[0], internal[0]), with_internal_uuid(uuids[1], internal[1])];
+	let fss = |n: usize| -> std::collections::HashMap<_, _> {
+		uuids.iter().zip(&sbs).take(n).map(|(u, sb)| (*u, filesystem(sb, "/dev/sda"))).collect()
+	};
+	let uuid_of = |n, spec: &str| resolve(&spec.parse().unwrap(), &mut fss(n)).map(|fs| *fs.uuid());
+
+	assert_eq!(uuid_of(2, "5a4b3c2d-1e0f-4a1b-9c2d-3e4f5a6b7c8d"), Ok(uuids[0]));
+	// prefixes only ever match external UUIDs
+	assert_eq!(uuid_of(2, "5a4b3c2d"), Err(ResolveError::NotFound));
+	assert_eq!(uuid_of(1, "8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a"), Ok(uuids[0]));
+	assert_eq!(
+		uuid_of(2, "8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a"),
+		Err(ResolveError::AmbiguousUuid(vec![uuids[1], uuids[0]]))
+	);
+	let err = anyhow::Error::new(ResolveError::AmbiguousUuid(vec![uuids[1], uuids[0]]));
+	assert!(
+		err.to_string().starts_with("UUID matched 2 filesystems, as the UUID of one and the internal UUID of another: "),
+		"{}",
+		err
+	);
+	assert_eq!(exit::kind(&err), exit::ErrorKind::Ambiguous);
+}
+
+#[test]
+fn ambiguity_is_reported_with_the_candidates() {
+	let candidates = vec![uuid::Uuid::from_u128(1), uuid::Uuid::from_u128(2)];
+	let err = anyhow::Error::new(ResolveError::AmbiguousPrefix(candidates.clone()));
+	assert_eq!(
+		err.to_string(),
+		"UUID prefix matched 2 filesystems: 00000000-0000-0000-0000-000000000001, \
+		 00000000-0000-0000-0000-000000000002; give more of the UUID"
+	);
+	assert_eq!(exit::kind(&err), exit::ErrorKind::Ambiguous);
+	assert_ne!(exit::kind(&err).exit_code(), exit::ErrorKind::NotFound.exit_code());
+	assert!(exit::json(&err, None).contains(r#""kind":"ambiguous","id":"AmbiguousPrefix""#));
+
+	let err = anyhow::Error::new(ResolveError::AmbiguousLabel(candidates));
+	assert!(err.to_string().starts_with("label matched 2 filesystems: "), "{}", err);
+	assert_eq!(exit::kind(&anyhow::Error::new(ResolveError::NotFound)), exit::ErrorKind::NotFound);
+}
+
+#[test]
+fn filesystem_specs() {
+	assert_eq!(
+		"LABEL=".parse::<FsSpec>().unwrap_err().to_string(),
+		"LABEL= is neither a filesystem UUID, the start of one, nor LABEL=<label>"
+	);
+	assert!("tank".parse::<FsSpec>().is_err());
+	assert!("00000000-0000-0000-0000-000000000000".parse::<FsSpec>().is_err());
+	assert_eq!("LABEL=a=b".parse::<FsSpec>().unwrap(), FsSpec::Label("a=b".to_owned()));
+}
+
+/// A scan as a user sure their disk is there might see it
+fn scan() -> Scan {
+	let skipped = |device: &str, skip| Skipped { device: PathBuf::from(device), skip };
+	Scan {
+		scanned: 6,
+		skipped: vec![
+			skipped("/dev/sda1", Skip::NotBcachefs),
+			skipped("/dev/sda2", Skip::NotBcachefs),
+			skipped("/dev/sdb", Skip::NotBcachefs),
+			skipped("/dev/sdc", Skip::PermissionDenied),
+			skipped("/dev/sdd", Skip::Vanished),
+		],
+	}
+}
+
+#[test]
+fn skipped_devices_are_classified() {
+	use std::io::{Error, ErrorKind};
+
+	assert_eq!(Skip::of(&Error::new(ErrorKind::InvalidData, "Not a BCacheFS SuperBlock")), Skip::NotBcachefs);
+	assert_eq!(Skip::of(&Error::new(ErrorKind::NotFound, "Failed to Read SuperBlock")), Skip::Vanished);
+	assert_eq!(Skip::of(&Error::new(ErrorKind::PermissionDenied, "Access Permission Denied")), Skip::PermissionDenied);
+	assert_eq!(Skip::of(&Error::new(ErrorKind::Other, "Failed to Read SuperBlock")), Skip::Unreadable);
+
+	let mut scan = scan();
+	assert_eq!(scan.to_string(), "6 devices scanned: 1 bcachefs, 3 not bcachefs, 1 vanished, 1 permission denied");
+	scan.classify(|device| match device.to_str() {
+		Some("/dev/sdb") => None,
+		_ => Some("ext4".to_owned()),
+	});
+	assert_eq!(scan.skipped[0].skip, Skip::OtherFs("ext4".to_owned()));
+	assert_eq!(scan.skipped[2].skip, Skip::Empty);
+	assert_eq!(scan.skipped[0].to_string(), "/dev/sda1: other filesystem (ext4)");
+	assert_eq!(scan.skipped[3].to_string(), "/dev/sdc: permission denied");
+	assert_eq!(
+		scan.counts(),
+		[("other_filesystem", 2), ("empty", 1), ("vanished", 1), ("permission_denied", 1)]
+	);
+	assert_eq!(
+		scan.to_string(),
+		"6 devices scanned: 1 bcachefs, 2 other filesystems, 1 empty, 1 vanished, 1 permission denied"
+	);
+	assert_eq!(Scan { scanned: 1, skipped: Vec::new() }.to_string(), "1 device scanned: 1 bcachefs");
+	assert_eq!(Scan::default().to_string(), "0 devices scanned");
+}
+
+#[test]
+fn scans_as_json() {
+	let mut scan = scan();
+	scan.skipped.truncate(4);
+	scan.classify(|device| if device.ends_with("sdb") { None } else { Some("ext4".to_owned()) });
+	assert_eq!(
+		scan.to_json(),
+		concat!(
+			r#"{"scanned":6,"skipped":["#,
+			r#"{"device":"/dev/sda1","reason":"other_filesystem","fs_type":"ext4"},"#,
+			r#"{"device":"/dev/sda2","reason":"other_filesystem","fs_type":"ext4"},"#,
+			r#"{"device":"/dev/sdb","reason":"empty","fs_type":null},"#,
+			r#"{"device":"/dev/sdc","reason":"permission_denied","fs_type":null}]}"#
+		)
+	);
+}
+
+#[test]
+fn not_found_says_what_was_scanned() {
+	let (fss, scan) = probe_scan(&Fake(Vec::new()), &Paths::default()).unwrap();
+	assert!(fss.is_empty());
+	assert_eq!(scan, Scan::default());
+
+	let err = probe_for(&FsSpec::Uuid(UUID), &Fake(Vec::new()), &Paths::default()).unwrap_err();
+	assert_eq!(err.to_string(), "filesystem was not found; 0 devices scanned");
+	// still a ResolveError for --retry, and FsNotFound for --json-errors
+	assert_eq!(err.downcast_ref::<ResolveError>(), Some(&ResolveError::NotFound));
+	assert_eq!(exit::kind(&err), exit::ErrorKind::NotFound);
+	assert!(exit::json(&err, None).contains(r#""kind":"not_found","id":"FsNotFound""#));
+
+	// devices that aren't there hold no filesystem udev knows of
+	let err = not_found_in(ResolveError::NotFound, scan_of_missing(), &Paths::default());
+	assert_eq!(err.to_string(), "filesystem was not found; 1 device scanned: 1 empty");
+	let err = not_found_in(ResolveError::AmbiguousLabel(vec![UUID]), scan_of_missing(), &Paths::default());
+	assert!(err.to_string().starts_with("label matched 1 filesystems"), "{}", err);
+}
+
+fn scan_of_missing() -> Scan {
+	let device = PathBuf::from("/nonexistent/bcachefs-mount-probe");
+	Scan { scanned: 1, skipped: vec![Skipped { device, skip: Skip::NotBcachefs }] }
+}
+
+/// Ends the probe before device number `allowed`, counting from 0
+struct Impatient {
+	allowed: usize,
+	asked: usize,
+	found: usize,
+}
+
+impl Progress for Impatient {
+	fn found(&mut self, _: &FileSystem) {
+		self.found += 1;
+	}
+
+	fn proceed(&mut self) -> anyhow::Result<()> {
+		self.asked += 1;
+		match self.asked > self.allowed {
+			true => Err(anyhow::anyhow!("no point in going on")),
+			false => Ok(()),
+		}
+	}
+}
+
+#[test]
+fn progress_can_end_the_probe() {
+	let devices = Fake(vec![PathBuf::from("/nonexistent/a"), PathBuf::from("/nonexistent/b")]);
+	let spec = FsSpec::Uuid(UUID);
+
+	let mut progress = Impatient { allowed: 0, asked: 0, found: 0 };
+	let err = probe_for_with(&spec, &devices, &Paths::default(), &mut progress).unwrap_err();
+	assert_eq!(err.to_string(), "no point in going on");
+	assert_eq!((progress.asked, progress.found), (1, 0));
+
+	// nothing to ask about without devices
+	let mut progress = Impatient { allowed: 0, asked: 0, found: 0 };
+	assert!(probe_for_with(&spec, &Fake(Vec::new()), &Paths::default(), &mut progress).is_err());
+	assert_eq!((progress.asked, progress.found), (0, 0));
+}
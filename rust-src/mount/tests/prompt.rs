@@ -0,0 +1,128 @@
+//! The passphrase prompt's way down from /dev/tty to stdin, on pseudo
+//! terminals standing in for both.
+
+use bcachefs_mount::exit::{kind, ErrorKind};
+use bcachefs_mount::key::{EchoOff, TtyPrompt};
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+const PROMPT: &str = "Enter passphrase: ";
+
+/// A pseudo terminal: the master end, the slave end and its path
+struct Pty {
+	master: std::fs::File,
+	slave: std::fs::File,
+	path: PathBuf,
+}
+
+fn pty() -> Pty {
+	let (mut master, mut slave) = (0, 0);
+	let mut name = [0 as libc::c_char; 64];
+	let ret = unsafe {
+		libc::openpty(&mut master, &mut slave, name.as_mut_ptr(), std::ptr::null_mut(), std::ptr::null_mut())
+	};
+	assert_eq!(ret, 0, "openpty: {}", std::io::Error::last_os_error());
+	let path = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) }.to_str().unwrap().into();
+	unsafe { Pty { master: std::fs::File::from_raw_fd(master), slave: std::fs::File::from_raw_fd(slave), path } }
+}
+
+fn echo(fd: RawFd) -> bool {
+	let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+	assert_eq!(unsafe { libc::tcgetattr(fd, &mut termios) }, 0);
+	termios.c_lflag & libc::ECHO != 0
+}
+
+/// Whatever the terminal has shown so far, without waiting for more
+fn shown(master: &mut std::fs::File) -> String {
+	let fd = master.as_raw_fd();
+	unsafe { libc::fcntl(fd, libc::F_SETFL, libc::fcntl(fd, libc::F_GETFL) | libc::O_NONBLOCK) };
+	let mut out = Vec::new();
+	let mut buf = [0u8; 256];
+	while let Ok(n) = master.read(&mut buf) {
+		if n == 0 {
+			break;
+		}
+		out.extend_from_slice(&buf[..n]);
+	}
+	String::from_utf8(out).unwrap()
+}
+
+fn no_tty() -> &'static Path {
+	Path::new("/nonexistent/tty")
+}
+
+#[test]
+fn prompts_on_the_terminal_without_echo() {
+	let Pty { mut master, slave, path } = pty();
+	let stdin = slave.as_raw_fd();
+	let reader = std::thread::spawn(move || TtyPrompt::default().read(PROMPT, &path, stdin).unwrap());
+
+	let mut seen = Vec::new();
+	let mut buf = [0u8; 64];
+	while !String::from_utf8_lossy(&seen).contains(PROMPT) {
+		let n = master.read(&mut buf).unwrap();
+		seen.extend_from_slice(&buf[..n]);
+	}
+	// echo goes off after the prompt is written; typing before would show
+	while echo(stdin) {
+		std::thread::sleep(std::time::Duration::from_millis(1));
+	}
+	master.write_all(b"correct horse\n").unwrap();
+	assert_eq!(reader.join().unwrap(), "correct horse");
+
+	assert!(!shown(&mut master).contains("correct horse"));
+	assert!(echo(stdin));
+	drop(slave);
+}
+
+#[test]
+fn falls_back_to_stdin_when_it_is_a_terminal() {
+	let Pty { mut master, slave, .. } = pty();
+	let stdin = slave.as_raw_fd();
+	let reader = std::thread::spawn(move || TtyPrompt::default().read(PROMPT, no_tty(), stdin).unwrap());
+
+	while echo(stdin) {
+		std::thread::sleep(std::time::Duration::from_millis(1));
+	}
+	master.write_all(b"battery staple\r\n").unwrap();
+	assert_eq!(reader.join().unwrap(), "battery staple");
+
+	assert!(!shown(&mut master).contains("battery staple"));
+	assert!(echo(stdin));
+	drop(slave);
+}
+
+#[test]
+fn echoes_only_when_asked_to() {
+	let mut fds = [0; 2];
+	assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+	let (stdin, mut typed) = unsafe { (std::fs::File::from_raw_fd(fds[0]), std::fs::File::from_raw_fd(fds[1])) };
+	typed.write_all(b"in the clear\nnext line\n").unwrap();
+
+	for prompt in [TtyPrompt::default(), TtyPrompt { force: true, ..TtyPrompt::default() }].iter() {
+		let e = prompt.read(PROMPT, no_tty(), stdin.as_raw_fd()).unwrap_err();
+		assert_eq!(kind(&e), ErrorKind::KeyUnavailable);
+	}
+
+	let insecure = TtyPrompt { insecure_echo: true, ..TtyPrompt::default() };
+	assert_eq!(insecure.read(PROMPT, no_tty(), stdin.as_raw_fd()).unwrap(), "in the clear");
+	// only the line is taken from stdin
+	assert_eq!(insecure.read(PROMPT, no_tty(), stdin.as_raw_fd()).unwrap(), "next line");
+	drop(typed);
+	assert_eq!(insecure.read(PROMPT, no_tty(), stdin.as_raw_fd()).unwrap(), "");
+}
+
+#[test]
+fn echo_comes_back_after_a_panic() {
+	// the slave end fails with EIO once the master end is closed
+	let Pty { master: _master, slave, .. } = pty();
+	let fd = slave.as_raw_fd();
+	let result = std::panic::catch_unwind(|| {
+		let _echo_off = EchoOff::new(fd).unwrap();
+		assert!(!echo(fd));
+		panic!("while reading the passphrase");
+	});
+	assert!(result.is_err());
+	assert!(echo(fd));
+}
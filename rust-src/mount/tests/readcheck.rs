@@ -0,0 +1,64 @@
+//! The bounded walk and reads of --verify-after-mount, on a scratch tree.
+
+mod common;
+
+use bcachefs_mount::readcheck::check;
+use common::TempDir;
+use std::time::Duration;
+
+fn tree(name: &str) -> TempDir {
+	let root = TempDir::new(&format!("readcheck.{}", name));
+	std::fs::create_dir_all(root.join("a/b/c")).unwrap();
+	std::fs::write(root.join("top"), vec![1u8; 3 << 20]).unwrap();
+	std::fs::write(root.join("a/one"), vec![2u8; 1 << 20]).unwrap();
+	std::fs::write(root.join("a/b/two"), vec![3u8; 100]).unwrap();
+	// three levels down is too deep
+	std::fs::write(root.join("a/b/c/deep"), vec![4u8; 1 << 20]).unwrap();
+	std::fs::write(root.join("empty"), b"").unwrap();
+	std::os::unix::fs::symlink("/dev/zero", root.join("zero")).unwrap();
+	let fifo = std::ffi::CString::new(root.join("fifo").to_str().unwrap()).unwrap();
+	assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+	root
+}
+
+#[test]
+fn reads_regular_files_near_the_top() {
+	let root = tree("walk");
+	let report = check(&root, 64 << 20, Duration::from_secs(30));
+
+	assert!(report.errors.is_empty(), "{:?}", report.errors);
+	assert_eq!(report.files, 3);
+	assert_eq!(report.bytes, (3 << 20) + (1 << 20) + 100);
+	assert!(!report.timed_out);
+}
+
+#[test]
+fn spreads_the_budget() {
+	let root = tree("budget");
+	let report = check(&root, 1 << 20, Duration::from_secs(30));
+
+	assert_eq!(report.files, 3);
+	// what the small file doesn't use goes to the others
+	assert_eq!(report.bytes, 1 << 20);
+}
+
+#[test]
+fn missing_root_is_no_error() {
+	let report = check(std::path::Path::new("/nonexistent/bcachefs-mount"), 1 << 20, Duration::from_secs(1));
+	assert_eq!(report.files, 0);
+	assert!(report.errors.is_empty());
+}
+
+#[test]
+fn optional_amount() {
+	use bcachefs_mount::Options;
+	use structopt::StructOpt;
+
+	let parse = |args: &[&str]| {
+		Options::from_iter_safe(["bcachefs-mount"].iter().chain(args)).map(|o| (o.verify_after_mount, o.uuid.is_some()))
+	};
+	assert_eq!(parse(&["--verify-after-mount", "LABEL=x", "/mnt"]).unwrap(), (Some(None), true));
+	assert_eq!(parse(&["--verify-after-mount=4", "LABEL=x"]).unwrap(), (Some(Some(4)), true));
+	assert_eq!(parse(&["LABEL=x"]).unwrap(), (None, true));
+	assert!(parse(&["--verify-strict", "LABEL=x"]).is_err());
+}
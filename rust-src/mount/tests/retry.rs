@@ -0,0 +1,51 @@
+//! `--retry` parsing and the retry loop shared with keyring polls.
+
+use bcachefs_mount::retry::Retry;
+use std::time::{Duration, Instant};
+
+#[test]
+fn parses_count_and_delay() {
+	let retry = |s: &str| s.parse::<Retry>().map(|r| (r.count, r.delay));
+	assert_eq!(retry("5x2s").unwrap(), (5, Duration::from_secs(2)));
+	assert_eq!(retry("3x500ms").unwrap(), (3, Duration::from_millis(500)));
+	assert_eq!(retry("1x1m").unwrap(), (1, Duration::from_secs(60)));
+	assert_eq!(retry("10x3").unwrap(), (10, Duration::from_secs(3)));
+	for bad in &["", "5", "5x", "x2s", "5x2h", "-1x2s", "5x2.5s", "5xs"] {
+		let e = retry(bad).unwrap_err();
+		assert!(e.to_string().starts_with("invalid retry"), "{}: {}", bad, e);
+	}
+}
+
+#[test]
+fn retries_until_success_or_the_count() {
+	let retry = Retry { count: 3, delay: Duration::from_millis(10) };
+
+	let mut tries = 0;
+	let start = Instant::now();
+	let result: Result<u32, &str> = retry.run(|_| true, |n| {
+		tries += 1;
+		if n == 2 {
+			Ok(n)
+		} else {
+			Err("not yet")
+		}
+	});
+	assert_eq!(result, Ok(2));
+	assert_eq!(tries, 3);
+	assert!(start.elapsed() >= Duration::from_millis(20));
+
+	let mut tries = 0;
+	let result: Result<(), u32> = retry.run(|_| true, |n| {
+		tries += 1;
+		Err(n)
+	});
+	assert_eq!(result, Err(3));
+	assert_eq!(tries, 4);
+}
+
+#[test]
+fn other_failures_end_it() {
+	let retry = Retry { count: 5, delay: Duration::from_secs(60) };
+	let result: Result<(), &str> = retry.run(|e| *e == "not found", |_| Err("broken"));
+	assert_eq!(result, Err("broken"));
+}
@@ -0,0 +1,123 @@
+//! `--check-sb-copies` on images with deliberately damaged superblock copies.
+
+#![cfg(feature = "mount")]
+
+mod common;
+
+use bch_bindgen::bcachefs::{bch_sb, bch_sb_layout};
+use bch_bindgen::rs::SUPERBLOCK_MAGIC;
+use common::{Superblock, TempDir};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// An image `name` in `dir` with the superblock copies at `sectors`, each of
+/// seq `seq`, and whatever `doctor` does to each copy
+fn image(dir: &Path, name: &str, sectors: &[u64], seq: u64, doctor: impl Fn(usize, &mut bch_sb)) -> PathBuf {
+	let copy = |n| {
+		Superblock::default()
+			.doctor(|sb| {
+				sb.seq = seq;
+				sb.layout.magic.b = *SUPERBLOCK_MAGIC.as_bytes();
+				sb.layout.sb_max_size_bits = 7;
+				sb.layout.nr_superblocks = sectors.len() as u8;
+				sb.layout.sb_offset[..sectors.len()].copy_from_slice(sectors);
+				doctor(n, sb);
+			})
+			.bytes()
+	};
+
+	let primary = copy(usize::MAX);
+	let mut image = vec![0u8; *sectors.iter().max().unwrap() as usize * 512 + primary.len()];
+	let layout = &primary[primary.len() - std::mem::size_of::<bch_sb_layout>()..];
+	image[3584..3584 + layout.len()].copy_from_slice(layout);
+	for (n, sector) in sectors.iter().enumerate() {
+		let at = *sector as usize * 512;
+		image[at..at + primary.len()].copy_from_slice(&copy(n));
+	}
+	let path = dir.join(format!("{}.img", name));
+	std::fs::write(&path, &image).unwrap();
+	path
+}
+
+fn check(images: &[&PathBuf]) -> Output {
+	let mut cmd = Command::new(env!("CARGO_BIN_EXE_bcachefs-mount"));
+	for image in images {
+		cmd.arg("--check-sb-copies").arg(image);
+	}
+	cmd.output().unwrap()
+}
+
+#[test]
+fn intact_copies() {
+	let dir = TempDir::new("sb-copies.intact");
+	let path = image(&dir, "intact", &[8, 64], 3, |_, _| {});
+	let out = check(&[&path]);
+	let stdout = String::from_utf8_lossy(&out.stdout);
+	assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+	assert_eq!(
+		stdout,
+		format!(
+			"{0}: superblock copy 0 at sector 8: ok, seq 3\n{0}: superblock copy 1 at sector 64: ok, seq 3\n",
+			path.display()
+		)
+	);
+}
+
+#[test]
+fn damaged_primary_with_an_intact_backup() {
+	// the primary's checksum is wrong, the third copy is an older one
+	let dir = TempDir::new("sb-copies.damaged");
+	let path = image(&dir, "damaged", &[8, 64, 128], 3, |n, sb| match n {
+		0 => sb.csum.lo = 1,
+		2 => sb.seq = 2,
+		_ => {}
+	});
+	let out = check(&[&path]);
+	let stdout = String::from_utf8_lossy(&out.stdout);
+	assert_eq!(out.status.code(), Some(9));
+	let name = path.display();
+	assert!(stdout.contains(&format!("{}: superblock copy 0 at sector 8: checksum mismatch\n", name)), "{}", stdout);
+	assert!(stdout.contains(&format!("{}: superblock copy 1 at sector 64: ok, seq 3\n", name)), "{}", stdout);
+	let intact = format!("{0}: superblock copy 1 is intact, see `bcachefs-show-super --sb 1 {0}`\n", name);
+	assert!(stdout.contains(&intact), "{}", stdout);
+	assert!(String::from_utf8_lossy(&out.stderr).contains("on 1 of 1 devices"));
+}
+
+#[test]
+fn stale_copy_and_member() {
+	let dir = TempDir::new("sb-copies.stale");
+	let behind = image(&dir, "behind", &[8, 64], 2, |_, _| {});
+	let current = image(&dir, "current", &[8, 64], 3, |n, sb| {
+		if n == 1 {
+			sb.seq = 2;
+		}
+	});
+	let out = check(&[&behind, &current]);
+	let stdout = String::from_utf8_lossy(&out.stdout);
+	assert_eq!(out.status.code(), Some(9));
+	assert!(stdout.contains("at sector 64: seq 2, the primary's is 3\n"), "{}", stdout);
+	assert!(stdout.contains(&format!("{}: superblock seq 2 is behind the 3 of other members", behind.display())));
+	assert!(String::from_utf8_lossy(&out.stderr).contains("on 2 of 2 devices"));
+}
+
+#[test]
+fn intact_backup_inside_an_image() {
+	let dir = TempDir::new("sb-copies.offset");
+	let path = image(&dir, "offset", &[8, 64], 3, |n, sb| {
+		if n == 0 {
+			sb.csum.lo = 1;
+		}
+	});
+	let mut image = vec![0u8; 1 << 20];
+	image.extend(std::fs::read(&path).unwrap());
+	std::fs::write(&path, &image).unwrap();
+
+	let out = Command::new(env!("CARGO_BIN_EXE_bcachefs-mount"))
+		.args(&["--offset", "1048576", "--check-sb-copies"])
+		.arg(&path)
+		.output()
+		.unwrap();
+	let stdout = String::from_utf8_lossy(&out.stdout);
+	let intact = format!("see `bcachefs-show-super --sb 1 --offset 1048576 {}`\n", path.display());
+	assert!(stdout.contains(&intact), "{}", stdout);
+}
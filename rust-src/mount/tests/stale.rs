@@ -0,0 +1,98 @@
+//! Telling stale whole-disk superblocks apart, with a made up disk layout,
+//! and refusing to wipe anything that isn't one.
+
+mod common;
+
+use bcachefs_mount::stale::{drop_stale, stale_whole_disks, wipe};
+use common::TempDir;
+use std::path::{Path, PathBuf};
+
+fn partitions_of(dev: &Path) -> Vec<PathBuf> {
+	match dev.to_str().unwrap() {
+		"/dev/sdb" => vec![PathBuf::from("/dev/sdb1"), PathBuf::from("/dev/sdb2")],
+		"/dev/sdc" => vec![PathBuf::from("/dev/sdc1")],
+		_ => Vec::new(),
+	}
+}
+
+fn has_superblock(dev: &Path) -> bool {
+	["/dev/sda", "/dev/sdb", "/dev/sdb2", "/dev/sdc"].contains(&dev.to_str().unwrap())
+}
+
+#[test]
+fn disk_is_stale_when_a_partition_has_a_superblock() {
+	let devices: Vec<PathBuf> = ["/dev/sda", "/dev/sdb", "/dev/sdb2", "/dev/sdc"].iter().map(PathBuf::from).collect();
+	// sda has no partitions, and sdc's partition isn't bcachefs
+	assert_eq!(stale_whole_disks(&devices, partitions_of, has_superblock), vec![PathBuf::from("/dev/sdb")]);
+	// the partition needn't have been probed itself, e.g. with --only-device
+	assert_eq!(
+		stale_whole_disks(&[PathBuf::from("/dev/sdb")], partitions_of, has_superblock),
+		vec![PathBuf::from("/dev/sdb")]
+	);
+}
+
+#[test]
+fn wipe_needs_a_partitioned_disk() {
+	let dir = TempDir::new("stale");
+	let image = dir.join("image");
+	std::fs::write(&image, vec![0u8; 1 << 16]).unwrap();
+	let err = wipe(&image, &Default::default(), |_| panic!("asked to confirm")).unwrap_err();
+	assert!(err.to_string().ends_with("is not a whole disk with partitions, refusing to wipe its superblock"), "{}", err);
+	assert_eq!(bcachefs_mount::exit::kind(&err), bcachefs_mount::exit::ErrorKind::InvalidArgument);
+}
+
+#[test]
+fn filesystems_are_not_left_without_members() {
+	use bcachefs_mount::paths::Paths;
+	use common::{Superblock, UUID};
+
+	let base = TempDir::new("drop-stale");
+	let (dev, sys) = (base.join("dev"), base.join("sys"));
+	std::fs::create_dir_all(&dev).unwrap();
+	// sdb and sdc were partitioned after holding bcachefs, and their first
+	// partitions hold it now
+	for (disk, part) in &[("sdb", "sdb1"), ("sdc", "sdc1")] {
+		// at sector 8, with a layout saying how much of it to read back
+		let mut image = vec![0u8; 4096];
+		image.extend(Superblock::default().doctor(|sb| sb.layout.sb_max_size_bits = 7).bytes());
+		std::fs::write(dev.join(part), image).unwrap();
+		std::fs::write(dev.join(disk), "").unwrap();
+		let part = sys.join("class/block").join(disk).join(part);
+		std::fs::create_dir_all(&part).unwrap();
+		std::fs::write(part.join("partition"), "1\n").unwrap();
+	}
+	std::fs::write(dev.join("sda"), "").unwrap();
+	let paths = Paths { dev_root: dev, sys_root: sys, ..Paths::default() };
+
+	let (other, only_stale) = (uuid::Uuid::from_u128(1), uuid::Uuid::from_u128(2));
+	let member = |uuid, dev_idx| {
+		Superblock::default()
+			.uuid(uuid)
+			.doctor(|sb| {
+				sb.nr_devices = 2;
+				sb.dev_idx = dev_idx;
+			})
+			.build()
+	};
+	let sbs = [member(UUID, 0), member(UUID, 1), member(other, 0), member(other, 1)];
+	let sb_only_stale = Superblock::default().uuid(only_stale).build();
+	let mut fs = common::filesystem(&sbs[0], "/dev/sdb1");
+	fs.merge(common::filesystem(&sbs[1], "/dev/sda"));
+	let mut fs_other = common::filesystem(&sbs[2], "/dev/sdc1");
+	fs_other.merge(common::filesystem(&sbs[3], "/dev/sdc"));
+	let mut fss: std::collections::HashMap<_, _> = vec![
+		(UUID, fs),
+		(other, fs_other),
+		(only_stale, common::filesystem(&sb_only_stale, "/dev/sdb")),
+	]
+	.into_iter()
+	.collect();
+
+	let mut dropped = drop_stale(&mut fss, &paths);
+	dropped.sort();
+	assert_eq!(dropped, vec![PathBuf::from("/dev/sdb"), PathBuf::from("/dev/sdc")]);
+	assert_eq!(fss.len(), 2);
+	assert_eq!(fss[&UUID].device_string(), "/dev/sdb1:/dev/sda");
+	assert_eq!(fss[&other].device_string(), "/dev/sdc1");
+	assert!(!fss.contains_key(&only_stale));
+}
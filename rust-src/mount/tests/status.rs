@@ -0,0 +1,120 @@
+//! The --status line format, which monitoring scripts depend on.
+
+mod common;
+
+use bcachefs_mount::filesystem::Status;
+use common::{TempDir, UUID};
+use std::path::PathBuf;
+
+fn status() -> Status {
+	Status {
+		uuid: UUID,
+		label: Some("tank".to_owned()),
+		devices_found: 4,
+		devices_total: 4,
+		encrypted: true,
+		mounted: vec![PathBuf::from("/mnt")],
+	}
+}
+
+#[test]
+fn keys_in_fixed_order() {
+	assert_eq!(
+		status().to_string(),
+		"uuid=8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a label=tank state=ok devices=4/4 encrypted=yes mounted=/mnt"
+	);
+}
+
+#[test]
+fn degraded_and_unmounted() {
+	let s = Status { devices_found: 3, encrypted: false, label: None, mounted: Vec::new(), ..status() };
+	assert!(s.to_string().ends_with(" label= state=degraded devices=3/4 encrypted=no mounted="), "{}", s);
+}
+
+#[test]
+fn values_never_contain_separators() {
+	let s = Status {
+		label: Some("my pool".to_owned()),
+		mounted: vec![PathBuf::from("/srv/a,b"), PathBuf::from("/srv/c d")],
+		..status()
+	};
+	let line = s.to_string();
+	assert!(line.contains(" label=my\\040pool "), "{}", line);
+	assert!(line.ends_with(" mounted=/srv/a\\054b,/srv/c\\040d"), "{}", line);
+}
+
+#[test]
+fn as_json() {
+	let s = Status { label: None, devices_found: 3, mounted: vec![PathBuf::from("/srv/\"a\"")], ..status() };
+	assert_eq!(
+		s.to_json(),
+		concat!(
+			r#"{"uuid":"8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a","label":null,"state":"degraded","#,
+			r#""devices_found":3,"devices_total":4,"encrypted":true,"mounted":["/srv/\"a\""]}"#
+		)
+	);
+}
+
+#[test]
+fn status_file_schema() {
+	use bcachefs_mount::statusfile::render;
+
+	let degraded = Status { devices_found: 1, devices_total: 2, label: None, mounted: Vec::new(), ..status() };
+	assert_eq!(
+		render(&[status(), degraded]),
+		concat!(
+			r#"{"schema_version":1,"filesystems":["#,
+			r#"{"uuid":"8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a","label":"tank","state":"ok","devices_found":4,"#,
+			r#""devices_total":4,"encrypted":true,"mounted":["/mnt"]},"#,
+			r#"{"uuid":"8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a","label":null,"state":"degraded","devices_found":1,"#,
+			r#""devices_total":2,"encrypted":true,"mounted":[]}]}"#,
+			"\n"
+		)
+	);
+	assert_eq!(render(&[]), "{\"schema_version\":1,\"filesystems\":[]}\n");
+}
+
+#[test]
+fn status_file_is_replaced_readable_by_all() {
+	use bcachefs_mount::statusfile::write;
+	use std::os::unix::fs::PermissionsExt;
+
+	let dir = TempDir::new("statusfile");
+	let path = dir.join("status.json");
+	let old_umask = unsafe { libc::umask(0o077) };
+	write(&path, &[status()]).unwrap();
+	write(&path, &[]).unwrap();
+	unsafe { libc::umask(old_umask) };
+
+	let mode = std::fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+	let contents = std::fs::read_to_string(&path).unwrap();
+	let mut left: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+	left.sort();
+
+	assert_eq!(mode, 0o644);
+	assert_eq!(contents, "{\"schema_version\":1,\"filesystems\":[]}\n");
+	// only the lock file stays next to it
+	assert_eq!(left, ["status.json", "status.json.lock"]);
+}
+
+#[test]
+fn placements_for_list_verbose() {
+	use bcachefs_mount::filesystem::Placement;
+	use bch_bindgen::bcachefs::Role;
+
+	let fast = Placement {
+		device: PathBuf::from("/dev/nvme0n1"),
+		dev_idx: 0,
+		label: Some("ssd.fast".to_owned()),
+		roles: vec![Role::Foreground, Role::Promote],
+	};
+	assert_eq!(fast.to_string(), "/dev/nvme0n1 (dev 0): ssd.fast, foreground promote");
+	assert_eq!(
+		fast.to_json(),
+		r#"{"device":"/dev/nvme0n1","dev_idx":0,"label":"ssd.fast","roles":["foreground","promote"]}"#
+	);
+
+	let unlabelled = Placement { device: PathBuf::from("/dev/sdb"), dev_idx: 2, label: None, roles: Vec::new() };
+	assert_eq!(unlabelled.to_string(), "/dev/sdb (dev 2): no label");
+	assert_eq!(unlabelled.to_json(), r#"{"device":"/dev/sdb","dev_idx":2,"label":null,"roles":[]}"#);
+}
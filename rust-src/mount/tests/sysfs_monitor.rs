@@ -0,0 +1,138 @@
+//! bcachefs-watch on sysfs trees laid out as older and newer kernels do.
+
+mod common;
+
+use bcachefs_mount::exit::{kind, ErrorKind};
+use bcachefs_mount::sysfs_monitor::{changes, read_members, watch, Change, IoErrors, Member};
+use bcachefs_mount::paths::Paths;
+use common::{TempDir, UUID};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// `io_errors` of a newer kernel, with other counts since the last reset
+fn io_errors(read: u64, write: u64, checksum: u64) -> String {
+	format!(
+		"IO errors since filesystem creation\n  read:    {}\n  write:   {}\n  checksum:{}\n\
+		IO errors since 8 h ago\n  read:    99\n  write:   99\n  checksum:99\n",
+		read, write, checksum
+	)
+}
+
+/// A sysfs root with the filesystem's directory, attributes of its own and
+/// members `dev-<index>` on `loop<index>`, with `state` and optionally
+/// `io_errors` attributes
+fn sysfs(name: &str, members: &[(u32, &str, Option<String>)]) -> (TempDir, Paths) {
+	let base = TempDir::new(&format!("monitor.{}", name));
+	let fs = base.join("sys/fs/bcachefs").join(UUID.to_string());
+	std::fs::create_dir_all(fs.join("options")).unwrap();
+	std::fs::create_dir_all(fs.join("internal")).unwrap();
+	std::fs::write(fs.join("internal_uuid"), "5e0b3c1d-0000-4000-8000-000000000001\n").unwrap();
+	for (index, state, errors) in members {
+		let dev = fs.join(format!("dev-{}", index));
+		std::fs::create_dir_all(&dev).unwrap();
+		std::fs::write(dev.join("state"), state).unwrap();
+		std::fs::write(dev.join("durability"), "1\n").unwrap();
+		let block = format!("../../../../devices/virtual/block/loop{}", index);
+		std::os::unix::fs::symlink(block, dev.join("block")).unwrap();
+		if let Some(errors) = errors {
+			std::fs::write(dev.join("io_errors"), errors).unwrap();
+		}
+	}
+	let paths = Paths { sys_root: base.join("sys"), ..Paths::default() };
+	(base, paths)
+}
+
+fn state_file(base: &Path, index: u32) -> PathBuf {
+	base.join("sys/fs/bcachefs").join(UUID.to_string()).join(format!("dev-{}/state", index))
+}
+
+#[test]
+fn reads_older_kernel_layout() {
+	let (_base, paths) = sysfs("older", &[(1, "rw [ro] failed spare\n", None), (0, "[rw] ro failed spare\n", None)]);
+	let members = read_members(&UUID, &paths).unwrap();
+	assert_eq!(
+		members,
+		vec![
+			Member { index: 0, device: Some(PathBuf::from("/dev/loop0")), state: "rw".to_owned(), errors: None },
+			Member { index: 1, device: Some(PathBuf::from("/dev/loop1")), state: "ro".to_owned(), errors: None },
+		]
+	);
+}
+
+#[test]
+fn reads_newer_kernel_layout() {
+	let (_base, paths) = sysfs("newer", &[(0, "rw\n", Some(io_errors(1, 0, 3)))]);
+	let members = read_members(&UUID, &paths).unwrap();
+	assert_eq!(members[0].state, "rw");
+	assert_eq!(members[0].errors, Some(IoErrors { read: 1, write: 0, checksum: 3 }));
+}
+
+#[test]
+fn changes_between_polls() {
+	let member = |index, state: &str, checksum| Member {
+		index,
+		device: None,
+		state: state.to_owned(),
+		errors: Some(IoErrors { read: 0, write: 0, checksum }),
+	};
+	let old = [member(0, "rw", 0), member(1, "rw", 0)];
+	let new = [member(0, "rw", 2), member(2, "failed", 0)];
+	let found = changes(&old, &new);
+	let lines: Vec<_> = found.iter().map(|c| c.to_string()).collect();
+	assert_eq!(lines, ["dev-1: removed", "dev-0: checksum errors 0 -> 2", "dev-2: added, failed"]);
+	// only going to failed counts as failing
+	assert!(!found.iter().any(Change::failed));
+
+	let failed = changes(&[member(1, "rw", 0)], &[member(1, "failed", 0)]);
+	assert!(failed[0].failed());
+	assert_eq!(failed[0].to_string(), "dev-1: rw -> failed");
+}
+
+#[test]
+fn exits_when_a_member_fails() {
+	let (base, paths) = sysfs("fails", &[(0, "[rw] ro failed spare\n", None), (1, "[rw] ro failed spare\n", None)]);
+	let state = state_file(&base, 1);
+	let failer = std::thread::spawn(move || {
+		std::thread::sleep(Duration::from_millis(100));
+		// renamed into place, so that a poll doesn't see it half written
+		let new = state.with_extension("new");
+		std::fs::write(&new, "rw ro [failed] spare\n").unwrap();
+		std::fs::rename(new, state).unwrap();
+	});
+	let mut out = Vec::new();
+	let e = watch(&mut out, &UUID, Duration::from_millis(10), &paths).unwrap_err();
+	failer.join().unwrap();
+
+	assert_eq!(kind(&e), ErrorKind::Device);
+	assert_eq!(e.to_string(), format!("member dev-1 (/dev/loop1) of filesystem {} failed", UUID));
+	assert_eq!(
+		String::from_utf8(out).unwrap(),
+		"dev-0 (/dev/loop0): rw\ndev-1 (/dev/loop1): rw\ndev-1 (/dev/loop1): rw -> failed\n"
+	);
+}
+
+#[test]
+fn ends_when_unmounted() {
+	let (base, paths) = sysfs("unmounted", &[(0, "rw\n", Some(io_errors(0, 0, 0)))]);
+	let fs = base.join("sys/fs/bcachefs").join(UUID.to_string());
+	let unmount = std::thread::spawn(move || {
+		std::thread::sleep(Duration::from_millis(100));
+		// all at once, as unmounting does
+		std::fs::rename(&fs, fs.with_file_name("unmounted")).unwrap();
+	});
+	let mut out = Vec::new();
+	watch(&mut out, &UUID, Duration::from_millis(10), &paths).unwrap();
+	unmount.join().unwrap();
+
+	let out = String::from_utf8(out).unwrap();
+	assert_eq!(
+		out,
+		format!(
+			"dev-0 (/dev/loop0): rw, I/O errors: 0 read, 0 write, 0 checksum\nfilesystem {} is no longer mounted\n",
+			UUID
+		)
+	);
+
+	let e = watch(&mut Vec::new(), &UUID, Duration::from_millis(10), &paths).unwrap_err();
+	assert_eq!(kind(&e), ErrorKind::NotFound);
+}
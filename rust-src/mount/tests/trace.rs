@@ -0,0 +1,109 @@
+//! Chrome trace events for --trace-output, checked against a snapshot of a
+//! small synthetic trace.
+
+mod common;
+
+use bcachefs_mount::trace::ChromeLayer;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::layer::SubscriberExt;
+
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.0.lock().unwrap().extend_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+
+static NOW: AtomicU64 = AtomicU64::new(1000);
+
+/// Ten microseconds pass between any two events
+fn clock() -> u64 {
+	NOW.fetch_add(10, Ordering::SeqCst)
+}
+
+fn gettid() -> String {
+	unsafe { libc::syscall(libc::SYS_gettid) }.to_string()
+}
+
+fn contents(buffer: &Buffer) -> String {
+	String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap()
+}
+
+#[test]
+fn spans_become_begin_end_pairs() {
+	let buffer = Buffer::default();
+	let layer = ChromeLayer::with_clock(buffer.clone(), clock).unwrap();
+	let subscriber = tracing_subscriber::registry().with(layer);
+	tracing::subscriber::with_default(subscriber, || {
+		let mount = tracing::info_span!("mount", uuid = %uuid::Uuid::from_u128(1), devices = "/dev/sda:/dev/\"b\"");
+		mount.in_scope(|| {
+			tracing::info_span!("phase", phase = "key").in_scope(|| {});
+		});
+	});
+
+	let expected = r#"[
+{"name":"mount","ph":"B","ts":1000,"pid":PID,"tid":TID,"args":{"uuid":"00000000-0000-0000-0000-000000000001","devices":"/dev/sda:/dev/\"b\""}},
+{"name":"phase","ph":"B","ts":1010,"pid":PID,"tid":TID,"args":{"phase":"key"}},
+{"name":"phase","ph":"E","ts":1020,"pid":PID,"tid":TID},
+{"name":"mount","ph":"E","ts":1030,"pid":PID,"tid":TID},
+"#;
+	let expected = expected.replace("PID", &std::process::id().to_string()).replace("TID", &gettid());
+	assert_eq!(contents(&buffer), expected);
+}
+
+#[test]
+fn threads_have_their_own_tid() {
+	static NOW: AtomicU64 = AtomicU64::new(1000);
+	fn clock() -> u64 {
+		NOW.fetch_add(10, Ordering::SeqCst)
+	}
+
+	let buffer = Buffer::default();
+	let layer = ChromeLayer::with_clock(buffer.clone(), clock).unwrap();
+	let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(layer));
+	let mut probe = String::new();
+	tracing::dispatcher::with_default(&dispatch, || {
+		tracing::info_span!("mount").in_scope(|| {
+			// e.g. a device probed in the background while the key is asked for
+			let dispatch = dispatch.clone();
+			let thread = std::thread::spawn(move || {
+				tracing::dispatcher::with_default(&dispatch, || tracing::info_span!("probe").in_scope(gettid))
+			});
+			probe = thread.join().unwrap();
+		});
+	});
+
+	let main = gettid();
+	assert_ne!(main, probe);
+	let expected = r#"[
+{"name":"mount","ph":"B","ts":1000,"pid":PID,"tid":MAIN,"args":{}},
+{"name":"probe","ph":"B","ts":1010,"pid":PID,"tid":PROBE,"args":{}},
+{"name":"probe","ph":"E","ts":1020,"pid":PID,"tid":PROBE},
+{"name":"mount","ph":"E","ts":1030,"pid":PID,"tid":MAIN},
+"#;
+	let expected = expected.replace("PID", &std::process::id().to_string());
+	assert_eq!(contents(&buffer), expected.replace("MAIN", &main).replace("PROBE", &probe));
+}
+
+#[test]
+fn trace_file_is_created() {
+	let dir = common::TempDir::new("trace");
+	let path = dir.join("trace.json");
+	{
+		let layer = ChromeLayer::create(&path).unwrap();
+		let subscriber = tracing_subscriber::registry().with(layer);
+		tracing::subscriber::with_default(subscriber, || tracing::info_span!("probe").in_scope(|| {}));
+	}
+	let trace = std::fs::read_to_string(&path).unwrap();
+	assert!(trace.starts_with("[\n{\"name\":\"probe\",\"ph\":\"B\""), "{}", trace);
+	assert_eq!(trace.lines().count(), 3);
+}
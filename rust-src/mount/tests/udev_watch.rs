@@ -0,0 +1,62 @@
+//! Incremental updates of the filesystems --watch knows about, on doctored
+//! superblocks.
+
+mod common;
+
+use bcachefs_mount::udev_watch::Inventory;
+use bch_bindgen::rs::SbBuf;
+use common::{filesystem as probed, Superblock, UUID};
+use std::path::{Path, PathBuf};
+
+/// The superblock of member `dev_idx` of a two device filesystem
+fn member(dev_idx: u8) -> SbBuf {
+	Superblock::default()
+		.doctor(|sb| {
+			sb.nr_devices = 2;
+			sb.dev_idx = dev_idx;
+		})
+		.build()
+}
+
+#[test]
+fn members_come_and_go() {
+	let (a, b) = (member(0), member(1));
+	let mut inventory = Inventory::default();
+
+	let change = inventory.add(UUID, probed(&a, "/dev/sda")).unwrap();
+	assert!(change.added && change.degraded());
+	let change = inventory.add(UUID, probed(&b, "/dev/sdb")).unwrap();
+	assert_eq!(change.devices, vec![PathBuf::from("/dev/sda"), PathBuf::from("/dev/sdb")]);
+	assert!(!change.degraded());
+	assert_eq!(inventory.filesystems()[&UUID].device_string(), "/dev/sda:/dev/sdb");
+
+	let change = inventory.remove(Path::new("/dev/sda")).unwrap();
+	assert!(!change.added && change.degraded());
+	assert_eq!(inventory.filesystems()[&UUID].device_string(), "/dev/sdb");
+
+	inventory.remove(Path::new("/dev/sdb")).unwrap();
+	assert!(inventory.filesystems().is_empty());
+}
+
+#[test]
+fn unchanged_composition_is_not_reported() {
+	let sb = member(0);
+	let mut inventory = Inventory::default();
+	inventory.add(UUID, probed(&sb, "/dev/sda")).unwrap();
+	assert_eq!(inventory.add(UUID, probed(&sb, "/dev/sda")), None);
+	// another path to the same member
+	assert_eq!(inventory.add(UUID, probed(&sb, "/dev/sdc")), None);
+	assert_eq!(inventory.filesystems()[&UUID].device_string(), "/dev/sda");
+	assert_eq!(inventory.remove(Path::new("/dev/sdc")), None);
+}
+
+#[test]
+fn change_as_json() {
+	let sb = member(0);
+	let mut inventory = Inventory::default();
+	let change = inventory.add(UUID, probed(&sb, "/dev/sda")).unwrap();
+	assert_eq!(
+		change.to_json(),
+		r#"{"event":"add","uuid":"8b1c7a3e-5f0e-4d0a-9b5e-3c2a1d0e9f8a","device":"/dev/sda","devices":["/dev/sda"],"devices_found":1,"devices_total":2,"degraded":true}"#
+	);
+}